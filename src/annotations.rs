@@ -0,0 +1,233 @@
+use serde::Serialize;
+
+/*
+Move annotations
+
+Lichess and chess.com put machine readable commands in move comments:
+
+    1. e4 { [%eval 0.32] [%clk 0:02:59] } 1... e5 { [%clk 0:02:58.5] }
+
+%clk is the clock after the move (increment included), %eval is from white's
+point of view in pawns, or "#-3" for a mate. They are kept per ply, with the
+time the move took worked out from the previous clock of the same side and
+the TimeControl header, and stored per game as one blob:
+
+    per ply, big endian:
+        u32 clock remaining, centiseconds (u32::MAX = none)
+        u32 time spent, centiseconds      (u32::MAX = none)
+        i32 eval, centipawns              (i32::MIN = none, |v| >= MATE_OFFSET mate in |v| - MATE_OFFSET)
+ */
+
+pub const PLY_ANNOTATION_SIZE: usize = 12;
+const MATE_OFFSET: i32 = 1_000_000;
+const NONE_U32: u32 = u32::MAX;
+const NONE_EVAL: i32 = i32::MIN;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Eval {
+    Centipawns(i32),
+    Mate(i32), // moves to mate, negative when black mates
+}
+
+impl Eval {
+    // centipawns with mates pinned to +-limit, for graphs and eval swings
+    pub fn clamped(&self, limit: i32) -> i32 {
+        match *self {
+            Eval::Centipawns(cp) => cp.clamp(-limit, limit),
+            Eval::Mate(n) if n < 0 => -limit,
+            Eval::Mate(_) => limit,
+        }
+    }
+
+    fn encode(eval: Option<Eval>) -> i32 {
+        match eval {
+            None => NONE_EVAL,
+            Some(Eval::Centipawns(cp)) => cp.clamp(1 - MATE_OFFSET, MATE_OFFSET - 1),
+            Some(Eval::Mate(n)) if n < 0 => -MATE_OFFSET + n,
+            Some(Eval::Mate(n)) => MATE_OFFSET + n,
+        }
+    }
+
+    fn decode(v: i32) -> Option<Eval> {
+        match v {
+            NONE_EVAL => None,
+            v if v >= MATE_OFFSET => Some(Eval::Mate(v - MATE_OFFSET)),
+            v if v <= -MATE_OFFSET => Some(Eval::Mate(v + MATE_OFFSET)),
+            v => Some(Eval::Centipawns(v)),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct PlyAnnotation {
+    pub clock: Option<u32>, // centiseconds left after the move
+    pub spent: Option<u32>, // centiseconds the move took
+    pub eval: Option<Eval>,
+}
+
+// "180+2" -> 180s base, 2s increment; "40/7200:3600" uses the first period;
+// "-" (no clock) and anything unreadable give None
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct TimeControl {
+    pub base: u32,      // seconds
+    pub increment: u32, // seconds
+}
+
+impl TimeControl {
+    pub fn parse(tc: &str) -> Option<TimeControl> {
+        let period = tc.trim().split(':').next()?;
+        let (base, increment) = period.split_once('+').unwrap_or((period, "0"));
+        // moves/seconds, as in "40/7200" or chess.com's daily "1/86400"
+        let base = base.rsplit('/').next()?;
+        Some(TimeControl { base: base.parse().ok()?, increment: increment.parse().ok()? })
+    }
+}
+
+// the %clk and %eval commands in a comment
+pub fn parse_commands(comment: &str) -> (Option<u32>, Option<Eval>) {
+    let (mut clock, mut eval) = (None, None);
+    for (name, value) in commands(comment) {
+        match name {
+            "clk" => clock = parse_clock(value).or(clock),
+            "eval" => eval = parse_eval(value).or(eval),
+            _ => (),
+        }
+    }
+    (clock, eval)
+}
+
+// the comment with the [%...] commands taken out
+pub fn strip_commands(comment: &str) -> String {
+    let mut text = String::with_capacity(comment.len());
+    let mut rest = comment;
+    while let Some(start) = rest.find("[%") {
+        text.push_str(&rest[..start]);
+        rest = match rest[start..].find(']') {
+            Some(end) => &rest[start + end + 1..],
+            None => "",
+        };
+    }
+    text.push_str(rest);
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn commands(comment: &str) -> impl Iterator<Item = (&str, &str)> {
+    comment.split("[%").skip(1).filter_map(|c| {
+        let body = c.split(']').next()?.trim();
+        Some(body.split_once(char::is_whitespace).unwrap_or((body, "")))
+    })
+}
+
+// h:mm:ss with optional fractions of a second, in centiseconds
+fn parse_clock(value: &str) -> Option<u32> {
+    let mut seconds = 0.0;
+    for part in value.trim().split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some((seconds * 100.0).round() as u32)
+}
+
+// "0.32", "-1.5,23" (with depth), "#-3"
+fn parse_eval(value: &str) -> Option<Eval> {
+    let value = value.trim().split(',').next()?;
+    match value.strip_prefix('#') {
+        Some(mate) => Some(Eval::Mate(mate.parse().ok()?)),
+        None => Some(Eval::Centipawns((value.parse::<f64>().ok()? * 100.0).round() as i32)),
+    }
+}
+
+// time spent per ply from the clocks: the side's previous clock (or the base time
+// for its first move) plus the increment, minus the clock after the move
+pub fn fill_spent(plies: &mut [PlyAnnotation], tc: Option<TimeControl>) {
+    let increment = tc.map_or(0, |tc| tc.increment * 100);
+    for ply in 0..plies.len() {
+        let previous = match ply {
+            0 | 1 => tc.map(|tc| tc.base * 100),
+            _ => plies[ply - 2].clock,
+        };
+        plies[ply].spent = match (previous, plies[ply].clock) {
+            (Some(previous), Some(clock)) => Some((previous + increment).saturating_sub(clock)),
+            _ => None,
+        };
+    }
+}
+
+pub fn encode(plies: &[PlyAnnotation]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(plies.len() * PLY_ANNOTATION_SIZE);
+    for ply in plies {
+        bytes.extend_from_slice(&ply.clock.unwrap_or(NONE_U32).to_be_bytes());
+        bytes.extend_from_slice(&ply.spent.unwrap_or(NONE_U32).to_be_bytes());
+        bytes.extend_from_slice(&Eval::encode(ply.eval).to_be_bytes());
+    }
+    bytes
+}
+
+pub fn decode(bytes: &[u8]) -> Vec<PlyAnnotation> {
+    let optional = |v: u32| (v != NONE_U32).then_some(v);
+    bytes
+        .chunks_exact(PLY_ANNOTATION_SIZE)
+        .map(|b| PlyAnnotation {
+            clock: optional(u32::from_be_bytes(b[0..4].try_into().unwrap())),
+            spent: optional(u32::from_be_bytes(b[4..8].try_into().unwrap())),
+            eval: Eval::decode(i32::from_be_bytes(b[8..12].try_into().unwrap())),
+        })
+        .collect()
+}
+
+// plies where the mover had under `under` centiseconds left and the eval swung at
+// least `drop` centipawns against them
+pub fn time_trouble_blunders(plies: &[PlyAnnotation], under: u32, drop: i32) -> Vec<usize> {
+    let mut blunders = Vec::new();
+    for ply in 1..plies.len() {
+        let (before, after) = match (plies[ply - 1].eval, plies[ply].eval) {
+            (Some(before), Some(after)) => (before.clamped(10_000), after.clamped(10_000)),
+            _ => continue,
+        };
+        // the clock before the move was made
+        let clock = match ply {
+            1 => None,
+            _ => plies[ply - 2].clock,
+        };
+        let swing = if ply % 2 == 0 { before - after } else { after - before };
+        if clock.is_some_and(|c| c < under) && swing >= drop {
+            blunders.push(ply);
+        }
+    }
+    blunders
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::game_for_pgn;
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(parse_commands("[%eval 0.32] [%clk 0:02:59]"), (Some(17900), Some(Eval::Centipawns(32))));
+        assert_eq!(parse_commands(" [%clk 1:00:00.5] great move [%eval #-3]"), (Some(360050), Some(Eval::Mate(-3))));
+        assert_eq!(parse_commands("[%eval -1.5,23] [%csl Ga4]"), (None, Some(Eval::Centipawns(-150))));
+        assert_eq!(parse_commands("no commands"), (None, None));
+        assert_eq!(strip_commands("[%eval 0.32] a  good [%clk 0:02:59] move"), "a good move");
+
+        assert_eq!(TimeControl::parse("180+2"), Some(TimeControl { base: 180, increment: 2 }));
+        assert_eq!(TimeControl::parse("40/7200:3600"), Some(TimeControl { base: 7200, increment: 0 }));
+        assert_eq!(TimeControl::parse("-"), None);
+    }
+
+    #[test]
+    fn test_plies_from_pgn() {
+        let pgn = "[TimeControl \"60+1\"] 1. e4 { [%eval 0.3] [%clk 0:01:00] } 1... e5 { [%eval 0.3] [%clk 0:00:58] } \
+                   2. Qh5 { [%eval -0.5] [%clk 0:00:55] } 2... Ke7 { [%eval #4] [%clk 0:00:09] } 3. Qxe5# { [%eval #0] } 1-0";
+        let gv = game_for_pgn(pgn).unwrap();
+        let plies = gv.annotations();
+        assert_eq!(plies.len(), 5);
+        assert_eq!(plies.iter().map(|p| p.clock).collect::<Vec<_>>(), vec![Some(6000), Some(5800), Some(5500), Some(900), None]);
+        assert_eq!(plies.iter().map(|p| p.spent).collect::<Vec<_>>(), vec![Some(100), Some(300), Some(600), Some(5000), None]);
+        assert_eq!(plies[3].eval, Some(Eval::Mate(4)));
+        assert!(gv.comments.is_empty());
+
+        assert_eq!(decode(&encode(&plies)), plies);
+        // black was under a minute when Ke7 threw the game away
+        assert_eq!(time_trouble_blunders(&plies, 6000, 300), vec![3]);
+    }
+}
@@ -0,0 +1,514 @@
+use std::io::{self, Write};
+
+/*
+Arrow IPC files
+
+Just enough of the Arrow IPC file format (what pandas.read_feather, polars and
+duckdb read as Feather V2 / .arrow) to write tables of nullable Utf8, Int64 and
+Float64 columns a record batch at a time, so a big export never has to sit in memory.
+No dictionaries, no compression. The layout, from the Arrow columnar spec:
+
+    "ARROW1\0\0"
+    message: schema
+    message: record batch, every BATCH_ROWS rows
+    0xFFFFFFFF 0x00000000 (end of stream)
+    footer flatbuffer: the schema again and where each record batch starts
+    i32 footer length, "ARROW1"
+
+A message is 0xFFFFFFFF, the i32 length of its flatbuffer metadata padded to
+8 bytes, the metadata, then the body: each column's buffers (validity bitmap,
+then values, or offsets and utf8 data) one after the other, each starting on 8
+bytes. All integers are little endian.
+
+The flatbuffers are built forwards: a table's vtable and inline fields first,
+then the strings, vectors and tables it points to, whose offsets are patched
+in once they are written (flatbuffer offsets only ever point forwards).
+ */
+
+const MAGIC: &[u8; 6] = b"ARROW1";
+const CONTINUATION: u32 = 0xffff_ffff;
+const METADATA_V5: i16 = 4;
+const HEADER_SCHEMA: u8 = 1;
+const HEADER_RECORD_BATCH: u8 = 3;
+const TYPE_INT: u8 = 2;
+const TYPE_FLOATING_POINT: u8 = 3;
+const PRECISION_DOUBLE: i16 = 2;
+const TYPE_UTF8: u8 = 5;
+pub const BATCH_ROWS: usize = 65_536;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    Utf8,
+    Int64,
+    Float64,
+}
+
+pub enum Cell<'a> {
+    Text(Option<&'a str>),
+    Int(Option<i64>),
+    Float(Option<f64>),
+}
+
+enum Column {
+    Utf8 { valid: Vec<bool>, offsets: Vec<i32>, data: Vec<u8> },
+    Int64 { valid: Vec<bool>, values: Vec<i64> },
+    Float64 { valid: Vec<bool>, values: Vec<f64> },
+}
+
+impl Column {
+    fn new(column_type: ColumnType) -> Column {
+        match column_type {
+            ColumnType::Utf8 => Column::Utf8 { valid: Vec::new(), offsets: vec![0], data: Vec::new() },
+            ColumnType::Int64 => Column::Int64 { valid: Vec::new(), values: Vec::new() },
+            ColumnType::Float64 => Column::Float64 { valid: Vec::new(), values: Vec::new() },
+        }
+    }
+}
+
+// offset of the message in the file, its metadata length (prefix included) and body length
+struct Block {
+    offset: i64,
+    metadata: i32,
+    body: i64,
+}
+
+pub struct ArrowWriter<W: Write> {
+    out: W,
+    written: usize,
+    schema: Vec<(String, ColumnType)>,
+    columns: Vec<Column>,
+    rows: usize,
+    blocks: Vec<Block>,
+}
+
+impl<W: Write> ArrowWriter<W> {
+    pub fn new(out: W, schema: &[(&str, ColumnType)]) -> io::Result<ArrowWriter<W>> {
+        let schema: Vec<(String, ColumnType)> = schema.iter().map(|(name, t)| (name.to_string(), *t)).collect();
+        let mut writer = ArrowWriter {
+            out,
+            written: 0,
+            columns: schema.iter().map(|(_, t)| Column::new(*t)).collect(),
+            schema,
+            rows: 0,
+            blocks: Vec::new(),
+        };
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[0, 0])?;
+        let message = message(HEADER_SCHEMA, schema_table(&writer.schema), 0);
+        writer.write_message(&message, &[])?;
+        Ok(writer)
+    }
+
+    // cells in schema order; a cell of the wrong type is written as null
+    pub fn push(&mut self, row: &[Cell]) -> io::Result<()> {
+        for (column, cell) in self.columns.iter_mut().zip(row) {
+            match (column, cell) {
+                (Column::Utf8 { valid, offsets, data }, cell) => {
+                    let text = match cell {
+                        Cell::Text(text) => *text,
+                        _ => None,
+                    };
+                    valid.push(text.is_some());
+                    data.extend_from_slice(text.unwrap_or("").as_bytes());
+                    offsets.push(data.len() as i32);
+                }
+                (Column::Int64 { valid, values }, cell) => {
+                    let value = match cell {
+                        Cell::Int(value) => *value,
+                        _ => None,
+                    };
+                    valid.push(value.is_some());
+                    values.push(value.unwrap_or(0));
+                }
+                (Column::Float64 { valid, values }, cell) => {
+                    let value = match cell {
+                        Cell::Float(value) => *value,
+                        _ => None,
+                    };
+                    valid.push(value.is_some());
+                    values.push(value.unwrap_or(0.0));
+                }
+            }
+        }
+        self.rows += 1;
+        if self.rows == BATCH_ROWS {
+            self.flush_batch()?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.rows > 0 {
+            self.flush_batch()?;
+        }
+        self.write_all(&CONTINUATION.to_le_bytes())?;
+        self.write_all(&0u32.to_le_bytes())?;
+
+        let blocks = self.blocks.iter().flat_map(|b| {
+            let mut bytes = Vec::with_capacity(24);
+            bytes.extend_from_slice(&b.offset.to_le_bytes());
+            bytes.extend_from_slice(&b.metadata.to_le_bytes());
+            bytes.extend_from_slice(&[0; 4]);
+            bytes.extend_from_slice(&b.body.to_le_bytes());
+            bytes
+        });
+        let footer = Fb::Table(vec![
+            Some(Fb::Short(METADATA_V5)),
+            Some(schema_table(&self.schema)),
+            None,
+            Some(Fb::Structs(self.blocks.len(), blocks.collect())),
+        ]);
+        let footer = finish_root(&footer);
+        self.write_all(&footer)?;
+        self.write_all(&(footer.len() as i32).to_le_bytes())?;
+        self.write_all(MAGIC)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn flush_batch(&mut self) -> io::Result<()> {
+        let mut body = Vec::new();
+        let mut nodes = Vec::new();
+        let mut buffers = Vec::new();
+        let mut buffer = |body: &mut Vec<u8>, bytes: &[u8]| {
+            buffers.extend_from_slice(&(body.len() as i64).to_le_bytes());
+            buffers.extend_from_slice(&(bytes.len() as i64).to_le_bytes());
+            body.extend_from_slice(bytes);
+            body.resize(body.len().next_multiple_of(8), 0);
+        };
+        let fresh = self.schema.iter().map(|(_, t)| Column::new(*t)).collect();
+        let columns = std::mem::replace(&mut self.columns, fresh);
+        for column in columns.iter() {
+            let valid = match column {
+                Column::Utf8 { valid, .. } | Column::Int64 { valid, .. } | Column::Float64 { valid, .. } => valid,
+            };
+            nodes.extend_from_slice(&(valid.len() as i64).to_le_bytes());
+            nodes.extend_from_slice(&(valid.iter().filter(|v| !**v).count() as i64).to_le_bytes());
+            buffer(&mut body, &bitmap(valid));
+            match column {
+                Column::Utf8 { offsets, data, .. } => {
+                    buffer(&mut body, &offsets.iter().flat_map(|o| o.to_le_bytes()).collect::<Vec<u8>>());
+                    buffer(&mut body, data);
+                }
+                Column::Int64 { values, .. } => {
+                    buffer(&mut body, &values.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>());
+                }
+                Column::Float64 { values, .. } => {
+                    buffer(&mut body, &values.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>());
+                }
+            }
+        }
+        let batch = Fb::Table(vec![
+            Some(Fb::Long(self.rows as i64)),
+            Some(Fb::Structs(self.columns.len(), nodes)),
+            Some(Fb::Structs(buffers.len() / 16, buffers)),
+        ]);
+        let message = message(HEADER_RECORD_BATCH, batch, body.len());
+        let offset = self.write_message(&message, &body)?;
+        self.blocks.push(Block { offset: offset as i64, metadata: 8 + message.len() as i32, body: body.len() as i64 });
+        self.rows = 0;
+        Ok(())
+    }
+
+    // returns where the message starts
+    fn write_message(&mut self, metadata: &[u8], body: &[u8]) -> io::Result<usize> {
+        let offset = self.written;
+        self.write_all(&CONTINUATION.to_le_bytes())?;
+        self.write_all(&(metadata.len() as i32).to_le_bytes())?;
+        self.write_all(metadata)?;
+        self.write_all(body)?;
+        Ok(offset)
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes)?;
+        self.written += bytes.len();
+        Ok(())
+    }
+}
+
+fn bitmap(valid: &[bool]) -> Vec<u8> {
+    let mut bits = vec![0u8; valid.len().div_ceil(8)];
+    for (i, _) in valid.iter().enumerate().filter(|(_, v)| **v) {
+        bits[i / 8] |= 1 << (i % 8);
+    }
+    bits
+}
+
+fn schema_table(schema: &[(String, ColumnType)]) -> Fb<'_> {
+    let fields = schema
+        .iter()
+        .map(|(name, column_type)| {
+            let (type_type, type_table) = match column_type {
+                ColumnType::Utf8 => (TYPE_UTF8, Vec::new()),
+                ColumnType::Int64 => (TYPE_INT, vec![Some(Fb::Int(64)), Some(Fb::Bool(true))]),
+                ColumnType::Float64 => (TYPE_FLOATING_POINT, vec![Some(Fb::Short(PRECISION_DOUBLE))]),
+            };
+            vec![
+                Some(Fb::Str(name)),
+                Some(Fb::Bool(true)),
+                Some(Fb::Byte(type_type)),
+                Some(Fb::Table(type_table)),
+                None,
+                Some(Fb::Tables(Vec::new())),
+            ]
+        })
+        .collect();
+    Fb::Table(vec![Some(Fb::Short(0)), Some(Fb::Tables(fields))])
+}
+
+// Message flatbuffer, padded to 8 bytes
+fn message(header_type: u8, header: Fb, body_length: usize) -> Vec<u8> {
+    let message = Fb::Table(vec![
+        Some(Fb::Short(METADATA_V5)),
+        Some(Fb::Byte(header_type)),
+        Some(header),
+        Some(Fb::Long(body_length as i64)),
+    ]);
+    finish_root(&message)
+}
+
+// a flatbuffer value; tables list their fields by id, None for absent ones
+enum Fb<'a> {
+    Byte(u8),
+    Bool(bool),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Str(&'a str),
+    Table(Vec<Option<Fb<'a>>>),
+    Tables(Vec<Vec<Option<Fb<'a>>>>),
+    Structs(usize, Vec<u8>), // count, the structs' bytes (8-byte aligned structs)
+}
+
+impl Fb<'_> {
+    // bytes taken inline in a table: the scalar, or a 4 byte offset
+    fn inline_size(&self) -> usize {
+        match self {
+            Fb::Byte(_) | Fb::Bool(_) => 1,
+            Fb::Short(_) => 2,
+            Fb::Long(_) => 8,
+            _ => 4,
+        }
+    }
+}
+
+fn finish_root(root: &Fb) -> Vec<u8> {
+    let mut buf = vec![0u8; 4];
+    let table = match root {
+        Fb::Table(fields) => write_table(&mut buf, fields),
+        _ => unreachable!("the root is a table"),
+    };
+    patch(&mut buf, 0, table);
+    buf.resize(buf.len().next_multiple_of(8), 0);
+    buf
+}
+
+fn align(buf: &mut Vec<u8>, to: usize) {
+    buf.resize(buf.len().next_multiple_of(to), 0);
+}
+
+// point the uoffset at `at` to `target`
+fn patch(buf: &mut [u8], at: usize, target: usize) {
+    buf[at..at + 4].copy_from_slice(&((target - at) as u32).to_le_bytes());
+}
+
+fn write_table(buf: &mut Vec<u8>, fields: &[Option<Fb>]) -> usize {
+    // widest fields first so each lands aligned after the 4 byte vtable offset
+    let mut order: Vec<usize> = (0..fields.len()).filter(|i| fields[*i].is_some()).collect();
+    order.sort_by_key(|i| std::cmp::Reverse(fields[*i].as_ref().unwrap().inline_size()));
+    let mut offsets = vec![0usize; fields.len()];
+    let mut size: usize = 4;
+    for &i in order.iter() {
+        let field_size = fields[i].as_ref().unwrap().inline_size();
+        size = size.next_multiple_of(field_size);
+        offsets[i] = size;
+        size += field_size;
+    }
+
+    align(buf, 2);
+    let vtable = buf.len();
+    buf.extend_from_slice(&(4 + 2 * fields.len() as u16).to_le_bytes());
+    buf.extend_from_slice(&(size as u16).to_le_bytes());
+    for offset in offsets.iter() {
+        buf.extend_from_slice(&(*offset as u16).to_le_bytes());
+    }
+
+    align(buf, 8);
+    let table = buf.len();
+    buf.extend_from_slice(&((table - vtable) as i32).to_le_bytes());
+    buf.resize(table + size, 0);
+    for &i in order.iter() {
+        let at = table + offsets[i];
+        let bytes: Vec<u8> = match fields[i].as_ref().unwrap() {
+            Fb::Byte(v) => vec![*v],
+            Fb::Bool(v) => vec![*v as u8],
+            Fb::Short(v) => v.to_le_bytes().to_vec(),
+            Fb::Int(v) => v.to_le_bytes().to_vec(),
+            Fb::Long(v) => v.to_le_bytes().to_vec(),
+            _ => continue,
+        };
+        buf[at..at + bytes.len()].copy_from_slice(&bytes);
+    }
+
+    for &i in order.iter() {
+        let at = table + offsets[i];
+        let target = match fields[i].as_ref().unwrap() {
+            Fb::Str(s) => {
+                align(buf, 4);
+                let start = buf.len();
+                buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                buf.extend_from_slice(s.as_bytes());
+                buf.push(0);
+                start
+            }
+            Fb::Table(fields) => write_table(buf, fields),
+            Fb::Tables(tables) => {
+                align(buf, 4);
+                let start = buf.len();
+                buf.extend_from_slice(&(tables.len() as u32).to_le_bytes());
+                buf.resize(start + 4 + 4 * tables.len(), 0);
+                for (n, fields) in tables.iter().enumerate() {
+                    let table = write_table(buf, fields);
+                    patch(buf, start + 4 + 4 * n, table);
+                }
+                start
+            }
+            Fb::Structs(count, bytes) => {
+                // the length is 4 bytes, the structs after it start on 8
+                align(buf, 4);
+                if buf.len().is_multiple_of(8) {
+                    buf.extend_from_slice(&[0; 4]);
+                }
+                let start = buf.len();
+                buf.extend_from_slice(&(*count as u32).to_le_bytes());
+                buf.extend_from_slice(bytes);
+                start
+            }
+            _ => continue,
+        };
+        patch(buf, at, target);
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(buf: &[u8], at: usize) -> usize {
+        u32::from_le_bytes(buf[at..at + 4].try_into().unwrap()) as usize
+    }
+
+    // (table position, field offsets by id) of the table a uoffset at `at` points to
+    fn table_at(buf: &[u8], at: usize) -> (usize, Vec<usize>) {
+        let table = at + u32_at(buf, at);
+        let vtable = table - i32::from_le_bytes(buf[table..table + 4].try_into().unwrap()) as usize;
+        let fields = (u16::from_le_bytes([buf[vtable], buf[vtable + 1]]) as usize - 4) / 2;
+        let offsets = (0..fields)
+            .map(|i| u16::from_le_bytes([buf[vtable + 4 + 2 * i], buf[vtable + 5 + 2 * i]]) as usize)
+            .collect();
+        (table, offsets)
+    }
+
+    #[test]
+    fn test_file_layout() {
+        let schema = [("name", ColumnType::Utf8), ("elo", ColumnType::Int64)];
+        let mut writer = ArrowWriter::new(Vec::new(), &schema).unwrap();
+        writer.push(&[Cell::Text(Some("alice")), Cell::Int(Some(2100))]).unwrap();
+        writer.push(&[Cell::Text(None), Cell::Int(None)]).unwrap();
+        writer.push(&[Cell::Text(Some("bob")), Cell::Int(Some(1850))]).unwrap();
+        let file = writer.finish().unwrap();
+
+        assert_eq!(&file[..6], MAGIC);
+        assert_eq!(&file[file.len() - 6..], MAGIC);
+        assert_eq!(&file[6..8], [0, 0]);
+
+        // schema message: padded metadata, a Message whose header is a Schema
+        assert_eq!(u32_at(&file, 8), CONTINUATION as usize);
+        let metadata = u32_at(&file, 12);
+        assert_eq!(metadata % 8, 0);
+        let meta = &file[16..16 + metadata];
+        let (message, fields) = table_at(meta, 0);
+        assert_eq!(meta[message + fields[1]], HEADER_SCHEMA);
+        let (schema_table, schema_fields) = table_at(meta, message + fields[2]);
+        let vector = schema_table + schema_fields[1];
+        let vector = vector + u32_at(meta, vector);
+        assert_eq!(u32_at(meta, vector), 2);
+        let (field, field_offsets) = table_at(meta, vector + 4);
+        let name = field + field_offsets[0];
+        let name = name + u32_at(meta, name);
+        assert_eq!(&meta[name + 4..name + 4 + u32_at(meta, name)], b"name");
+
+        // the footer lists one record batch: 3 rows, null counts, values where the buffers say
+        let footer_len = u32_at(&file, file.len() - 10);
+        let footer = &file[file.len() - 10 - footer_len..file.len() - 10];
+        let (table, offsets) = table_at(footer, 0);
+        let blocks = table + offsets[3];
+        let blocks = blocks + u32_at(footer, blocks);
+        assert_eq!(u32_at(footer, blocks), 1);
+        let block = &footer[blocks + 4..blocks + 28];
+        let offset = i64::from_le_bytes(block[0..8].try_into().unwrap()) as usize;
+        let meta_len = i32::from_le_bytes(block[8..12].try_into().unwrap()) as usize;
+        assert_eq!(offset % 8, 0);
+        assert_eq!(u32_at(&file, offset), CONTINUATION as usize);
+        assert_eq!(u32_at(&file, offset + 4) + 8, meta_len);
+
+        let meta = &file[offset + 8..offset + meta_len];
+        let body = &file[offset + meta_len..];
+        let (message, fields) = table_at(meta, 0);
+        assert_eq!(meta[message + fields[1]], HEADER_RECORD_BATCH);
+        let (batch, batch_fields) = table_at(meta, message + fields[2]);
+        assert_eq!(i64::from_le_bytes(meta[batch + batch_fields[0]..batch + batch_fields[0] + 8].try_into().unwrap()), 3);
+        let long = |bytes: &[u8], at: usize| i64::from_le_bytes(bytes[at..at + 8].try_into().unwrap()) as usize;
+        let nodes = batch + batch_fields[1];
+        let nodes = nodes + u32_at(meta, nodes);
+        assert_eq!((long(meta, nodes + 4), long(meta, nodes + 12)), (3, 1));
+        let buffers = batch + batch_fields[2];
+        let buffers = buffers + u32_at(meta, buffers);
+        assert_eq!(u32_at(meta, buffers), 5);
+        // validity of name, then its utf8 data
+        assert_eq!(body[long(meta, buffers + 4)], 0b101);
+        let data = long(meta, buffers + 4 + 2 * 16);
+        assert_eq!(&body[data..data + 8], b"alicebob");
+        let elo = long(meta, buffers + 4 + 4 * 16);
+        assert_eq!(long(body, elo + 16), 1850);
+    }
+
+    // what the arrow crates read back, over two record batches
+    #[test]
+    fn test_arrow_reader() {
+        use arrow_array::cast::AsArray;
+        use arrow_array::types::{Float64Type, Int64Type};
+
+        let schema = [("name", ColumnType::Utf8), ("elo", ColumnType::Int64), ("score", ColumnType::Float64)];
+        let mut writer = ArrowWriter::new(Vec::new(), &schema).unwrap();
+        for i in 0..BATCH_ROWS + 2 {
+            let name = format!("player{}", i);
+            let name = (i % 3 != 1).then_some(name.as_str());
+            let elo = (i % 5 != 0).then_some(i as i64);
+            writer.push(&[Cell::Text(name), Cell::Int(elo), Cell::Float(Some(i as f64 / 2.0))]).unwrap();
+        }
+        let file = writer.finish().unwrap();
+
+        let reader = arrow_ipc::reader::FileReader::try_new(std::io::Cursor::new(file), None).unwrap();
+        let fields: Vec<(String, arrow_schema::DataType)> =
+            reader.schema().fields().iter().map(|f| (f.name().clone(), f.data_type().clone())).collect();
+        assert_eq!(fields, [
+            ("name".to_string(), arrow_schema::DataType::Utf8),
+            ("elo".to_string(), arrow_schema::DataType::Int64),
+            ("score".to_string(), arrow_schema::DataType::Float64),
+        ]);
+        let batches: Vec<_> = reader.map(|batch| batch.unwrap()).collect();
+        assert_eq!(batches.iter().map(|b| b.num_rows()).collect::<Vec<_>>(), [BATCH_ROWS, 2]);
+        let last = &batches[1];
+        let i = BATCH_ROWS + 1; // 65537: a name, an elo
+        assert_eq!(last.column(0).as_string::<i32>().value(1), format!("player{}", i));
+        assert_eq!(last.column(1).as_primitive::<Int64Type>().value(1), i as i64);
+        assert_eq!(last.column(2).as_primitive::<Float64Type>().value(1), i as f64 / 2.0);
+        let first = &batches[0];
+        assert!(first.column(0).is_null(1) && first.column(1).is_null(0));
+        assert_eq!(first.column(0).null_count(), BATCH_ROWS / 3);
+        assert_eq!(first.column(1).as_primitive::<Int64Type>().value(7), 7);
+    }
+}
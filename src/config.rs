@@ -0,0 +1,177 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/*
+Configuration
+
+Where the config file comes from, first match wins:
+
+    --config <file>
+    $CRUSTY_CONFIG
+    <config_dir>/config.toml, then <config_dir>/config.json
+
+config_dir is $CRUSTY_CONFIG_DIR, $XDG_CONFIG_HOME/crusty or ~/.config/crusty.
+Without a config file everything is defaulted, data_dir being
+$XDG_DATA_HOME/crusty or ~/.local/share/crusty. $CRUSTY_DATA_DIR overrides
+whatever the file says. A relative data_dir is taken relative to the config file.
+
+    version = 1
+    data_dir = "/srv/chess"
+ */
+
+pub const CONFIG_VERSION: u8 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct RustyConfig {
+    pub version: u8,
+    pub data_dir: PathBuf,
+    #[serde(skip_serializing)] // always the directory the file was found in
+    pub config_dir: PathBuf,
+    #[serde(skip)]
+    pub source: Option<PathBuf>, // the file this was loaded from, if any
+}
+
+impl RustyConfig {
+    pub fn load(explicit: Option<&Path>) -> Result<RustyConfig, Box<dyn Error>> {
+        RustyConfig::load_with_env(explicit, |key| std::env::var_os(key))
+    }
+
+    fn load_with_env<E: Fn(&str) -> Option<OsString>>(explicit: Option<&Path>, env: E) -> Result<RustyConfig, Box<dyn Error>> {
+        let home = env("HOME").map(PathBuf::from);
+        let xdg = |var: &str, fallback: &str| {
+            env(var)
+                .map(PathBuf::from)
+                .or(home.as_ref().map(|h| h.join(fallback)))
+                .unwrap_or_default()
+                .join("crusty")
+        };
+        let config_dir = env("CRUSTY_CONFIG_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| xdg("XDG_CONFIG_HOME", ".config"));
+
+        let file = match explicit.map(Path::to_path_buf).or(env("CRUSTY_CONFIG").map(PathBuf::from)) {
+            Some(file) => Some(file),
+            None => ["config.toml", "config.json"]
+                .iter()
+                .map(|name| config_dir.join(name))
+                .find(|path| path.exists()),
+        };
+
+        let mut config = match &file {
+            Some(file) => {
+                let mut config = RustyConfig::parse_file(file)?;
+                let file_dir = file.parent().unwrap_or(Path::new("")).to_path_buf();
+                if config.data_dir.is_relative() && !config.data_dir.as_os_str().is_empty() {
+                    config.data_dir = file_dir.join(&config.data_dir);
+                }
+                config.config_dir = file_dir;
+                config.source = Some(file.clone());
+                config
+            }
+            None => RustyConfig {
+                version: CONFIG_VERSION,
+                config_dir,
+                ..Default::default()
+            },
+        };
+        if config.data_dir.as_os_str().is_empty() {
+            config.data_dir = xdg("XDG_DATA_HOME", ".local/share");
+        }
+        if let Some(data_dir) = env("CRUSTY_DATA_DIR") {
+            config.data_dir = PathBuf::from(data_dir);
+        }
+        Ok(config)
+    }
+
+    fn parse_file(path: &Path) -> Result<RustyConfig, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let config: RustyConfig = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&text)?,
+            _ => toml::from_str(&text)?,
+        };
+        if config.version > CONFIG_VERSION {
+            return Err(format!(
+                "{}: config version {} is newer than this build understands ({})",
+                path.display(), config.version, CONFIG_VERSION).into());
+        }
+        Ok(config)
+    }
+
+    pub fn db_path(&self) -> PathBuf {
+        self.data_dir.join("data.db")
+    }
+
+
+    // where `crusty init` writes the config when there is none yet
+    pub fn config_file(&self) -> PathBuf {
+        self.source.clone().unwrap_or(self.config_dir.join("config.toml"))
+    }
+
+    pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        toml::to_string(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use std::collections::HashMap;
+
+    fn env_from(vars: &[(&str, &Path)]) -> impl Fn(&str) -> Option<OsString> {
+        let vars: HashMap<String, OsString> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.as_os_str().to_os_string()))
+            .collect();
+        move |key| vars.get(key).cloned()
+    }
+
+    #[test]
+    fn test_defaults_follow_xdg() {
+        let config = RustyConfig::load_with_env(None, env_from(&[("HOME", Path::new("/nonexistent/home"))])).unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.config_dir, Path::new("/nonexistent/home/.config/crusty"));
+        assert_eq!(config.db_path(), Path::new("/nonexistent/home/.local/share/crusty/data.db"));
+        assert!(config.source.is_none());
+
+        let config = RustyConfig::load_with_env(
+            None,
+            env_from(&[("HOME", Path::new("/nonexistent/home")), ("XDG_DATA_HOME", Path::new("/data"))]),
+        )
+        .unwrap();
+        assert_eq!(config.data_dir, Path::new("/data/crusty"));
+    }
+
+    #[test]
+    fn test_config_files_and_overrides() {
+        let temp = TempDir::new("config");
+        let dir = temp.path();
+        let toml_file = dir.join("config.toml");
+        std::fs::write(&toml_file, "version = 1\ndata_dir = \"games\"\n").unwrap();
+        let json_file = dir.join("other.json");
+        std::fs::write(&json_file, "{\"data_dir\": \"/srv/chess\"}").unwrap();
+
+        // found in the config dir, relative data_dir resolved against it
+        let config = RustyConfig::load_with_env(None, env_from(&[("CRUSTY_CONFIG_DIR", dir)])).unwrap();
+        assert_eq!(config.data_dir, dir.join("games"));
+        assert_eq!(config.source.as_deref(), Some(toml_file.as_path()));
+
+        let config = RustyConfig::load_with_env(Some(&json_file), env_from(&[])).unwrap();
+        assert_eq!(config.data_dir, Path::new("/srv/chess"));
+
+        let config = RustyConfig::load_with_env(
+            None,
+            env_from(&[("CRUSTY_CONFIG", &json_file), ("CRUSTY_DATA_DIR", Path::new("/tmp/override"))]),
+        )
+        .unwrap();
+        assert_eq!(config.data_dir, Path::new("/tmp/override"));
+
+        assert!(RustyConfig::load_with_env(Some(&dir.join("missing.toml")), env_from(&[])).is_err());
+        std::fs::write(&toml_file, "version = 99\n").unwrap();
+        assert!(RustyConfig::load_with_env(Some(&toml_file), env_from(&[])).is_err());
+    }
+}
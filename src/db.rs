@@ -1,6 +1,13 @@
 use const_format::concatcp;
-use rusqlite::{named_params, Connection, Error, OpenFlags};
-use std::path::Path;
+use serde::Serialize;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::{named_params, Connection, Error, OpenFlags, OptionalExtension, Row, Rows};
+use std::path::{Path, PathBuf};
+
+use crate::annotations::{self, PlyAnnotation};
+use crate::variations::MoveTree;
+use shakmaty::variant::VariantPosition;
+use crate::persistance::{Position as StoredPosition, PositionStore};
 
 const GAMES_TABLE: &str = "games";
 const GAMES_DDSQL: &str = concatcp!(
@@ -40,6 +47,11 @@ const GET_BY_ID_GAMES_SQL: &str = concatcp!(
     GAMES_TABLE,
     " where id = :id order by id asc"
 );
+const GET_ALL_PGN_SQL: &str = concatcp!(
+    "SELECT id, pgn FROM ",
+    GAMES_TABLE,
+    " where pgn is not null order by id asc"
+);
 const INSERT_INTO_GAMES_SQL: &str = concatcp!(
     "INSERT INTO ",
     GAMES_TABLE,
@@ -67,6 +79,14 @@ const INSERT_INTO_GAMES_SQL: &str = concatcp!(
        start_time,
        end_time,
        link,
+       classified_eco,
+       external_id,
+       rated,
+       time_class,
+       white_accuracy,
+       black_accuracy,
+       white_rating_diff,
+       black_rating_diff,
        hash)
     VALUES (
        :pgn,
@@ -93,8 +113,29 @@ const INSERT_INTO_GAMES_SQL: &str = concatcp!(
        :start_time,
        :end_time,
        :link,
+       :classified_eco,
+       :external_id,
+       :rated,
+       :time_class,
+       :white_accuracy,
+       :black_accuracy,
+       :white_rating_diff,
+       :black_rating_diff,
        :hash)"
 );
+// eco as worked out from the moves (see eco.rs), whatever the ECO header said
+const GAMES_CLASSIFIED_ECO_DDSQL: &str = concatcp!("ALTER TABLE ", GAMES_TABLE, " ADD COLUMN classified_eco TEXT");
+// what chess.com and lichess JSON downloads have beyond the pgn (see json_games.rs):
+// the site's game id (chess.com uuid, lichess id), accuracies and rating changes
+const GAMES_JSON_FIELDS_DDSQL: [&str; 7] = [
+    concatcp!("ALTER TABLE ", GAMES_TABLE, " ADD COLUMN external_id TEXT"),
+    concatcp!("ALTER TABLE ", GAMES_TABLE, " ADD COLUMN rated INTEGER"),
+    concatcp!("ALTER TABLE ", GAMES_TABLE, " ADD COLUMN time_class TEXT"),
+    concatcp!("ALTER TABLE ", GAMES_TABLE, " ADD COLUMN white_accuracy REAL"),
+    concatcp!("ALTER TABLE ", GAMES_TABLE, " ADD COLUMN black_accuracy REAL"),
+    concatcp!("ALTER TABLE ", GAMES_TABLE, " ADD COLUMN white_rating_diff INTEGER"),
+    concatcp!("ALTER TABLE ", GAMES_TABLE, " ADD COLUMN black_rating_diff INTEGER"),
+];
 
 const R12_TABLE: &str = "R12";
 const R12_DDSQL: &str = concatcp!(
@@ -200,51 +241,670 @@ const GET_ALL_POSITIONS_SQL: &str = concatcp!(
     POSITIONS_TABLE
 );
 
+// the position list of each game, keyed by the 64-bit zobrist key of every position reached
+// (ply 0 is the starting position); zobrist values are stored as the signed bit pattern
+const GAME_KEYS_TABLE: &str = "game_position_keys";
+const GAME_KEYS_DDSQL: &str = concatcp!(
+    "CREATE TABLE IF NOT EXISTS ",
+    GAME_KEYS_TABLE,
+    " (
+            game_id  INTEGER NOT NULL,
+            ply      INTEGER NOT NULL,
+            zobrist  INTEGER NOT NULL,
+            PRIMARY KEY (game_id, ply),
+            FOREIGN KEY(game_id) REFERENCES ",
+    GAMES_TABLE,
+    "(id)
+    ) WITHOUT ROWID"
+);
+const GAME_KEYS_INDEX_DDSQL: &str = concatcp!(
+    "CREATE INDEX IF NOT EXISTS ",
+    GAME_KEYS_TABLE,
+    "_zobrist ON ",
+    GAME_KEYS_TABLE,
+    " (zobrist)"
+);
+const INSERT_GAME_KEYS_SQL: &str = concatcp!(
+    "INSERT OR IGNORE INTO ",
+    GAME_KEYS_TABLE,
+    " ( game_id, ply, zobrist ) VALUES ( :game_id, :ply, :zobrist )"
+);
+const GAMES_FOR_ZOBRIST_SQL: &str = concatcp!(
+    "SELECT game_id, MIN(ply) FROM ",
+    GAME_KEYS_TABLE,
+    " WHERE zobrist = :zobrist GROUP BY game_id ORDER BY game_id"
+);
+const PLIES_FOR_ZOBRIST_SQL: &str = concatcp!(
+    "SELECT k.game_id, k.ply, g.pgn FROM ",
+    GAME_KEYS_TABLE,
+    " k JOIN ",
+    GAMES_TABLE,
+    " g ON g.id = k.game_id WHERE k.zobrist = :zobrist ORDER BY k.game_id, k.ply"
+);
+
+// material signature of each game, one row per ply at which it changes (ply 0 always)
+const GAME_MATERIAL_TABLE: &str = "game_material";
+const GAME_MATERIAL_DDSQL: &str = concatcp!(
+    "CREATE TABLE IF NOT EXISTS ",
+    GAME_MATERIAL_TABLE,
+    " (
+            game_id    INTEGER NOT NULL,
+            ply        INTEGER NOT NULL,
+            signature  INTEGER NOT NULL,
+            PRIMARY KEY (game_id, ply),
+            FOREIGN KEY(game_id) REFERENCES ",
+    GAMES_TABLE,
+    "(id)
+    ) WITHOUT ROWID"
+);
+const GAME_MATERIAL_INDEX_DDSQL: &str = concatcp!(
+    "CREATE INDEX IF NOT EXISTS ",
+    GAME_MATERIAL_TABLE,
+    "_signature ON ",
+    GAME_MATERIAL_TABLE,
+    " (signature)"
+);
+const INSERT_GAME_MATERIAL_SQL: &str = concatcp!(
+    "INSERT OR IGNORE INTO ",
+    GAME_MATERIAL_TABLE,
+    " ( game_id, ply, signature ) VALUES ( :game_id, :ply, :signature )"
+);
+const GAMES_FOR_MATERIAL_SQL: &str = concatcp!(
+    "SELECT game_id, MIN(ply) FROM ",
+    GAME_MATERIAL_TABLE,
+    " WHERE signature = :signature GROUP BY game_id ORDER BY game_id"
+);
+
+// pawn structure of each game, one row per ply at which it changes (ply 0 always)
+const GAME_PAWNS_TABLE: &str = "game_pawns";
+const GAME_PAWNS_DDSQL: &str = concatcp!(
+    "CREATE TABLE IF NOT EXISTS ",
+    GAME_PAWNS_TABLE,
+    " (
+            game_id    INTEGER NOT NULL,
+            ply        INTEGER NOT NULL,
+            pawn_key   INTEGER NOT NULL,
+            PRIMARY KEY (game_id, ply),
+            FOREIGN KEY(game_id) REFERENCES ",
+    GAMES_TABLE,
+    "(id)
+    ) WITHOUT ROWID"
+);
+const GAME_PAWNS_INDEX_DDSQL: &str = concatcp!(
+    "CREATE INDEX IF NOT EXISTS ",
+    GAME_PAWNS_TABLE,
+    "_pawn_key ON ",
+    GAME_PAWNS_TABLE,
+    " (pawn_key)"
+);
+const INSERT_GAME_PAWNS_SQL: &str = concatcp!(
+    "INSERT OR IGNORE INTO ",
+    GAME_PAWNS_TABLE,
+    " ( game_id, ply, pawn_key ) VALUES ( :game_id, :ply, :pawn_key )"
+);
+const GAMES_FOR_PAWNS_SQL: &str = concatcp!(
+    "SELECT p.game_id, MIN(p.ply), g.white, g.black, g.result FROM ",
+    GAME_PAWNS_TABLE,
+    " p JOIN ",
+    GAMES_TABLE,
+    " g ON g.id = p.game_id WHERE p.pawn_key = :pawn_key GROUP BY p.game_id ORDER BY p.game_id"
+);
+const UNINDEXED_PAWNS_SQL: &str = concatcp!(
+    "SELECT COUNT(*) FROM ",
+    GAMES_TABLE,
+    " g WHERE NOT EXISTS (SELECT 1 FROM ",
+    GAME_PAWNS_TABLE,
+    " p WHERE p.game_id = g.id)"
+);
+const DELETE_GAME_PAWNS_SQL: &str = concatcp!("DELETE FROM ", GAME_PAWNS_TABLE);
+
+// every pawn structure seen, with the number of games and positions it came up in;
+// white and black are the pawn bitboards (a1 = bit 0) so structures can be matched without replaying
+const PAWN_STRUCTURES_TABLE: &str = "pawn_structures";
+const PAWN_STRUCTURES_DDSQL: &str = concatcp!(
+    "CREATE TABLE IF NOT EXISTS ",
+    PAWN_STRUCTURES_TABLE,
+    " (
+            pawn_key   INTEGER PRIMARY KEY,
+            white      INTEGER NOT NULL,
+            black      INTEGER NOT NULL,
+            games      INTEGER NOT NULL,
+            positions  INTEGER NOT NULL
+    )"
+);
+const ADD_PAWN_STRUCTURE_SQL: &str = concatcp!(
+    "INSERT INTO ",
+    PAWN_STRUCTURES_TABLE,
+    " ( pawn_key, white, black, games, positions ) VALUES ( :pawn_key, :white, :black, :games, :positions )
+    ON CONFLICT(pawn_key) DO UPDATE SET games = games + excluded.games, positions = positions + excluded.positions"
+);
+const ALL_PAWN_STRUCTURES_SQL: &str = concatcp!(
+    "SELECT pawn_key, white, black, games, positions FROM ",
+    PAWN_STRUCTURES_TABLE,
+    " ORDER BY games DESC, positions DESC"
+);
+const DELETE_PAWN_STRUCTURES_SQL: &str = concatcp!("DELETE FROM ", PAWN_STRUCTURES_TABLE);
+
+// for every game passing through a position, the position reached on the next ply
+const NEXT_POSITIONS_SQL: &str = concatcp!(
+    "SELECT n.zobrist, g.result, COUNT(*) FROM ",
+    GAME_KEYS_TABLE,
+    " k JOIN ",
+    GAME_KEYS_TABLE,
+    " n ON n.game_id = k.game_id AND n.ply = k.ply + 1 JOIN ",
+    GAMES_TABLE,
+    " g ON g.id = k.game_id WHERE k.zobrist = :zobrist GROUP BY n.zobrist, g.result"
+);
+
+// moves imported from polyglot books, keyed the same way as game_position_keys
+const BOOK_MOVES_TABLE: &str = "book_moves";
+const BOOK_MOVES_DDSQL: &str = concatcp!(
+    "CREATE TABLE IF NOT EXISTS ",
+    BOOK_MOVES_TABLE,
+    " (
+            source   TEXT NOT NULL,
+            zobrist  INTEGER NOT NULL,
+            move     INTEGER NOT NULL,
+            weight   INTEGER NOT NULL,
+            learn    INTEGER NOT NULL,
+            PRIMARY KEY (source, zobrist, move)
+    ) WITHOUT ROWID"
+);
+const BOOK_MOVES_INDEX_DDSQL: &str = concatcp!(
+    "CREATE INDEX IF NOT EXISTS ",
+    BOOK_MOVES_TABLE,
+    "_zobrist ON ",
+    BOOK_MOVES_TABLE,
+    " (zobrist)"
+);
+const DELETE_BOOK_SOURCE_SQL: &str = concatcp!("DELETE FROM ", BOOK_MOVES_TABLE, " WHERE source = :source");
+const INSERT_BOOK_MOVE_SQL: &str = concatcp!(
+    "INSERT OR REPLACE INTO ",
+    BOOK_MOVES_TABLE,
+    " ( source, zobrist, move, weight, learn ) VALUES ( :source, :zobrist, :move, :weight, :learn )"
+);
+const BOOK_MOVES_FOR_ZOBRIST_SQL: &str = concatcp!(
+    "SELECT source, move, weight, learn FROM ",
+    BOOK_MOVES_TABLE,
+    " WHERE zobrist = :zobrist ORDER BY source, weight DESC"
+);
+
+// full-text index over comments and metadata, rowid = games.id
+const GAME_TEXT_TABLE: &str = "game_text";
+const GAME_TEXT_DDSQL: &str = concatcp!(
+    "CREATE VIRTUAL TABLE IF NOT EXISTS ",
+    GAME_TEXT_TABLE,
+    " USING fts5(comments, event, site, opening)"
+);
+// games imported before the index existed get their metadata indexed right away;
+// their comments need `crusty search --reindex`
+const GAME_TEXT_BACKFILL_SQL: &str = concatcp!(
+    "INSERT INTO ",
+    GAME_TEXT_TABLE,
+    " (rowid, comments, event, site, opening) SELECT id, '', event, site, opening FROM ",
+    GAMES_TABLE,
+    " WHERE id NOT IN (SELECT rowid FROM ",
+    GAME_TEXT_TABLE,
+    ")"
+);
+const DELETE_GAME_TEXT_SQL: &str = concatcp!("DELETE FROM ", GAME_TEXT_TABLE, " WHERE rowid = :game_id");
+const DELETE_ALL_GAME_TEXT_SQL: &str = concatcp!("DELETE FROM ", GAME_TEXT_TABLE);
+const INSERT_GAME_TEXT_SQL: &str = concatcp!(
+    "INSERT INTO ",
+    GAME_TEXT_TABLE,
+    " (rowid, comments, event, site, opening) VALUES (:game_id, :comments, :event, :site, :opening)"
+);
+// bm25 is lower for better matches; snippet column -1 picks the best matching column
+const SEARCH_GAME_TEXT_SQL: &str = concatcp!(
+    "SELECT ",
+    GAMES_TABLE,
+    ".*, bm25(",
+    GAME_TEXT_TABLE,
+    ") AS rank, snippet(",
+    GAME_TEXT_TABLE,
+    ", -1, '[', ']', '...', 12) AS snippet FROM ",
+    GAME_TEXT_TABLE,
+    " JOIN ",
+    GAMES_TABLE,
+    " ON ",
+    GAMES_TABLE,
+    ".id = ",
+    GAME_TEXT_TABLE,
+    ".rowid WHERE ",
+    GAME_TEXT_TABLE,
+    " MATCH :query ORDER BY rank LIMIT :limit"
+);
+
+// PositionStore backed by sqlite; keys and boards are stored as signed bit patterns
+const POSITION_STORE_TABLE: &str = "position_store";
+const POSITION_STORE_DDSQL: &str = concatcp!(
+    "CREATE TABLE IF NOT EXISTS ",
+    POSITION_STORE_TABLE,
+    " (
+            zobrist  INTEGER NOT NULL,
+            r12      INTEGER NOT NULL,
+            r34      INTEGER NOT NULL,
+            r56      INTEGER NOT NULL,
+            r78      INTEGER NOT NULL,
+            PRIMARY KEY (zobrist, r12, r34, r56, r78)
+    ) WITHOUT ROWID"
+);
+const INSERT_POSITION_STORE_SQL: &str = concatcp!(
+    "INSERT OR IGNORE INTO ",
+    POSITION_STORE_TABLE,
+    " ( zobrist, r12, r34, r56, r78 ) VALUES ( :zobrist, :r12, :r34, :r56, :r78 )"
+);
+const POSITION_STORE_RANGE_SQL: &str = concatcp!(
+    "SELECT zobrist, r12, r34, r56, r78 FROM ",
+    POSITION_STORE_TABLE,
+    " WHERE zobrist >= :from AND zobrist <= :to ORDER BY zobrist, r12, r34, r56, r78"
+);
+
+// %clk / %eval per ply, encoded as in annotations.rs
+const GAME_ANNOTATIONS_TABLE: &str = "game_annotations";
+const GAME_ANNOTATIONS_DDSQL: &str = concatcp!(
+    "CREATE TABLE IF NOT EXISTS ",
+    GAME_ANNOTATIONS_TABLE,
+    " ( game_id INTEGER PRIMARY KEY, plies BLOB NOT NULL )"
+);
+const INSERT_GAME_ANNOTATIONS_SQL: &str = concatcp!(
+    "INSERT OR REPLACE INTO ",
+    GAME_ANNOTATIONS_TABLE,
+    " ( game_id, plies ) VALUES ( :game_id, :plies )"
+);
+const GET_GAME_ANNOTATIONS_SQL: &str = concatcp!("SELECT plies FROM ", GAME_ANNOTATIONS_TABLE, " WHERE game_id = :game_id");
+const ANNOTATED_GAMES_SQL: &str = concatcp!(
+    "SELECT ",
+    GAMES_TABLE,
+    ".*, a.plies AS plies FROM ",
+    GAME_ANNOTATIONS_TABLE,
+    " a JOIN ",
+    GAMES_TABLE,
+    " ON ",
+    GAMES_TABLE,
+    ".id = a.game_id"
+);
+const GET_ANNOTATED_GAMES_SQL: &str = concatcp!(ANNOTATED_GAMES_SQL, " ORDER BY ", GAMES_TABLE, ".id");
+
+// full move trees, encoded as in variations.rs, and the positions reached only in
+// their variations (mainline positions are in game_position_keys)
+const GAME_TREES_TABLE: &str = "game_trees";
+const GAME_TREES_DDSQL: &str = concatcp!(
+    "CREATE TABLE IF NOT EXISTS ",
+    GAME_TREES_TABLE,
+    " ( game_id INTEGER PRIMARY KEY, tree BLOB NOT NULL )"
+);
+const INSERT_GAME_TREE_SQL: &str = concatcp!(
+    "INSERT OR REPLACE INTO ",
+    GAME_TREES_TABLE,
+    " ( game_id, tree ) VALUES ( :game_id, :tree )"
+);
+const GET_GAME_TREE_SQL: &str = concatcp!("SELECT tree FROM ", GAME_TREES_TABLE, " WHERE game_id = :game_id");
+const VARIATION_KEYS_TABLE: &str = "variation_position_keys";
+const VARIATION_KEYS_DDSQL: &str = concatcp!(
+    "CREATE TABLE IF NOT EXISTS ",
+    VARIATION_KEYS_TABLE,
+    " ( game_id INTEGER NOT NULL, ply INTEGER NOT NULL, zobrist INTEGER NOT NULL, PRIMARY KEY (game_id, ply, zobrist) )"
+);
+const VARIATION_KEYS_INDEX_DDSQL: &str = concatcp!(
+    "CREATE INDEX IF NOT EXISTS ",
+    VARIATION_KEYS_TABLE,
+    "_zobrist ON ",
+    VARIATION_KEYS_TABLE,
+    " (zobrist)"
+);
+const INSERT_VARIATION_KEYS_SQL: &str = concatcp!(
+    "INSERT OR IGNORE INTO ",
+    VARIATION_KEYS_TABLE,
+    " ( game_id, ply, zobrist ) VALUES ( :game_id, :ply, :zobrist )"
+);
+const VARIATION_GAMES_FOR_ZOBRIST_SQL: &str = concatcp!(
+    "SELECT game_id, MIN(ply) FROM ",
+    VARIATION_KEYS_TABLE,
+    " WHERE zobrist = :zobrist GROUP BY game_id ORDER BY game_id"
+);
+
+/*
+Player identities
+
+One person can show up under several White/Black strings: a lichess handle, a
+chess.com handle, "Surname, Firstname" over the board. Names are only
+comparable within a source, so an alias is keyed on (source, name) where the
+source is worked out from the game's Site (see site_source), and every alias
+points at one row of players. Imports give each new alias a player of its own;
+`crusty players merge` and `split` move aliases between players afterwards.
+ */
+const PLAYERS_TABLE: &str = "players";
+const PLAYERS_DDSQL: &str = concatcp!(
+    "CREATE TABLE IF NOT EXISTS ",
+    PLAYERS_TABLE,
+    " ( id INTEGER PRIMARY KEY, name TEXT NOT NULL )"
+);
+const PLAYER_ALIASES_TABLE: &str = "player_aliases";
+const PLAYER_ALIASES_DDSQL: &str = concatcp!(
+    "CREATE TABLE IF NOT EXISTS ",
+    PLAYER_ALIASES_TABLE,
+    " (
+            source     TEXT NOT NULL,
+            name       TEXT NOT NULL COLLATE NOCASE,
+            player_id  INTEGER NOT NULL REFERENCES ",
+    PLAYERS_TABLE,
+    " (id),
+            PRIMARY KEY (source, name)
+    ) WITHOUT ROWID"
+);
+const PLAYER_ALIASES_INDEX_DDSQL: &str = concatcp!(
+    "CREATE INDEX IF NOT EXISTS ",
+    PLAYER_ALIASES_TABLE,
+    "_player ON ",
+    PLAYER_ALIASES_TABLE,
+    " (player_id)"
+);
+const INSERT_PLAYER_SQL: &str = concatcp!("INSERT INTO ", PLAYERS_TABLE, " ( name ) VALUES ( :name )");
+const DELETE_PLAYER_SQL: &str = concatcp!("DELETE FROM ", PLAYERS_TABLE, " WHERE id = :id");
+const GET_PLAYER_SQL: &str = concatcp!("SELECT id, name FROM ", PLAYERS_TABLE, " WHERE id = :id");
+const INSERT_PLAYER_ALIAS_SQL: &str = concatcp!(
+    "INSERT INTO ",
+    PLAYER_ALIASES_TABLE,
+    " ( source, name, player_id ) VALUES ( :source, :name, :player_id )"
+);
+const GET_PLAYER_ALIAS_SQL: &str = concatcp!(
+    "SELECT source, name, player_id FROM ",
+    PLAYER_ALIASES_TABLE,
+    " WHERE source = :source AND name = :name"
+);
+const ALIASES_FOR_PLAYER_SQL: &str = concatcp!(
+    "SELECT source, name, player_id FROM ",
+    PLAYER_ALIASES_TABLE,
+    " WHERE player_id = :player_id ORDER BY source, name"
+);
+const PLAYERS_FOR_ALIAS_NAME_SQL: &str = concatcp!(
+    "SELECT DISTINCT p.id, p.name FROM ",
+    PLAYER_ALIASES_TABLE,
+    " a JOIN ",
+    PLAYERS_TABLE,
+    " p ON p.id = a.player_id WHERE a.name = :name ORDER BY p.id"
+);
+const LIST_PLAYERS_SQL: &str = concatcp!(
+    "SELECT id, name FROM ",
+    PLAYERS_TABLE,
+    " WHERE id IN (SELECT player_id FROM ",
+    PLAYER_ALIASES_TABLE,
+    " WHERE name LIKE :pattern) ORDER BY name, id LIMIT :limit"
+);
+const MOVE_PLAYER_ALIASES_SQL: &str = concatcp!(
+    "UPDATE ",
+    PLAYER_ALIASES_TABLE,
+    " SET player_id = :into WHERE player_id = :from"
+);
+const MOVE_PLAYER_ALIAS_SQL: &str = concatcp!(
+    "UPDATE ",
+    PLAYER_ALIASES_TABLE,
+    " SET player_id = :player_id WHERE source = :source AND name = :name"
+);
+const GAME_PLAYER_NAMES_SQL: &str = concatcp!(
+    "SELECT white, site FROM ",
+    GAMES_TABLE,
+    " WHERE white IS NOT NULL UNION SELECT black, site FROM ",
+    GAMES_TABLE,
+    " WHERE black IS NOT NULL"
+);
+
+/*
+Schema migrations
+
+Every schema change is a numbered migration, applied in order and recorded in
+schema_version, so databases created by older builds catch up when opened.
+Never edit a migration that has shipped; add a new one (ALTER TABLE ... ADD
+COLUMN etc.) to the end of MIGRATIONS instead.
+
+Databases from before schema_version existed start at version 0. Migrations
+1-5 only contain CREATE ... IF NOT EXISTS, so they are harmless on those.
+ */
+const SCHEMA_VERSION_TABLE: &str = "schema_version";
+const SCHEMA_VERSION_DDSQL: &str = concatcp!(
+    "CREATE TABLE IF NOT EXISTS ",
+    SCHEMA_VERSION_TABLE,
+    " ( version INTEGER PRIMARY KEY, name TEXT NOT NULL, applied_at INTEGER NOT NULL)"
+);
+const SCHEMA_VERSION_EXISTS_SQL: &str = concatcp!(
+    "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = '",
+    SCHEMA_VERSION_TABLE,
+    "'"
+);
+const GET_SCHEMA_VERSION_SQL: &str = concatcp!("SELECT COALESCE(MAX(version), 0) FROM ", SCHEMA_VERSION_TABLE);
+const INSERT_SCHEMA_VERSION_SQL: &str = concatcp!(
+    "INSERT INTO ",
+    SCHEMA_VERSION_TABLE,
+    " (version, name, applied_at) VALUES (:version, :name, strftime('%s', 'now'))"
+);
+
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub statements: &'static [&'static str],
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "games and positions",
+        statements: &[GAMES_DDSQL, R12_DDSQL, R34_DDSQL, R56_DDSQL, R78_DDSQL, POSITIONS_DDSQL, GAME_POS_DDSQL],
+    },
+    Migration {
+        version: 2,
+        name: "zobrist keys per game ply",
+        statements: &[GAME_KEYS_DDSQL, GAME_KEYS_INDEX_DDSQL],
+    },
+    Migration {
+        version: 3,
+        name: "polyglot book moves",
+        statements: &[BOOK_MOVES_DDSQL, BOOK_MOVES_INDEX_DDSQL],
+    },
+    Migration {
+        version: 4,
+        name: "material signatures per game",
+        statements: &[GAME_MATERIAL_DDSQL, GAME_MATERIAL_INDEX_DDSQL],
+    },
+    Migration {
+        version: 5,
+        name: "sqlite position store",
+        statements: &[POSITION_STORE_DDSQL],
+    },
+    Migration {
+        version: 6,
+        name: "full-text index over comments and metadata",
+        statements: &[GAME_TEXT_DDSQL, GAME_TEXT_BACKFILL_SQL],
+    },
+    Migration {
+        version: 7,
+        name: "players and aliases",
+        statements: &[PLAYERS_DDSQL, PLAYER_ALIASES_DDSQL, PLAYER_ALIASES_INDEX_DDSQL],
+    },
+    Migration {
+        version: 8,
+        name: "eco classified from the moves",
+        statements: &[GAMES_CLASSIFIED_ECO_DDSQL],
+    },
+    Migration {
+        version: 9,
+        name: "clock and eval per ply",
+        statements: &[GAME_ANNOTATIONS_DDSQL],
+    },
+    Migration {
+        version: 10,
+        name: "move trees and variation positions",
+        statements: &[GAME_TREES_DDSQL, VARIATION_KEYS_DDSQL, VARIATION_KEYS_INDEX_DDSQL],
+    },
+    Migration {
+        version: 11,
+        name: "fields from json game downloads",
+        statements: &GAMES_JSON_FIELDS_DDSQL,
+    },
+    Migration {
+        version: 12,
+        name: "pawn structures per game",
+        statements: &[GAME_PAWNS_DDSQL, GAME_PAWNS_INDEX_DDSQL, PAWN_STRUCTURES_DDSQL],
+    },
+];
+
+// WAL lets readers carry on while an import writes; with WAL, synchronous=NORMAL
+// only risks the last transactions on power loss, never corruption
+const CONNECTION_PRAGMAS_SQL: &str = "PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+    PRAGMA busy_timeout = 5000;
+    PRAGMA cache_size = -65536;";
+// the journal mode belongs to the database file, so a reader just waits for locks
+const READ_ONLY_PRAGMAS_SQL: &str = "PRAGMA busy_timeout = 5000;
+    PRAGMA cache_size = -65536;";
+// for imports that can simply be rerun: no fsyncs, 256MB page cache, temp tables in memory
+const BULK_PRAGMAS_SQL: &str = "PRAGMA synchronous = OFF;
+    PRAGMA cache_size = -262144;
+    PRAGMA temp_store = MEMORY;";
+const STATEMENT_CACHE_CAPACITY: usize = 64;
+
+// One connection per Db, opened in new() and used by every query; statements go
+// through prepare_cached so each is only compiled once per connection.
 pub struct Db<'a> {
     path: &'a Path,
+    conn: Connection,
+    read_only: bool,
 }
 
 impl Db<'_> {
     pub fn new(dbpath: &Path) -> Db {
-        Db { path: dbpath }
+        Db {
+            path: dbpath,
+            conn: Db::open(dbpath, false),
+            read_only: false,
+        }
     }
 
-    fn connect(&self) -> Connection {
-        match Connection::open_with_flags(
-            self.path,
-            OpenFlags::SQLITE_OPEN_READ_WRITE
-                | OpenFlags::SQLITE_OPEN_CREATE
-                | OpenFlags::SQLITE_OPEN_URI
-                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    // for servers and other readers that must never change the data; the database has
+    // to exist already, and any write fails with SQLITE_READONLY
+    pub fn read_only(dbpath: &Path) -> Db<'_> {
+        Db {
+            path: dbpath,
+            conn: Db::open(dbpath, true),
+            read_only: true,
+        }
+    }
+
+    // for a look that must leave the disk as it was (db migrate --dry-run): read-only, and
+    // immutable when no writer has a -wal file, as sqlite would create -wal and -shm otherwise
+    pub fn inspect(dbpath: &Path) -> Db<'_> {
+        if PathBuf::from(format!("{}-wal", dbpath.display())).exists() {
+            return Db::read_only(dbpath);
+        }
+        let uri = format!(
+            "file:{}?immutable=1",
+            dbpath.display().to_string().replace('%', "%25").replace('?', "%3f").replace('#', "%23"));
+        let conn = match Connection::open_with_flags(
+            uri,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX,
         ) {
             Ok(conn) => conn,
             Err(why) => panic!("{}", why),
+        };
+        Db {
+            path: dbpath,
+            conn,
+            read_only: true,
         }
     }
 
+    fn open(path: &Path, read_only: bool) -> Connection {
+        let (flags, pragmas) = match read_only {
+            true => (OpenFlags::SQLITE_OPEN_READ_ONLY, READ_ONLY_PRAGMAS_SQL),
+            false => (OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE, CONNECTION_PRAGMAS_SQL),
+        };
+        let conn = match Connection::open_with_flags(
+            path,
+            flags | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        ) {
+            Ok(conn) => conn,
+            Err(why) => panic!("{}", why),
+        };
+        if let Err(why) = conn.execute_batch(pragmas) {
+            panic!("setting pragmas on {} failed: {}", path.display(), why);
+        }
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        // so a query can match a game's players to their aliases (see GameFilter)
+        let registered = conn.create_scalar_function(
+            "site_source",
+            1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+            |ctx| Ok(site_source(&ctx.get::<Option<String>>(0)?.unwrap_or_default())),
+        );
+        if let Err(why) = registered {
+            panic!("registering site_source on {} failed: {}", path.display(), why);
+        }
+        conn
+    }
+
+    // an extra connection to the same database, for readers that need their own
+    fn connect(&self) -> Connection {
+        Db::open(self.path, self.read_only)
+    }
+
+    // trade durability for speed while importing, until this Db is dropped
+    pub fn bulk_mode(&self) -> Result<(), Error> {
+        self.conn.execute_batch(BULK_PRAGMAS_SQL)
+    }
+
+    // bring the schema up to date, see MIGRATIONS
     pub fn init_schema(&self) -> () {
-        let conn = self.connect();
-        for sql in [
-            GAMES_DDSQL,
-            R12_DDSQL,
-            R34_DDSQL,
-            R56_DDSQL,
-            R78_DDSQL,
-            POSITIONS_DDSQL,
-            GAME_POS_DDSQL,
-        ] {
-            self.create_schema(&conn, sql);
+        if let Err(why) = self.migrate() {
+            panic!("schema migration failed: {}", why);
+        }
+    }
+
+    // only reads, so it works on read-only connections; no schema_version table is version 0
+    pub fn schema_version(&self) -> Result<u32, Error> {
+        let exists: i64 = self.conn.query_row(SCHEMA_VERSION_EXISTS_SQL, (), |row| row.get(0))?;
+        if exists == 0 {
+            return Ok(0);
         }
+        self.conn.query_row(GET_SCHEMA_VERSION_SQL, (), |row| row.get(0))
     }
 
-    fn create_schema(&self, conn: &Connection, sql: &str) -> usize {
-        match conn.execute(sql, ()) {
-            Ok(res) => res,
-            Err(why) => panic!("schema create failed: {}", why),
+    pub fn pending_migrations(&self) -> Result<Vec<&'static Migration>, Error> {
+        let current = self.schema_version()?;
+        let latest = MIGRATIONS.last().map_or(0, |m| m.version);
+        if current > latest {
+            // refuse to touch a database written by a newer build
+            return Err(Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_ERROR),
+                Some(format!(
+                    "{} is at schema version {}, this build only knows up to {}",
+                    self.path.display(), current, latest)),
+            ));
         }
+        Ok(MIGRATIONS.iter().filter(|m| m.version > current).collect())
+    }
+
+    // apply pending migrations, each in its own transaction; returns the versions applied
+    pub fn migrate(&self) -> Result<Vec<u32>, Error> {
+        self.conn.execute(SCHEMA_VERSION_DDSQL, ())?;
+        let pending = self.pending_migrations()?;
+        let mut applied = Vec::new();
+        for migration in pending {
+            let tx = self.conn.unchecked_transaction()?;
+            for sql in migration.statements {
+                tx.execute(sql, ())?;
+            }
+            tx.execute(
+                INSERT_SCHEMA_VERSION_SQL,
+                named_params! { ":version": migration.version, ":name": migration.name },
+            )?;
+            tx.commit()?;
+            applied.push(migration.version);
+        }
+        Ok(applied)
     }
 }
 
+#[derive(Serialize, Debug, PartialEq)]
 pub struct Game {
     pub id: i64,
     pub hash: i64,
@@ -272,6 +932,14 @@ pub struct Game {
     pub start_time: Option<String>,
     pub end_time: Option<String>,
     pub link: Option<String>,
+    pub classified_eco: Option<String>,
+    pub external_id: Option<String>,
+    pub rated: Option<bool>,
+    pub time_class: Option<String>,
+    pub white_accuracy: Option<f64>,
+    pub black_accuracy: Option<f64>,
+    pub white_rating_diff: Option<i64>,
+    pub black_rating_diff: Option<i64>,
 }
 
 impl Game {
@@ -303,75 +971,233 @@ impl Game {
             start_time: None,
             end_time: None,
             link: None,
+            classified_eco: None,
+            external_id: None,
+            rated: None,
+            time_class: None,
+            white_accuracy: None,
+            black_accuracy: None,
+            white_rating_diff: None,
+            black_rating_diff: None,
         }
     }
 
     pub fn insert(db: &Db, game: &Game) -> Result<i64, Error> {
-        let conn = db.connect();
-        let mut stmt = conn.prepare(INSERT_INTO_GAMES_SQL).expect("prepare failed");
+        let mut stmt = db.conn.prepare_cached(INSERT_INTO_GAMES_SQL).expect("prepare failed");
         stmt.insert(named_params! { ":pgn": game.pgn, ":hash": game.hash, ":notes": game.notes, ":event": game.event, ":site": game.site,
         ":date": game.date, ":round": game.round, ":white": game.white, ":black": game.black, ":result": game.result,
         ":current_position": game.current_position, ":timezone": game.timezone, ":eco": game.eco, ":eco_url": game.eco_url,
         ":opening": game.opening, ":utc_date": game.utc_date, ":utc_time": game.utc_time, ":white_elo": game.white_elo,
         ":black_elo": game.black_elo, ":time_control": game.time_control, ":termination": game.termination,
-        ":variant": game.variant, ":start_time": game.start_time, ":end_time": game.end_time, ":link":  game.link})
+        ":variant": game.variant, ":start_time": game.start_time, ":end_time": game.end_time, ":link":  game.link,
+            ":classified_eco": game.classified_eco, ":external_id": game.external_id, ":rated": game.rated,
+            ":time_class": game.time_class, ":white_accuracy": game.white_accuracy, ":black_accuracy": game.black_accuracy,
+            ":white_rating_diff": game.white_rating_diff, ":black_rating_diff": game.black_rating_diff})
     }
 
-    pub fn bulk_insert(db: &Db, games: Vec<&Game>) -> Result<(), Error> {
-        let mut conn = db.connect();
-        let trans = conn.transaction()?;
-        let mut stmt = trans.prepare(INSERT_INTO_GAMES_SQL)?;
+    // returns the new row id for each game, None where the insert failed (e.g. duplicate hash)
+    pub fn bulk_insert(db: &Db, games: Vec<&Game>) -> Result<Vec<Option<i64>>, Error> {
+        let trans = db.conn.unchecked_transaction()?;
+        let mut stmt = trans.prepare_cached(INSERT_INTO_GAMES_SQL)?;
+        let mut ids = Vec::with_capacity(games.len());
         for game in games {
             match stmt.insert(named_params! { ":pgn": game.pgn, ":hash": game.hash, ":notes": game.notes, ":event": game.event, ":site": game.site,
             ":date": game.date, ":round": game.round, ":white": game.white, ":black": game.black, ":result": game.result,
             ":current_position": game.current_position, ":timezone": game.timezone, ":eco": game.eco, ":eco_url": game.eco_url,
             ":opening": game.opening, ":utc_date": game.utc_date, ":utc_time": game.utc_time, ":white_elo": game.white_elo,
             ":black_elo": game.black_elo, ":time_control": game.time_control, ":termination": game.termination,
-            ":variant": game.variant, ":start_time": game.start_time, ":end_time": game.end_time, ":link":  game.link}) {
-                Ok(_) => (),
-                Err(err) => (),
+            ":variant": game.variant, ":start_time": game.start_time, ":end_time": game.end_time, ":link":  game.link,
+            ":classified_eco": game.classified_eco, ":external_id": game.external_id, ":rated": game.rated,
+            ":time_class": game.time_class, ":white_accuracy": game.white_accuracy, ":black_accuracy": game.black_accuracy,
+            ":white_rating_diff": game.white_rating_diff, ":black_rating_diff": game.black_rating_diff}) {
+                Ok(id) => ids.push(Some(id)),
+                Err(_) => ids.push(None),
             }
         }
         drop(stmt);
-        trans.commit()
+        trans.commit()?;
+        Ok(ids)
     }
 
-    pub fn query_by_id(db: &Db, id: u32) -> Option<Game> {
-        let conn = db.connect();
-        let mut stmt = conn.prepare(GET_BY_ID_GAMES_SQL).expect("prepare failed");
-        let mut row_iter = stmt.query(named_params! {":id": id.to_string()}).unwrap();
-
-        match row_iter.next().expect("next failed") {
-            Some(row) => Some(Game {
-                id: row.get(0).unwrap(),
-                pgn: row.get(1).unwrap(),
-                notes: row.get(3).unwrap(),
-                event: row.get(4).unwrap(),
-                site: row.get(5).unwrap(),
-                date: row.get(6).unwrap(),
-                round: row.get(7).unwrap(),
-                white: row.get(8).unwrap(),
-                black: row.get(9).unwrap(),
-                result: row.get(10).unwrap(),
-                current_position: row.get(11).unwrap(),
-                timezone: row.get(12).unwrap(),
-                eco: row.get(13).unwrap(),
-                eco_url: row.get(14).unwrap(),
-                opening: row.get(15).unwrap(),
-                utc_date: row.get(16).unwrap(),
-                utc_time: row.get(17).unwrap(),
-                white_elo: row.get(18).unwrap(),
-                black_elo: row.get(19).unwrap(),
-                time_control: row.get(20).unwrap(),
-                termination: row.get(21).unwrap(),
-                variant: row.get(22).unwrap(),
-                start_time: row.get(23).unwrap(),
-                end_time: row.get(24).unwrap(),
-                link: row.get(25).unwrap(),
-                hash: row.get(26).unwrap(),
-            }),
-            None => None,
+    // walk every stored pgn without holding them all in memory
+    pub fn for_each_pgn<F: FnMut(i64, &str)>(db: &Db, mut f: F) -> Result<(), Error> {
+        let mut stmt = db.conn.prepare_cached(GET_ALL_PGN_SQL)?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let id: i64 = row.get(0)?;
+            let pgn: String = row.get(1)?;
+            f(id, &pgn);
         }
+        Ok(())
+    }
+
+    pub fn query_by_id(db: &Db, id: i64) -> Result<Option<Game>, Error> {
+        db.conn
+            .prepare_cached(GET_BY_ID_GAMES_SQL)?
+            .query_row(named_params! {":id": id}, Game::from_row)
+            .optional()
+    }
+}
+
+/*
+Game filters
+
+The filters shared by commands that pick games out of the database (export so
+far). Every filter that is set has to match; names are compared without case.
+A player with a row in players matches through player_aliases, by the name and
+the source of each game's Site, so a merged identity brings all its aliases and
+a namesake on another site stays out; a bare name (one no import has seen)
+matches on any site. Dates are compared as the pgn writes
+them, "2023.05.01", so a prefix such as 2023 or 2023.05 works at both ends.
+ */
+#[derive(Debug, Default, Clone)]
+pub struct GameFilter {
+    pub player_id: Option<i64>,
+    pub players: Vec<String>, // bare names, on any site
+    pub eco: Option<String>, // prefix of the header or the classified eco
+    pub result: Option<String>,
+    pub variant: Option<String>,
+    pub event: Option<String>, // substring
+    pub site: Option<String>,  // substring
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub limit: Option<usize>,
+}
+
+// what a game export adds to the games row from the other tables
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct GameFacts {
+    pub plies: u32,
+    pub has_clock: bool,
+}
+
+impl GameFilter {
+    fn sql(&self) -> (String, Vec<String>) {
+        let mut clauses = Vec::<String>::new();
+        let mut values = Vec::<String>::new();
+        if let Some(player_id) = self.player_id {
+            clauses.push(format!(
+                "EXISTS (SELECT 1 FROM {aliases} a WHERE a.player_id = ? \
+                   AND a.source = site_source({games}.site) \
+                   AND (a.name = {games}.white OR a.name = {games}.black))",
+                aliases = PLAYER_ALIASES_TABLE, games = GAMES_TABLE));
+            values.push(player_id.to_string());
+        }
+        if !self.players.is_empty() {
+            let marks = vec!["?"; self.players.len()].join(", ");
+            clauses.push(format!(
+                "(white COLLATE NOCASE IN ({marks}) OR black COLLATE NOCASE IN ({marks}))"
+            ));
+            values.extend(self.players.iter().cloned());
+            values.extend(self.players.iter().cloned());
+        }
+        if let Some(eco) = &self.eco {
+            clauses.push("(eco LIKE ? || '%' OR classified_eco LIKE ? || '%')".to_string());
+            values.extend([eco.clone(), eco.clone()]);
+        }
+        let exact = [("result", &self.result), ("variant", &self.variant)];
+        for (column, value) in exact {
+            if let Some(value) = value {
+                clauses.push(format!("{} = ? COLLATE NOCASE", column));
+                values.push(value.clone());
+            }
+        }
+        for (column, value) in [("event", &self.event), ("site", &self.site)] {
+            if let Some(value) = value {
+                clauses.push(format!("{} LIKE '%' || ? || '%'", column));
+                values.push(value.clone());
+            }
+        }
+        if let Some(since) = &self.since {
+            clauses.push("coalesce(utc_date, date) >= ?".to_string());
+            values.push(since.clone());
+        }
+        if let Some(until) = &self.until {
+            clauses.push("substr(coalesce(utc_date, date), 1, length(?)) <= ?".to_string());
+            values.extend([until.clone(), until.clone()]);
+        }
+
+        let mut sql = format!(
+            "SELECT {games}.*, \
+               (SELECT MAX(ply) FROM {keys} k WHERE k.game_id = {games}.id) AS plies, \
+               EXISTS (SELECT 1 FROM {annotations} a WHERE a.game_id = {games}.id) AS has_clock \
+             FROM {games}",
+            games = GAMES_TABLE, keys = GAME_KEYS_TABLE, annotations = GAME_ANNOTATIONS_TABLE);
+        if !clauses.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&clauses.join(" AND "));
+        }
+        sql.push_str(" ORDER BY id");
+        if let Some(limit) = self.limit {
+            sql.push_str(&format!(" LIMIT {}", limit));
+        }
+        (sql, values)
+    }
+
+    // stream the matching games in id order; f's error stops the walk and is returned
+    pub fn for_each<E, F>(&self, db: &Db, mut f: F) -> Result<(), E>
+    where
+        E: From<Error>,
+        F: FnMut(Game, GameFacts) -> Result<(), E>,
+    {
+        let (sql, values) = self.sql();
+        let mut stmt = db.conn.prepare(&sql)?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(values.iter()))?;
+        while let Some(row) = rows.next()? {
+            let facts = GameFacts {
+                plies: row.get::<_, Option<u32>>("plies")?.unwrap_or(0),
+                has_clock: row.get("has_clock")?,
+            };
+            f(Game::from_row(row)?, facts)?;
+        }
+        Ok(())
+    }
+}
+
+// Maps a result row onto a model by column name, so queries can select columns
+// in any order (or SELECT *) without the mapping going out of step with the DDL.
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self, Error>;
+}
+
+impl FromRow for Game {
+    fn from_row(row: &Row) -> Result<Game, Error> {
+        Ok(Game {
+            id: row.get("id")?,
+            hash: row.get("hash")?,
+            pgn: row.get("pgn")?,
+            notes: row.get("notes")?,
+            event: row.get::<_, Option<String>>("event")?.unwrap_or_default(),
+            site: row.get::<_, Option<String>>("site")?.unwrap_or_default(),
+            date: row.get("date")?,
+            round: row.get("round")?,
+            white: row.get("white")?,
+            black: row.get("black")?,
+            result: row.get("result")?,
+            current_position: row.get("current_position")?,
+            timezone: row.get("timezone")?,
+            eco: row.get("eco")?,
+            eco_url: row.get("eco_url")?,
+            opening: row.get("opening")?,
+            utc_date: row.get("utc_date")?,
+            utc_time: row.get("utc_time")?,
+            white_elo: row.get("white_elo")?,
+            black_elo: row.get("black_elo")?,
+            time_control: row.get("time_control")?,
+            termination: row.get("termination")?,
+            variant: row.get("variant")?,
+            start_time: row.get("start_time")?,
+            end_time: row.get("end_time")?,
+            link: row.get("link")?,
+            classified_eco: row.get("classified_eco")?,
+            external_id: row.get("external_id")?,
+            rated: row.get("rated")?,
+            time_class: row.get("time_class")?,
+            white_accuracy: row.get("white_accuracy")?,
+            black_accuracy: row.get("black_accuracy")?,
+            white_rating_diff: row.get("white_rating_diff")?,
+            black_rating_diff: row.get("black_rating_diff")?,
+        })
     }
 }
 
@@ -385,18 +1211,17 @@ pub struct Position {
 
 impl Position {
     pub fn insert(db: &Db, r12: u64, r34: u64, r56: u64, r78: u64) -> Result<i64, Error> {
-        let mut conn = db.connect();
-        let trans = conn.transaction().expect("error starting transaction");
+        let trans = db.conn.unchecked_transaction().expect("error starting transaction");
 
         let get_id = |insert_sql: &str, get_sql: &str, v: u64| -> i64 {
             match trans
-                .prepare(insert_sql)
+                .prepare_cached(insert_sql)
                 .expect("prepare r12 insert failed")
                 .insert(named_params! {":row": v as i64 })
             {
                 Ok(id) => id,
                 Err(_) => trans
-                    .prepare(get_sql)
+                    .prepare_cached(get_sql)
                     .expect("prepare failed")
                     .query_row([v as i64], |row| row.get(0))
                     .expect("query failed"),
@@ -409,13 +1234,13 @@ impl Position {
         let r78id = get_id(INSERT_INTO_R78_SQL, R78_GET_ID, r78);
 
         let pos_id = match trans
-            .prepare(INSERT_INTO_POSITIONS_SQL)
+            .prepare_cached(INSERT_INTO_POSITIONS_SQL)
             .expect("prepare failed")
             .insert(named_params! {":r12id": r12id, ":r34id": r34id, ":r56id": r56id, ":r78id": r78id })
             {
                 Ok(id) => id,
                 Err(_) => trans
-                    .prepare(GET_POS_FOR_IDS_SQL)
+                    .prepare_cached(GET_POS_FOR_IDS_SQL)
                     .expect("prepare failed")
                     .query_row(named_params! {":r12id": r12id, ":r34id": r34id, ":r56id": r56id, ":r78id": r78id }, |row| row.get(0))
                     .expect("query failed"),
@@ -426,9 +1251,9 @@ impl Position {
     }
 
     pub fn get_all(db: &Db) -> Result<Vec<(u32, u64, u64, u64, u64)>, Error> {
-        let conn = db.connect();
+        let conn = &db.conn;
         let mut stmt = conn
-            .prepare(GET_ALL_POSITIONS_SQL)
+            .prepare_cached(GET_ALL_POSITIONS_SQL)
             .expect("failed to prepare get_all_positions_sql");
 
         stmt.query_map([], |r| {
@@ -448,11 +1273,10 @@ pub struct GamePosition {
 
 impl GamePosition {
     pub fn insert(db: &Db, game_id: i64, position_ids: Vec<i64>) -> Result<(), Error> {
-        let mut conn = db.connect();
-        let trans = conn.transaction().expect("failed to start transaction");
+        let trans = db.conn.unchecked_transaction().expect("failed to start transaction");
         for (turn, pos_id) in position_ids.iter().enumerate() {
             trans
-                .prepare(INSERT_GAMES_POS_SQL)
+                .prepare_cached(INSERT_GAMES_POS_SQL)
                 .expect("prepare failed")
                 .insert(named_params! {":game_id": game_id, ":pos_id": pos_id, ":turn": turn})
                 .expect("insert failed");
@@ -460,3 +1284,855 @@ impl GamePosition {
         trans.commit()
     }
 }
+
+pub struct GamePositionKey {
+    pub game_id: i64,
+    pub ply: u16,
+}
+
+impl GamePositionKey {
+    // games: (game_id, zobrist key for each ply)
+    pub fn bulk_insert(db: &Db, games: &[(i64, Vec<u64>)]) -> Result<(), Error> {
+        let trans = db.conn.unchecked_transaction()?;
+        let mut stmt = trans.prepare_cached(INSERT_GAME_KEYS_SQL)?;
+        for (game_id, keys) in games {
+            for (ply, zobrist) in keys.iter().enumerate() {
+                stmt.execute(named_params! {":game_id": game_id, ":ply": ply, ":zobrist": *zobrist as i64})?;
+            }
+        }
+        drop(stmt);
+        trans.commit()
+    }
+
+    // every game that reached the position, with the first ply it was reached at
+    pub fn games_for_zobrist(db: &Db, zobrist: u64) -> Result<Vec<GamePositionKey>, Error> {
+        let mut stmt = db.conn.prepare_cached(GAMES_FOR_ZOBRIST_SQL)?;
+        let rows = stmt.query_map(named_params! {":zobrist": zobrist as i64}, |row| {
+            Ok(GamePositionKey {
+                game_id: row.get(0)?,
+                ply: row.get(1)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    // games_for_zobrist for when other boards share the key: each game is replayed
+    // and only the plies where the board itself comes up are kept
+    pub fn games_for_board(db: &Db, zobrist: u64, bits: (u64, u64, u64, u64)) -> Result<Vec<GamePositionKey>, Error> {
+        let mut stmt = db.conn.prepare_cached(PLIES_FOR_ZOBRIST_SQL)?;
+        let mut rows = stmt.query(named_params! {":zobrist": zobrist as i64})?;
+        let mut games: Vec<GamePositionKey> = Vec::new();
+        let mut replayed: Option<(i64, Option<crate::parsing::GameVisitor>)> = None;
+        while let Some(row) = rows.next()? {
+            let (game_id, ply): (i64, u16) = (row.get("game_id")?, row.get("ply")?);
+            if games.last().is_some_and(|g| g.game_id == game_id) {
+                continue;
+            }
+            if replayed.as_ref().is_none_or(|(id, _)| *id != game_id) {
+                let pgn: Option<String> = row.get("pgn")?;
+                replayed = Some((game_id, pgn.as_deref().and_then(crate::execution::game_for_pgn)));
+            }
+            let board = match replayed.as_ref().and_then(|(_, gv)| gv.as_ref()) {
+                Some(gv) if ply == 0 => Some(gv.start_position()),
+                Some(gv) => gv.fens.get(ply as usize - 1).cloned(),
+                None => None,
+            };
+            if board.is_some_and(|b| b.to_bits() == bits) {
+                games.push(GamePositionKey { game_id, ply });
+            }
+        }
+        Ok(games)
+    }
+
+    // games: (game_id, (ply, zobrist key) for each position reached in a variation)
+    pub fn bulk_insert_variations(db: &Db, games: &[(i64, Vec<(u16, u64)>)]) -> Result<(), Error> {
+        let trans = db.conn.unchecked_transaction()?;
+        let mut stmt = trans.prepare_cached(INSERT_VARIATION_KEYS_SQL)?;
+        for (game_id, keys) in games {
+            for (ply, zobrist) in keys {
+                stmt.execute(named_params! {":game_id": game_id, ":ply": ply, ":zobrist": *zobrist as i64})?;
+            }
+        }
+        drop(stmt);
+        trans.commit()
+    }
+
+    // games that reach the position in a variation, not (necessarily) in the game itself
+    pub fn variation_games_for_zobrist(db: &Db, zobrist: u64) -> Result<Vec<GamePositionKey>, Error> {
+        let mut stmt = db.conn.prepare_cached(VARIATION_GAMES_FOR_ZOBRIST_SQL)?;
+        let rows = stmt.query_map(named_params! {":zobrist": zobrist as i64}, |row| {
+            Ok(GamePositionKey {
+                game_id: row.get(0)?,
+                ply: row.get(1)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    // (next position zobrist, game result, count) for every continuation seen in the db
+    pub fn next_positions(db: &Db, zobrist: u64) -> Result<Vec<(u64, Option<String>, i64)>, Error> {
+        let mut stmt = db.conn.prepare_cached(NEXT_POSITIONS_SQL)?;
+        let rows = stmt.query_map(named_params! {":zobrist": zobrist as i64}, |row| {
+            Ok((row.get::<_, i64>(0)? as u64, row.get(1)?, row.get(2)?))
+        })?;
+        rows.collect()
+    }
+}
+
+pub struct GameMaterial {
+    pub game_id: i64,
+    pub ply: u16,
+}
+
+impl GameMaterial {
+    // games: (game_id, [(ply, signature)]) with only the plies where the signature changes
+    pub fn bulk_insert(db: &Db, games: &[(i64, Vec<(u16, u64)>)]) -> Result<(), Error> {
+        let trans = db.conn.unchecked_transaction()?;
+        let mut stmt = trans.prepare_cached(INSERT_GAME_MATERIAL_SQL)?;
+        for (game_id, changes) in games {
+            for (ply, signature) in changes {
+                stmt.execute(named_params! {":game_id": game_id, ":ply": ply, ":signature": *signature as i64})?;
+            }
+        }
+        drop(stmt);
+        trans.commit()
+    }
+
+    // every game that reached the material signature, with the first ply it did so
+    pub fn games_for_signature(db: &Db, signature: u64) -> Result<Vec<GameMaterial>, Error> {
+        let mut stmt = db.conn.prepare_cached(GAMES_FOR_MATERIAL_SQL)?;
+        let rows = stmt.query_map(named_params! {":signature": signature as i64}, |row| {
+            Ok(GameMaterial {
+                game_id: row.get(0)?,
+                ply: row.get(1)?,
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+pub struct GamePawns {
+    pub game_id: i64,
+    pub ply: u16,
+    pub white: Option<String>,
+    pub black: Option<String>,
+    pub result: Option<String>,
+}
+
+impl GamePawns {
+    // games: (game_id, [(ply, pawn key)]) with only the plies where the structure changes
+    pub fn bulk_insert(db: &Db, games: &[(i64, Vec<(u16, u64)>)]) -> Result<(), Error> {
+        let trans = db.conn.unchecked_transaction()?;
+        let mut stmt = trans.prepare_cached(INSERT_GAME_PAWNS_SQL)?;
+        for (game_id, changes) in games {
+            for (ply, pawn_key) in changes {
+                stmt.execute(named_params! {":game_id": game_id, ":ply": ply, ":pawn_key": *pawn_key as i64})?;
+            }
+        }
+        drop(stmt);
+        trans.commit()
+    }
+
+    // games imported before the pawn structure index existed, see `crusty pawns --rebuild`
+    pub fn unindexed_games(db: &Db) -> Result<i64, Error> {
+        db.conn.query_row(UNINDEXED_PAWNS_SQL, (), |row| row.get(0))
+    }
+
+    // every game that reached the pawn structure, with the first ply it did so
+    pub fn games_for_key(db: &Db, pawn_key: u64) -> Result<Vec<GamePawns>, Error> {
+        let mut stmt = db.conn.prepare_cached(GAMES_FOR_PAWNS_SQL)?;
+        let rows = stmt.query_map(named_params! {":pawn_key": pawn_key as i64}, |row| {
+            Ok(GamePawns {
+                game_id: row.get(0)?,
+                ply: row.get(1)?,
+                white: row.get(2)?,
+                black: row.get(3)?,
+                result: row.get(4)?,
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PawnStructure {
+    pub pawn_key: u64,
+    pub white: u64,
+    pub black: u64,
+    pub games: i64,
+    pub positions: i64,
+}
+
+impl PawnStructure {
+    // adds the counts to what is already there for each structure
+    pub fn bulk_add(db: &Db, structures: &[PawnStructure]) -> Result<(), Error> {
+        let trans = db.conn.unchecked_transaction()?;
+        let mut stmt = trans.prepare_cached(ADD_PAWN_STRUCTURE_SQL)?;
+        for ps in structures {
+            stmt.execute(named_params! {
+                ":pawn_key": ps.pawn_key as i64,
+                ":white": ps.white as i64,
+                ":black": ps.black as i64,
+                ":games": ps.games,
+                ":positions": ps.positions,
+            })?;
+        }
+        drop(stmt);
+        trans.commit()
+    }
+
+    // most played first
+    pub fn for_each<F: FnMut(PawnStructure)>(db: &Db, mut f: F) -> Result<(), Error> {
+        let mut stmt = db.conn.prepare_cached(ALL_PAWN_STRUCTURES_SQL)?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            f(PawnStructure {
+                pawn_key: row.get::<_, i64>(0)? as u64,
+                white: row.get::<_, i64>(1)? as u64,
+                black: row.get::<_, i64>(2)? as u64,
+                games: row.get(3)?,
+                positions: row.get(4)?,
+            });
+        }
+        Ok(())
+    }
+
+    // drops the structures and their game refs, before indexing every game again
+    pub fn clear(db: &Db) -> Result<(), Error> {
+        let trans = db.conn.unchecked_transaction()?;
+        trans.execute(DELETE_GAME_PAWNS_SQL, ())?;
+        trans.execute(DELETE_PAWN_STRUCTURES_SQL, ())?;
+        trans.commit()
+    }
+}
+
+pub struct BookMove {
+    pub source: String,
+    pub zobrist: u64,
+    pub mv: u16,
+    pub weight: u16,
+    pub learn: u32,
+}
+
+impl BookMove {
+    // replaces everything previously imported under the same source name
+    pub fn replace_source(db: &Db, source: &str, moves: &[BookMove]) -> Result<usize, Error> {
+        let trans = db.conn.unchecked_transaction()?;
+        trans.execute(DELETE_BOOK_SOURCE_SQL, named_params! {":source": source})?;
+        let mut stmt = trans.prepare_cached(INSERT_BOOK_MOVE_SQL)?;
+        for m in moves {
+            stmt.execute(named_params! {":source": source, ":zobrist": m.zobrist as i64, ":move": m.mv,
+                ":weight": m.weight, ":learn": m.learn})?;
+        }
+        drop(stmt);
+        trans.commit()?;
+        Ok(moves.len())
+    }
+
+    pub fn for_zobrist(db: &Db, zobrist: u64) -> Result<Vec<BookMove>, Error> {
+        let mut stmt = db.conn.prepare_cached(BOOK_MOVES_FOR_ZOBRIST_SQL)?;
+        let rows = stmt.query_map(named_params! {":zobrist": zobrist as i64}, |row| {
+            Ok(BookMove {
+                source: row.get(0)?,
+                zobrist,
+                mv: row.get(1)?,
+                weight: row.get(2)?,
+                learn: row.get(3)?,
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+// what the full-text index knows about a game
+pub struct GameText {
+    pub game_id: i64,
+    pub comments: String,
+    pub event: String,
+    pub site: String,
+    pub opening: Option<String>,
+}
+
+pub struct SearchHit {
+    pub game: Game,
+    pub rank: f64,
+    pub snippet: String,
+}
+
+impl GameText {
+    pub fn bulk_insert(db: &Db, texts: &[GameText]) -> Result<(), Error> {
+        let trans = db.conn.unchecked_transaction()?;
+        let mut delete = trans.prepare_cached(DELETE_GAME_TEXT_SQL)?;
+        let mut insert = trans.prepare_cached(INSERT_GAME_TEXT_SQL)?;
+        for text in texts {
+            delete.execute(named_params! {":game_id": text.game_id})?;
+            insert.execute(named_params! {":game_id": text.game_id, ":comments": text.comments,
+                ":event": text.event, ":site": text.site, ":opening": text.opening})?;
+        }
+        drop(delete);
+        drop(insert);
+        trans.commit()
+    }
+
+    pub fn clear(db: &Db) -> Result<usize, Error> {
+        db.conn.execute(DELETE_ALL_GAME_TEXT_SQL, ())
+    }
+
+    // query uses fts5 syntax: plain terms are ANDed, "quoted phrases", OR, NOT, prefix*
+    pub fn search(db: &Db, query: &str, limit: usize) -> Result<Vec<SearchHit>, Error> {
+        let mut stmt = db.conn.prepare_cached(SEARCH_GAME_TEXT_SQL)?;
+        let rows = stmt.query_map(named_params! {":query": query, ":limit": limit as i64}, |row| {
+            Ok(SearchHit {
+                game: Game::from_row(row)?,
+                rank: row.get("rank")?,
+                snippet: row.get("snippet")?,
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+pub struct GameAnnotations {
+    pub game_id: i64,
+    pub plies: Vec<PlyAnnotation>,
+}
+
+impl GameAnnotations {
+    pub fn bulk_insert(db: &Db, games: &[GameAnnotations]) -> Result<(), Error> {
+        let trans = db.conn.unchecked_transaction()?;
+        let mut stmt = trans.prepare_cached(INSERT_GAME_ANNOTATIONS_SQL)?;
+        for game in games {
+            stmt.execute(named_params! {":game_id": game.game_id, ":plies": annotations::encode(&game.plies)})?;
+        }
+        drop(stmt);
+        trans.commit()
+    }
+
+    pub fn for_game(db: &Db, game_id: i64) -> Result<Option<Vec<PlyAnnotation>>, Error> {
+        db.conn
+            .prepare_cached(GET_GAME_ANNOTATIONS_SQL)?
+            .query_row(named_params! {":game_id": game_id}, |row| Ok(annotations::decode(&row.get::<_, Vec<u8>>(0)?)))
+            .optional()
+    }
+
+    // walk every annotated game without holding them all in memory
+    pub fn for_each_game<F: FnMut(Game, Vec<PlyAnnotation>)>(db: &Db, f: F) -> Result<(), Error> {
+        let mut stmt = db.conn.prepare_cached(GET_ANNOTATED_GAMES_SQL)?;
+        let rows = stmt.query(())?;
+        GameAnnotations::walk(rows, f)
+    }
+
+    // the annotated games where White or Black goes by one of the names (any case), in one query
+    pub fn for_each_game_of<F: FnMut(Game, Vec<PlyAnnotation>)>(db: &Db, names: &[String], f: F) -> Result<(), Error> {
+        if names.is_empty() {
+            return Ok(());
+        }
+        let marks = vec!["?"; names.len()].join(", ");
+        let sql = format!(
+            "{} WHERE white COLLATE NOCASE IN ({marks}) OR black COLLATE NOCASE IN ({marks}) ORDER BY {}.id",
+            ANNOTATED_GAMES_SQL, GAMES_TABLE
+        );
+        let mut stmt = db.conn.prepare_cached(&sql)?;
+        let rows = stmt.query(rusqlite::params_from_iter(names.iter().chain(names.iter())))?;
+        GameAnnotations::walk(rows, f)
+    }
+
+    fn walk<F: FnMut(Game, Vec<PlyAnnotation>)>(mut rows: Rows, mut f: F) -> Result<(), Error> {
+        while let Some(row) = rows.next()? {
+            f(Game::from_row(row)?, annotations::decode(&row.get::<_, Vec<u8>>("plies")?));
+        }
+        Ok(())
+    }
+}
+
+pub struct GameTree {
+    pub game_id: i64,
+    pub tree: MoveTree,
+}
+
+impl GameTree {
+    pub fn bulk_insert(db: &Db, games: &[GameTree]) -> Result<(), Error> {
+        let trans = db.conn.unchecked_transaction()?;
+        let mut stmt = trans.prepare_cached(INSERT_GAME_TREE_SQL)?;
+        for game in games {
+            stmt.execute(named_params! {":game_id": game.game_id, ":tree": game.tree.encode()})?;
+        }
+        drop(stmt);
+        trans.commit()
+    }
+
+    // None for games imported without --variations; a blob that does not decode is an error.
+    // start is the game's start position (GameVisitor::start), trees only store moves
+    pub fn for_game(db: &Db, game_id: i64, start: &VariantPosition) -> Result<Option<MoveTree>, Error> {
+        let bytes: Option<Vec<u8>> = db
+            .conn
+            .prepare_cached(GET_GAME_TREE_SQL)?
+            .query_row(named_params! {":game_id": game_id}, |row| row.get(0))
+            .optional()?;
+        match bytes {
+            Some(bytes) => match MoveTree::decode(&bytes, start) {
+                Some(tree) => Ok(Some(tree)),
+                None => Err(Error::InvalidColumnType(0, "tree".to_string(), rusqlite::types::Type::Blob)),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+// "https://lichess.org/abcd1234" and "Chess.com" give the host, anything that is not
+// a domain ("Hastings ENG", "?") is over the board and gives ""
+pub fn site_source(site: &str) -> String {
+    let site = site.trim().to_lowercase();
+    let host = site.split_once("://").map_or(site.as_str(), |(_, rest)| rest);
+    let host = host.split('/').next().unwrap_or("");
+    let host = host.strip_prefix("www.").unwrap_or(host);
+    if host.contains('.') && !host.contains(char::is_whitespace) {
+        host.to_string()
+    } else {
+        String::new()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Player {
+    pub id: i64,
+    pub name: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct PlayerAlias {
+    pub source: String,
+    pub name: String,
+    pub player_id: i64,
+}
+
+impl PlayerAlias {
+    // "lichess.org:alice", or just the name when over the board
+    pub fn key(&self) -> String {
+        if self.source.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{}:{}", self.source, self.name)
+        }
+    }
+
+    pub fn get(db: &Db, source: &str, name: &str) -> Result<Option<PlayerAlias>, Error> {
+        db.conn
+            .prepare_cached(GET_PLAYER_ALIAS_SQL)?
+            .query_row(named_params! {":source": source, ":name": name}, PlayerAlias::from_row)
+            .optional()
+    }
+}
+
+impl FromRow for PlayerAlias {
+    fn from_row(row: &Row) -> Result<PlayerAlias, Error> {
+        Ok(PlayerAlias { source: row.get("source")?, name: row.get("name")?, player_id: row.get("player_id")? })
+    }
+}
+
+impl FromRow for Player {
+    fn from_row(row: &Row) -> Result<Player, Error> {
+        Ok(Player { id: row.get("id")?, name: row.get("name")? })
+    }
+}
+
+impl Player {
+
+    pub fn get(db: &Db, id: i64) -> Result<Option<Player>, Error> {
+        db.conn
+            .prepare_cached(GET_PLAYER_SQL)?
+            .query_row(named_params! {":id": id}, Player::from_row)
+            .optional()
+    }
+
+    // every player with an alias of that name, whatever the source
+    pub fn for_alias_name(db: &Db, name: &str) -> Result<Vec<Player>, Error> {
+        db.conn
+            .prepare_cached(PLAYERS_FOR_ALIAS_NAME_SQL)?
+            .query_map(named_params! {":name": name}, Player::from_row)?
+            .collect()
+    }
+
+    // players with an alias LIKE pattern
+    pub fn list(db: &Db, pattern: &str, limit: usize) -> Result<Vec<Player>, Error> {
+        db.conn
+            .prepare_cached(LIST_PLAYERS_SQL)?
+            .query_map(named_params! {":pattern": pattern, ":limit": limit as i64}, Player::from_row)?
+            .collect()
+    }
+
+    pub fn aliases(&self, db: &Db) -> Result<Vec<PlayerAlias>, Error> {
+        db.conn
+            .prepare_cached(ALIASES_FOR_PLAYER_SQL)?
+            .query_map(named_params! {":player_id": self.id}, PlayerAlias::from_row)?
+            .collect()
+    }
+
+    // give every (source, name) not seen before a player of its own; returns how many were new
+    pub fn register<'g, I: IntoIterator<Item = (&'g str, &'g str)>>(db: &Db, names_and_sites: I) -> Result<usize, Error> {
+        let trans = db.conn.unchecked_transaction()?;
+        let mut get = trans.prepare_cached(GET_PLAYER_ALIAS_SQL)?;
+        let mut insert_player = trans.prepare_cached(INSERT_PLAYER_SQL)?;
+        let mut insert_alias = trans.prepare_cached(INSERT_PLAYER_ALIAS_SQL)?;
+        let mut added = 0;
+        for (name, site) in names_and_sites {
+            let (name, source) = (name.trim(), site_source(site));
+            if name.is_empty() || name == "?" || get.exists(named_params! {":source": source, ":name": name})? {
+                continue;
+            }
+            let player_id = insert_player.insert(named_params! {":name": name})?;
+            insert_alias.execute(named_params! {":source": source, ":name": name, ":player_id": player_id})?;
+            added += 1;
+        }
+        drop((get, insert_player, insert_alias));
+        trans.commit()?;
+        Ok(added)
+    }
+
+    // register the players of every game in the database
+    pub fn sync(db: &Db) -> Result<usize, Error> {
+        let pairs = db
+            .conn
+            .prepare_cached(GAME_PLAYER_NAMES_SQL)?
+            .query_map((), |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Player::register(db, pairs.iter().map(|(name, site)| (name.as_str(), site.as_deref().unwrap_or(""))))
+    }
+
+    // move all of from's aliases to into and drop from
+    pub fn merge(db: &Db, into: i64, from: i64) -> Result<(), Error> {
+        if into == from {
+            return Ok(());
+        }
+        let trans = db.conn.unchecked_transaction()?;
+        trans.execute(MOVE_PLAYER_ALIASES_SQL, named_params! {":into": into, ":from": from})?;
+        trans.execute(DELETE_PLAYER_SQL, named_params! {":id": from})?;
+        trans.commit()
+    }
+
+    // give one alias a player of its own again, returns the new player
+    pub fn split(db: &Db, alias: &PlayerAlias) -> Result<Player, Error> {
+        let trans = db.conn.unchecked_transaction()?;
+        let id = trans.prepare_cached(INSERT_PLAYER_SQL)?.insert(named_params! {":name": alias.name})?;
+        trans.execute(MOVE_PLAYER_ALIAS_SQL, named_params! {":player_id": id, ":source": alias.source, ":name": alias.name})?;
+        trans.commit()?;
+        Ok(Player { id, name: alias.name.clone() })
+    }
+}
+
+pub struct SqlitePositionStore {
+    path: PathBuf,
+    conn: Connection,
+    added: usize,
+}
+
+impl SqlitePositionStore {
+    pub fn new(db: &Db) -> SqlitePositionStore {
+        SqlitePositionStore {
+            path: db.path.to_path_buf(),
+            conn: db.connect(),
+            added: 0,
+        }
+    }
+
+    // inclusive range in sqlite's signed order
+    fn for_each_signed(&self, from: i64, to: i64, f: &mut dyn FnMut(&StoredPosition)) -> Result<(), Error> {
+        let mut stmt = self.conn.prepare_cached(POSITION_STORE_RANGE_SQL)?;
+        let mut rows = stmt.query(named_params! {":from": from, ":to": to})?;
+        while let Some(row) = rows.next()? {
+            let quad = |i: usize| row.get::<_, i64>(i).map(|v| v as u64);
+            f(&StoredPosition {
+                zobrist: quad(0)?,
+                r12: quad(1)?,
+                r34: quad(2)?,
+                r56: quad(3)?,
+                r78: quad(4)?,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl PositionStore for SqlitePositionStore {
+    fn insert_batch(&mut self, positions: &[StoredPosition]) -> Result<(), Box<dyn std::error::Error>> {
+        let trans = self.conn.transaction()?;
+        {
+            let mut stmt = trans.prepare_cached(INSERT_POSITION_STORE_SQL)?;
+            for p in positions {
+                // 0 rows when the position is stored already
+                self.added += stmt.execute(named_params! {":zobrist": p.zobrist as i64, ":r12": p.r12 as i64,
+                    ":r34": p.r34 as i64, ":r56": p.r56 as i64, ":r78": p.r78 as i64})?;
+            }
+        }
+        trans.commit()?;
+        Ok(())
+    }
+
+    fn lookup(&mut self, zobrist: u64) -> Result<Vec<StoredPosition>, Box<dyn std::error::Error>> {
+        let mut found = Vec::new();
+        self.for_each_signed(zobrist as i64, zobrist as i64, &mut |p| found.push(*p))?;
+        Ok(found)
+    }
+
+    fn for_each_in_range(&mut self, from: u64, to: u64, f: &mut dyn FnMut(&StoredPosition)) -> Result<(), Box<dyn std::error::Error>> {
+        if from >= to {
+            return Ok(());
+        }
+        let last = to - 1;
+        // keys above i64::MAX are stored as negative numbers and sort first in sqlite
+        if from <= i64::MAX as u64 && last > i64::MAX as u64 {
+            self.for_each_signed(from as i64, i64::MAX, f)?;
+            self.for_each_signed(i64::MIN, last as i64, f)?;
+        } else {
+            self.for_each_signed(from as i64, last as i64, f)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
+        // inserts are committed per batch already
+        Ok(std::mem::take(&mut self.added))
+    }
+
+    fn name(&self) -> String {
+        format!("sqlite {}", self.path.display())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn full_game(hash: i64) -> Game {
+        let text = |column: &str| Some(format!("{} {}", column, hash));
+        Game {
+            id: 0,
+            hash,
+            pgn: text("pgn"),
+            notes: text("notes"),
+            event: "event".to_string(),
+            site: "site".to_string(),
+            date: text("date"),
+            round: text("round"),
+            white: text("white"),
+            black: text("black"),
+            result: text("result"),
+            current_position: text("current_position"),
+            timezone: text("timezone"),
+            eco: text("eco"),
+            eco_url: text("eco_url"),
+            opening: text("opening"),
+            utc_date: text("utc_date"),
+            utc_time: text("utc_time"),
+            white_elo: text("white_elo"),
+            black_elo: text("black_elo"),
+            time_control: text("time_control"),
+            termination: text("termination"),
+            variant: text("variant"),
+            start_time: text("start_time"),
+            end_time: text("end_time"),
+            link: text("link"),
+            classified_eco: text("classified_eco"),
+            external_id: text("external_id"),
+            rated: Some(hash % 2 == 0),
+            time_class: text("time_class"),
+            white_accuracy: Some(hash as f64 / 4.0),
+            black_accuracy: Some(87.5),
+            white_rating_diff: Some(hash),
+            black_rating_diff: Some(-hash),
+        }
+    }
+
+    #[test]
+    fn test_game_round_trip() {
+        let dir = TempDir::new("games");
+        let path = dir.join("data.db");
+        let db = Db::new(&path);
+        db.init_schema();
+
+        let mut game = full_game(-42);
+        game.id = Game::insert(&db, &game).unwrap();
+        assert_eq!(Game::query_by_id(&db, game.id).unwrap(), Some(game));
+
+        let games = [full_game(1), full_game(2), full_game(1)];
+        let ids = Game::bulk_insert(&db, games.iter().collect()).unwrap();
+        assert!(ids[0].is_some() && ids[1].is_some());
+        assert_eq!(ids[2], None); // duplicate hash
+        let mut second = Game::query_by_id(&db, ids[1].unwrap()).unwrap().unwrap();
+        second.id = 0;
+        assert_eq!(second, games[1]);
+
+        // nullable columns come back as None, missing rows as Ok(None)
+        let mut sparse = Game::new();
+        sparse.hash = 3;
+        sparse.id = Game::insert(&db, &sparse).unwrap();
+        assert_eq!(Game::query_by_id(&db, sparse.id).unwrap(), Some(sparse));
+        assert_eq!(Game::query_by_id(&db, 999).unwrap(), None);
+    }
+
+    #[test]
+    fn test_connection_settings() {
+        let dir = TempDir::new("connection");
+        let path = dir.join("data.db");
+        let db = Db::new(&path);
+        db.init_schema();
+        let pragma = |conn: &Connection, name: &str| -> String {
+            conn.query_row(&format!("PRAGMA {}", name), [], |row| row.get::<_, rusqlite::types::Value>(0))
+                .map(|v| match v {
+                    rusqlite::types::Value::Integer(i) => i.to_string(),
+                    rusqlite::types::Value::Text(t) => t,
+                    other => format!("{:?}", other),
+                })
+                .unwrap()
+        };
+        assert_eq!(pragma(&db.conn, "journal_mode"), "wal");
+        assert_eq!(pragma(&db.conn, "synchronous"), "1"); // NORMAL
+        assert_eq!(pragma(&db.conn, "busy_timeout"), "5000");
+
+        Game::insert(&db, &full_game(1)).unwrap();
+        db.bulk_mode().unwrap();
+        Game::insert(&db, &full_game(2)).unwrap();
+        db.bulk_mode().unwrap();
+        assert_eq!(pragma(&db.conn, "synchronous"), "0"); // OFF
+        assert_eq!(pragma(&db.conn, "temp_store"), "2"); // MEMORY
+
+        // a second connection sees the writes and keeps the default durability
+        let other = db.connect();
+        assert_eq!(pragma(&other, "synchronous"), "1");
+        let count: i64 = other.query_row("SELECT COUNT(*) FROM games", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_search_comments_and_metadata() {
+        let dir = TempDir::new("search");
+        let path = dir.join("data.db");
+        let db = Db::new(&path);
+        db.init_schema();
+
+        let pgns = [
+            "[Event \"Hastings\"] [Site \"Hastings ENG\"] 1. e4 { a quiet start } e5 2. Bxh7 { a bishop sacrifice } *",
+            "[Event \"Wijk aan Zee\"] 1. d4 { he was in time trouble already } d5 *",
+        ];
+        let visitors: Vec<_> = pgns.iter().map(|pgn| crate::execution::game_for_pgn(pgn).unwrap()).collect();
+        let ids = Game::bulk_insert(&db, visitors.iter().map(|gv| &gv.game).collect()).unwrap();
+        let texts: Vec<_> = visitors.iter().zip(ids).map(|(gv, id)| gv.text(id.unwrap())).collect();
+        assert_eq!(texts[0].comments, "a quiet start a bishop sacrifice");
+        GameText::bulk_insert(&db, &texts).unwrap();
+
+        let hits = GameText::search(&db, "sacrifice", 10).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].game.event, "Hastings");
+        assert!(hits[0].snippet.contains("[sacrifice]"));
+        assert_eq!(GameText::search(&db, "\"time trouble\"", 10).unwrap()[0].game.event, "Wijk aan Zee");
+        assert_eq!(GameText::search(&db, "hastings", 10).unwrap().len(), 1);
+        assert!(GameText::search(&db, "\"unbalanced", 10).is_err());
+
+        // indexing a game again replaces its entry
+        GameText::bulk_insert(&db, &texts).unwrap();
+        assert_eq!(GameText::search(&db, "sacrifice", 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_games_for_board_resolves_collisions() {
+        let dir = TempDir::new("collisions");
+        let path = dir.join("data.db");
+        let db = Db::new(&path);
+        db.init_schema();
+
+        let visitors: Vec<_> = ["1. e4 e5 *", "1. d4 d5 *"]
+            .iter()
+            .map(|pgn| crate::execution::game_for_pgn(pgn).unwrap())
+            .collect();
+        let ids: Vec<i64> = Game::bulk_insert(&db, visitors.iter().map(|gv| &gv.game).collect())
+            .unwrap()
+            .into_iter()
+            .map(Option::unwrap)
+            .collect();
+        // pretend 1. e4 e5 and 1. d4 d5 came out with the same key
+        GamePositionKey::bulk_insert(&db, &[(ids[0], vec![1, 2, 99]), (ids[1], vec![1, 3, 99])]).unwrap();
+        assert_eq!(GamePositionKey::games_for_zobrist(&db, 99).unwrap().len(), 2);
+
+        let e4_e5 = visitors[0].fens[1].to_bits();
+        let found = GamePositionKey::games_for_board(&db, 99, e4_e5).unwrap();
+        assert_eq!(found.iter().map(|k| (k.game_id, k.ply)).collect::<Vec<_>>(), vec![(ids[0], 2)]);
+        let start = visitors[1].start_position().to_bits();
+        assert_eq!(GamePositionKey::games_for_board(&db, 1, start).unwrap().len(), 2);
+        assert!(GamePositionKey::games_for_board(&db, 99, start).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_player_aliases() {
+        assert_eq!(site_source("https://lichess.org/abcd1234"), "lichess.org");
+        assert_eq!(site_source("Chess.com"), "chess.com");
+        assert_eq!(site_source("http://www.chess.com/game/live/1"), "chess.com");
+        assert_eq!(site_source("Hastings ENG"), "");
+        assert_eq!(site_source("?"), "");
+
+        let dir = TempDir::new("players");
+        let path = dir.join("data.db");
+        let db = Db::new(&path);
+        db.init_schema();
+
+        let added = Player::register(&db, [
+            ("alice", "https://lichess.org/1"),
+            ("Alice", "https://lichess.org/2"), // same handle, names compare case-insensitively
+            ("alice", "Chess.com"),
+            ("Smith, Alice", "Hastings ENG"),
+            ("?", "Hastings ENG"),
+        ])
+        .unwrap();
+        assert_eq!(added, 3);
+        assert_eq!(Player::register(&db, [("alice", "https://lichess.org/3")]).unwrap(), 0);
+
+        // one alice per site until they are merged
+        let alices = Player::for_alias_name(&db, "ALICE").unwrap();
+        assert_eq!(alices.len(), 2);
+        let smith = PlayerAlias::get(&db, "", "smith, alice").unwrap().unwrap();
+        Player::merge(&db, alices[0].id, alices[1].id).unwrap();
+        Player::merge(&db, alices[0].id, smith.player_id).unwrap();
+        let keys: Vec<_> = alices[0].aliases(&db).unwrap().iter().map(PlayerAlias::key).collect();
+        assert_eq!(keys, vec!["Smith, Alice", "chess.com:alice", "lichess.org:alice"]);
+        assert_eq!(Player::get(&db, alices[1].id).unwrap(), None);
+        assert_eq!(Player::list(&db, "%alice%", 10).unwrap(), vec![alices[0].clone()]);
+
+        let split = Player::split(&db, &PlayerAlias::get(&db, "chess.com", "alice").unwrap().unwrap()).unwrap();
+        assert_eq!(PlayerAlias::get(&db, "chess.com", "alice").unwrap().unwrap().player_id, split.id);
+        assert_eq!(alices[0].aliases(&db).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_migrations_bring_old_databases_up_to_date() {
+        let dir = TempDir::new("migrate");
+        let path = dir.join("data.db");
+        let db = Db::new(&path);
+
+        // a database from before schema_version: only the original tables
+        db.conn.execute(GAMES_DDSQL, ()).unwrap();
+        assert_eq!(db.schema_version().unwrap(), 0);
+        assert_eq!(db.pending_migrations().unwrap().len(), MIGRATIONS.len());
+        // asking does not create the version table, so a read-only connection can ask too
+        let tables: i64 = db.conn.query_row(SCHEMA_VERSION_EXISTS_SQL, (), |row| row.get(0)).unwrap();
+        assert_eq!(tables, 0);
+        assert_eq!(Db::read_only(&path).pending_migrations().unwrap().len(), MIGRATIONS.len());
+
+        let applied = db.migrate().unwrap();
+        assert_eq!(applied, MIGRATIONS.iter().map(|m| m.version).collect::<Vec<_>>());
+        assert_eq!(db.schema_version().unwrap(), MIGRATIONS.last().unwrap().version);
+        assert!(db.pending_migrations().unwrap().is_empty());
+        assert!(db.migrate().unwrap().is_empty());
+
+        // written by a newer build
+        db.conn
+            .execute(INSERT_SCHEMA_VERSION_SQL, named_params! { ":version": 999, ":name": "future" })
+            .unwrap();
+        assert!(db.pending_migrations().is_err());
+
+        drop(db);
+        let wal = PathBuf::from(format!("{}-wal", path.display()));
+        assert!(!wal.exists());
+        assert_eq!(Db::inspect(&path).schema_version().unwrap(), 999);
+        assert!(!wal.exists());
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use once_cell::sync::Lazy;
+use shakmaty::{san::San, Chess, Position};
+
+use crate::parsing::BitPosition;
+
+/*
+ECO classification
+
+data/eco/{a,b,c,d,e}.tsv are opening tables in the same layout as the lichess
+chess-openings repository, one line per named line of play:
+
+    eco	name	pgn
+    B90	Sicilian Defense: Najdorf Variation	1. e4 c5 2. Nf3 d6 3. d4 cxd4 4. Nxd4 Nf6 5. Nc3 a6
+
+Every line is played out once and keyed by the zobrist key of the position it
+ends in, so transpositions classify the same as the main move order. A game is
+classified by the deepest position it reaches that has an entry. The tables
+cover every code from A00 to E99 and every line must parse; the tests check
+both.
+ */
+
+const ECO_TABLES: [&str; 5] = [
+    include_str!("../data/eco/a.tsv"),
+    include_str!("../data/eco/b.tsv"),
+    include_str!("../data/eco/c.tsv"),
+    include_str!("../data/eco/d.tsv"),
+    include_str!("../data/eco/e.tsv"),
+];
+
+#[derive(Debug, PartialEq)]
+pub struct EcoOpening {
+    pub eco: String,
+    pub name: String,
+}
+
+// the openings by position key, and the number of plies in the longest line
+static OPENINGS: Lazy<(HashMap<u64, EcoOpening>, usize)> = Lazy::new(|| {
+    let mut openings = HashMap::new();
+    let mut max_plies = 0;
+    for line in ECO_TABLES.iter().flat_map(|table| table.lines().skip(1)) {
+        if let Some((key, plies, opening)) = parse_line(line) {
+            // first line wins when two move orders reach the same position
+            openings.entry(key).or_insert(opening);
+            max_plies = max_plies.max(plies);
+        }
+    }
+    (openings, max_plies)
+});
+
+fn parse_line(line: &str) -> Option<(u64, usize, EcoOpening)> {
+    let mut columns = line.split('\t');
+    let (eco, name, pgn) = (columns.next()?, columns.next()?, columns.next()?);
+    let mut pos = Chess::default();
+    let mut plies = 0;
+    // move numbers ("1.") are not san, everything else has to be a legal move
+    for token in pgn.split_whitespace().filter(|t| !t.ends_with('.')) {
+        let m = San::from_str(token.trim_end_matches(['+', '#'])).ok()?.to_move(&pos).ok()?;
+        pos.play_unchecked(&m);
+        plies += 1;
+    }
+    let opening = EcoOpening { eco: eco.to_string(), name: name.to_string() };
+    Some((BitPosition::from_chess(&pos).zobrist(), plies, opening))
+}
+
+// the deepest of the positions (in game order, positions[0] after the first move) that is a named opening
+pub fn classify(positions: &[BitPosition]) -> Option<&'static EcoOpening> {
+    let (openings, max_plies) = &*OPENINGS;
+    positions[..positions.len().min(*max_plies)].iter().rev().find_map(|p| openings.get(&p.zobrist()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::game_for_pgn;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_tables_parse() {
+        for line in ECO_TABLES.iter().flat_map(|t| t.lines().skip(1)) {
+            assert!(parse_line(line).is_some(), "{line}");
+        }
+        // every code from A00 to E99 names at least one line
+        let codes = ECO_TABLES.iter().flat_map(|t| t.lines().skip(1)).filter_map(|l| l.split('\t').next()).collect::<HashSet<_>>();
+        for code in ['A', 'B', 'C', 'D', 'E'].iter().flat_map(|v| (0..100).map(move |n| format!("{v}{n:02}"))) {
+            assert!(codes.contains(code.as_str()), "{code}");
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        // Najdorf reached from 1. Nf3, then play leaves the book
+        let gv = game_for_pgn("1. Nf3 d6 2. e4 c5 3. d4 cxd4 4. Nxd4 Nf6 5. Nc3 a6 6. Bd2 *").unwrap();
+        assert_eq!(classify(&gv.fens).unwrap().name, "Sicilian Defense: Najdorf Variation");
+        assert_eq!(gv.game.classified_eco.as_deref(), Some("B90"));
+        assert_eq!(gv.game.opening.as_deref(), Some("Sicilian Defense: Najdorf Variation"));
+
+        // headers are kept, the classification is recorded next to them
+        let gv = game_for_pgn("[ECO \"C00\"] [Opening \"French\"] 1. d4 e6 2. e4 d5 3. Nc3 Bb4 *").unwrap();
+        assert_eq!(gv.game.eco.as_deref(), Some("C00"));
+        assert_eq!(gv.game.opening.as_deref(), Some("French"));
+        assert_eq!(gv.game.classified_eco.as_deref(), Some("C15"));
+
+        // only the start position, which has no entry
+        assert_eq!(classify(&game_for_pgn("*").unwrap().fens), None);
+    }
+}
@@ -1,5 +1,6 @@
 use std::fs::File;
 // standard lib
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::error::Error;
 
@@ -7,69 +8,121 @@ use std::error::Error;
 use pgn_reader::BufferedReader;
 
 // our modules
-use crate::persistance::{self, Position};
+use crate::persistance::{self, MemoryStore, PositionStore, SegmentStore};
 use persistance::PositionSegment;
 
 use crate::parsing;
 use parsing::{BitPosition, GameVisitor};
 
 use crate::db;
-use db::{Db, Game};
+use db::{Db, Game, SqlitePositionStore};
+
+use crate::json_games;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StoreKind {
+    /// sorted segment files listed in the dataset manifest
+    Segment,
+    /// position_store table in data.db
+    Sqlite,
+    /// keep positions in memory only, nothing is written
+    Memory,
+}
+
+pub fn open_store(kind: StoreKind, data_dir: &Path, db: &Db) -> Result<Box<dyn PositionStore>, Box<dyn Error>> {
+    Ok(match kind {
+        StoreKind::Segment => Box::new(SegmentStore::open(data_dir)?),
+        StoreKind::Sqlite => Box::new(SqlitePositionStore::new(db)),
+        StoreKind::Memory => Box::new(MemoryStore::default()),
+    })
+}
+
+// for readers that keep the store open and must not write to the data directory
+pub fn open_store_read_only(kind: StoreKind, data_dir: &Path, db: &Db) -> Result<Box<dyn PositionStore>, Box<dyn Error>> {
+    Ok(match kind {
+        StoreKind::Segment => Box::new(SegmentStore::open_read_only(data_dir)?),
+        kind => open_store(kind, data_dir, db)?,
+    })
+}
 
 // from a given .pgn file, create a 1:n segments, each segment consisting of
 // a list of positions + 1 table of games.
 // Games will reference positions by segment_id and byte offset
 // (or equivalent) within segment.
 //
-pub fn games_for_buffs(games_reader: BufferedReader<File>) -> Vec<GameVisitor> {
+// with `variations` each game also gets its full move tree, see variations.rs
+pub fn games_for_buffs<R: Read>(games_reader: BufferedReader<R>, variations: bool) -> Result<Vec<GameVisitor>, std::io::Error> {
     let mut games = Vec::<GameVisitor>::new();
 
-    let mut pos_id: u64 = 0;
-    let visitor = &mut GameVisitor::new();
+    let visitor = &mut if variations { GameVisitor::with_variations() } else { GameVisitor::new() };
     for visit in games_reader.into_iter(visitor) {
-        // play through each move in the pgn and generate a BitPosition for each position reached
-        // TODO handle bad pgn gracefully
-        let result = visit.expect("failed to parse pgn");
-        games.push(result);
-    }
-    games
-}
-
-pub fn game_visitor_to_positions(visitor: GameVisitor) {
-    for bitpos in visitor.fens {
-        let (r12,r34,r56, r78) = bitpos.to_bits();
-        let pos = Position {
-            r12,
-            r34,
-            r56,
-            r78,
-        };
+        // play through each move in the pgn and generate a BitPosition for each position reached;
+        // the reader only fails on I/O, which ends the file, the caller decides what that means
+        games.push(visit?);
     }
+    Ok(games)
 }
 
-pub fn create_readers_for_dir(dir: &Path) -> Result<Vec<BufferedReader<File>>, Box<dyn Error>> {
-    let mut readers = Vec::<BufferedReader<File>>::new();
+// what import reads: pgn, and JSON / NDJSON game downloads (see json_games.rs)
+const GAME_FILE_EXTENSIONS: [&str; 4] = ["pgn", "json", "ndjson", "jsonl"];
 
-    /*
-    let fh: File = match File::open(dir) {
-        Ok(myfile) => myfile,
-        Err(e) => return Err(e),
-    };
-    */
-    let pgns = std::fs::read_dir(dir)?
+// a file as it is, or the game files in a directory
+pub fn game_files(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    if dir.is_file() {
+        return Ok(vec![dir.to_path_buf()]);
+    }
+    let files = std::fs::read_dir(dir)?
         .filter_map(|res| res.ok())
         .map(|entry| entry.path())
-        .filter_map(|path| {
-            if path.extension().map_or(false, |ext| ext == "pgn") {
-                Some(path)
-            } else {
-                None
-            }
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| GAME_FILE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
         })
         .collect::<Vec<_>>();
-    for pgn in pgns {
-        let reader = BufferedReader::new(std::fs::File::open(pgn.as_path()).expect("Failed to open pgn file"));
-        readers.push(reader);
+    Ok(files)
+}
+
+// the games in a file: JSON by extension, pgn otherwise
+pub fn games_for_file(path: &Path, variations: bool) -> Result<Vec<GameVisitor>, Box<dyn Error>> {
+    let file = File::open(path)?;
+    let extension = path.extension().and_then(|ext| ext.to_str()).map(str::to_lowercase);
+    match extension.as_deref() {
+        Some(json @ ("json" | "ndjson" | "jsonl")) => json_games::games_for_json(std::io::BufReader::new(file), json != "json", variations),
+        _ => Ok(games_for_buffs(BufferedReader::new(file), variations)?),
+    }
+}
+
+// replay a single pgn string (e.g. games.pgn as stored in the db) through a GameVisitor
+pub fn game_for_pgn(pgn: &str) -> Option<GameVisitor> {
+    let mut reader = BufferedReader::new_cursor(pgn.as_bytes());
+    let visitor = &mut GameVisitor::new();
+    reader.read_game(visitor).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a pgn that breaks off with a read error, as an upload or download can
+    struct Failing<'a>(&'a [u8]);
+
+    impl Read for Failing<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "connection reset"));
+            }
+            let n = self.0.len().min(buf.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_read_error() {
+        let games = games_for_buffs(BufferedReader::new(Failing(b"1. e4 e5 *\n\n1. d4")), false);
+        assert!(matches!(games, Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset));
+        assert_eq!(games_for_buffs(BufferedReader::new_cursor(&b"1. e4 e5 *\n\n1. d4 *"[..]), false).unwrap().len(), 2);
     }
-    Ok(readers)
 }
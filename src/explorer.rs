@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use serde::Serialize;
+use shakmaty::{san::San, variant::Variant, CastlingMode, Position};
+
+use crate::db::{BookMove, Db, GamePositionKey};
+use crate::parsing::BitPosition;
+use crate::polyglot::encode_move;
+use crate::variants;
+
+/*
+Opening explorer
+
+Lists the legal moves of a position together with what the game database and
+any imported polyglot books know about them. Both sides are keyed by zobrist:
+database moves are found by looking up the key of the position each legal move
+leads to, book moves by matching the polyglot move encoding.
+ */
+
+#[derive(Serialize, Default)]
+pub struct BookWeight {
+    pub source: String,
+    pub weight: u16,
+}
+
+#[derive(Serialize, Default)]
+pub struct ExplorerMove {
+    pub san: String,
+    pub uci: String,
+    pub games: u64,
+    pub white_wins: u64,
+    pub draws: u64,
+    pub black_wins: u64,
+    pub book: Vec<BookWeight>,
+}
+
+impl ExplorerMove {
+    // score for the side playing the move, 0.0 - 1.0
+    pub fn score(&self, white_to_move: bool) -> Option<f64> {
+        if self.games == 0 {
+            return None;
+        }
+        let wins = if white_to_move { self.white_wins } else { self.black_wins };
+        Some((wins as f64 + self.draws as f64 / 2.0) / self.games as f64)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Exploration {
+    pub fen: String,
+    pub zobrist: u64,
+    pub white_to_move: bool,
+    pub moves: Vec<ExplorerMove>,
+}
+
+// the fen is read as a position of `variant`, whose games are the only ones it can match
+pub fn explore(db: &Db, fen: &str, variant: Variant) -> Result<Exploration, Box<dyn Error>> {
+    let pos = variants::position_from_fen(fen, variant, CastlingMode::Standard)?;
+    let zobrist = BitPosition::from_variant(&pos).zobrist();
+
+    let mut db_stats = HashMap::<u64, ExplorerMove>::new();
+    for (next, result, count) in GamePositionKey::next_positions(db, zobrist)? {
+        let stats = db_stats.entry(next).or_default();
+        let count = count as u64;
+        stats.games += count;
+        match result.as_deref() {
+            Some("1-0") => stats.white_wins += count,
+            Some("0-1") => stats.black_wins += count,
+            Some("1/2-1/2") => stats.draws += count,
+            _ => (),
+        }
+    }
+    let book_moves = BookMove::for_zobrist(db, zobrist)?;
+
+    let mut moves = Vec::new();
+    for m in pos.legal_moves() {
+        let mut after = pos.clone();
+        after.play_unchecked(&m);
+        let next = BitPosition::from_variant(&after).zobrist();
+
+        let mut explorer_move = db_stats.remove(&next).unwrap_or_default();
+        explorer_move.san = San::from_move(&pos, &m).to_string();
+        explorer_move.uci = m.to_uci(pos.castles().mode()).to_string();
+        let polyglot = encode_move(&m);
+        explorer_move.book = book_moves
+            .iter()
+            .filter(|b| Some(b.mv) == polyglot)
+            .map(|b| BookWeight { source: b.source.clone(), weight: b.weight })
+            .collect();
+
+        if explorer_move.games > 0 || !explorer_move.book.is_empty() {
+            moves.push(explorer_move);
+        }
+    }
+    moves.sort_by(|a, b| {
+        b.games
+            .cmp(&a.games)
+            .then(b.book.iter().map(|w| w.weight).max().cmp(&a.book.iter().map(|w| w.weight).max()))
+    });
+
+    Ok(Exploration {
+        fen: fen.to_string(),
+        zobrist,
+        white_to_move: pos.turn().is_white(),
+        moves,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use shakmaty::{fen::Fen, Chess};
+
+    #[test]
+    fn test_book_moves_match_legal_moves() {
+        let dir = TempDir::new("explorer");
+        let path = dir.join("data.db");
+        let db = Db::new(&path);
+        db.init_schema();
+
+        let fen = "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3";
+        let pos: Chess = fen.parse::<Fen>().unwrap().into_position(CastlingMode::Standard).unwrap();
+        let zobrist = BitPosition::from_chess(&pos).zobrist();
+        // f1b5 and f1c4 in polyglot encoding
+        let book = [(5 << 6 | 33, 10), (5 << 6 | 26, 4)]
+            .iter()
+            .map(|&(mv, weight)| BookMove { source: "test".to_string(), zobrist, mv, weight, learn: 0 })
+            .collect::<Vec<_>>();
+        BookMove::replace_source(&db, "test", &book).unwrap();
+
+        let exploration = explore(&db, fen, Variant::Chess).unwrap();
+        let sans: Vec<_> = exploration.moves.iter().map(|m| m.san.as_str()).collect();
+        assert_eq!(sans, vec!["Bb5", "Bc4"]);
+        assert_eq!(exploration.moves[0].book[0].weight, 10);
+    }
+}
@@ -0,0 +1,308 @@
+use std::error::Error;
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::arrow::{ArrowWriter, Cell, ColumnType};
+use crate::db::{site_source, Db, Game, GameFacts, GameFilter, PlayerAlias};
+
+/*
+Export
+
+Streams the games a GameFilter picks out to one of four formats, one game at a
+time (arrow holds one record batch), so memory stays flat however many games
+match:
+
+    pgn     the stored pgn, headers one per line, movetext ending in the result
+    ndjson  one JSON object per line: every games column and the derived fields
+    csv     RFC 4180, a header row and the columns below (no pgn, for spreadsheets)
+    arrow   Arrow IPC file (Feather V2) with the same columns as csv, see arrow.rs
+
+Derived fields are worked out per game from the other tables: plies from the
+position keys, whether there are clock annotations, the source of the Site
+header and the player ids its White and Black aliases belong to.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// PGN, as imported
+    Pgn,
+    /// JSON Lines, all game fields plus derived ones
+    Ndjson,
+    /// comma separated values, one game a row
+    Csv,
+    /// Arrow IPC file (Feather V2), for pandas, polars and duckdb
+    Arrow,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Derived {
+    pub plies: u32,
+    pub has_clock: bool,
+    pub source: String,
+    pub white_player_id: Option<i64>,
+    pub black_player_id: Option<i64>,
+}
+
+impl Derived {
+    fn for_game(db: &Db, game: &Game, facts: GameFacts) -> Result<Derived, Box<dyn Error>> {
+        let source = site_source(&game.site);
+        let player_id = |name: &Option<String>| -> Result<Option<i64>, rusqlite::Error> {
+            match name {
+                Some(name) => Ok(PlayerAlias::get(db, &source, name)?.map(|alias| alias.player_id)),
+                None => Ok(None),
+            }
+        };
+        Ok(Derived {
+            plies: facts.plies,
+            has_clock: facts.has_clock,
+            white_player_id: player_id(&game.white)?,
+            black_player_id: player_id(&game.black)?,
+            source,
+        })
+    }
+}
+
+// csv and arrow columns
+const COLUMNS: [(&str, ColumnType); 35] = [
+    ("id", ColumnType::Int64),
+    ("event", ColumnType::Utf8),
+    ("site", ColumnType::Utf8),
+    ("date", ColumnType::Utf8),
+    ("round", ColumnType::Utf8),
+    ("white", ColumnType::Utf8),
+    ("black", ColumnType::Utf8),
+    ("result", ColumnType::Utf8),
+    ("white_elo", ColumnType::Int64),
+    ("black_elo", ColumnType::Int64),
+    ("eco", ColumnType::Utf8),
+    ("classified_eco", ColumnType::Utf8),
+    ("opening", ColumnType::Utf8),
+    ("time_control", ColumnType::Utf8),
+    ("termination", ColumnType::Utf8),
+    ("variant", ColumnType::Utf8),
+    ("utc_date", ColumnType::Utf8),
+    ("utc_time", ColumnType::Utf8),
+    ("start_time", ColumnType::Utf8),
+    ("end_time", ColumnType::Utf8),
+    ("timezone", ColumnType::Utf8),
+    ("link", ColumnType::Utf8),
+    ("current_position", ColumnType::Utf8),
+    ("external_id", ColumnType::Utf8),
+    ("rated", ColumnType::Int64),
+    ("time_class", ColumnType::Utf8),
+    ("white_accuracy", ColumnType::Float64),
+    ("black_accuracy", ColumnType::Float64),
+    ("white_rating_diff", ColumnType::Int64),
+    ("black_rating_diff", ColumnType::Int64),
+    ("plies", ColumnType::Int64),
+    ("has_clock", ColumnType::Int64),
+    ("source", ColumnType::Utf8),
+    ("white_player_id", ColumnType::Int64),
+    ("black_player_id", ColumnType::Int64),
+];
+
+fn cells<'a>(game: &'a Game, derived: &'a Derived) -> [Cell<'a>; 35] {
+    let text = |value: &'a Option<String>| Cell::Text(value.as_deref());
+    let elo = |value: &Option<String>| Cell::Int(value.as_deref().and_then(|v| v.trim().parse().ok()));
+    [
+        Cell::Int(Some(game.id)),
+        Cell::Text(Some(&game.event)),
+        Cell::Text(Some(&game.site)),
+        text(&game.date),
+        text(&game.round),
+        text(&game.white),
+        text(&game.black),
+        text(&game.result),
+        elo(&game.white_elo),
+        elo(&game.black_elo),
+        text(&game.eco),
+        text(&game.classified_eco),
+        text(&game.opening),
+        text(&game.time_control),
+        text(&game.termination),
+        text(&game.variant),
+        text(&game.utc_date),
+        text(&game.utc_time),
+        text(&game.start_time),
+        text(&game.end_time),
+        text(&game.timezone),
+        text(&game.link),
+        text(&game.current_position),
+        text(&game.external_id),
+        Cell::Int(game.rated.map(i64::from)),
+        text(&game.time_class),
+        Cell::Float(game.white_accuracy),
+        Cell::Float(game.black_accuracy),
+        Cell::Int(game.white_rating_diff),
+        Cell::Int(game.black_rating_diff),
+        Cell::Int(Some(derived.plies as i64)),
+        Cell::Int(Some(derived.has_clock as i64)),
+        Cell::Text(Some(&derived.source)),
+        Cell::Int(derived.white_player_id),
+        Cell::Int(derived.black_player_id),
+    ]
+}
+
+// write every game the filter matches; returns how many were written
+pub fn export<W: Write>(db: &Db, filter: &GameFilter, format: ExportFormat, out: W) -> Result<u64, Box<dyn Error>> {
+    let mut count = 0;
+    match format {
+        ExportFormat::Arrow => {
+            let mut writer = ArrowWriter::new(out, &COLUMNS)?;
+            filter.for_each(db, |game, facts| -> Result<(), Box<dyn Error>> {
+                let derived = Derived::for_game(db, &game, facts)?;
+                writer.push(&cells(&game, &derived))?;
+                count += 1;
+                Ok(())
+            })?;
+            writer.finish()?;
+        }
+        _ => {
+            let mut out = std::io::BufWriter::new(out);
+            if format == ExportFormat::Csv {
+                let header: Vec<&str> = COLUMNS.iter().map(|(name, _)| *name).collect();
+                writeln!(out, "{}", header.join(","))?;
+            }
+            filter.for_each(db, |game, facts| -> Result<(), Box<dyn Error>> {
+                let derived = Derived::for_game(db, &game, facts)?;
+                match format {
+                    ExportFormat::Pgn => write_pgn(&mut out, &game)?,
+                    ExportFormat::Ndjson => {
+                        #[derive(Serialize)]
+                        struct Record<'a> {
+                            #[serde(flatten)]
+                            game: &'a Game,
+                            #[serde(flatten)]
+                            derived: &'a Derived,
+                        }
+                        serde_json::to_writer(&mut out, &Record { game: &game, derived: &derived })?;
+                        writeln!(out)?;
+                    }
+                    _ => write_csv_row(&mut out, &cells(&game, &derived))?,
+                }
+                count += 1;
+                Ok(())
+            })?;
+            out.flush()?;
+        }
+    }
+    Ok(count)
+}
+
+// games.pgn keeps the headers and movetext on one line and leaves the result out
+fn write_pgn<W: Write>(out: &mut W, game: &Game) -> std::io::Result<()> {
+    let mut rest = game.pgn.as_deref().unwrap_or("").trim_start();
+    while rest.starts_with('[') {
+        let end = match rest.find("\"]") {
+            Some(end) => end + 2,
+            None => break,
+        };
+        writeln!(out, "{}", &rest[..end])?;
+        rest = rest[end..].trim_start();
+    }
+    let result = game.result.as_deref().unwrap_or("*");
+    writeln!(out)?;
+    if rest.trim().is_empty() {
+        writeln!(out, "{}\n", result)
+    } else {
+        writeln!(out, "{} {}\n", rest.trim_end(), result)
+    }
+}
+
+fn write_csv_row<W: Write>(out: &mut W, cells: &[Cell]) -> std::io::Result<()> {
+    let fields: Vec<String> = cells
+        .iter()
+        .map(|cell| match cell {
+            Cell::Int(value) => value.map(|v| v.to_string()).unwrap_or_default(),
+            Cell::Float(value) => value.map(|v| v.to_string()).unwrap_or_default(),
+            Cell::Text(None) => String::new(),
+            Cell::Text(Some(text)) if text.contains([',', '"', '\n', '\r']) => format!("\"{}\"", text.replace('"', "\"\"")),
+            Cell::Text(Some(text)) => text.to_string(),
+        })
+        .collect();
+    writeln!(out, "{}", fields.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::game_for_pgn;
+    use crate::test_support::TempDir;
+
+    fn add_games(db: &Db) {
+        db.init_schema();
+        for pgn in [
+            "[Event \"Club, round 1\"] [Site \"https://lichess.org/abc\"] [Date \"2023.05.01\"] [White \"alice\"] [Black \"bob\"] [Result \"1-0\"] [WhiteElo \"2100\"] 1. e4 e5 2. Nf3 1-0",
+            "[Event \"Open\"] [Site \"Hastings\"] [Date \"2024.01.02\"] [White \"carol\"] [Black \"Alice\"] [Result \"0-1\"] 1. d4 d5 0-1",
+        ] {
+            let gv = game_for_pgn(pgn).unwrap();
+            let id = Game::insert(db, &gv.game).unwrap();
+            let keys: Vec<u64> = std::iter::once(gv.start_position()).chain(gv.fens.iter().cloned()).map(|p| p.zobrist()).collect();
+            crate::db::GamePositionKey::bulk_insert(db, &[(id, keys)]).unwrap();
+        }
+        crate::db::Player::register(db, [("alice", "https://lichess.org/abc")]).unwrap();
+    }
+
+    fn exported(db: &Db, filter: &GameFilter, format: ExportFormat) -> String {
+        let mut out = Vec::new();
+        export(db, filter, format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_export_formats() {
+        let dir = TempDir::new("export-formats");
+        let path = dir.join("data.db");
+        let db = Db::new(&path);
+        add_games(&db);
+        let all = GameFilter::default();
+
+        let pgn = exported(&db, &all, ExportFormat::Pgn);
+        assert!(pgn.starts_with("[Event \"Club, round 1\"]\n[Site \"https://lichess.org/abc\"]\n"));
+        assert!(pgn.contains("\n\n1. e4 1... e5 2. Nf3 1-0\n\n[Event \"Open\"]"));
+
+        let lines: Vec<serde_json::Value> =
+            exported(&db, &all, ExportFormat::Ndjson).lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["white"], "alice");
+        assert_eq!(lines[0]["plies"], 3);
+        assert_eq!(lines[0]["source"], "lichess.org");
+        assert!(lines[0]["white_player_id"].is_i64());
+        assert!(lines[1]["white_player_id"].is_null());
+
+        let csv = exported(&db, &all, ExportFormat::Csv);
+        let rows: Vec<&str> = csv.lines().collect();
+        assert!(rows[0].starts_with("id,event,site,date,"));
+        assert!(rows[1].starts_with("1,\"Club, round 1\",https://lichess.org/abc,2023.05.01,"));
+        assert_eq!(rows[1].matches(',').count(), rows[0].matches(',').count() + 1);
+
+        let arrow = exported_bytes(&db, &all);
+        assert!(arrow.starts_with(b"ARROW1") && arrow.ends_with(b"ARROW1"));
+    }
+
+    fn exported_bytes(db: &Db, filter: &GameFilter) -> Vec<u8> {
+        let mut out = Vec::new();
+        export(db, filter, ExportFormat::Arrow, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_filters() {
+        let dir = TempDir::new("export-filters");
+        let path = dir.join("data.db");
+        let db = Db::new(&path);
+        add_games(&db);
+        let count = |filter: GameFilter| export(&db, &filter, ExportFormat::Ndjson, std::io::sink()).unwrap();
+        assert_eq!(count(GameFilter { players: vec!["ALICE".to_string()], ..Default::default() }), 2);
+        // the lichess alice, not the namesake over the board
+        let alice = PlayerAlias::get(&db, "lichess.org", "alice").unwrap().unwrap();
+        assert_eq!(count(GameFilter { player_id: Some(alice.player_id), ..Default::default() }), 1);
+        assert_eq!(count(GameFilter { result: Some("0-1".to_string()), ..Default::default() }), 1);
+        assert_eq!(count(GameFilter { since: Some("2024".to_string()), ..Default::default() }), 1);
+        assert_eq!(count(GameFilter { until: Some("2023.05".to_string()), ..Default::default() }), 1);
+        assert_eq!(count(GameFilter { event: Some("round".to_string()), ..Default::default() }), 1);
+        assert_eq!(count(GameFilter { eco: Some("C".to_string()), ..Default::default() }), 1);
+        assert_eq!(count(GameFilter { limit: Some(1), ..Default::default() }), 1);
+    }
+}
@@ -0,0 +1,359 @@
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/*
+Fetching game archives
+
+`crusty fetch <site> <player> --from YYYY-MM --to YYYY-MM` downloads a player's
+games a month at a time as pgn, keeps each month on disk and imports them:
+
+    chess.com   https://api.chess.com/pub/player/<player>/games/<yyyy>/<mm>/pgn
+    lichess     https://lichess.org/api/games/user/<player>?since=<ms>&until=<ms>&clocks=true&evals=true
+
+Player names go into both the url and the cache path, so only names made of
+what the sites allow in a username, [A-Za-z0-9_-], are fetched.
+
+Months are cached as <data_dir>/fetch-cache/<site>/<player>/<yyyy-mm>.pgn. A
+month that is over is only downloaded once (unless --refresh); the current one
+is downloaded again each time, as more games may have been played since.
+
+Both sites ask for one request at a time, which is all this does, with at
+least `interval` between requests. A 429 waits for Retry-After (lichess asks
+for a full minute when there is none) and tries again, up to RETRIES times.
+
+HTTP goes through the HttpClient trait, ureq in the binary; the tests point a
+Fetcher at a stub server on localhost.
+ */
+
+const USER_AGENT: &str = concat!("crusty/", env!("CARGO_PKG_VERSION"));
+const CHESS_COM_URL: &str = "https://api.chess.com";
+const LICHESS_URL: &str = "https://lichess.org";
+const RETRIES: u32 = 3;
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+    pub retry_after: Option<Duration>,
+}
+
+pub trait HttpClient {
+    // any status is a response; Err is for requests that got no response at all
+    fn get(&self, url: &str) -> Result<HttpResponse, Box<dyn Error>>;
+}
+
+pub struct UreqClient {
+    agent: ureq::Agent,
+}
+
+impl UreqClient {
+    pub fn new() -> UreqClient {
+        let agent = ureq::AgentBuilder::new().user_agent(USER_AGENT).timeout(Duration::from_secs(120)).build();
+        UreqClient { agent }
+    }
+}
+
+impl HttpClient for UreqClient {
+    fn get(&self, url: &str) -> Result<HttpResponse, Box<dyn Error>> {
+        let response = match self.agent.get(url).set("Accept", "application/x-chess-pgn").call() {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(e) => return Err(e.into()),
+        };
+        let status = response.status();
+        let retry_after = response.header("Retry-After").and_then(|s| s.trim().parse().ok()).map(Duration::from_secs);
+        let mut body = Vec::new();
+        response.into_reader().read_to_end(&mut body)?;
+        Ok(HttpResponse { status, body, retry_after })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Site {
+    /// chess.com monthly archives
+    #[value(name = "chess.com", alias = "chesscom")]
+    ChessCom,
+    /// lichess game export
+    #[value(alias = "lichess.org")]
+    Lichess,
+}
+
+impl Site {
+    fn host(self) -> &'static str {
+        match self {
+            Site::ChessCom => "chess.com",
+            Site::Lichess => "lichess.org",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Month {
+    pub year: i32,
+    pub month: u32,
+}
+
+impl FromStr for Month {
+    type Err = String;
+
+    // "2023-05", or "2023/05" / "2023.05"
+    fn from_str(s: &str) -> Result<Month, String> {
+        let invalid = || format!("'{}' is not a month, expected YYYY-MM", s);
+        let (year, month) = s.trim().split_once(['-', '/', '.']).ok_or_else(invalid)?;
+        let month = Month { year: year.parse().map_err(|_| invalid())?, month: month.parse().map_err(|_| invalid())? };
+        if !(1..=12).contains(&month.month) {
+            return Err(invalid());
+        }
+        Ok(month)
+    }
+}
+
+impl fmt::Display for Month {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}", self.year, self.month)
+    }
+}
+
+impl Month {
+    pub fn next(self) -> Month {
+        match self.month {
+            12 => Month { year: self.year + 1, month: 1 },
+            month => Month { year: self.year, month: month + 1 },
+        }
+    }
+
+    // from..=to
+    pub fn range(from: Month, to: Month) -> Vec<Month> {
+        let mut months = Vec::new();
+        let mut month = from;
+        while month <= to {
+            months.push(month);
+            month = month.next();
+        }
+        months
+    }
+
+    // milliseconds since the epoch at the start of the month, UTC
+    pub fn start_millis(self) -> i64 {
+        // days from civil, http://howardhinnant.github.io/date_algorithms.html
+        let (month, year) = (self.month as i64, self.year as i64 - (self.month <= 2) as i64);
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        (era * 146_097 + day_of_era - 719_468) * 86_400_000
+    }
+
+    fn is_over(self) -> bool {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as i64);
+        self.next().start_millis() <= now
+    }
+}
+
+pub enum Fetched {
+    Cached(PathBuf),
+    Downloaded(PathBuf, usize),
+}
+
+pub struct Fetcher<'c> {
+    client: &'c dyn HttpClient,
+    cache_dir: PathBuf,
+    pub chess_com_url: String,
+    pub lichess_url: String,
+    pub interval: Duration,   // between requests
+    pub retry_wait: Duration, // after a 429 without Retry-After
+    pub refresh: bool,
+    last_request: Option<Instant>,
+}
+
+impl<'c> Fetcher<'c> {
+    pub fn new(client: &'c dyn HttpClient, cache_dir: PathBuf) -> Fetcher<'c> {
+        Fetcher {
+            client,
+            cache_dir,
+            chess_com_url: CHESS_COM_URL.to_string(),
+            lichess_url: LICHESS_URL.to_string(),
+            interval: Duration::from_secs(1),
+            retry_wait: Duration::from_secs(60),
+            refresh: false,
+            last_request: None,
+        }
+    }
+
+    fn url(&self, site: Site, player: &str, month: Month) -> Result<String, Box<dyn Error>> {
+        let player = check_player(player)?;
+        Ok(match site {
+            Site::ChessCom => format!(
+                "{}/pub/player/{}/games/{:04}/{:02}/pgn",
+                self.chess_com_url, player.to_lowercase(), month.year, month.month),
+            Site::Lichess => format!(
+                "{}/api/games/user/{}?since={}&until={}&clocks=true&evals=true",
+                self.lichess_url, player, month.start_millis(), month.next().start_millis() - 1),
+        })
+    }
+
+    pub fn cache_path(&self, site: Site, player: &str, month: Month) -> Result<PathBuf, Box<dyn Error>> {
+        let player = check_player(player)?;
+        Ok(self.cache_dir.join(site.host()).join(player.to_lowercase()).join(format!("{}.pgn", month)))
+    }
+
+    pub fn fetch_month(&mut self, site: Site, player: &str, month: Month) -> Result<Fetched, Box<dyn Error>> {
+        let path = self.cache_path(site, player, month)?;
+        if path.exists() && month.is_over() && !self.refresh {
+            return Ok(Fetched::Cached(path));
+        }
+        let url = self.url(site, player, month)?;
+        for _ in 0..=RETRIES {
+            if let Some(wait) = self.last_request.and_then(|last| self.interval.checked_sub(last.elapsed())) {
+                std::thread::sleep(wait);
+            }
+            let response = self.client.get(&url);
+            self.last_request = Some(Instant::now());
+            let response = response?;
+            match response.status {
+                200 => {
+                    // written aside and renamed, so an interrupted download never looks cached
+                    std::fs::create_dir_all(path.parent().unwrap_or(&self.cache_dir))?;
+                    let partial = path.with_extension("part");
+                    std::fs::write(&partial, &response.body)?;
+                    std::fs::rename(&partial, &path)?;
+                    return Ok(Fetched::Downloaded(path, response.body.len()));
+                }
+                404 => return Err(format!("{} has no player '{}'", site.host(), player).into()),
+                429 => {
+                    let wait = response.retry_after.unwrap_or(self.retry_wait);
+                    println!("rate limited by {}, waiting {}s", site.host(), wait.as_secs());
+                    std::thread::sleep(wait);
+                }
+                status => return Err(format!("{} returned {}", url, status).into()),
+            }
+        }
+        Err(format!("{} still rate limited after {} retries", site.host(), RETRIES).into())
+    }
+}
+
+// a username as both sites allow them, so it cannot leave the cache directory or
+// change the url it is put in ("../x", "a/b", "a?b", "")
+fn check_player(player: &str) -> Result<&str, String> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    match !player.is_empty() && player.chars().all(allowed) {
+        true => Ok(player),
+        false => Err(format!("'{}' is not a username, expected letters, digits, _ and -", player)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    // answers one request per connection with the next canned response, then stops
+    // listening; returns the base url and the request paths seen
+    fn stub_server(responses: Vec<String>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let paths = Arc::new(Mutex::new(Vec::new()));
+        let seen = paths.clone();
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request);
+                let path = request.split(' ').nth(1).unwrap_or("").to_string();
+                seen.lock().unwrap().push(path);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, paths)
+    }
+
+    fn response(status: &str, headers: &str, body: &str) -> String {
+        format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n{}", status, body.len(), headers, body)
+    }
+
+    #[test]
+    fn test_months() {
+        let from: Month = "2023-11".parse().unwrap();
+        assert_eq!(from, Month { year: 2023, month: 11 });
+        assert_eq!(Month::range(from, "2024/02".parse().unwrap()).iter().map(Month::to_string).collect::<Vec<_>>(),
+            ["2023-11", "2023-12", "2024-01", "2024-02"]);
+        assert!("2023-13".parse::<Month>().is_err());
+        assert!("May 2023".parse::<Month>().is_err());
+        assert_eq!(Month { year: 1970, month: 1 }.start_millis(), 0);
+        assert_eq!(Month { year: 2024, month: 3 }.start_millis(), 1_709_251_200_000);
+    }
+
+    #[test]
+    fn test_fetch_from_stub_server() {
+        let pgn = "[White \"alice\"]\n[Black \"bob\"]\n[Result \"1-0\"]\n\n1. e4 e5 1-0\n";
+        let (url, paths) = stub_server(vec![
+            response("429 Too Many Requests", "Retry-After: 0\r\n", ""),
+            response("200 OK", "", pgn),
+            response("200 OK", "", ""),
+            response("404 Not Found", "", ""),
+        ]);
+        let client = UreqClient::new();
+        let dir = TempDir::new("fetch");
+        let cache_dir = dir.join("cache");
+        let mut fetcher = Fetcher::new(&client, cache_dir.clone());
+        fetcher.chess_com_url = url.clone();
+        fetcher.lichess_url = url;
+        fetcher.interval = Duration::ZERO;
+
+        // rate limited once, then downloaded; the second time it comes from the cache
+        let month = Month { year: 2020, month: 1 };
+        let path = match fetcher.fetch_month(Site::ChessCom, "Alice", month).unwrap() {
+            Fetched::Downloaded(path, size) => {
+                assert_eq!(size, pgn.len());
+                path
+            }
+            Fetched::Cached(_) => panic!("nothing cached yet"),
+        };
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), pgn);
+        assert!(matches!(fetcher.fetch_month(Site::ChessCom, "alice", month).unwrap(), Fetched::Cached(p) if p == path));
+
+        assert!(matches!(fetcher.fetch_month(Site::Lichess, "bob", month).unwrap(), Fetched::Downloaded(_, 0)));
+        assert!(fetcher.fetch_month(Site::Lichess, "nobody", month).is_err());
+        assert_eq!(*paths.lock().unwrap(), [
+            "/pub/player/alice/games/2020/01/pgn",
+            "/pub/player/alice/games/2020/01/pgn",
+            "/api/games/user/bob?since=1577836800000&until=1580515199999&clocks=true&evals=true",
+            "/api/games/user/nobody?since=1577836800000&until=1580515199999&clocks=true&evals=true",
+        ]);
+    }
+
+    #[test]
+    fn test_hostile_player_names() {
+        // nothing listens here: a name that got as far as a request would fail differently
+        let client = UreqClient::new();
+        let dir = TempDir::new("fetch-hostile");
+        let cache_dir = dir.join("cache");
+        let mut fetcher = Fetcher::new(&client, cache_dir.clone());
+        fetcher.chess_com_url = "http://127.0.0.1:9".to_string();
+        fetcher.lichess_url = "http://127.0.0.1:9".to_string();
+        let month = Month { year: 2020, month: 1 };
+        for name in ["../../etc", "..", "a/b", "a\\b", "bob?since=0", "bob#", "al ice", "", "älice"] {
+            for site in [Site::ChessCom, Site::Lichess] {
+                let err = fetcher.fetch_month(site, name, month).err().unwrap();
+                assert!(err.to_string().contains("is not a username"), "{}: {}", name, err);
+                assert!(fetcher.cache_path(site, name, month).is_err());
+            }
+        }
+        assert!(!cache_dir.exists());
+        assert_eq!(fetcher.cache_path(Site::Lichess, "Magnus_Carlsen-1", month).unwrap(),
+            cache_dir.join("lichess.org").join("magnus_carlsen-1").join("2020-01.pgn"));
+    }
+}
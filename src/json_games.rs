@@ -0,0 +1,206 @@
+use std::error::Error;
+use std::io::{BufRead, Read};
+
+use pgn_reader::BufferedReader;
+use serde_json::Value;
+
+use crate::execution::games_for_buffs;
+use crate::parsing::GameVisitor;
+
+/*
+JSON game downloads
+
+chess.com's public API and lichess's game export can hand out games as JSON
+with the pgn embedded. A file may hold one JSON value or a stream of them (NDJSON,
+one game a line), and each value is a game, an array of games or an object with
+a "games" array (a chess.com monthly archive).
+
+The embedded pgn goes through GameVisitor like any pgn file, so positions,
+clocks and classification all work the same; the fields the pgn does not carry
+are then copied onto the Game:
+
+    external_id         chess.com uuid / lichess id
+    rated               rated
+    time_class          chess.com time_class / lichess speed
+    *_accuracy          chess.com accuracies.white / lichess players.white.analysis.accuracy
+    *_rating_diff       lichess players.white.ratingDiff
+    *_elo, link         rating and url, where the pgn has no WhiteElo/BlackElo/Link header
+
+Games without a pgn (lichess exports without pgnInJson) are skipped and counted.
+NDJSON is read a line at a time, and a line that does not parse is skipped and
+counted too, as a broken line of a download should lose that game and not the
+file. A single document that does not parse is an error.
+ */
+
+// `ndjson` for .ndjson and .jsonl files; a .json file is NDJSON too when its first line is a
+// whole value on its own
+pub fn games_for_json<R: BufRead>(mut reader: R, ndjson: bool, variations: bool) -> Result<Vec<GameVisitor>, Box<dyn Error>> {
+    let mut games = Vec::new();
+    let mut skipped = 0;
+    let mut first = String::new();
+    while first.trim().is_empty() && reader.read_line(&mut first)? > 0 {}
+    let first_value = serde_json::from_str::<Value>(&first);
+    match ndjson || first_value.is_ok() {
+        true => {
+            let mut bad = 0;
+            match first_value {
+                Ok(value) => bad += !add_games(&value, variations, &mut games, &mut skipped) as usize,
+                Err(_) => bad += !first.trim().is_empty() as usize,
+            }
+            for line in reader.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                // a broken line of a download costs that game, not the file
+                match serde_json::from_str::<Value>(&line) {
+                    Ok(value) => bad += !add_games(&value, variations, &mut games, &mut skipped) as usize,
+                    Err(_) => bad += 1,
+                }
+            }
+            if bad > 0 {
+                println!("skipped {} lines that are not JSON games", bad);
+            }
+        }
+        false => {
+            let document = std::io::Cursor::new(first).chain(reader);
+            for value in serde_json::Deserializer::from_reader(document).into_iter::<Value>() {
+                let value = value?;
+                if !add_games(&value, variations, &mut games, &mut skipped) {
+                    return Err(format!("expected a game object, found {}", value).into());
+                }
+            }
+        }
+    }
+    if skipped > 0 {
+        println!("skipped {} games without a pgn", skipped);
+    }
+    Ok(games)
+}
+
+// the games of a value that is a game, an array of them or an archive; false for anything else
+fn add_games(value: &Value, variations: bool, games: &mut Vec<GameVisitor>, skipped: &mut usize) -> bool {
+    let records = match value {
+        Value::Array(records) => records.as_slice(),
+        Value::Object(fields) => match fields.get("games") {
+            Some(Value::Array(records)) => records.as_slice(),
+            _ => std::slice::from_ref(value),
+        },
+        _ => return false,
+    };
+    for record in records {
+        match game_for_record(record, variations) {
+            Some(gv) => games.push(gv),
+            None => *skipped += 1,
+        }
+    }
+    true
+}
+
+fn game_for_record(record: &Value, variations: bool) -> Option<GameVisitor> {
+    let pgn = record.get("pgn")?.as_str()?;
+    let mut gv = games_for_buffs(BufferedReader::new_cursor(pgn.as_bytes()), variations).ok()?.into_iter().next()?;
+    let game = &mut gv.game;
+
+    let text = |value: Option<&Value>| value.and_then(Value::as_str).map(str::to_string);
+    // lichess nests the sides under "players", chess.com has them at the top
+    let side = |color: &str| record.get("players").unwrap_or(record).get(color);
+
+    game.external_id = text(record.get("uuid")).or_else(|| text(record.get("id")));
+    game.rated = record.get("rated").and_then(Value::as_bool);
+    game.time_class = text(record.get("time_class")).or_else(|| text(record.get("speed")));
+    game.white_accuracy = accuracy(record, side("white"), "white");
+    game.black_accuracy = accuracy(record, side("black"), "black");
+    game.white_rating_diff = side("white").and_then(|s| s.get("ratingDiff")).and_then(Value::as_i64);
+    game.black_rating_diff = side("black").and_then(|s| s.get("ratingDiff")).and_then(Value::as_i64);
+
+    let rating = |side: Option<&Value>| side.and_then(|s| s.get("rating")).and_then(Value::as_i64).map(|r| r.to_string());
+    if game.white_elo.is_none() {
+        game.white_elo = rating(side("white"));
+    }
+    if game.black_elo.is_none() {
+        game.black_elo = rating(side("black"));
+    }
+    if game.link.is_none() {
+        game.link = text(record.get("url"));
+    }
+    Some(gv)
+}
+
+fn accuracy(record: &Value, side: Option<&Value>, color: &str) -> Option<f64> {
+    record
+        .get("accuracies")
+        .and_then(|a| a.get(color))
+        .or_else(|| side.and_then(|s| s.get("analysis")).and_then(|a| a.get("accuracy")))
+        .and_then(Value::as_f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHESS_COM: &str = r#"{"games": [{
+        "url": "https://www.chess.com/game/live/81234567",
+        "pgn": "[Event \"Live Chess\"]\n[Site \"Chess.com\"]\n[White \"alice\"]\n[Black \"bob\"]\n[Result \"1-0\"]\n[WhiteElo \"1502\"]\n\n1. e4 {[%clk 0:09:58.1]} 1... e5 2. Qh5 Nc6 3. Bc4 Nf6 4. Qxf7# 1-0\n",
+        "time_control": "600", "end_time": 1690000000, "rated": true,
+        "accuracies": {"white": 91.2, "black": 40.5},
+        "uuid": "6d8a3c1e-2b1f-11ee-9f0c-6cfe544c0428", "time_class": "rapid", "rules": "chess",
+        "white": {"rating": 1502, "result": "win", "username": "alice"},
+        "black": {"rating": 1498, "result": "checkmated", "username": "bob"}
+    }]}"#;
+
+    const LICHESS: &str = concat!(
+        r#"{"id": "q7ZvsdUF", "rated": false, "variant": "standard", "speed": "blitz", "players": {"white": {"user": {"name": "carol"}, "rating": 1800, "ratingDiff": 6, "analysis": {"accuracy": 88}}, "black": {"user": {"name": "dave"}, "rating": 1750, "ratingDiff": -6}}, "pgn": "[Event \"Rated Blitz game\"]\n[Site \"https://lichess.org/q7ZvsdUF\"]\n[White \"carol\"]\n[Black \"dave\"]\n[Result \"0-1\"]\n\n1. d4 d5 0-1\n"}"#,
+        "\n",
+        r#"{"id": "noPgn123", "moves": "e4 e5"}"#,
+        "\n",
+    );
+
+    #[test]
+    fn test_chess_com_archive() {
+        let games = games_for_json(CHESS_COM.as_bytes(), false, false).unwrap();
+        assert_eq!(games.len(), 1);
+        let (gv, game) = (&games[0], &games[0].game);
+        assert_eq!(gv.fens.len(), 7);
+        assert_eq!(game.white.as_deref(), Some("alice"));
+        assert_eq!(game.external_id.as_deref(), Some("6d8a3c1e-2b1f-11ee-9f0c-6cfe544c0428"));
+        assert_eq!(game.rated, Some(true));
+        assert_eq!(game.time_class.as_deref(), Some("rapid"));
+        assert_eq!((game.white_accuracy, game.black_accuracy), (Some(91.2), Some(40.5)));
+        assert_eq!(game.white_elo.as_deref(), Some("1502"));
+        assert_eq!(game.black_elo.as_deref(), Some("1498"));
+        assert_eq!(game.link.as_deref(), Some("https://www.chess.com/game/live/81234567"));
+        assert!(gv.plies[0].clock.is_some());
+    }
+
+    #[test]
+    fn test_lichess_ndjson() {
+        let games = games_for_json(LICHESS.as_bytes(), false, false).unwrap();
+        assert_eq!(games.len(), 1);
+        let game = &games[0].game;
+        assert_eq!(game.external_id.as_deref(), Some("q7ZvsdUF"));
+        assert_eq!(game.rated, Some(false));
+        assert_eq!(game.time_class.as_deref(), Some("blitz"));
+        assert_eq!((game.white_accuracy, game.black_accuracy), (Some(88.0), None));
+        assert_eq!((game.white_rating_diff, game.black_rating_diff), (Some(6), Some(-6)));
+        assert_eq!(game.result.as_deref(), Some("0-1"));
+
+        assert!(games_for_json("[1, 2]".as_bytes(), false, false).is_ok());
+        assert!(games_for_json("{\"pgn\": ".as_bytes(), false, false).is_err());
+    }
+
+    #[test]
+    fn test_bad_lines() {
+        let broken = format!("{{\"id\": \"cut\", \"pgn\": \"[Event\n42\n{}not json\n", LICHESS);
+        let games = games_for_json(broken.as_bytes(), true, false).unwrap();
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].game.external_id.as_deref(), Some("q7ZvsdUF"));
+    }
+
+    // a pretty printed document is one value over many lines, and a mistake in it is an error
+    #[test]
+    fn test_broken_document() {
+        let broken = CHESS_COM.replace("\"rated\": true,", "\"rated\": true");
+        assert!(games_for_json(broken.as_bytes(), false, false).is_err());
+    }
+}
@@ -7,22 +7,36 @@ use std::time::Instant;
 use std::io::prelude::*;
 
 use std::collections::btree_map::BTreeMap;
+use std::collections::HashMap;
 
 // third party modules
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use colored::*;
 use pgn_reader::BufferedReader;
+use shakmaty::{variant::Variant, CastlingMode};
 
 /*
 Import our modules here
  */
+// Clock and eval annotations module
+mod annotations;
+use annotations::Eval;
+
+// Config module
+mod config;
+use config::RustyConfig;
+
+// Dataset manifest module
+mod manifest;
+use manifest::Manifest;
+
 // DB module
 mod db;
-use db::{Db, Game};
+use db::{BookMove, Db, Game, GameAnnotations, GameFilter, GameMaterial, GamePawns, GamePositionKey, GameText, GameTree, PawnStructure, MIGRATIONS, Player, PlayerAlias};
 
 // Persistance module
 mod persistance;
-use persistance::{PositionSegment, PositionTrie, PositionTrieAddress};
+use persistance::{Position as StoredPosition, PositionSegment, PositionTrie, PositionTrieAddress};
 
 // Parsing module
 mod parsing;
@@ -31,11 +45,539 @@ use parsing::{BitPosition, GameVisitor};
 // Execution module?
 
 mod execution;
-use execution::{create_readers_for_dir, games_for_buffs, game_visitor_to_positions};
+use execution::{game_files, games_for_file, game_for_pgn, open_store, StoreKind};
 use threadpool::ThreadPool;
 
+// Move tree module
+mod movetree;
+use movetree::MoveGraph;
+
+// Opening book module
+mod polyglot;
+use polyglot::{BookBuilder, BookFilter, BookWeight, PolyglotBook};
+
+// Explorer module
+mod explorer;
+
+// Pattern search module
+mod pattern;
+use pattern::PatternQuery;
+
+// ECO classification module
+mod eco;
+
+// Player statistics module
+mod player;
+use player::{split_alias_key, PlayerIdentity, PlayerReport, Score};
+
+// Time usage module
+mod time_usage;
+use time_usage::TimeUsageReport;
+
+// Variations module
+mod variations;
+
+// Variants module
+mod variants;
+
+// Arrow IPC module
+mod arrow;
+
+// Export module
+mod export;
+use export::ExportFormat;
+
+// JSON game import module
+mod json_games;
+
+// Archive fetching module
+mod fetch;
+use fetch::{Fetched, Fetcher, Month, Site, UreqClient};
+
+// Position similarity module
+mod similarity;
+use similarity::{Metric, SimilarityIndex};
+
+// Pawn structure module
+mod pawns;
+
+// Query server module
+mod serve;
+use serve::QueryServer;
+
+// Test fixtures module
+#[cfg(test)]
+mod test_support;
+
+const START_FEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
 #[derive(Parser)]
+#[command(subcommand_precedence_over_arg = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // with no subcommand, import these pgn / json files and directories
+    pgn_paths: Vec<PathBuf>,
+
+    /// Also keep variations, NAGs and comments as a move tree, and index the positions in variations
+    #[arg(long)]
+    variations: bool,
+
+    /// Where positions are kept
+    #[arg(long, global = true, value_enum, default_value_t = StoreKind::Segment)]
+    store: StoreKind,
+
+    /// Config file (TOML, or JSON with a .json extension)
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create the data directory, an empty data.db and a config file if there is none
+    Init(InitArgs),
+    /// Build a transposition-aware graph of positions reached from a root position
+    Tree(TreeArgs),
+    /// Look a position up by FEN in the game database and the segment file
+    Position(PositionArgs),
+    /// Full-text search over game comments, event, site and opening, e.g. "sacrifice" or "\"time trouble\""
+    Search(SearchArgs),
+    /// List a game's moves with the clock, time spent and engine eval from its %clk / %eval comments
+    Moves(MovesArgs),
+    /// Positions like a given one, nearest first, from the similarity index (built with --build)
+    Similar(SimilarArgs),
+    /// Find games reaching a piece pattern or material balance, e.g. "P@d* !P@c* !P@e*" or "material=KRPvKR"
+    Pattern(PatternArgs),
+    /// Games by pawn structure, from a fen, a pawn layout or a preset: carlsbad, maroczy, iqp, hedgehog
+    Pawns(PawnsArgs),
+    /// Summarise a player's games: score by colour, rating history, openings, time controls and streaks
+    Player(PlayerArgs),
+    /// Time usage from %clk comments: time per move by phase, time trouble and how it relates to results
+    Time(TimeArgs),
+    /// Download a player's games from chess.com or lichess a month at a time, cache and import them
+    Fetch(FetchArgs),
+    /// Write games out as PGN, JSON Lines, CSV or an Arrow IPC file, optionally filtered
+    Export(ExportArgs),
+    /// Answer JSON queries over HTTP: games, search, positions, explorer moves and player reports
+    Serve(ServeArgs),
+    /// Manage player identities: which White/Black names on which sites are the same person
+    Players {
+        #[command(subcommand)]
+        command: PlayersCommand,
+    },
+    /// Build or inspect Polyglot (.bin) opening books
+    Book {
+        #[command(subcommand)]
+        command: BookCommand,
+    },
+    /// Work with the position stores
+    Store {
+        #[command(subcommand)]
+        command: StoreCommand,
+    },
+    /// Maintain data.db
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    /// Inspect and maintain the segment files listed in the dataset manifest
+    Dataset {
+        #[command(subcommand)]
+        command: DatasetCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum DbCommand {
+    /// Apply pending schema migrations (every other command does this on open too)
+    Migrate(DbMigrateArgs),
+}
+
+#[derive(clap::Args)]
+struct DbMigrateArgs {
+    /// Only print the pending migrations and their SQL
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Subcommand)]
+enum DatasetCommand {
+    /// List the segments of the dataset
+    List,
+    /// Check every segment's record count, key range and checksum against its file
+    Verify,
+    /// Rewrite all segments as a single one without duplicate positions
+    Merge,
+}
+
+#[derive(clap::Args)]
+struct TimeArgs {
+    /// Only this player's side of their games: name, source:name or #id
+    #[arg(long)]
+    player: Option<String>,
+
+    /// Seconds left on the clock that count as time trouble
+    #[arg(long, default_value_t = 30)]
+    under: u32,
+
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Subcommand)]
+enum PlayersCommand {
+    /// List players with an alias matching a LIKE pattern, e.g. "%carlsen%"
+    List(PlayersListArgs),
+    /// Make the other players aliases of the first one
+    Merge {
+        /// Player to keep: name, source:name or #id
+        into: String,
+        /// Players to fold into it
+        #[arg(required = true)]
+        from: Vec<String>,
+    },
+    /// Give one alias (source:name, or the name for over the board games) a player of its own
+    Split { alias: String },
+    /// Register the players of games imported before player identities existed
+    Sync,
+}
+
+#[derive(clap::Args)]
+struct PlayersListArgs {
+    #[arg(default_value = "%")]
+    pattern: String,
+
+    /// Maximum number of players to list
+    #[arg(long, default_value_t = 50)]
+    limit: usize,
+}
+
+#[derive(Subcommand)]
+enum StoreCommand {
+    /// Copy every position from the --store backend into another one, timing both sides
+    Copy(StoreCopyArgs),
+}
+
+#[derive(clap::Args)]
+struct StoreCopyArgs {
+    #[arg(value_enum)]
+    to: StoreKind,
+
+    /// Positions per insert batch
+    #[arg(long, default_value_t = 100_000)]
+    batch: usize,
+}
+
+#[derive(clap::Args)]
+struct InitArgs {
+    /// Keep the dataset here instead of the configured data_dir; saved to a new config file
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct MovesArgs {
+    game_id: i64,
+
+    /// Mark moves made with less than this many seconds left that lost at least --drop pawns
+    #[arg(long, default_value_t = 30)]
+    under: u32,
+
+    /// Eval swing, in pawns, that counts as a blunder
+    #[arg(long, default_value_t = 2.0)]
+    drop: f64,
+
+    /// Print the move tree with variations, NAGs and comments (games imported with --variations)
+    #[arg(long)]
+    variations: bool,
+
+    /// Print the moves as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args)]
+struct SearchArgs {
+    /// fts5 query: terms are ANDed, "quoted phrases", OR, NOT and prefix* work
+    #[arg(required_unless_present = "reindex")]
+    terms: Option<String>,
+
+    /// Maximum number of games to list
+    #[arg(long, default_value_t = 20)]
+    limit: usize,
+
+    /// Rebuild the index from the pgn stored in data.db first
+    #[arg(long)]
+    reindex: bool,
+}
+
+#[derive(clap::Args)]
+struct PlayerArgs {
+    /// Player name as in the White/Black headers (case-insensitive), source:name (e.g. lichess.org:alice) or #id
+    name: String,
+
+    /// Number of openings and time controls to list
+    #[arg(long, default_value_t = 10)]
+    limit: usize,
+
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args)]
+struct FetchArgs {
+    #[arg(value_enum)]
+    site: Site,
+
+    /// Username on that site
+    player: String,
+
+    /// First month, YYYY-MM
+    #[arg(long)]
+    from: Month,
+
+    /// Last month, YYYY-MM (default: the --from month)
+    #[arg(long)]
+    to: Option<Month>,
+
+    /// Download months that are over again, even when cached
+    #[arg(long)]
+    refresh: bool,
+
+    /// Only download, do not import
+    #[arg(long)]
+    no_import: bool,
+
+    /// Also keep variations, NAGs and comments as a move tree when importing
+    #[arg(long)]
+    variations: bool,
+}
+
+#[derive(clap::Args)]
+struct GameFilterArgs {
+    /// Games of this player: name, source:name or #id (all of a merged identity's names)
+    #[arg(long)]
+    player: Option<String>,
+
+    /// ECO code or prefix, from the header or the classification, e.g. B or B90
+    #[arg(long)]
+    eco: Option<String>,
+
+    /// Result, e.g. 1-0
+    #[arg(long)]
+    result: Option<String>,
+
+    /// Variant header, e.g. Chess960
+    #[arg(long)]
+    variant: Option<String>,
+
+    /// Event containing this text
+    #[arg(long)]
+    event: Option<String>,
+
+    /// Site containing this text, e.g. lichess.org
+    #[arg(long)]
+    site: Option<String>,
+
+    /// Played on or after this date, e.g. 2023 or 2023.05.01
+    #[arg(long)]
+    since: Option<String>,
+
+    /// Played on or before this date, e.g. 2023.12
+    #[arg(long)]
+    until: Option<String>,
+
+    /// Maximum number of games
+    #[arg(long)]
+    limit: Option<usize>,
+}
+
+impl GameFilterArgs {
+    fn filter(&self, db: &Db) -> Result<GameFilter, Box<dyn std::error::Error>> {
+        let player = match &self.player {
+            Some(spec) => PlayerIdentity::resolve(db, spec)?.game_filter(),
+            None => GameFilter::default(),
+        };
+        Ok(GameFilter {
+            eco: self.eco.clone(),
+            result: self.result.clone(),
+            variant: self.variant.clone(),
+            event: self.event.clone(),
+            site: self.site.clone(),
+            since: self.since.clone(),
+            until: self.until.clone(),
+            limit: self.limit,
+            ..player
+        })
+    }
+}
+
+#[derive(clap::Args)]
+struct ServeArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    bind: String,
+
+    /// Worker threads, each with its own database connection; defaults to the number of cpus
+    #[arg(long)]
+    threads: Option<usize>,
+
+    /// Accept pgn posted to /api/games and import it; the server is read-only without it
+    #[arg(long)]
+    allow_import: bool,
+}
+
+#[derive(clap::Args)]
+struct ExportArgs {
+    #[command(flatten)]
+    filter: GameFilterArgs,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = ExportFormat::Pgn)]
+    format: ExportFormat,
+
+    /// File to write, stdout when not given
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct SimilarArgs {
+    /// FEN of the position to compare with
+    fen: Option<String>,
+
+    /// What counts as a difference
+    #[arg(long, value_enum, default_value_t = Metric::Placement)]
+    metric: Metric,
+
+    /// Number of positions to list
+    #[arg(long, default_value_t = 10)]
+    limit: usize,
+
+    /// Index entries to read per block before settling for an approximate answer
+    #[arg(long, default_value_t = 100_000)]
+    candidates: usize,
+
+    /// Build the index from the position store first (needed again after imports)
+    #[arg(long)]
+    build: bool,
+
+    /// Print the positions as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args)]
+struct PatternArgs {
+    query: String,
+
+    /// Maximum number of games to list
+    #[arg(long, default_value_t = 50)]
+    limit: usize,
+}
+
+#[derive(clap::Args)]
+struct PawnsArgs {
+    /// Fen (pieces are ignored), pawn layout like 8/pp3ppp/2p5/3p4/3P4/4P3/PP3PPP/8, or preset name; the most common structures without it
+    structure: Option<String>,
+
+    /// Maximum number of games or structures to list
+    #[arg(long, default_value_t = 50)]
+    limit: usize,
+
+    /// Index the pawn structures of every game again, e.g. for games imported before the index existed
+    #[arg(long)]
+    rebuild: bool,
+}
+
+#[derive(Subcommand)]
+enum BookCommand {
+    /// Write a Polyglot book from the position -> move statistics of a game collection
+    Export(BookExportArgs),
+    /// Show the moves a book has for a position, or a summary of the book without a FEN
+    Probe(BookProbeArgs),
+    /// Load a Polyglot book into data.db so `position` shows its moves
+    Import(BookImportArgs),
+}
+
+#[derive(clap::Args)]
+struct BookImportArgs {
+    book: PathBuf,
+
+    /// Name to file the moves under, defaults to the book's file name; re-importing replaces it
+    #[arg(long)]
+    source: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct BookExportArgs {
+    /// Output .bin file
+    out: PathBuf,
+
+    /// Drop moves played in fewer games than this
+    #[arg(long, default_value_t = 1)]
+    min_games: u64,
+
+    /// Only count moves played by players rated at least this
+    #[arg(long, default_value_t = 0)]
+    min_elo: u32,
+
+    /// Only count moves up to this ply
+    #[arg(long, default_value_t = 40)]
+    max_ply: usize,
+
+    #[arg(long, value_enum, default_value_t = BookWeight::Frequency)]
+    weight: BookWeight,
+
+    /// Read games from these pgn / json files and directories instead of data.db
+    pgn_paths: Vec<PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct BookProbeArgs {
+    book: PathBuf,
+    fen: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct PositionArgs {
+    fen: String,
+
+    /// Variant the position is from: standard, chess960, crazyhouse, atomic, antichess, kingofthehill, 3check, racingkings or horde
+    #[arg(long, default_value = "standard")]
+    variant: String,
+
+    /// Maximum number of game ids to list
+    #[arg(long, default_value_t = 20)]
+    limit: usize,
+
+    /// Print the database and book moves as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args)]
+struct TreeArgs {
+    /// Root position, defaults to the standard starting position
+    #[arg(long, default_value = START_FEN)]
+    fen: String,
+
+    /// Number of plies to follow past the root
+    #[arg(long, default_value_t = 8)]
+    depth: usize,
+
+    /// Write the graph in Graphviz DOT format
+    #[arg(long)]
+    dot: Option<PathBuf>,
+
+    /// Write the graph as JSON
+    #[arg(long)]
+    json: Option<PathBuf>,
+
+    /// Read games from these pgn / json files and directories instead of data.db
     pgn_paths: Vec<PathBuf>,
 }
 /*
@@ -96,7 +638,1236 @@ fn parse_in_parallel() {
 
 fn main() {
     let args = Args::parse();
-    for path in args.pgn_paths.iter() {
+    let mut config = match RustyConfig::load(args.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("loading config failed: {}", e);
+            return;
+        }
+    };
+    if let Some(Command::Init(init_args)) = &args.command {
+        init(&mut config, init_args);
+        return;
+    }
+    if let Err(e) = std::fs::create_dir_all(&config.data_dir) {
+        println!("creating {} failed: {}", config.data_dir.display(), e);
+        return;
+    }
+
+    match args.command {
+        Some(Command::Init(_)) => (),
+        Some(Command::Tree(tree_args)) => tree(&config, &tree_args),
+        Some(Command::Position(position_args)) => position(&config, &position_args, args.store),
+        Some(Command::Search(search_args)) => search(&config, &search_args),
+        Some(Command::Moves(moves_args)) => moves(&config, &moves_args),
+        Some(Command::Pattern(pattern_args)) => pattern(&config, &pattern_args),
+        Some(Command::Pawns(pawns_args)) => pawn_structures(&config, &pawns_args),
+        Some(Command::Player(player_args)) => player(&config, &player_args),
+        Some(Command::Players { command }) => players(&config, &command),
+        Some(Command::Time(time_args)) => time_usage(&config, &time_args),
+        Some(Command::Similar(similar_args)) => similar(&config, &similar_args, args.store),
+        Some(Command::Fetch(fetch_args)) => fetch(&config, &fetch_args, args.store),
+        Some(Command::Export(export_args)) => export(&config, &export_args),
+        Some(Command::Serve(serve_args)) => serve(&config, &serve_args, args.store),
+        Some(Command::Book { command: BookCommand::Export(export_args) }) => book_export(&config, &export_args),
+        Some(Command::Book { command: BookCommand::Probe(probe_args) }) => book_probe(&probe_args),
+        Some(Command::Book { command: BookCommand::Import(import_args) }) => book_import(&config, &import_args),
+        Some(Command::Store { command: StoreCommand::Copy(copy_args) }) => store_copy(&config, &copy_args, args.store),
+        Some(Command::Db { command: DbCommand::Migrate(migrate_args) }) => db_migrate(&config, &migrate_args),
+        Some(Command::Dataset { command }) => dataset(&config, &command),
+        None => import(&config, &args.pgn_paths, args.variations, args.store),
+    }
+}
+
+fn init(config: &mut RustyConfig, args: &InitArgs) {
+    if let Some(data_dir) = &args.data_dir {
+        config.data_dir = std::path::absolute(data_dir).unwrap_or(data_dir.clone());
+    }
+    if let Err(e) = std::fs::create_dir_all(&config.data_dir) {
+        println!("creating {} failed: {}", config.data_dir.display(), e);
+        return;
+    }
+    let db_path = config.db_path();
+    let existed = db_path.exists();
+    Db::new(&db_path).init_schema();
+    println!("{} {}", if existed { "found" } else { "created" }, db_path.display().to_string().green());
+
+    let manifest_path = Manifest::path(&config.data_dir);
+    if manifest_path.exists() {
+        println!("found {}", manifest_path.display());
+    } else {
+        match Manifest::load(&config.data_dir).and_then(|manifest| manifest.save()) {
+            Ok(_) => println!("created {}", manifest_path.display().to_string().green()),
+            Err(e) => println!("writing {} failed: {}", manifest_path.display(), e),
+        }
+    }
+
+    let config_file = config.config_file();
+    if config_file.exists() && args.data_dir.is_none() {
+        println!("using {}", config_file.display());
+        return;
+    }
+    if config_file.exists() {
+        println!("{} already exists, not overwriting it; point CRUSTY_DATA_DIR at {} instead",
+            config_file.display(), config.data_dir.display());
+        return;
+    }
+    let written = config_file
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| {
+            let text = config.to_toml().map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            std::fs::write(&config_file, text)
+        });
+    match written {
+        Ok(_) => println!("wrote {}", config_file.display().to_string().green()),
+        Err(e) => println!("writing {} failed: {}", config_file.display(), e),
+    }
+}
+
+fn tree(config: &RustyConfig, args: &TreeArgs) {
+    let root = match BitPosition::parse_from_str(&args.fen) {
+        Ok(pos) => pos,
+        Err(why) => {
+            println!("invalid root fen '{}': {}", args.fen.red(), why);
+            return;
+        }
+    };
+    let mut graph = MoveGraph::new(&root, args.depth);
+
+    if let Err(e) = for_each_game(config, &args.pgn_paths, |gv| {
+        graph.add_game(gv);
+    }) {
+        println!("reading games failed: {}", e);
+        return;
+    }
+
+    println!(
+        "{} games reach the root, {} positions, {} moves",
+        graph.games(), graph.node_count(), graph.edge_count());
+
+    if let Some(path) = &args.dot {
+        match File::create(path).and_then(|mut fh| graph.write_dot(&mut fh)) {
+            Ok(_) => println!("wrote {}", path.display().to_string().green()),
+            Err(e) => println!("writing {} failed: {}", path.display(), e),
+        }
+    }
+    if let Some(path) = &args.json {
+        let res = File::create(path)
+            .map_err(serde_json::Error::io)
+            .and_then(|fh| graph.write_json(std::io::BufWriter::new(fh)));
+        match res {
+            Ok(_) => println!("wrote {}", path.display().to_string().green()),
+            Err(e) => println!("writing {} failed: {}", path.display(), e),
+        }
+    }
+}
+
+// Feed every game either from the given pgn files / directories or, when none are
+// given, from the pgn stored in data.db.
+fn for_each_game<F: FnMut(&GameVisitor)>(config: &RustyConfig, pgn_paths: &[PathBuf], mut f: F) -> Result<(), rusqlite::Error> {
+    if pgn_paths.is_empty() {
+        let db_path = config.db_path();
+        let db = Db::new(&db_path);
+        db.init_schema();
+        return Game::for_each_pgn(&db, |_id, pgn| {
+            if let Some(gv) = game_for_pgn(pgn) {
+                f(&gv);
+            }
+        });
+    }
+
+    for path in pgn_paths.iter() {
+        let files = match game_files(path) {
+            Ok(files) => files,
+            Err(e) => {
+                println!("skipping {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        for file in files {
+            match games_for_file(&file, false) {
+                Ok(games) => games.iter().for_each(&mut f),
+                Err(e) => println!("skipping {}: {}", file.display(), e),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn book_export(config: &RustyConfig, args: &BookExportArgs) {
+    let mut builder = BookBuilder::new(BookFilter {
+        min_games: args.min_games,
+        min_elo: args.min_elo,
+        max_ply: args.max_ply,
+    });
+    if let Err(e) = for_each_game(config, &args.pgn_paths, |gv| builder.add_game(gv)) {
+        println!("reading games failed: {}", e);
+        return;
+    }
+
+    let entries = builder.entries(args.weight);
+    match polyglot::write_book(&args.out, &entries) {
+        Ok(count) => println!(
+            "{} games, wrote {} book entries to {}",
+            builder.games(), count, args.out.display().to_string().green()),
+        Err(e) => println!("writing {} failed: {}", args.out.display(), e),
+    }
+}
+
+fn book_probe(args: &BookProbeArgs) {
+    let mut book = match PolyglotBook::open(&args.book) {
+        Ok(book) => book,
+        Err(e) => {
+            println!("opening {} failed: {}", args.book.display(), e);
+            return;
+        }
+    };
+
+    let fen = match &args.fen {
+        Some(fen) => fen,
+        None => {
+            println!("{}: {} entries", args.book.display(), book.len());
+            match book.entries() {
+                Ok(mut entries) => {
+                    entries.dedup_by_key(|e| e.key);
+                    println!("  {} positions", entries.len());
+                }
+                Err(e) => println!("reading entries failed: {}", e),
+            }
+            return;
+        }
+    };
+
+    let pos = match BitPosition::parse_from_str(fen) {
+        Ok(pos) => pos,
+        Err(why) => {
+            println!("invalid fen '{}': {}", fen.red(), why);
+            return;
+        }
+    };
+    let key = pos.zobrist();
+    match book.probe(key) {
+        Ok(entries) => {
+            println!("{} ({:#018x}): {} moves", pos.to_board_fen().green(), key, entries.len());
+            let total: u64 = entries.iter().map(|e| e.weight as u64).sum();
+            for entry in entries {
+                println!(
+                    "  {: <6} weight {: >5} ({:.1}%)",
+                    entry.move_uci(), entry.weight, 100.0 * entry.weight as f64 / total.max(1) as f64);
+            }
+        }
+        Err(e) => println!("probe failed: {}", e),
+    }
+}
+
+fn book_import(config: &RustyConfig, args: &BookImportArgs) {
+    let source = match &args.source {
+        Some(source) => source.clone(),
+        None => args.book.file_name().map_or("book".to_string(), |f| f.to_string_lossy().to_string()),
+    };
+    let entries = match PolyglotBook::open(&args.book).and_then(|mut book| book.entries()) {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("reading {} failed: {}", args.book.display(), e);
+            return;
+        }
+    };
+    let moves: Vec<BookMove> = entries
+        .iter()
+        .map(|e| BookMove { source: source.clone(), zobrist: e.key, mv: e.mv, weight: e.weight, learn: e.learn })
+        .collect();
+
+    let db_path = config.db_path();
+    let db = Db::new(&db_path);
+    db.init_schema();
+    match BookMove::replace_source(&db, &source, &moves) {
+        Ok(count) => println!("imported {} book moves as '{}'", count, source.green()),
+        Err(e) => println!("book import failed: {}", e),
+    }
+}
+
+fn store_copy(config: &RustyConfig, args: &StoreCopyArgs, from: StoreKind) {
+    if args.to == from {
+        println!("source and destination are both {:?}", from);
+        return;
+    }
+    let db_path = config.db_path();
+    let db = Db::new(&db_path);
+    db.init_schema();
+    let stores = open_store(from, &config.data_dir, &db)
+        .and_then(|source| Ok((source, open_store(args.to, &config.data_dir, &db)?)));
+    let (mut source, mut dest) = match stores {
+        Ok(stores) => stores,
+        Err(e) => {
+            println!("opening position stores failed: {}", e);
+            return;
+        }
+    };
+
+    let start = Instant::now();
+    let mut positions = Vec::<StoredPosition>::new();
+    // the range end is exclusive, so the last key needs its own lookup
+    let read = source
+        .for_each_in_range(0, u64::MAX, &mut |p| positions.push(*p))
+        .and_then(|_| source.lookup(u64::MAX));
+    match read {
+        Ok(last) => positions.extend(last),
+        Err(e) => {
+            println!("reading {} failed: {}", source.name(), e);
+            return;
+        }
+    }
+    println!("read {} positions from {} in {:?}", positions.len(), source.name(), start.elapsed());
+
+    let start = Instant::now();
+    for batch in positions.chunks(args.batch.max(1)) {
+        if let Err(e) = dest.insert_batch(batch) {
+            println!("writing {} failed: {}", dest.name(), e);
+            return;
+        }
+    }
+    match dest.flush() {
+        Ok(written) => println!("wrote {} new positions to {} in {:?}", written, dest.name(), start.elapsed()),
+        Err(e) => println!("flushing {} failed: {}", dest.name(), e),
+    }
+}
+
+fn db_migrate(config: &RustyConfig, args: &DbMigrateArgs) {
+    let db_path = config.db_path();
+    // a dry run only reads: a missing database is version 0 and is not created
+    let db = match (args.dry_run, db_path.exists()) {
+        (true, false) => None,
+        (true, true) => Some(Db::inspect(&db_path)),
+        (false, _) => Some(Db::new(&db_path)),
+    };
+    let status = match db.as_ref() {
+        Some(db) => db.schema_version().and_then(|version| Ok((version, db.pending_migrations()?))),
+        None => Ok((0, MIGRATIONS.iter().collect())),
+    };
+    let pending = match status {
+        Ok((version, pending)) => {
+            println!("{} is at schema version {}", db_path.display(), version);
+            pending
+        }
+        Err(e) => {
+            println!("{}", e.to_string().red());
+            return;
+        }
+    };
+    if pending.is_empty() {
+        println!("up to date");
+        return;
+    }
+    for migration in pending.iter() {
+        println!("  {: >3} {}", migration.version, migration.name.green());
+        if args.dry_run {
+            for sql in migration.statements {
+                println!("{};", sql);
+            }
+        }
+    }
+    if args.dry_run {
+        println!("{} pending, nothing applied (dry run)", pending.len());
+        return;
+    }
+    match db.map(|db| db.migrate()) {
+        Some(Ok(applied)) => println!("applied {} migrations", applied.len()),
+        Some(Err(e)) => println!("migration failed: {}", e),
+        None => (),
+    }
+}
+
+fn dataset(config: &RustyConfig, command: &DatasetCommand) {
+    let mut manifest = match Manifest::load(&config.data_dir) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            println!("loading manifest failed: {}", e);
+            return;
+        }
+    };
+    match command {
+        DatasetCommand::List => {
+            println!(
+                "dataset {:016x} in {}: {} segments, {} positions",
+                manifest.dataset_id, config.data_dir.display(), manifest.segments.len(), manifest.records());
+            for segment in manifest.segments.iter() {
+                println!(
+                    "  {: >4} {} {: >10} records  keys {:#018x}-{:#018x}  crc32 {:08x}",
+                    segment.id, segment.path.display(), segment.records,
+                    segment.min_key, segment.max_key, segment.checksum);
+            }
+        }
+        DatasetCommand::Verify => {
+            let problems = manifest.verify();
+            for problem in problems.iter() {
+                println!("{}", problem.red());
+            }
+            println!("{} segments checked, {} problems", manifest.segments.len(), problems.len());
+        }
+        DatasetCommand::Merge => {
+            let before = manifest.records();
+            match manifest.merge() {
+                Ok(Some(merged)) => println!(
+                    "merged into segment {}: {} positions, {} duplicates dropped",
+                    merged.id, merged.records, before - merged.records),
+                Ok(None) => println!("nothing to merge"),
+                Err(e) => println!("merge failed: {}", e),
+            }
+        }
+    }
+}
+
+// centiseconds as h:mm:ss.s / m:ss.s
+fn format_clock(centis: u32) -> String {
+    let (hours, minutes, seconds) = (centis / 360_000, centis / 6000 % 60, centis % 6000);
+    match hours {
+        0 => format!("{}:{:04.1}", minutes, seconds as f64 / 100.0),
+        _ => format!("{}:{:02}:{:04.1}", hours, minutes, seconds as f64 / 100.0),
+    }
+}
+
+fn moves(config: &RustyConfig, args: &MovesArgs) {
+    let db_path = config.db_path();
+    let db = Db::new(&db_path);
+    db.init_schema();
+
+    let gv = match Game::query_by_id(&db, args.game_id) {
+        Ok(Some(game)) => match game.pgn.as_deref().and_then(game_for_pgn) {
+            Some(gv) => gv,
+            None => {
+                println!("game {} has no readable pgn", args.game_id);
+                return;
+            }
+        },
+        Ok(None) => {
+            println!("no game {}", args.game_id);
+            return;
+        }
+        Err(e) => {
+            println!("loading game {} failed: {}", args.game_id, e);
+            return;
+        }
+    };
+    if args.variations {
+        match GameTree::for_game(&db, args.game_id, &gv.start) {
+            Ok(Some(tree)) if args.json => match serde_json::to_string_pretty(&tree) {
+                Ok(json) => println!("{}", json),
+                Err(e) => println!("{}", e),
+            },
+            Ok(Some(tree)) => println!("{} {}", tree.to_pgn(), gv.game.result.as_deref().unwrap_or("*")),
+            Ok(None) => println!("game {} has no move tree, import it with --variations", args.game_id),
+            Err(e) => println!("loading move tree failed: {}", e),
+        }
+        return;
+    }
+    // games imported before annotations were stored are worked out from the pgn
+    let plies = match GameAnnotations::for_game(&db, args.game_id) {
+        Ok(Some(plies)) => plies,
+        Ok(None) => gv.annotations(),
+        Err(e) => {
+            println!("loading annotations failed: {}", e);
+            return;
+        }
+    };
+    let blunders = annotations::time_trouble_blunders(&plies, args.under * 100, (args.drop * 100.0).round() as i32);
+
+    if args.json {
+        #[derive(serde::Serialize)]
+        struct Move<'a> {
+            ply: usize,
+            san: String,
+            #[serde(flatten)]
+            annotation: &'a annotations::PlyAnnotation,
+            time_trouble_blunder: bool,
+        }
+        let moves: Vec<_> = plies
+            .iter()
+            .zip(gv.sans.iter())
+            .enumerate()
+            .map(|(i, (annotation, san))| Move { ply: i + 1, san: san.to_string(), annotation, time_trouble_blunder: blunders.contains(&i) })
+            .collect();
+        match serde_json::to_string_pretty(&moves) {
+            Ok(json) => println!("{}", json),
+            Err(e) => println!("{}", e),
+        }
+        return;
+    }
+
+    println!("{: >5} {: <8} {: >10} {: >8} {: >7}", "", "move", "clock", "spent", "eval");
+    for (i, (ply, san)) in plies.iter().zip(gv.sans.iter()).enumerate() {
+        let number = if i % 2 == 0 { format!("{}.", i / 2 + 1) } else { format!("{}...", i / 2 + 1) };
+        let eval = match ply.eval {
+            Some(Eval::Centipawns(cp)) => format!("{:+.2}", cp as f64 / 100.0),
+            Some(Eval::Mate(n)) => format!("#{}", n),
+            None => String::new(),
+        };
+        let line = format!(
+            "{: >5} {: <8} {: >10} {: >8} {: >7}",
+            number, san.to_string(),
+            ply.clock.map(format_clock).unwrap_or_default(),
+            ply.spent.map(format_clock).unwrap_or_default(),
+            eval);
+        if blunders.contains(&i) {
+            println!("{}  {}", line.red(), "time trouble blunder".red());
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+fn search(config: &RustyConfig, args: &SearchArgs) {
+    let db_path = config.db_path();
+    let db = Db::new(&db_path);
+    db.init_schema();
+
+    if args.reindex {
+        if let Err(e) = GameText::clear(&db) {
+            println!("clearing the index failed: {}", e);
+            return;
+        }
+        let mut texts = Vec::<GameText>::new();
+        let mut indexed = 0;
+        let res = Game::for_each_pgn(&db, |id, pgn| {
+            if let Some(gv) = game_for_pgn(pgn) {
+                texts.push(gv.text(id));
+            }
+            if texts.len() >= 1000 {
+                indexed += texts.len();
+                if let Err(e) = GameText::bulk_insert(&db, &std::mem::take(&mut texts)) {
+                    println!("indexing failed: {}", e);
+                }
+            }
+        });
+        indexed += texts.len();
+        match res.and_then(|_| GameText::bulk_insert(&db, &texts)) {
+            Ok(_) => println!("indexed {} games", indexed),
+            Err(e) => println!("indexing failed: {}", e),
+        }
+    }
+
+    let terms = match &args.terms {
+        Some(terms) => terms,
+        None => return,
+    };
+    let hits = match GameText::search(&db, terms, args.limit) {
+        Ok(hits) => hits,
+        Err(e) => {
+            println!("search failed: {}", e);
+            return;
+        }
+    };
+    for hit in hits.iter() {
+        let game = &hit.game;
+        println!(
+            "{: >8}  {} - {} {}  {}, {}",
+            game.id,
+            game.white.as_deref().unwrap_or("?").green(),
+            game.black.as_deref().unwrap_or("?").green(),
+            game.result.as_deref().unwrap_or("*"),
+            game.event,
+            game.date.as_deref().unwrap_or("????.??.??"));
+        println!("          {}  ({:.2})", hit.snippet, hit.rank);
+    }
+    println!("{} games", hits.len());
+}
+
+fn player(config: &RustyConfig, args: &PlayerArgs) {
+    let db_path = config.db_path();
+    let db = Db::new(&db_path);
+    db.init_schema();
+
+    let report = match PlayerReport::for_player(&db, &args.name) {
+        Ok(report) => report,
+        Err(e) => {
+            println!("player report failed: {}", e);
+            return;
+        }
+    };
+    if args.json {
+        match serde_json::to_string_pretty(&report) {
+            Ok(json) => println!("{}", json),
+            Err(e) => println!("{}", e),
+        }
+        return;
+    }
+    if report.total.games == 0 {
+        println!("no games found for {}", args.name.red());
+        return;
+    }
+
+    let score_line = |label: &str, score: &Score| {
+        println!(
+            "  {: <12} {: >6} {: >6} {: >6} {: >6} {: >7}",
+            label, score.games, score.wins, score.draws, score.losses,
+            score.percentage().map_or("-".to_string(), |p| format!("{:.1}%", p * 100.0)));
+    };
+    match report.player_id {
+        Some(id) => println!("player {} #{}  {}", report.name.green(), id, report.aliases.join(", ")),
+        None => println!("player {}", report.name.green()),
+    }
+    println!("  {: <12} {: >6} {: >6} {: >6} {: >6} {: >7}", "", "games", "won", "drawn", "lost", "score");
+    score_line("total", &report.total);
+    score_line("as white", &report.white);
+    score_line("as black", &report.black);
+    println!("  longest winning streak {}, losing streak {}", report.longest_win_streak, report.longest_loss_streak);
+
+    if let (Some(first), Some(last)) = (report.rating_history.first(), report.rating_history.last()) {
+        let peak = report.rating_history.iter().max_by_key(|p| p.rating).unwrap();
+        println!(
+            "  rating {} ({}) -> {} ({}), peak {} ({}), {} dates",
+            first.rating, first.date, last.rating, last.date, peak.rating, peak.date, report.rating_history.len());
+    }
+
+    if !report.openings.is_empty() {
+        println!("  openings");
+    }
+    for opening in report.openings.iter().take(args.limit) {
+        score_line(&opening.eco, &opening.score);
+        if let Some(name) = &opening.opening {
+            println!("  {: <12} {}", "", name);
+        }
+    }
+    if !report.time_controls.is_empty() {
+        println!("  time controls");
+    }
+    for tc in report.time_controls.iter().take(args.limit) {
+        println!("  {: <12} {: >6}", tc.time_control, tc.games);
+    }
+}
+
+#[derive(serde::Serialize)]
+struct SimilarPosition {
+    distance: u32,
+    board: String,
+    zobrist: String,
+    games: usize,
+    game_ids: Vec<i64>,
+}
+
+fn similar(config: &RustyConfig, args: &SimilarArgs, store: StoreKind) {
+    let index_path = config.data_dir.join("similar.idx");
+    let db_path = config.db_path();
+    let db = Db::new(&db_path);
+    db.init_schema();
+
+    if args.build {
+        let start = Instant::now();
+        let mut failed = None;
+        // the range end is exclusive, so the last key needs its own lookup
+        let built = similarity::IndexBuilder::new(&index_path).map_err(|e| e.into()).and_then(|mut builder| {
+            let mut source = open_store(store, &config.data_dir, &db)?;
+            source.for_each_in_range(0, u64::MAX, &mut |p| {
+                if failed.is_none() {
+                    failed = builder.push(p).err();
+                }
+            })?;
+            for p in source.lookup(u64::MAX)? {
+                builder.push(&p)?;
+            }
+            match failed.take() {
+                Some(e) => Err(e.into()),
+                None => builder.finish(),
+            }
+        });
+        match built {
+            Ok(count) => println!("indexed {} positions in {:?}", count, start.elapsed()),
+            Err(e) => {
+                println!("building {} failed: {}", index_path.display(), e);
+                return;
+            }
+        }
+    }
+    let fen = match &args.fen {
+        Some(fen) => fen,
+        None if args.build => return,
+        None => {
+            println!("give a FEN to compare with");
+            return;
+        }
+    };
+    let pos = match variants::position_from_fen(fen, Variant::Chess, CastlingMode::Standard) {
+        Ok(pos) => BitPosition::from_variant(&pos),
+        Err(why) => {
+            println!("invalid fen '{}': {}", fen.red(), why);
+            return;
+        }
+    };
+    let (r12, r34, r56, r78) = pos.to_bits();
+    let query = StoredPosition { zobrist: pos.zobrist(), r12, r34, r56, r78 };
+
+    let nearest = SimilarityIndex::open(&index_path)
+        .and_then(|mut index| Ok((index.len(), index.nearest(&query, args.metric, args.limit, args.candidates)?)));
+    let (indexed, nearest) = match nearest {
+        Ok(found) => found,
+        Err(e) => {
+            println!("similarity search failed: {} (build the index with --build)", e);
+            return;
+        }
+    };
+    let mut found = Vec::new();
+    for similar in nearest.found.iter() {
+        let p = similar.position;
+        let games = GamePositionKey::games_for_zobrist(&db, p.zobrist).unwrap_or_default();
+        found.push(SimilarPosition {
+            distance: similar.distance,
+            board: BitPosition::from_bits(p.r12, p.r34, p.r56, p.r78).map_or(String::new(), |b| b.to_board_fen()),
+            zobrist: format!("{:#018x}", p.zobrist),
+            games: games.len(),
+            game_ids: games.iter().take(5).map(|key| key.game_id).collect(),
+        });
+    }
+    if args.json {
+        match serde_json::to_string_pretty(&found) {
+            Ok(json) => println!("{}", json),
+            Err(e) => println!("{}", e),
+        }
+        return;
+    }
+
+    println!("{} positions by {:?} distance, {} of {} indexed looked at{}",
+        found.len(), args.metric, nearest.candidates, indexed,
+        if nearest.exact { "" } else { " (approximate)" });
+    for similar in found.iter() {
+        let ids: Vec<String> = similar.game_ids.iter().map(|id| id.to_string()).collect();
+        println!("  {: >3} {} {} games {}", similar.distance, similar.board.green(), similar.games, ids.join(" "));
+    }
+}
+
+fn serve(config: &RustyConfig, args: &ServeArgs, store: StoreKind) {
+    let db_path = config.db_path();
+    if args.allow_import {
+        Db::new(&db_path).init_schema();
+    } else if !db_path.exists() {
+        println!("no database at {}, import some games first", db_path.display());
+        return;
+    } else {
+        // a read-only server cannot migrate the schema itself
+        match Db::read_only(&db_path).pending_migrations() {
+            Ok(pending) if pending.is_empty() => (),
+            Ok(_) => {
+                println!("{} needs migrating first: crusty db migrate", db_path.display());
+                return;
+            }
+            Err(e) => {
+                println!("reading {} failed: {}", db_path.display(), e);
+                return;
+            }
+        }
+    }
+
+    let mut server = match QueryServer::bind(&args.bind, &config.data_dir, &db_path, store) {
+        Ok(server) => server,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    if args.allow_import {
+        server.importer = Some(Box::new(|paths: &[PathBuf]| import(config, paths, false, store)));
+    }
+    let threads = args.threads.unwrap_or_else(num_cpus::get);
+    println!(
+        "serving {} on http://{} with {} threads{}",
+        config.data_dir.display(),
+        server.local_addr().map_or(args.bind.clone(), |addr| addr.to_string()).green(),
+        threads,
+        if args.allow_import { ", imports allowed" } else { ", read-only" });
+    server.run(threads);
+}
+
+fn fetch(config: &RustyConfig, args: &FetchArgs, store: StoreKind) {
+    let client = UreqClient::new();
+    let mut fetcher = Fetcher::new(&client, config.data_dir.join("fetch-cache"));
+    fetcher.refresh = args.refresh;
+
+    let mut paths = Vec::new();
+    for month in Month::range(args.from, args.to.unwrap_or(args.from)) {
+        match fetcher.fetch_month(args.site, &args.player, month) {
+            Ok(Fetched::Cached(path)) => {
+                println!("{} cached", month);
+                paths.push(path);
+            }
+            Ok(Fetched::Downloaded(path, size)) => {
+                println!("{} downloaded {} bytes", month, size);
+                paths.push(path);
+            }
+            Err(e) => {
+                println!("{} failed: {}", month, e);
+                break;
+            }
+        }
+    }
+    if !args.no_import && !paths.is_empty() {
+        import(config, &paths, args.variations, store);
+    }
+}
+
+// the games go to stdout unless --output is given, so messages go to stderr
+fn export(config: &RustyConfig, args: &ExportArgs) {
+    let db_path = config.db_path();
+    let db = Db::new(&db_path);
+    db.init_schema();
+
+    let filter = match args.filter.filter(&db) {
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("export failed: {}", e);
+            return;
+        }
+    };
+    let written = match &args.output {
+        Some(path) => File::create(path).map_err(|e| e.into()).and_then(|file| export::export(&db, &filter, args.format, file)),
+        None => export::export(&db, &filter, args.format, std::io::stdout().lock()),
+    };
+    match written {
+        Ok(count) => match &args.output {
+            Some(path) => println!("exported {} games to {}", count, path.display().to_string().green()),
+            None => eprintln!("exported {} games", count),
+        },
+        Err(e) => eprintln!("export failed: {}", e),
+    }
+}
+
+fn time_usage(config: &RustyConfig, args: &TimeArgs) {
+    let db_path = config.db_path();
+    let db = Db::new(&db_path);
+    db.init_schema();
+
+    let report = match &args.player {
+        Some(spec) => TimeUsageReport::for_player(&db, spec, args.under),
+        None => TimeUsageReport::for_all(&db, args.under),
+    };
+    let report = match report {
+        Ok(report) => report,
+        Err(e) => {
+            println!("time usage failed: {}", e);
+            return;
+        }
+    };
+    if args.json {
+        match serde_json::to_string_pretty(&report) {
+            Ok(json) => println!("{}", json),
+            Err(e) => println!("{}", e),
+        }
+        return;
+    }
+    if report.sides == 0 {
+        println!("no games with clock data");
+        return;
+    }
+
+    println!("{} game sides with clock data", report.sides);
+    println!("  {: <12} {: >8} {: >10} {: >9}", "", "moves", "avg time", "% budget");
+    for phase in report.phases.iter() {
+        println!(
+            "  {: <12} {: >8} {: >10} {: >9}",
+            phase.phase, phase.moves,
+            phase.avg_seconds.map_or("-".to_string(), |s| format!("{:.1}s", s)),
+            phase.avg_budget_percent.map_or("-".to_string(), |p| format!("{:.2}%", p)));
+    }
+    println!(
+        "  under {}s in {} sides, first at move {}",
+        report.under, report.time_trouble,
+        report.avg_first_move_under.map_or("-".to_string(), |m| format!("{:.1} on average", m)));
+    for count in report.first_moves_under.iter() {
+        println!("    move {: >3} {: >6}", count.move_number, count.sides);
+    }
+    let score = |score: &Score| score.percentage().map_or("-".to_string(), |p| format!("{:.1}%", p * 100.0));
+    println!(
+        "  score in time trouble {} ({} games), otherwise {} ({} games)",
+        score(&report.score_in_time_trouble), report.score_in_time_trouble.games,
+        score(&report.score_otherwise), report.score_otherwise.games);
+    if let Some(r) = report.clock_result_correlation {
+        println!("  lowest clock vs result correlation {:.2}", r);
+    }
+}
+
+fn players(config: &RustyConfig, command: &PlayersCommand) {
+    let db_path = config.db_path();
+    let db = Db::new(&db_path);
+    db.init_schema();
+
+    // names have to resolve to a registered player here, not just match games
+    let resolve = |spec: &str| match PlayerIdentity::resolve(&db, spec) {
+        Ok(PlayerIdentity { player: Some(player), .. }) => Some(player),
+        Ok(_) => {
+            println!("no player {}", spec.red());
+            None
+        }
+        Err(e) => {
+            println!("{}", e);
+            None
+        }
+    };
+    match command {
+        PlayersCommand::List(args) => match Player::list(&db, &args.pattern, args.limit) {
+            Ok(players) => {
+                for player in players.iter() {
+                    let aliases = player.aliases(&db).unwrap_or_default();
+                    let keys: Vec<_> = aliases.iter().map(|a| a.key()).collect();
+                    println!("{: >8}  {}  {}", format!("#{}", player.id), player.name.green(), keys.join(", "));
+                }
+                println!("{} players", players.len());
+            }
+            Err(e) => println!("listing players failed: {}", e),
+        },
+        PlayersCommand::Merge { into, from } => {
+            let into = match resolve(into) {
+                Some(player) => player,
+                None => return,
+            };
+            for spec in from.iter() {
+                let other = match resolve(spec) {
+                    Some(player) => player,
+                    None => return,
+                };
+                match Player::merge(&db, into.id, other.id) {
+                    Ok(_) => println!("merged #{} {} into #{} {}", other.id, other.name, into.id, into.name),
+                    Err(e) => println!("merge failed: {}", e),
+                }
+            }
+        }
+        PlayersCommand::Split { alias } => {
+            let (source, name) = split_alias_key(alias).unwrap_or((String::new(), alias.as_str()));
+            match PlayerAlias::get(&db, &source, name) {
+                Ok(Some(alias)) => match Player::split(&db, &alias) {
+                    Ok(player) => println!("{} is now player #{}", alias.key(), player.id),
+                    Err(e) => println!("split failed: {}", e),
+                },
+                Ok(None) => println!("no alias {} (aliases from online sites are written source:name)", alias.red()),
+                Err(e) => println!("split failed: {}", e),
+            }
+        }
+        PlayersCommand::Sync => match Player::sync(&db) {
+            Ok(added) => println!("{} new aliases", added),
+            Err(e) => println!("sync failed: {}", e),
+        },
+    }
+}
+
+fn pawn_structures(config: &RustyConfig, args: &PawnsArgs) {
+    let db_path = config.db_path();
+    let db = Db::new(&db_path);
+    db.init_schema();
+
+    if args.rebuild {
+        if let Err(e) = rebuild_pawns(&db) {
+            println!("indexing pawn structures failed: {}", e);
+            return;
+        }
+    }
+
+    let mut structures = Vec::<PawnStructure>::new();
+    let res = match args.structure.as_deref() {
+        None => PawnStructure::for_each(&db, |ps| {
+            if structures.len() < args.limit {
+                structures.push(ps)
+            }
+        }),
+        Some(name) => match pawns::preset(name) {
+            Some(preset) => {
+                let queries = preset.queries();
+                PawnStructure::for_each(&db, |ps| {
+                    let pos = pawns::from_bitboards(ps.white, ps.black);
+                    if queries.iter().any(|q| q.matches(&pos)) {
+                        structures.push(ps);
+                    }
+                })
+            }
+            None => match pawns::parse_structure(name) {
+                Ok(pos) => {
+                    let (white, black) = pos.pawn_bitboards();
+                    structures.push(PawnStructure { pawn_key: pos.pawn_key(), white, black, games: 0, positions: 0 });
+                    Ok(())
+                }
+                Err(why) => {
+                    println!("invalid pawn structure: {}", why.red());
+                    return;
+                }
+            },
+        },
+    };
+    if let Err(e) = res {
+        println!("reading pawn structures failed: {}", e);
+        return;
+    }
+
+    let structure = match args.structure.as_deref() {
+        Some(structure) => structure,
+        None => {
+            if structures.is_empty() {
+                println!("no pawn structures indexed (try --rebuild)");
+            }
+            for ps in structures.iter() {
+                let board = pawns::from_bitboards(ps.white, ps.black).to_board_fen();
+                println!("{: >8} games {: >10} positions  {}", ps.games, ps.positions, board.green());
+            }
+            return;
+        }
+    };
+
+    // a game can pass through several of a preset's structures, it is listed at the first
+    let mut games = BTreeMap::<i64, GamePawns>::new();
+    for ps in structures.iter() {
+        match GamePawns::games_for_key(&db, ps.pawn_key) {
+            Ok(found) => {
+                for g in found {
+                    match games.get(&g.game_id) {
+                        Some(seen) if seen.ply <= g.ply => {}
+                        _ => {
+                            games.insert(g.game_id, g);
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                println!("pawn structure lookup failed: {}", e);
+                return;
+            }
+        }
+    }
+
+    let mut results = BTreeMap::<&str, usize>::new();
+    for g in games.values() {
+        *results.entry(g.result.as_deref().unwrap_or("*")).or_default() += 1;
+    }
+    for g in games.values().take(args.limit) {
+        println!(
+            "game {: >8}  ply {: >3}  {} - {}  {}",
+            g.game_id, g.ply,
+            g.white.as_deref().unwrap_or("?"),
+            g.black.as_deref().unwrap_or("?"),
+            g.result.as_deref().unwrap_or("*"));
+    }
+    let results: Vec<String> = results.iter().map(|(result, count)| format!("{} {}", result, count)).collect();
+    let structure = match pawns::preset(structure) {
+        Some(preset) => format!("{} ({})", preset.name, preset.description),
+        None => structure.to_string(),
+    };
+    println!("{} games in {} structures matching {}: {}", games.len(), structures.len(), structure, results.join(", "));
+}
+
+// the pawn structures of every game, replayed from the stored pgn
+fn rebuild_pawns(db: &Db) -> Result<(), Box<dyn std::error::Error>> {
+    let start = Instant::now();
+    PawnStructure::clear(db)?;
+    let mut counts = HashMap::<u64, PawnStructure>::new();
+    let mut game_pawns = Vec::<(i64, Vec<(u16, u64)>)>::new();
+    Game::for_each_pgn(db, |id, pgn| {
+        if let Some(gv) = game_for_pgn(pgn) {
+            game_pawns.push((id, pawns::game_structures(&gv.start_position(), &gv.fens, &mut counts)));
+        }
+    })?;
+    GamePawns::bulk_insert(db, &game_pawns)?;
+    PawnStructure::bulk_add(db, &counts.into_values().collect::<Vec<_>>())?;
+    println!("indexed the pawn structures of {} games in {:?}", game_pawns.len(), start.elapsed());
+    Ok(())
+}
+
+// games with a stored pawn structure matching the pawn terms, with the first ply they do;
+// None when some games have not had their structures indexed and all need replaying
+fn pattern_pawn_candidates(db: &Db, pawn_query: &PatternQuery) -> Result<Option<HashMap<i64, u16>>, rusqlite::Error> {
+    if GamePawns::unindexed_games(db)? > 0 {
+        return Ok(None);
+    }
+    let mut keys = Vec::new();
+    PawnStructure::for_each(db, |ps| {
+        if pawn_query.matches(&pawns::from_bitboards(ps.white, ps.black)) {
+            keys.push(ps.pawn_key);
+        }
+    })?;
+    let mut games = HashMap::<i64, u16>::new();
+    for key in keys {
+        for g in GamePawns::games_for_key(db, key)? {
+            let ply = games.entry(g.game_id).or_insert(g.ply);
+            *ply = (*ply).min(g.ply);
+        }
+    }
+    Ok(Some(games))
+}
+
+fn pattern(config: &RustyConfig, args: &PatternArgs) {
+    let query = match PatternQuery::parse(&args.query) {
+        Ok(query) => query,
+        Err(why) => {
+            println!("invalid pattern: {}", why.red());
+            return;
+        }
+    };
+
+    let db_path = config.db_path();
+    let db = Db::new(&db_path);
+    db.init_schema();
+
+    // with a material term only games that ever had that material need replaying
+    let mut candidates: Option<HashMap<i64, u16>> = match query.material() {
+        Some(sig) => match GameMaterial::games_for_signature(&db, sig.to_u64()) {
+            Ok(games) => Some(games.iter().map(|g| (g.game_id, g.ply)).collect()),
+            Err(e) => {
+                println!("material lookup failed: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+    // likewise with pawn terms: only games that had a pawn structure satisfying them
+    if let Some(pawn_query) = query.pawn_terms() {
+        match pattern_pawn_candidates(&db, &pawn_query) {
+            Ok(Some(games)) => {
+                candidates = Some(match candidates {
+                    Some(material) => material
+                        .into_iter()
+                        .filter_map(|(id, ply)| games.get(&id).map(|&pawn_ply| (id, ply.max(pawn_ply))))
+                        .collect(),
+                    None => games,
+                })
+            }
+            Ok(None) => (),
+            Err(e) => {
+                println!("pawn structure lookup failed: {}", e);
+                return;
+            }
+        }
+    }
+
+    let mut matches = 0;
+    let res = Game::for_each_pgn(&db, |id, pgn| {
+        // nothing can match before the material first shows up
+        let from_ply = match candidates.as_ref().map(|c| c.get(&id)) {
+            Some(Some(&ply)) => ply as usize,
+            Some(None) => return,
+            None => 0,
+        };
+        let gv = match game_for_pgn(pgn) {
+            Some(gv) => gv,
+            None => return,
+        };
+        if let Some(ply) = query.first_match(&gv.start_position(), &gv.fens, from_ply) {
+            matches += 1;
+            if matches <= args.limit {
+                println!(
+                    "game {: >8}  ply {: >3}  {} - {}  {}",
+                    id, ply,
+                    gv.game.white.as_deref().unwrap_or("?"),
+                    gv.game.black.as_deref().unwrap_or("?"),
+                    gv.game.result.as_deref().unwrap_or("*"));
+            }
+        }
+    });
+    match res {
+        Ok(_) => println!("{} games match", matches),
+        Err(e) => println!("reading games failed: {}", e),
+    }
+}
+
+fn position(config: &RustyConfig, args: &PositionArgs, store: StoreKind) {
+    let variant = match variants::parse_variant(&args.variant) {
+        Some((variant, _)) => variant,
+        None => {
+            println!("unknown variant '{}'", args.variant.red());
+            return;
+        }
+    };
+    let pos = match variants::position_from_fen(&args.fen, variant, CastlingMode::Standard) {
+        Ok(pos) => BitPosition::from_variant(&pos),
+        Err(why) => {
+            println!("invalid fen '{}': {}", args.fen.red(), why);
+            return;
+        }
+    };
+    let zobrist = pos.zobrist();
+    let (r12, r34, r56, r78) = pos.to_bits();
+
+    let db_path = config.db_path();
+    let db = Db::new(&db_path);
+    db.init_schema();
+    let exploration = match explorer::explore(&db, &args.fen, variant) {
+        Ok(exploration) => exploration,
+        Err(e) => {
+            println!("explorer failed: {}", e);
+            return;
+        }
+    };
+    if args.json {
+        match serde_json::to_string_pretty(&exploration) {
+            Ok(json) => println!("{}", json),
+            Err(e) => println!("{}", e),
+        }
+        return;
+    }
+
+    println!("position {}", pos.to_board_fen().green());
+    println!("  zobrist {:#018x}", zobrist);
+
+    // games are indexed by key only; when other boards share it they are told apart by replaying
+    let mut collides = false;
+    match open_store(store, &config.data_dir, &db) {
+        Ok(mut positions) => match positions.lookup(zobrist) {
+            Ok(candidates) => {
+                let found = candidates.iter().any(|p| p.same_board(r12, r34, r56, r78));
+                println!("  {}: {}", positions.name(), if found { "found" } else { "not found" });
+                if candidates.len() > found as usize {
+                    println!("    {} positions with a colliding key", candidates.len() - found as usize);
+                    collides = true;
+                }
+            }
+            Err(e) => println!("  {}: lookup failed: {}", positions.name(), e),
+        },
+        Err(e) => println!("  opening position store failed: {}", e),
+    }
+
+    let games = if collides {
+        GamePositionKey::games_for_board(&db, zobrist, (r12, r34, r56, r78))
+    } else {
+        GamePositionKey::games_for_zobrist(&db, zobrist)
+    };
+    match games {
+        Ok(games) => {
+            println!("  games {}", games.len());
+            for key in games.iter().take(args.limit) {
+                match Game::query_by_id(&db, key.game_id) {
+                    Ok(Some(game)) => println!(
+                        "    game {} at ply {}: {} - {} {}",
+                        key.game_id, key.ply,
+                        game.white.as_deref().unwrap_or("?"),
+                        game.black.as_deref().unwrap_or("?"),
+                        game.result.as_deref().unwrap_or("*")),
+                    Ok(None) => println!("    game {} at ply {}", key.game_id, key.ply),
+                    Err(e) => println!("    game {}: {}", key.game_id, e),
+                }
+            }
+        }
+        Err(e) => println!("  game lookup failed: {}", e),
+    }
+    match GamePositionKey::variation_games_for_zobrist(&db, zobrist) {
+        Ok(games) if !games.is_empty() => {
+            println!("  games with it in a variation {}", games.len());
+            for key in games.iter().take(args.limit) {
+                println!("    game {} at ply {} (variation)", key.game_id, key.ply);
+            }
+        }
+        Ok(_) => (),
+        Err(e) => println!("  variation lookup failed: {}", e),
+    }
+
+    println!("  {: <8} {: >8} {: >7}   book", "move", "games", "score");
+    for m in exploration.moves.iter() {
+        let score = m
+            .score(exploration.white_to_move)
+            .map_or("-".to_string(), |s| format!("{:.1}%", 100.0 * s));
+        let book = m
+            .book
+            .iter()
+            .map(|b| format!("{} {}", b.source, b.weight))
+            .collect::<Vec<_>>()
+            .join(", ");
+        println!("  {: <8} {: >8} {: >7}   {}", m.san, m.games, score, book);
+    }
+}
+
+fn import(config: &RustyConfig, pgn_paths: &[PathBuf], variations: bool, store: StoreKind) {
+    for path in pgn_paths.iter() {
         println!("called with arg : {}", path.display().to_string().green());
     };
 
@@ -105,39 +1876,127 @@ fn main() {
     let mut duplicate_games: i64 = 0;
     let start_time = Instant::now();
 
-    let dbpath = Path::new("data.db");
-    let db = Db::new(dbpath);
+    let db_path = config.db_path();
+    let db = Db::new(&db_path);
     db.init_schema();
+    if let Err(e) = db.bulk_mode() {
+        println!("bulk mode: {}", e);
+    }
 
-    let mut segment = PositionSegment::new("segment1.db");
+    let mut store = match open_store(store, &config.data_dir, &db) {
+        Ok(store) => store,
+        Err(e) => {
+            println!("opening position store failed: {}", e);
+            return;
+        }
+    };
 
-    let mut readers = Vec::<BufferedReader<File>>::new();
+    let mut files = Vec::<PathBuf>::new();
 
-    for path in args.pgn_paths.iter() {
-        readers.append(&mut create_readers_for_dir(path).unwrap());
+    for path in pgn_paths.iter() {
+        files.append(&mut game_files(path).unwrap());
     }
 
     //let pool = ThreadPool::new(num_cpus::get());
 
-    for reader in readers {
-        let game_visitors = games_for_buffs(reader);
+    for file in files {
+        let game_visitors = match games_for_file(&file, variations) {
+            Ok(game_visitors) => game_visitors,
+            Err(e) => {
+                println!("skipping {}: {}", file.display(), e);
+                continue;
+            }
+        };
         //let foo: Vec<&Game> = game_visitors.iter().map(|gv| &gv.game).collect();
-        match Game::bulk_insert(&db, game_visitors.iter().map(|gv| &gv.game).collect()) {
-            Ok(_) => (),
-            Err(e) => println!("bulk_insert: {}", e),
+        let game_ids = match Game::bulk_insert(&db, game_visitors.iter().map(|gv| &gv.game).collect()) {
+            Ok(ids) => ids,
+            Err(e) => {
+                println!("bulk_insert: {}", e);
+                vec![None; game_visitors.len()]
+            }
         };
-        for gv in game_visitors {
+        let mut game_keys = Vec::<(i64, Vec<u64>)>::with_capacity(game_visitors.len());
+        let mut game_materials = Vec::<(i64, Vec<(u16, u64)>)>::with_capacity(game_visitors.len());
+        let mut game_pawns = Vec::<(i64, Vec<(u16, u64)>)>::with_capacity(game_visitors.len());
+        let mut pawn_counts = HashMap::<u64, PawnStructure>::new();
+        let mut texts = Vec::<GameText>::with_capacity(game_visitors.len());
+        let mut annotated = Vec::<GameAnnotations>::new();
+        let mut trees = Vec::<GameTree>::new();
+        let mut variation_keys = Vec::<(i64, Vec<(u16, u64)>)>::new();
+        let mut batch = Vec::<StoredPosition>::new();
+        for (gv, game_id) in game_visitors.iter().zip(game_ids) {
             /*
             let insert_result = Game::insert(&db, &gv.game);
             if insert_result.is_err() {
                 continue;
             } */
             game_count += 1;
-            for fen in gv.fens.iter() {
+            let start = gv.start_position();
+            let mut keys = Vec::<u64>::with_capacity(gv.fens.len() + 1);
+            keys.push(start.zobrist());
+            let mut materials = vec![(0u16, start.material().to_u64())];
+            for (ply, fen) in gv.fens.iter().enumerate() {
                 positions_parsed += 1;
+                let zobrist = fen.zobrist();
+                keys.push(zobrist);
+                let material = fen.material().to_u64();
+                if materials.last().is_none_or(|(_, m)| *m != material) {
+                    materials.push(((ply + 1) as u16, material));
+                }
                 let (r12,r34,r56, r78) = fen.to_bits();
-                segment.insert(r12, r34, r56, r78);
+                batch.push(StoredPosition { zobrist, r12, r34, r56, r78 });
             }
+            match game_id {
+                Some(id) => {
+                    game_keys.push((id, keys));
+                    game_materials.push((id, materials));
+                    game_pawns.push((id, pawns::game_structures(&start, &gv.fens, &mut pawn_counts)));
+                    texts.push(gv.text(id));
+                    if gv.plies.iter().any(|p| p.clock.is_some() || p.eval.is_some()) {
+                        annotated.push(GameAnnotations { game_id: id, plies: gv.annotations() });
+                    }
+                    if let Some(tree) = gv.tree.as_ref() {
+                        trees.push(GameTree { game_id: id, tree: tree.tree() });
+                        if !tree.variation_keys.is_empty() {
+                            variation_keys.push((id, tree.variation_keys.clone()));
+                        }
+                    }
+                }
+                None => duplicate_games += 1,
+            }
+        }
+        if let Err(e) = store.insert_batch(&batch) {
+            println!("position insert: {}", e);
+        }
+        if let Err(e) = GamePositionKey::bulk_insert(&db, &game_keys) {
+            println!("game position keys insert: {}", e);
+        }
+        if let Err(e) = GameMaterial::bulk_insert(&db, &game_materials) {
+            println!("game material insert: {}", e);
+        }
+        if let Err(e) = GamePawns::bulk_insert(&db, &game_pawns) {
+            println!("game pawns insert: {}", e);
+        }
+        if let Err(e) = PawnStructure::bulk_add(&db, &pawn_counts.into_values().collect::<Vec<_>>()) {
+            println!("pawn structure insert: {}", e);
+        }
+        if let Err(e) = GameText::bulk_insert(&db, &texts) {
+            println!("game text insert: {}", e);
+        }
+        if let Err(e) = GameAnnotations::bulk_insert(&db, &annotated) {
+            println!("game annotations insert: {}", e);
+        }
+        if let Err(e) = GameTree::bulk_insert(&db, &trees) {
+            println!("game tree insert: {}", e);
+        }
+        if let Err(e) = GamePositionKey::bulk_insert_variations(&db, &variation_keys) {
+            println!("variation position keys insert: {}", e);
+        }
+        let names = game_visitors.iter().flat_map(|gv| {
+            [&gv.game.white, &gv.game.black].into_iter().flatten().map(|name| (name.as_str(), gv.game.site.as_str()))
+        });
+        if let Err(e) = Player::register(&db, names) {
+            println!("player insert: {}", e);
         }
     };
 
@@ -145,11 +2004,14 @@ fn main() {
     let games_per_sec = game_count as f64 / duration;
 
     println!(
-        "games {: >6}\n  positions parsed {}\n    duration {: >6.2} sec, {:.2} games/s\n    positions {}",
-        game_count, positions_parsed, duration, games_per_sec, positions_parsed);
+        "games {: >6}\n  positions parsed {}\n    duration {: >6.2} sec, {:.2} games/s\n    positions {}\n    duplicate games {}",
+        game_count, positions_parsed, duration, games_per_sec, positions_parsed, duplicate_games);
 
-    println!("writing segment file, {} positions", segment.len());
-    segment.write();
+    println!("flushing {}", store.name());
+    match store.flush() {
+        Ok(written) => println!("  {} new positions", written),
+        Err(e) => println!("flushing positions failed: {}", e),
+    }
     println!("finished");
 }
 
@@ -193,7 +2055,7 @@ fn main_old() {
 
     let ptrie = PositionTrie::new();
 
-    let mut segment = PositionSegment::new("segment1.db");
+    let mut segment = PositionSegment::new(Path::new("segment1.db"));
 
     for visit in reader.into_iter(visitor) {
         // play through each move in the pgn and generate a BitPosition for each position reached
@@ -235,7 +2097,7 @@ fn main_old() {
 
             //println!("Inserting position {} {} {} {} ({}, {}, {}, {})", *r12id, *r34id, *r56id, *r78id, r12, r34, r56, r78);
             //positions.insert((r12id, r34id, r56id, r78id));
-            segment.insert(r12, r34, r56, r78);
+            segment.insert(gv.zobrist(), r12, r34, r56, r78);
             pos_id += 1;
         }
 
@@ -0,0 +1,336 @@
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::persistance::{
+    read_block_header, Position, PositionSegment, SegmentFile, SEGMENT_RECORD_SIZE,
+};
+
+/*
+Dataset manifest
+
+manifest.json in the data directory lists the segment files that make up the
+dataset, so everything that reads positions works on "the dataset" instead of
+on a hand-picked file:
+
+    {
+      "version": 1,
+      "dataset_id": 1234,
+      "next_segment_id": 3,
+      "segments": [
+        { "id": 1, "path": "segments/00000001.seg", "records": 1000,
+          "min_key": 17, "max_key": 18446744073709551000,
+          "checksum": 3735928559, "created": 1700000000 },
+        ...
+      ]
+    }
+
+Segment paths are relative to the data directory. The checksum is a crc32 of
+the whole file and the key range is inclusive. A data directory from before the
+manifest existed gets its segment1.db adopted as segment 1, unless it is from
+before zobrist keys, whose records cannot be converted (see read_block_header).
+ */
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+pub const MANIFEST_VERSION: u8 = 1;
+const SEGMENTS_DIR: &str = "segments";
+const LEGACY_SEGMENT: &str = "segment1.db";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SegmentInfo {
+    pub id: u32,
+    pub path: PathBuf,
+    pub records: u64,
+    pub min_key: u64,
+    pub max_key: u64,
+    pub checksum: u32,
+    pub created: u64, // unix seconds
+}
+
+impl SegmentInfo {
+    pub fn overlaps(&self, from: u64, to: u64) -> bool {
+        self.records > 0 && self.min_key < to && self.max_key >= from
+    }
+}
+
+// what a segment file actually holds, worked out by reading all of it
+#[derive(Debug, PartialEq)]
+pub struct SegmentSummary {
+    pub records: u64,
+    pub min_key: u64,
+    pub max_key: u64,
+    pub checksum: u32,
+}
+
+pub fn summarize_segment(path: &Path) -> Result<SegmentSummary, std::io::Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut summary = SegmentSummary { records: 0, min_key: u64::MAX, max_key: 0, checksum: 0 };
+    let mut record = [0u8; SEGMENT_RECORD_SIZE];
+    while let Some(header) = read_block_header(&mut reader)? {
+        hasher.update(&header.bytes);
+        for _ in 0..header.records {
+            reader.read_exact(&mut record)?;
+            hasher.update(&record);
+            let zobrist = Position::from_record(&record).zobrist;
+            summary.min_key = summary.min_key.min(zobrist);
+            summary.max_key = summary.max_key.max(zobrist);
+        }
+        summary.records += header.records;
+    }
+    summary.checksum = hasher.finalize();
+    if summary.records == 0 {
+        summary.min_key = 0;
+    }
+    Ok(summary)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    pub version: u8,
+    pub dataset_id: u64,
+    pub next_segment_id: u32,
+    pub segments: Vec<SegmentInfo>,
+    #[serde(skip)]
+    data_dir: PathBuf,
+    #[serde(skip)]
+    read_only: bool,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+impl Manifest {
+    pub fn path(data_dir: &Path) -> PathBuf {
+        data_dir.join(MANIFEST_FILE)
+    }
+
+    pub fn load(data_dir: &Path) -> Result<Manifest, Box<dyn Error>> {
+        Manifest::load_with(data_dir, false)
+    }
+
+    // for readers that must leave the data directory as it is (crusty serve): a
+    // segment1.db is adopted in memory only, and nothing is ever saved
+    pub fn load_read_only(data_dir: &Path) -> Result<Manifest, Box<dyn Error>> {
+        Manifest::load_with(data_dir, true)
+    }
+
+    fn load_with(data_dir: &Path, read_only: bool) -> Result<Manifest, Box<dyn Error>> {
+        let path = Manifest::path(data_dir);
+        if path.exists() {
+            let mut manifest: Manifest = serde_json::from_reader(BufReader::new(File::open(&path)?))
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            if manifest.version > MANIFEST_VERSION {
+                return Err(format!("{}: manifest version {} is not supported", path.display(), manifest.version).into());
+            }
+            manifest.data_dir = data_dir.to_path_buf();
+            manifest.read_only = read_only;
+            return Ok(manifest);
+        }
+
+        let mut hasher = DefaultHasher::new();
+        (SystemTime::now(), std::process::id(), data_dir).hash(&mut hasher);
+        let mut manifest = Manifest {
+            version: MANIFEST_VERSION,
+            dataset_id: hasher.finish(),
+            next_segment_id: 1,
+            segments: Vec::new(),
+            data_dir: data_dir.to_path_buf(),
+            read_only,
+        };
+        if data_dir.join(LEGACY_SEGMENT).exists() {
+            manifest
+                .register(PathBuf::from(LEGACY_SEGMENT))
+                .map_err(|e| format!("{}: {}", data_dir.join(LEGACY_SEGMENT).display(), e))?;
+            if !read_only {
+                manifest.save()?;
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        // write next to the real file and rename, so a crash never leaves half a manifest
+        let path = Manifest::path(&self.data_dir);
+        if self.read_only {
+            return Err(format!("{} was loaded read-only", path.display()).into());
+        }
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn segment_path(&self, segment: &SegmentInfo) -> PathBuf {
+        self.data_dir.join(&segment.path)
+    }
+
+    pub fn records(&self) -> u64 {
+        self.segments.iter().map(|s| s.records).sum()
+    }
+
+    // record an existing segment file (path relative to the data directory)
+    fn register(&mut self, path: PathBuf) -> Result<SegmentInfo, Box<dyn Error>> {
+        let summary = summarize_segment(&self.data_dir.join(&path))?;
+        let segment = SegmentInfo {
+            id: self.next_segment_id,
+            path,
+            records: summary.records,
+            min_key: summary.min_key,
+            max_key: summary.max_key,
+            checksum: summary.checksum,
+            created: now(),
+        };
+        self.next_segment_id += 1;
+        self.segments.push(segment.clone());
+        Ok(segment)
+    }
+
+    // write the positions as a new sorted segment and add it to the dataset
+    pub fn add_segment(&mut self, positions: &[Position]) -> Result<SegmentInfo, Box<dyn Error>> {
+        if self.read_only {
+            return Err(format!("{} was loaded read-only", Manifest::path(&self.data_dir).display()).into());
+        }
+        let relative = Path::new(SEGMENTS_DIR).join(format!("{:08}.seg", self.next_segment_id));
+        let path = self.data_dir.join(&relative);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        if path.exists() {
+            // left behind by a run that died before saving the manifest
+            std::fs::remove_file(&path)?;
+        }
+        let mut segment = PositionSegment::new(&path);
+        for p in positions {
+            segment.insert(p.zobrist, p.r12, p.r34, p.r56, p.r78);
+        }
+        segment.write()?;
+        let info = self.register(relative)?;
+        self.save()?;
+        Ok(info)
+    }
+
+    // compare every segment against its file, returns one line per problem found
+    pub fn verify(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for segment in self.segments.iter() {
+            let path = self.segment_path(segment);
+            match summarize_segment(&path) {
+                Ok(summary) => {
+                    let expected = SegmentSummary {
+                        records: segment.records,
+                        min_key: segment.min_key,
+                        max_key: segment.max_key,
+                        checksum: segment.checksum,
+                    };
+                    if summary != expected {
+                        problems.push(format!(
+                            "segment {} ({}): manifest says {:?}, file has {:?}",
+                            segment.id, path.display(), expected, summary));
+                    }
+                }
+                Err(e) => problems.push(format!("segment {} ({}): {}", segment.id, path.display(), e)),
+            }
+        }
+        problems
+    }
+
+    // rewrite all segments as one, dropping duplicate positions; returns the new segment
+    pub fn merge(&mut self) -> Result<Option<SegmentInfo>, Box<dyn Error>> {
+        if self.segments.len() < 2 {
+            return Ok(None);
+        }
+        let mut positions = Vec::with_capacity(self.records() as usize);
+        for segment in self.segments.iter() {
+            SegmentFile::open(&self.segment_path(segment))?.for_each(&mut |p| positions.push(*p))?;
+        }
+        positions.sort_unstable();
+        positions.dedup();
+
+        let old: Vec<SegmentInfo> = self.segments.drain(..).collect();
+        let merged = self.add_segment(&positions)?;
+        for segment in old {
+            std::fs::remove_file(self.segment_path(&segment))?;
+        }
+        Ok(Some(merged))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn test_add_verify_merge() {
+        let temp = TempDir::new("manifest");
+        let dir = temp.path();
+        let position = |zobrist, r| Position { zobrist, r12: r, r34: r, r56: r, r78: r };
+
+        let mut manifest = Manifest::load(dir).unwrap();
+        assert!(manifest.segments.is_empty());
+        let first = manifest.add_segment(&[position(42, 1), position(7, 2)]).unwrap();
+        assert_eq!((first.id, first.records, first.min_key, first.max_key), (1, 2, 7, 42));
+        manifest.add_segment(&[position(42, 1), position(99, 3)]).unwrap();
+
+        // the saved manifest reads back the same
+        let reloaded = Manifest::load(dir).unwrap();
+        assert_eq!(reloaded.dataset_id, manifest.dataset_id);
+        assert_eq!(reloaded.segments, manifest.segments);
+        assert!(reloaded.verify().is_empty());
+
+        let merged = manifest.merge().unwrap().unwrap();
+        assert_eq!((merged.id, merged.records, merged.min_key, merged.max_key), (3, 3, 7, 99));
+        assert_eq!(manifest.segments.len(), 1);
+        assert!(manifest.verify().is_empty());
+
+        std::fs::write(manifest.segment_path(&merged), b"garbage").unwrap();
+        assert_eq!(manifest.verify().len(), 1);
+    }
+
+    #[test]
+    fn test_legacy_segments() {
+        let position = |zobrist, r| Position { zobrist, r12: r, r34: r, r56: r, r78: r };
+
+        // format 1: 8 byte header without a version, same records
+        let v1_dir = TempDir::new("legacy-v1");
+        let dir = v1_dir.path();
+        let mut v1 = vec![0xcc, 0xdd, 0x69, 0x42, 0, 0, 0, 2];
+        v1.extend(position(7, 2).to_record());
+        v1.extend(position(42, 1).to_record());
+        std::fs::write(dir.join(LEGACY_SEGMENT), &v1).unwrap();
+        // read-only, the segment is adopted all the same but manifest.json is not written
+        let mut read_only = Manifest::load_read_only(dir).unwrap();
+        assert_eq!(read_only.segments[0].records, 2);
+        assert!(read_only.save().is_err() && read_only.add_segment(&[position(1, 1)]).is_err());
+        assert!(!Manifest::path(dir).exists() && !dir.join(SEGMENTS_DIR).exists());
+        let manifest = Manifest::load(dir).unwrap();
+        assert_eq!((manifest.segments[0].records, manifest.segments[0].max_key), (2, 42));
+        assert!(manifest.verify().is_empty());
+        let mut file = SegmentFile::open(&manifest.segment_path(&manifest.segments[0])).unwrap();
+        assert!(file.lookup(42).unwrap()[0].1.same_board(1, 1, 1, 1));
+
+        // before zobrist keys: refused with a way out, and nothing is written
+        let v0_dir = TempDir::new("legacy-v0");
+        let dir = v0_dir.path();
+        let mut v0 = vec![0x01, 0x02, 0x04, 0x08, 0, 0, 0, 1];
+        v0.extend([0u8; 32]);
+        std::fs::write(dir.join(LEGACY_SEGMENT), &v0).unwrap();
+        let error = Manifest::load(dir).unwrap_err().to_string();
+        assert!(error.contains("segment1.db") && error.contains("import the games again"), "{}", error);
+        assert!(!Manifest::path(dir).exists());
+    }
+}
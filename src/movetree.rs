@@ -0,0 +1,215 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::parsing::{BitPosition, GameVisitor, PositionKey};
+
+/*
+Transposition-aware move tree
+
+Starting from a root position, follow every game that reaches it for up to
+max_depth plies and record the positions and moves seen along the way.
+
+Nodes are keyed by (BitPosition, depth below root), so different move orders
+that transpose into the same position at the same depth share a node. Every
+edge goes from depth d to depth d + 1, which keeps the graph acyclic even when
+a game shuffles pieces back and forth. (BitPosition carries no side to move;
+for a fixed root the depth pins that down as well.)
+ */
+
+#[derive(Serialize)]
+pub struct MoveGraphNode {
+    pub id: usize,
+    pub fen: String,
+    pub depth: usize,
+    pub games: u64,
+}
+
+#[derive(Serialize)]
+pub struct MoveGraphEdge {
+    pub from: usize,
+    pub to: usize,
+    pub san: String,
+    pub count: u64,
+}
+
+#[derive(Serialize)]
+pub struct MoveGraphExport<'a> {
+    pub root: &'a str,
+    pub max_depth: usize,
+    pub games: u64,
+    pub nodes: &'a Vec<MoveGraphNode>,
+    pub edges: Vec<&'a MoveGraphEdge>,
+}
+
+pub struct MoveGraph {
+    root: PositionKey,
+    root_fen: String,
+    max_depth: usize,
+    games: u64,
+    nodes: Vec<MoveGraphNode>,
+    node_ids: HashMap<(PositionKey, usize), usize>,
+    edges: BTreeMap<(usize, usize), MoveGraphEdge>,
+}
+
+impl MoveGraph {
+    pub fn new(root: &BitPosition, max_depth: usize) -> Self {
+        let mut graph = MoveGraph {
+            root: root.key(),
+            root_fen: root.to_board_fen(),
+            max_depth,
+            games: 0,
+            nodes: Vec::new(),
+            node_ids: HashMap::new(),
+            edges: BTreeMap::new(),
+        };
+        graph.node_id(root, 0);
+        graph
+    }
+
+    pub fn games(&self) -> u64 {
+        self.games
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.edges.len()
+    }
+
+    fn node_id(&mut self, pos: &BitPosition, depth: usize) -> usize {
+        let key = (pos.key(), depth);
+        if let Some(&id) = self.node_ids.get(&key) {
+            return id;
+        }
+        let id = self.nodes.len();
+        self.nodes.push(MoveGraphNode {
+            id,
+            fen: pos.to_board_fen(),
+            depth,
+            games: 0,
+        });
+        self.node_ids.insert(key, id);
+        id
+    }
+
+    // Adds the part of a game that follows the first occurrence of the root position.
+    // Returns false when the game never reaches the root.
+    pub fn add_game(&mut self, gv: &GameVisitor) -> bool {
+        let start = gv.start_position();
+        let positions: Vec<&BitPosition> = std::iter::once(&start).chain(gv.fens.iter()).collect();
+
+        let root_ply = match positions.iter().position(|p| p.key() == self.root) {
+            Some(ply) => ply,
+            None => return false,
+        };
+
+        self.games += 1;
+        let mut from = self.node_id(positions[root_ply], 0);
+        self.nodes[from].games += 1;
+
+        for depth in 1..=self.max_depth {
+            let ply = root_ply + depth;
+            if ply >= positions.len() {
+                break;
+            }
+            let to = self.node_id(positions[ply], depth);
+            self.nodes[to].games += 1;
+
+            let san = gv.sans[ply - 1].to_string();
+            self.edges
+                .entry((from, to))
+                .or_insert(MoveGraphEdge { from, to, san, count: 0 })
+                .count += 1;
+            from = to;
+        }
+        true
+    }
+
+    pub fn export(&self) -> MoveGraphExport<'_> {
+        MoveGraphExport {
+            root: &self.root_fen,
+            max_depth: self.max_depth,
+            games: self.games,
+            nodes: &self.nodes,
+            edges: self.edges.values().collect(),
+        }
+    }
+
+    pub fn write_json<W: Write>(&self, out: W) -> serde_json::Result<()> {
+        serde_json::to_writer_pretty(out, &self.export())
+    }
+
+    pub fn write_dot<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        writeln!(out, "digraph movetree {{")?;
+        writeln!(out, "  node [shape=box, fontname=monospace];")?;
+        for node in self.nodes.iter() {
+            writeln!(
+                out,
+                "  n{} [label=\"{}\\n{} games\"];",
+                node.id, node.fen, node.games
+            )?;
+        }
+        // scale pen width by frequency relative to the busiest edge
+        let max_count = self.edges.values().map(|e| e.count).max().unwrap_or(1) as f64;
+        for edge in self.edges.values() {
+            writeln!(
+                out,
+                "  n{} -> n{} [label=\"{} ({})\", penwidth={:.2}];",
+                edge.from,
+                edge.to,
+                edge.san,
+                edge.count,
+                1.0 + 4.0 * edge.count as f64 / max_count
+            )?;
+        }
+        writeln!(out, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::game_for_pgn;
+    use shakmaty::Chess;
+
+    #[test]
+    fn test_transpositions_share_a_node() {
+        let start = BitPosition::from_chess(&Chess::default());
+        let mut graph = MoveGraph::new(&start, 4);
+
+        for pgn in [
+            "1. e4 e5 2. Nf3 Nc6 3. Bb5 *",
+            "1. Nf3 Nc6 2. e4 e5 3. Bc4 *",
+            "1. d4 d5 *",
+        ] {
+            let gv = game_for_pgn(pgn).unwrap();
+            assert!(graph.add_game(&gv));
+        }
+
+        assert_eq!(graph.games(), 3);
+        // root, e4/e5/Nf3/Nc6 line (4), Nf3/Nc6/e4/e5 line (3 new, merged at depth 4), d4/d5 (2)
+        assert_eq!(graph.node_count(), 1 + 4 + 3 + 2);
+        assert_eq!(graph.edge_count(), 4 + 4 + 2);
+
+        let export = graph.export();
+        let merged: Vec<_> = export.nodes.iter().filter(|n| n.depth == 4).collect();
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].games, 2);
+    }
+
+    #[test]
+    fn test_root_not_reached() {
+        let root = BitPosition::parse_from_str(
+            "rnbqkbnr/pp1ppppp/8/2p5/4P3/8/PPPP1PPP/RNBQKBNR w KQkq c6 0 2",
+        )
+        .unwrap();
+        let mut graph = MoveGraph::new(&root, 2);
+        assert!(!graph.add_game(&game_for_pgn("1. d4 d5 *").unwrap()));
+        assert!(graph.add_game(&game_for_pgn("1. e4 c5 2. Nf3 d6 *").unwrap()));
+        assert_eq!(graph.node_count(), 3);
+    }
+}
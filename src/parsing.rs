@@ -1,51 +1,109 @@
 use bilge::arbitrary_int::Number;
-use serde::{Deserialize, Serialize};
-use std::{borrow::Borrow, collections::HashMap, hash::{DefaultHasher, Hash, Hasher}, path::PathBuf};
-
-#[derive(Serialize, Deserialize, Debug, Default)]
-pub struct RustyConfig {
-    pub version: u8,
-    pub data_dir: PathBuf,
-    pub config_dir: PathBuf,
-}
+use std::{borrow::Borrow, collections::HashMap, hash::{DefaultHasher, Hash, Hasher}};
 
 use bilge::prelude::*;
 
 use once_cell::sync::Lazy;
 
-use pgn_reader::{RawHeader, SanPlus, Nag, RawComment, Skip, Visitor};
+use pgn_reader::{BufferedReader, RawHeader, SanPlus, Nag, RawComment, Skip, Visitor};
 
-use shakmaty::{san::Suffix, Chess, Position, Role};
+use shakmaty::{
+    san::Suffix,
+    variant::{Variant, VariantPosition},
+    zobrist::{Zobrist64, ZobristValue},
+    Bitboard, Board, CastlingSide, Chess, Color, EnPassantMode, File, Piece, Position, RemainingChecks, Role, Square,
+};
 
-use crate::db::Game;
+use crate::annotations::{self, PlyAnnotation, TimeControl};
+use crate::db::{Game, GameText};
+use crate::eco;
+use crate::variants;
+use crate::variations::TreeBuilder;
 
 pub struct GameVisitor {
-    pub pos: Chess,
+    pub pos: VariantPosition,
+    pub start: VariantPosition, // from the Variant and FEN headers, see variants.rs
+    setup_fen: Option<String>,
     pub fens: Vec<BitPosition>,
+    pub sans: Vec<SanPlus>, // mainline moves, sans[i] is the move that produced fens[i]
+    pub comments: Vec<String>, // mainline comment text, without the braces and [%...] commands
+    pub plies: Vec<PlyAnnotation>, // %clk / %eval per mainline move, plies[i] belongs to sans[i]
     pub game: Game,
     pub move_count: u8,
     pub side_to_move: Side,
     pub pgn_bytes: Vec::<u8>,
+    pub tree: Option<TreeBuilder>, // the full move tree, when reading variations
 }
 
 impl GameVisitor {
     pub fn new() -> GameVisitor {
         GameVisitor {
-            pos: Chess::default(),
+            pos: VariantPosition::new(Variant::Chess),
+            start: VariantPosition::new(Variant::Chess),
+            setup_fen: None,
             fens: Vec::new(),
+            sans: Vec::new(),
+            comments: Vec::new(),
+            plies: Vec::new(),
             game: Game::new(),
             move_count: 0,
             side_to_move: Side::White,
             pgn_bytes: Vec::new(),
+            tree: None,
         }
     }
+
+    // also build the move tree with variations, NAGs and comments
+    pub fn with_variations() -> GameVisitor {
+        GameVisitor { tree: Some(TreeBuilder::new()), ..GameVisitor::new() }
+    }
+
+    // everything but the tree follows the mainline only
+    fn in_variation(&self) -> bool {
+        self.tree.as_ref().is_some_and(|tree| tree.in_variation())
+    }
+
+    // what goes into the full-text index for this game
+    pub fn text(&self, game_id: i64) -> GameText {
+        GameText {
+            game_id,
+            comments: self.comments.join(" "),
+            event: self.game.event.clone(),
+            site: self.game.site.clone(),
+            opening: self.game.opening.clone(),
+        }
+    }
+
+    // the position before the first move, fens[0] is the one after it
+    pub fn start_position(&self) -> BitPosition {
+        BitPosition::from_variant(&self.start)
+    }
+
+    // the per-ply annotations with the time spent on each move filled in
+    pub fn annotations(&self) -> Vec<PlyAnnotation> {
+        let mut plies = self.plies.clone();
+        annotations::fill_spent(&mut plies, self.game.time_control.as_deref().and_then(TimeControl::parse));
+        plies
+    }
 }
 
 impl Visitor for GameVisitor {
     type Result = GameVisitor;
 
     fn begin_variation(&mut self) -> Skip {
-        Skip(true) // stay in the mainline
+        match self.tree.as_mut() {
+            Some(tree) => {
+                tree.begin_variation();
+                Skip(false)
+            }
+            None => Skip(true), // stay in the mainline
+        }
+    }
+
+    fn end_variation(&mut self) {
+        if let Some(tree) = self.tree.as_mut() {
+            tree.end_variation();
+        }
     }
 
     fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
@@ -102,7 +160,7 @@ impl Visitor for GameVisitor {
             Ok(HEADER_ANNOTATOR) => (),
             Ok(HEADER_BLACK_TITLE) => (),
             Ok(HEADER_WHITE_TITLE) => (),
-            Ok(HEADER_FEN) => (),
+            Ok(HEADER_FEN) => self.setup_fen = Some(value.decode_utf8_lossy().to_string()),
             Ok(HEADER_SETUP) => (),
             Ok(HEADER_CHAPTER_MODE) => (),
             Ok(other) => println!("unknown header key: {}", other),
@@ -110,7 +168,32 @@ impl Visitor for GameVisitor {
         };
     }
 
+    fn end_headers(&mut self) -> Skip {
+        match variants::start_position(self.game.variant.as_deref(), self.setup_fen.as_deref()) {
+            Ok(start) => {
+                if let Some(tree) = self.tree.as_mut() {
+                    tree.start_from(&start);
+                }
+                self.pos = start.clone();
+                self.start = start;
+                Skip(false)
+            }
+            Err(e) => {
+                // replaying the moves as standard chess would index positions that never happened
+                println!("skipping the moves of {} - {}: {}",
+                    self.game.white.as_deref().unwrap_or("?"), self.game.black.as_deref().unwrap_or("?"), e);
+                Skip(true)
+            }
+        }
+    }
+
     fn nag(&mut self, nag: Nag) {
+        if let Some(tree) = self.tree.as_mut() {
+            tree.nag(nag.0);
+        }
+        if self.in_variation() {
+            return;
+        }
         for byte in nag.to_string().as_bytes() {
             self.pgn_bytes.push(*byte);
         }
@@ -118,6 +201,23 @@ impl Visitor for GameVisitor {
     }
 
     fn comment(&mut self, comment: RawComment<'_>) {
+        let raw = String::from_utf8_lossy(comment.as_bytes());
+        if let Some(tree) = self.tree.as_mut() {
+            tree.comment(&raw);
+        }
+        if self.in_variation() {
+            return;
+        }
+        // commands before the first move have no ply to go with
+        if let Some(ply) = self.plies.last_mut() {
+            let (clock, eval) = annotations::parse_commands(&raw);
+            ply.clock = clock.or(ply.clock);
+            ply.eval = eval.or(ply.eval);
+        }
+        let text = annotations::strip_commands(&raw);
+        if !text.is_empty() {
+            self.comments.push(text);
+        }
         self.pgn_bytes.push(OPEN_CURLY_BRACE);
         for byte in comment.as_bytes() {
             self.pgn_bytes.push(*byte);
@@ -127,6 +227,12 @@ impl Visitor for GameVisitor {
     }
 
     fn san(&mut self, san_plus: SanPlus) {
+        if let Some(tree) = self.tree.as_mut() {
+            tree.san(&san_plus);
+        }
+        if self.in_variation() {
+            return;
+        }
         let mut postfix: &str;
         if self.side_to_move == Side::White {
             self.move_count += 1;
@@ -158,62 +264,24 @@ impl Visitor for GameVisitor {
         if let Ok(m) = san_plus.san.to_move(&self.pos) {
             self.pos.play_unchecked(&m);
         }
-        let mut biter = self.pos.board().clone().into_iter();
-
-        let mut bp = BitPosition::new();
-
-        for (square, piece) in biter.by_ref() {
-            let isblack = piece.color.is_black();
-            let i: usize = square.into();
-            bp.board[i] = match piece.role {
-                Role::Bishop => {
-                    if isblack {
-                        &BLACK_BISHOP
-                    } else {
-                        &WHITE_BISHOP
-                    }
-                }
-                Role::Knight => {
-                    if isblack {
-                        &BLACK_KNIGHT
-                    } else {
-                        &WHITE_KNIGHT
-                    }
-                }
-                Role::Rook => {
-                    if isblack {
-                        &BLACK_ROOK
-                    } else {
-                        &WHITE_ROOK
-                    }
-                }
-                Role::King => {
-                    if isblack {
-                        &BLACK_KING
-                    } else {
-                        &WHITE_KING
-                    }
-                }
-                Role::Queen => {
-                    if isblack {
-                        &BLACK_QUEEN
-                    } else {
-                        &WHITE_QUEEN
-                    }
-                }
-                Role::Pawn => {
-                    if isblack {
-                        &BLACK_PAWN
-                    } else {
-                        &WHITE_PAWN
-                    }
-                }
-            };
-        }
-        self.fens.push(bp);
+        self.fens.push(BitPosition::from_variant(&self.pos));
+        self.sans.push(san_plus);
+        self.plies.push(PlyAnnotation::default());
     }
 
     fn end_game(&mut self) -> Self::Result {
+        // headers win; the classification is always recorded so the two can be compared.
+        // variant positions have keys of their own and never classify
+        if let Some(opening) = eco::classify(&self.fens) {
+            let absent = |value: &Option<String>| value.as_deref().is_none_or(|v| v.is_empty() || v == "?");
+            if absent(&self.game.eco) {
+                self.game.eco = Some(opening.eco.clone());
+            }
+            if absent(&self.game.opening) {
+                self.game.opening = Some(opening.name.clone());
+            }
+            self.game.classified_eco = Some(opening.eco.clone());
+        }
         match String::from_utf8(std::mem::take(self.pgn_bytes.as_mut())) {
             Ok(pgnstring) => {
                 let mut hasher = DefaultHasher::new();
@@ -223,10 +291,41 @@ impl Visitor for GameVisitor {
             },
             Err(e) => println!("Error convering pgn_bytes to str: {}", e),
         };
-        std::mem::replace(self, GameVisitor::new())
+        let next = match self.tree {
+            Some(_) => GameVisitor::with_variations(),
+            None => GameVisitor::new(),
+        };
+        std::mem::replace(self, next)
     }
 }
 
+// reads the headers of a stored pgn and skips the moves
+struct SetupFen(Option<String>);
+
+impl Visitor for SetupFen {
+    type Result = Option<String>;
+
+    fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
+        if key == HEADER_FEN.as_bytes() {
+            self.0 = Some(value.decode_utf8_lossy().to_string());
+        }
+    }
+
+    fn end_headers(&mut self) -> Skip {
+        Skip(true)
+    }
+
+    fn end_game(&mut self) -> Option<String> {
+        self.0.take()
+    }
+}
+
+// the FEN header of a pgn (a game stored without its start position), None for the usual start
+pub fn setup_fen(pgn: &str) -> Option<String> {
+    let mut reader = BufferedReader::new_cursor(pgn.as_bytes());
+    reader.read_game(&mut SetupFen(None)).ok().flatten().flatten()
+}
+
 // starting postion
 // rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1
 // ... after 1. e4
@@ -290,9 +389,181 @@ impl PieceInPlay {
     }
 }
 
-#[derive(Debug)]
+// the 256-bit board encoding, as produced by BitPosition::to_bits
+pub type PositionKey = (u64, u64, u64, u64);
+
+#[bitsize(4)]
+#[derive(DebugBits, PartialEq, FromBits, Default, Clone, Copy)]
+pub struct CastlingRights {
+    pub white_king: bool,
+    pub white_queen: bool,
+    pub black_king: bool,
+    pub black_queen: bool,
+}
+
+// Number of each piece per side, indexed [side][piece] in BitPiece order.
+// Packs into 48 bits (4 bits per count) so it can be stored and indexed as an INTEGER.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct MaterialSignature {
+    pub counts: [[u8; 6]; 2],
+}
+
+// display order, strongest first
+const MATERIAL_ORDER: [(BitPiece, char); 6] = [
+    (BitPiece::King, WHITE_KING_C),
+    (BitPiece::Queen, WHITE_QUEEN_C),
+    (BitPiece::Rook, WHITE_ROOK_C),
+    (BitPiece::Bishop, WHITE_BISHOP_C),
+    (BitPiece::Knight, WHITE_KNIGHT_C),
+    (BitPiece::Pawn, WHITE_PAWN_C),
+];
+
+impl MaterialSignature {
+    fn piece_index(piece: &BitPiece) -> Option<usize> {
+        match piece {
+            BitPiece::Rook => Some(0),
+            BitPiece::Knight => Some(1),
+            BitPiece::Bishop => Some(2),
+            BitPiece::Queen => Some(3),
+            BitPiece::King => Some(4),
+            BitPiece::Pawn => Some(5),
+            BitPiece::Empty => None,
+        }
+    }
+
+    fn side_index(side: &Side) -> usize {
+        match side {
+            Side::White => 0,
+            Side::Black => 1,
+        }
+    }
+
+    pub fn to_u64(self) -> u64 {
+        let mut value: u64 = 0;
+        for (shift, count) in self.counts.iter().flatten().enumerate() {
+            value |= ((*count as u64) & 0xf) << (shift * 4);
+        }
+        value
+    }
+
+    // "KRPvKR": white's pieces, 'v', black's pieces; upper or lower case both work
+    pub fn parse(s: &str) -> Result<MaterialSignature, String> {
+        let sides: Vec<&str> = s.split(['v', 'V']).collect();
+        if sides.len() != 2 {
+            return Err(format!("material '{}' should look like KRPvKR", s));
+        }
+        let mut sig = MaterialSignature::default();
+        for (side, pieces) in sides.iter().enumerate() {
+            for c in pieces.chars() {
+                let piece = MATERIAL_ORDER
+                    .iter()
+                    .find(|(_, pc)| *pc == c.to_ascii_uppercase())
+                    .map(|(piece, _)| piece)
+                    .ok_or(format!("unknown piece '{}' in material '{}'", c, s))?;
+                sig.counts[side][MaterialSignature::piece_index(piece).unwrap()] += 1;
+            }
+        }
+        Ok(sig)
+    }
+}
+
+impl std::fmt::Display for MaterialSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for side in 0..2 {
+            if side == 1 {
+                write!(f, "v")?;
+            }
+            for (piece, c) in MATERIAL_ORDER.iter() {
+                let count = self.counts[side][MaterialSignature::piece_index(piece).unwrap()];
+                for _ in 0..count {
+                    write!(f, "{}", c)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// What a position holds beyond board, turn, castling and en passant in the
+// variants (see variants.rs). All zero / standard for ordinary chess, which keeps
+// its zobrist keys the plain polyglot ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct VariantState {
+    pub variant: Variant,
+    pub castling_rooks: u64,       // bitboard of the rooks that can still castle, chess960 files
+    pub pockets: [[u8; 5]; 2],     // crazyhouse, [white, black][pawn, knight, bishop, rook, queen]
+    pub promoted: u64,             // crazyhouse, bitboard of promoted pieces
+    pub remaining_checks: [u8; 2], // three-check, [white, black]
+}
+
+// rooks on a1, h1, a8 and h8, where castling rights are implied by the polyglot keys
+const CORNER_ROOKS: u64 = 1 | 1 << 7 | 1 << 56 | 1 << 63;
+const VARIANT_SEED: u64 = 0x7661_7269_616e_7400;
+const CASTLING_ROOK_SEED: u64 = 0x6361_7374_6c65_0000;
+
+impl VariantState {
+    fn of<P: Position>(pos: &P, variant: Variant) -> VariantState {
+        let mut state = VariantState {
+            variant,
+            castling_rooks: pos.castles().castling_rights().0,
+            promoted: pos.promoted().0,
+            ..Default::default()
+        };
+        if let Some(pockets) = pos.pockets() {
+            for (side, pocket) in [&pockets.white, &pockets.black].into_iter().enumerate() {
+                state.pockets[side] = [pocket.pawn, pocket.knight, pocket.bishop, pocket.rook, pocket.queen];
+            }
+        }
+        if let Some(checks) = pos.remaining_checks() {
+            state.remaining_checks = [u8::from(checks.white), u8::from(checks.black)];
+        }
+        state
+    }
+
+    // xored into the polyglot key: 0 for standard chess. Pockets, promoted pieces and
+    // checks use shakmaty's variant keys, the variant and non-corner castling rooks
+    // keys of our own
+    fn zobrist(&self) -> u64 {
+        let mut z = 0;
+        if self.variant != Variant::Chess {
+            z ^= splitmix64(VARIANT_SEED + self.variant as u64);
+        }
+        for sq in Bitboard(self.castling_rooks & !CORNER_ROOKS) {
+            z ^= splitmix64(CASTLING_ROOK_SEED + u64::from(sq));
+        }
+        for (side, color) in [Color::White, Color::Black].into_iter().enumerate() {
+            for (count, role) in self.pockets[side].iter().zip(Role::ALL) {
+                z ^= Zobrist64::zobrist_for_pocket(color, role, *count).0;
+            }
+            if self.variant == Variant::ThreeCheck {
+                let checks = RemainingChecks::new(self.remaining_checks[side] as u32);
+                z ^= Zobrist64::zobrist_for_remaining_checks(color, checks).0;
+            }
+        }
+        for sq in Bitboard(self.promoted) {
+            z ^= Zobrist64::zobrist_for_promoted(sq).0;
+        }
+        z
+    }
+}
+
+fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// The board is the 256-bit part that goes into segments (see to_bits), the
+// remaining state is only needed to tell positions with identical boards apart,
+// which is what the zobrist key is for.
+#[derive(Debug, Clone)]
 pub struct BitPosition {
     pub board: [&'static Lazy<PieceInPlay>; 64],
+    pub side_to_move: Side,
+    pub castling: CastlingRights,
+    pub ep_file: Option<u8>, // file (0 = a) of the square skipped by a double pawn push
+    pub variant: VariantState,
 }
 
 impl BitPosition {
@@ -307,7 +578,214 @@ impl BitPosition {
                 &EMPTY, &EMPTY, &EMPTY, &EMPTY, &EMPTY, &EMPTY, &EMPTY, &EMPTY, &EMPTY, &EMPTY,
                 &EMPTY, &EMPTY, &EMPTY, &EMPTY,
             ],
+            side_to_move: Side::White,
+            castling: CastlingRights::default(),
+            ep_file: None,
+            variant: VariantState::default(),
+        }
+    }
+
+    pub fn from_chess(pos: &Chess) -> BitPosition {
+        BitPosition::from_position(pos, Variant::Chess)
+    }
+
+    pub fn from_variant(pos: &VariantPosition) -> BitPosition {
+        BitPosition::from_position(pos, pos.variant())
+    }
+
+    fn from_position<P: Position>(pos: &P, variant: Variant) -> BitPosition {
+        let mut bp = BitPosition::from_board(pos.board());
+        bp.side_to_move = if pos.turn().is_white() { Side::White } else { Side::Black };
+        let castles = pos.castles();
+        bp.castling = CastlingRights::new(
+            castles.has(Color::White, CastlingSide::KingSide),
+            castles.has(Color::White, CastlingSide::QueenSide),
+            castles.has(Color::Black, CastlingSide::KingSide),
+            castles.has(Color::Black, CastlingSide::QueenSide),
+        );
+        bp.ep_file = pos.ep_square(EnPassantMode::Always).map(|sq| sq.file() as u8);
+        bp.variant = VariantState::of(pos, variant);
+        bp
+    }
+
+    // 64-bit Zobrist key using the Polyglot random table, so keys match those found in .bin books.
+    pub fn zobrist(&self) -> u64 {
+        let mut z = Zobrist64::default();
+        for (idx, &sq) in self.board.iter().enumerate() {
+            let role = match sq.piece() {
+                BitPiece::Pawn => Role::Pawn,
+                BitPiece::Knight => Role::Knight,
+                BitPiece::Bishop => Role::Bishop,
+                BitPiece::Rook => Role::Rook,
+                BitPiece::Queen => Role::Queen,
+                BitPiece::King => Role::King,
+                BitPiece::Empty => continue,
+            };
+            let color = match sq.side() {
+                Side::White => Color::White,
+                Side::Black => Color::Black,
+            };
+            z ^= Zobrist64::zobrist_for_piece(Square::new(idx as u32), Piece { color, role });
+        }
+
+        if self.castling.white_king() {
+            z ^= Zobrist64::zobrist_for_castling_right(Color::White, CastlingSide::KingSide);
+        }
+        if self.castling.white_queen() {
+            z ^= Zobrist64::zobrist_for_castling_right(Color::White, CastlingSide::QueenSide);
+        }
+        if self.castling.black_king() {
+            z ^= Zobrist64::zobrist_for_castling_right(Color::Black, CastlingSide::KingSide);
+        }
+        if self.castling.black_queen() {
+            z ^= Zobrist64::zobrist_for_castling_right(Color::Black, CastlingSide::QueenSide);
+        }
+
+        // Polyglot only hashes the ep file when a pawn of the side to move could capture
+        if let Some(file) = self.ep_file {
+            let (rank, pawn) = match self.side_to_move {
+                Side::White => (4, &WHITE_PAWN),
+                Side::Black => (3, &BLACK_PAWN),
+            };
+            let capturable = [file as i32 - 1, file as i32 + 1]
+                .iter()
+                .filter(|f| (0..8).contains(*f))
+                .any(|&f| **self.board[rank * 8 + f as usize] == **pawn);
+            if capturable {
+                z ^= Zobrist64::zobrist_for_en_passant_file(File::new(file as u32));
+            }
         }
+
+        if self.side_to_move == Side::White {
+            z ^= Zobrist64::zobrist_for_white_turn();
+        }
+        z.0 ^ self.variant.zobrist()
+    }
+
+    // board index 0 is a1, 63 is h8 (same as shakmaty's Square)
+    pub fn from_board(board: &Board) -> BitPosition {
+        let mut bp = BitPosition::new();
+
+        for (square, piece) in board.clone().into_iter() {
+            let isblack = piece.color.is_black();
+            let i: usize = square.into();
+            bp.board[i] = match piece.role {
+                Role::Bishop => {
+                    if isblack {
+                        &BLACK_BISHOP
+                    } else {
+                        &WHITE_BISHOP
+                    }
+                }
+                Role::Knight => {
+                    if isblack {
+                        &BLACK_KNIGHT
+                    } else {
+                        &WHITE_KNIGHT
+                    }
+                }
+                Role::Rook => {
+                    if isblack {
+                        &BLACK_ROOK
+                    } else {
+                        &WHITE_ROOK
+                    }
+                }
+                Role::King => {
+                    if isblack {
+                        &BLACK_KING
+                    } else {
+                        &WHITE_KING
+                    }
+                }
+                Role::Queen => {
+                    if isblack {
+                        &BLACK_QUEEN
+                    } else {
+                        &WHITE_QUEEN
+                    }
+                }
+                Role::Pawn => {
+                    if isblack {
+                        &BLACK_PAWN
+                    } else {
+                        &WHITE_PAWN
+                    }
+                }
+            };
+        }
+        bp
+    }
+
+    pub fn material(&self) -> MaterialSignature {
+        let mut sig = MaterialSignature::default();
+        for &sq in self.board.iter() {
+            if let Some(piece) = MaterialSignature::piece_index(&sq.piece()) {
+                sig.counts[MaterialSignature::side_index(&sq.side())][piece] += 1;
+            }
+        }
+        sig
+    }
+
+    // the same board with everything but the pawns taken off, white to move, no castling or en passant
+    pub fn pawns(&self) -> BitPosition {
+        let mut pawns = BitPosition::new();
+        for (idx, &sq) in self.board.iter().enumerate() {
+            if sq.piece() == BitPiece::Pawn {
+                pawns.board[idx] = sq;
+            }
+        }
+        pawns
+    }
+
+    // key of the pawn structure alone: positions that differ only in their pieces share it
+    pub fn pawn_key(&self) -> u64 {
+        self.pawns().zobrist()
+    }
+
+    // (white, black) pawn bitboards, a1 = bit 0
+    pub fn pawn_bitboards(&self) -> (u64, u64) {
+        let (mut white, mut black) = (0u64, 0u64);
+        for (idx, &sq) in self.board.iter().enumerate() {
+            if sq.piece() == BitPiece::Pawn {
+                match sq.side() {
+                    Side::White => white |= 1u64 << idx,
+                    Side::Black => black |= 1u64 << idx,
+                }
+            }
+        }
+        (white, black)
+    }
+
+    pub fn key(&self) -> PositionKey {
+        self.to_bits()
+    }
+
+    // piece placement part of a fen, e.g. rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR
+    pub fn to_board_fen(&self) -> String {
+        let mut fen = String::new();
+        for rank in (0..8).rev() {
+            let mut empties = 0;
+            for file in 0..8 {
+                let sq = self.board[rank * 8 + file];
+                if sq.piece() == BitPiece::Empty {
+                    empties += 1;
+                    continue;
+                }
+                if empties > 0 {
+                    fen.push_str(&empties.to_string());
+                    empties = 0;
+                }
+                fen.push(sq.to_char());
+            }
+            if empties > 0 {
+                fen.push_str(&empties.to_string());
+            }
+            if rank > 0 {
+                fen.push('/');
+            }
+        }
+        fen
     }
 
     pub fn parse_from_str(fen: &str) -> Result<BitPosition, &str> {
@@ -322,19 +800,21 @@ impl BitPosition {
 
         let mut pos: BitPosition = BitPosition::new();
 
-        let mut idx = 0;
-        for pieces in position_parts.iter() {
+        // fen lists rank 8 first, our board starts at a1
+        for (row, pieces) in position_parts.iter().enumerate() {
+            let mut file = 0;
             for pc in pieces.chars() {
                 if pc.is_ascii_digit() {
-                    let digit = pc.to_digit(10).unwrap();
-                    if digit < 1 || digit > 8 {
-                        panic!("invalid digit in fen")
-                    }
-                    for _ in 0..digit {
-                        idx += 1;
+                    let digit = pc.to_digit(10).unwrap() as usize;
+                    if !(1..=8).contains(&digit) {
+                        return Err("invalid digit in fen");
                     }
+                    file += digit;
                 } else {
-                    pos.board[idx] = match pc {
+                    if file >= 8 {
+                        return Err("more than 8 squares in a rank");
+                    }
+                    pos.board[(7 - row) * 8 + file] = match pc {
                         WHITE_PAWN_C => &WHITE_PAWN,
                         WHITE_KNIGHT_C => &WHITE_KNIGHT,
                         WHITE_BISHOP_C => &WHITE_BISHOP,
@@ -347,12 +827,32 @@ impl BitPosition {
                         BLACK_QUEEN_C => &BLACK_QUEEN,
                         BLACK_KING_C => &BLACK_KING,
                         BLACK_ROOK_C => &BLACK_ROOK,
-                        other => panic!("error, invalid char in fen: {}", other),
+                        _ => return Err("invalid piece in fen"),
                     };
-                    idx += 1;
+                    file += 1;
                 }
             }
+            if file != 8 {
+                return Err("a rank is not 8 squares");
+            }
         }
+
+        pos.side_to_move = match parts[1] {
+            "w" => Side::White,
+            "b" => Side::Black,
+            _ => return Err("invalid side to move"),
+        };
+        pos.castling = CastlingRights::new(
+            parts[2].contains('K'),
+            parts[2].contains('Q'),
+            parts[2].contains('k'),
+            parts[2].contains('q'),
+        );
+        pos.ep_file = match parts[3].as_bytes() {
+            [b'-'] => None,
+            [file @ b'a'..=b'h', b'3' | b'6'] => Some(file - b'a'),
+            _ => return Err("invalid en passant square"),
+        };
         Ok(pos)
     }
 
@@ -535,3 +1035,50 @@ pub static VAL_TO_PIECE: Lazy<HashMap<u8, &Lazy<PieceInPlay>>> = Lazy::new(|| {
 //
 // 6 + 4 = 10 bits * 32 = 320 bits or less
 //
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::game_for_pgn;
+
+    // reference keys from the Polyglot book format description
+    const POLYGLOT_KEYS: [(&str, u64); 5] = [
+        ("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 0x463b96181691fc9c),
+        ("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1", 0x823c9b50fd114196),
+        ("rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq d6 0 2", 0x0756b94461c50fb0),
+        ("rnbqkbnr/ppp1pppp/8/3pP3/8/8/PPPP1PPP/RNBQKBNR b KQkq - 0 2", 0x662fafb965db29d4),
+        ("rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3", 0x22a48b5a8e47ff78),
+    ];
+
+    #[test]
+    fn test_zobrist_matches_polyglot() {
+        for (fen, key) in POLYGLOT_KEYS {
+            let pos = BitPosition::parse_from_str(fen).unwrap();
+            assert_eq!(pos.zobrist(), key, "{}", fen);
+        }
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_boards() {
+        for fen in [
+            "xnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "rnbqkbnrr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBN1R w KQkq - 0 1",
+            "rnbqkbn/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "rnbqkbnr/pppppppp/9/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "rnbqkbnr/pppppppp/0/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+        ] {
+            assert!(BitPosition::parse_from_str(fen).is_err(), "{}", fen);
+        }
+    }
+
+    #[test]
+    fn test_zobrist_from_visitor_matches_fen() {
+        let gv = game_for_pgn("1. e4 d5 2. e5 f5 *").unwrap();
+        assert_eq!(BitPosition::from_chess(&Chess::default()).zobrist(), POLYGLOT_KEYS[0].1);
+        for (bp, (fen, key)) in gv.fens.iter().zip(POLYGLOT_KEYS[1..].iter()) {
+            assert_eq!(bp.zobrist(), *key, "{}", fen);
+            assert_eq!(bp.to_bits(), BitPosition::parse_from_str(fen).unwrap().to_bits());
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use crate::parsing::{BitPiece, BitPosition, MaterialSignature, Side};
+
+/*
+Piece pattern queries
+
+A query is a whitespace separated list of terms, all of which must hold:
+
+    P@d4          a white pawn on d4
+    n@*6          a black knight somewhere on the 6th rank
+    !P@c*         no white pawn on the c file
+    material=KRPvKR
+                  exact material, white before the 'v', black after
+
+Pieces use fen letters (upper case white, lower case black). A square is a file
+a-h and a rank 1-8, either of which can be '*'. An isolated white queen's pawn
+is "P@d* !P@c* !P@e*".
+ */
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum PatternTerm {
+    Placement {
+        piece: BitPiece,
+        side: Side,
+        squares: u64, // bit i set = board index i (a1 = 0)
+        negated: bool,
+    },
+    Material(MaterialSignature),
+}
+
+impl PatternTerm {
+    pub fn matches(&self, pos: &BitPosition) -> bool {
+        match self {
+            PatternTerm::Placement { piece, side, squares, negated } => {
+                let found = (0..64).filter(|i| squares & (1u64 << i) != 0).any(|i| {
+                    let sq = pos.board[i];
+                    sq.piece() == *piece && sq.side() == *side
+                });
+                found != *negated
+            }
+            PatternTerm::Material(sig) => pos.material() == *sig,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PatternQuery {
+    pub terms: Vec<PatternTerm>,
+}
+
+impl PatternQuery {
+    pub fn parse(query: &str) -> Result<PatternQuery, String> {
+        let terms = query
+            .split_whitespace()
+            .map(parse_term)
+            .collect::<Result<Vec<_>, _>>()?;
+        if terms.is_empty() {
+            return Err("empty pattern".to_string());
+        }
+        Ok(PatternQuery { terms })
+    }
+
+    // the material term, if any, lets callers narrow the games down through the db first
+    pub fn material(&self) -> Option<MaterialSignature> {
+        self.terms.iter().find_map(|t| match t {
+            PatternTerm::Material(sig) => Some(*sig),
+            _ => None,
+        })
+    }
+
+    // the pawn placement terms on their own, which the stored pawn structures can be checked
+    // against to find the games worth replaying (see pawns.rs)
+    pub fn pawn_terms(&self) -> Option<PatternQuery> {
+        let terms: Vec<PatternTerm> = self
+            .terms
+            .iter()
+            .filter(|t| matches!(t, PatternTerm::Placement { piece: BitPiece::Pawn, .. }))
+            .cloned()
+            .collect();
+        (!terms.is_empty()).then_some(PatternQuery { terms })
+    }
+
+    pub fn matches(&self, pos: &BitPosition) -> bool {
+        self.terms.iter().all(|t| t.matches(pos))
+    }
+
+    // first ply (0 = starting position) at which the pattern holds, looking no earlier than from_ply
+    pub fn first_match(&self, start: &BitPosition, positions: &[BitPosition], from_ply: usize) -> Option<usize> {
+        std::iter::once(start)
+            .chain(positions.iter())
+            .enumerate()
+            .skip(from_ply)
+            .find(|(_, p)| self.matches(p))
+            .map(|(ply, _)| ply)
+    }
+}
+
+fn parse_term(term: &str) -> Result<PatternTerm, String> {
+    if let Some(material) = term.strip_prefix("material=") {
+        return Ok(PatternTerm::Material(MaterialSignature::parse(material)?));
+    }
+
+    let (negated, placement) = match term.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, term),
+    };
+    let (piece, square) = placement
+        .split_once('@')
+        .ok_or(format!("term '{}' should look like P@d4", term))?;
+
+    let mut piece_chars = piece.chars();
+    let (piece, side) = match (piece_chars.next(), piece_chars.next()) {
+        (Some(c), None) => {
+            let side = if c.is_ascii_uppercase() { Side::White } else { Side::Black };
+            let piece = match c.to_ascii_uppercase() {
+                'P' => BitPiece::Pawn,
+                'N' => BitPiece::Knight,
+                'B' => BitPiece::Bishop,
+                'R' => BitPiece::Rook,
+                'Q' => BitPiece::Queen,
+                'K' => BitPiece::King,
+                _ => return Err(format!("unknown piece '{}' in '{}'", c, term)),
+            };
+            (piece, side)
+        }
+        _ => return Err(format!("expected a single piece letter in '{}'", term)),
+    };
+
+    let square = square.as_bytes();
+    if square.len() != 2 {
+        return Err(format!("expected a square like d4, d* or *4 in '{}'", term));
+    }
+    let files: Vec<usize> = match square[0] {
+        b'*' => (0..8).collect(),
+        f @ b'a'..=b'h' => vec![(f - b'a') as usize],
+        _ => return Err(format!("bad file in '{}'", term)),
+    };
+    let ranks: Vec<usize> = match square[1] {
+        b'*' => (0..8).collect(),
+        r @ b'1'..=b'8' => vec![(r - b'1') as usize],
+        _ => return Err(format!("bad rank in '{}'", term)),
+    };
+    let mut squares: u64 = 0;
+    for rank in ranks.iter() {
+        for file in files.iter() {
+            squares |= 1u64 << (rank * 8 + file);
+        }
+    }
+
+    Ok(PatternTerm::Placement { piece, side, squares, negated })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::game_for_pgn;
+    use shakmaty::Chess;
+
+    #[test]
+    fn test_parse() {
+        let query = PatternQuery::parse("P@d4 !p@c* material=KQRRBBNNPPPPPPPPvKQRRBBNNPPPPPPPP").unwrap();
+        assert_eq!(query.terms.len(), 3);
+        assert_eq!(
+            query.terms[0],
+            PatternTerm::Placement { piece: BitPiece::Pawn, side: Side::White, squares: 1 << 27, negated: false }
+        );
+        assert!(query.material().is_some());
+        let pawns = query.pawn_terms().unwrap();
+        assert_eq!(pawns.terms, query.terms[..2].to_vec());
+        assert!(PatternQuery::parse("N@f3 material=KvK").unwrap().pawn_terms().is_none());
+        assert!(PatternQuery::parse("X@d4").is_err());
+        assert!(PatternQuery::parse("P@i4").is_err());
+        assert!(PatternQuery::parse("").is_err());
+    }
+
+    #[test]
+    fn test_isolated_queens_pawn() {
+        // 1. d4 d5 2. c4 e6 3. Nc3 c5 4. cxd5 exd5 5. Nf3 Nc6 6. dxc5 leaves black an IQP on d5
+        let gv = game_for_pgn("1. d4 d5 2. c4 e6 3. Nc3 c5 4. cxd5 exd5 5. Nf3 Nc6 6. dxc5 Bxc5 *").unwrap();
+        let start = BitPosition::from_chess(&Chess::default());
+        let iqp = PatternQuery::parse("p@d* !p@c* !p@e*").unwrap();
+        assert_eq!(iqp.first_match(&start, &gv.fens, 0), Some(11));
+
+        let start_material = PatternQuery::parse("material=KQRRBBNNPPPPPPPPvKQRRBBNNPPPPPPPP").unwrap();
+        assert_eq!(start_material.first_match(&start, &gv.fens, 0), Some(0));
+        assert_eq!(start.material().to_string(), "KQRRBBNNPPPPPPPPvKQRRBBNNPPPPPPPP");
+        assert_eq!(start.material().to_u64(), 0x811222_811222); // R N B Q K P counts, white in the low nibbles
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+
+use crate::db::PawnStructure;
+use crate::parsing::{BitPosition, BLACK_PAWN, BLACK_PAWN_C, WHITE_PAWN, WHITE_PAWN_C};
+use crate::pattern::PatternQuery;
+
+/*
+Pawn structures
+
+A position's pawn structure is the board with every piece but the pawns taken
+off (BitPosition::pawns), keyed by the zobrist key of what is left. Import
+records, like the material signature, the plies at which a game's structure
+changes (game_pawns), and tallies per structure the games and positions it came
+up in (pawn_structures, together with the two pawn bitboards).
+
+A structure is asked for as a fen, whose pieces are ignored, or as just the pawn
+layout, e.g. "8/pp3ppp/2p5/3p4/3P4/4P3/PP3PPP/8" for the Carlsbad. Exact layouts
+are a key lookup; the named presets are piece patterns (see pattern.rs) checked
+against every stored structure, so they hold whatever the other pawns are doing:
+
+    carlsbad    d4 against c6/d5 after the exchange on d5, or reversed (Caro-Kann exchange)
+    maroczy     pawns on c4 and e4 without a d-pawn, against a Sicilian without a c-pawn
+    iqp         an isolated d-pawn, for either side
+    hedgehog    a6 b6 d6 e6 against the Maroczy bind, or reversed
+ */
+
+pub struct Preset {
+    pub name: &'static str,
+    pub description: &'static str,
+    patterns: &'static [&'static str], // any one of them
+}
+
+pub const PRESETS: [Preset; 4] = [
+    Preset {
+        name: "carlsbad",
+        description: "d4 against c6/d5 with the c and e pawns traded, or reversed",
+        patterns: &["P@d4 !P@c* p@c6 p@d5 !p@e*", "p@d5 !p@c* P@c3 P@d4 !P@e*"],
+    },
+    Preset {
+        name: "maroczy",
+        description: "pawns on c4 and e4 without a d-pawn, against an open Sicilian",
+        patterns: &["P@c4 P@e4 !P@d* !p@c*", "p@c5 p@e5 !p@d* !P@c*"],
+    },
+    Preset {
+        name: "iqp",
+        description: "an isolated d-pawn",
+        patterns: &["P@d* !P@c* !P@e*", "p@d* !p@c* !p@e*"],
+    },
+    Preset {
+        name: "hedgehog",
+        description: "a6 b6 d6 e6 against the Maroczy bind, or reversed",
+        patterns: &["p@a6 p@b6 p@d6 p@e6 !p@c* P@c4 P@e4 !P@d*", "P@a3 P@b3 P@d3 P@e3 !P@c* p@c5 p@e5 !p@d*"],
+    },
+];
+
+pub fn preset(name: &str) -> Option<&'static Preset> {
+    PRESETS.iter().find(|p| p.name.eq_ignore_ascii_case(name))
+}
+
+impl Preset {
+    pub fn queries(&self) -> Vec<PatternQuery> {
+        self.patterns.iter().map(|p| PatternQuery::parse(p).unwrap()).collect()
+    }
+}
+
+// a fen or just its board part; pieces other than pawns are skipped, so the result only has pawns
+pub fn parse_structure(s: &str) -> Result<BitPosition, String> {
+    let board = s.split_whitespace().next().unwrap_or("");
+    let ranks: Vec<&str> = board.split('/').collect();
+    if ranks.len() != 8 {
+        return Err(format!("'{}' should have 8 ranks separated by '/'", board));
+    }
+    let mut pos = BitPosition::new();
+    // fen lists rank 8 first, the board starts at a1
+    for (row, squares) in ranks.iter().enumerate() {
+        let rank = 7 - row;
+        let mut file = 0;
+        for c in squares.chars() {
+            match c {
+                '1'..='8' => file += c.to_digit(10).unwrap() as usize,
+                WHITE_PAWN_C | BLACK_PAWN_C if rank == 0 || rank == 7 => {
+                    return Err(format!("pawn on rank {} in '{}'", rank + 1, board))
+                }
+                WHITE_PAWN_C | BLACK_PAWN_C if file < 8 => {
+                    pos.board[rank * 8 + file] = if c == WHITE_PAWN_C { &WHITE_PAWN } else { &BLACK_PAWN };
+                    file += 1;
+                }
+                'N' | 'B' | 'R' | 'Q' | 'K' | 'n' | 'b' | 'r' | 'q' | 'k' | 'P' | 'p' => file += 1,
+                _ => return Err(format!("unexpected '{}' in '{}'", c, board)),
+            }
+        }
+        if file != 8 {
+            return Err(format!("rank {} of '{}' is not 8 squares", rank + 1, board));
+        }
+    }
+    Ok(pos)
+}
+
+pub fn from_bitboards(white: u64, black: u64) -> BitPosition {
+    let mut pos = BitPosition::new();
+    for idx in 0..64 {
+        if white & (1u64 << idx) != 0 {
+            pos.board[idx] = &WHITE_PAWN;
+        } else if black & (1u64 << idx) != 0 {
+            pos.board[idx] = &BLACK_PAWN;
+        }
+    }
+    pos
+}
+
+// the plies at which the game's pawn structure changes (ply 0 always), adding the
+// game and its positions to the tally of each structure
+pub fn game_structures(
+    start: &BitPosition,
+    positions: &[BitPosition],
+    counts: &mut HashMap<u64, PawnStructure>,
+) -> Vec<(u16, u64)> {
+    let mut changes: Vec<(u16, u64)> = Vec::new();
+    for (ply, pos) in std::iter::once(start).chain(positions.iter()).enumerate() {
+        let pawn_key = pos.pawn_key();
+        let ps = counts.entry(pawn_key).or_insert_with(|| {
+            let (white, black) = pos.pawn_bitboards();
+            PawnStructure { pawn_key, white, black, games: 0, positions: 0 }
+        });
+        ps.positions += 1;
+        if changes.last().is_none_or(|(_, k)| *k != pawn_key) {
+            changes.push((ply as u16, pawn_key));
+        }
+    }
+    // pawns only move forward in chess, but crazyhouse drops can bring a structure back
+    let mut keys: Vec<u64> = changes.iter().map(|(_, k)| *k).collect();
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        counts.get_mut(&key).unwrap().games += 1;
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::db::{Db, Game, GamePawns};
+    use crate::execution::game_for_pgn;
+
+    fn last_position(pgn: &str) -> BitPosition {
+        game_for_pgn(pgn).unwrap().fens.last().unwrap().clone()
+    }
+
+    fn matches(preset: &Preset, pos: &BitPosition) -> bool {
+        preset.queries().iter().any(|q| q.matches(&pos.pawns()))
+    }
+
+    #[test]
+    fn test_structures() {
+        let carlsbad = last_position("1. d4 d5 2. c4 e6 3. Nc3 Nf6 4. cxd5 exd5 5. Bg5 c6 6. e3 *");
+        let layout = parse_structure("8/pp3ppp/2p5/3p4/3P4/4P3/PP3PPP/8").unwrap();
+        assert_eq!(carlsbad.pawn_key(), layout.pawn_key());
+        assert_eq!(parse_structure(&carlsbad.to_board_fen()).unwrap().pawn_key(), carlsbad.pawn_key());
+        let (white, black) = carlsbad.pawn_bitboards();
+        assert_eq!(from_bitboards(white, black).to_board_fen(), layout.to_board_fen());
+        assert!(parse_structure("8/8/8/8").is_err());
+        assert!(parse_structure("P7/8/8/8/8/8/8/8").is_err());
+        assert!(parse_structure("8/8/8/8/8/8/8/7x").is_err());
+
+        let maroczy = last_position("1. e4 c5 2. Nf3 Nc6 3. d4 cxd4 4. Nxd4 g6 5. c4 Bg7 6. Be3 Nf6 7. Nc3 d6 *");
+        let iqp = last_position("1. d4 d5 2. c4 e6 3. Nc3 c5 4. cxd5 exd5 5. Nf3 Nc6 6. dxc5 Bxc5 *");
+        let hedgehog = last_position(
+            "1. c4 c5 2. Nf3 Nf6 3. Nc3 e6 4. g3 b6 5. Bg2 Bb7 6. O-O a6 7. d4 cxd4 8. Nxd4 d6 9. e4 *",
+        );
+        let caro_exchange = last_position("1. e4 c6 2. d4 d5 3. exd5 cxd5 4. c3 *");
+        let start = parse_structure("8/pppppppp/8/8/8/8/PPPPPPPP/8").unwrap();
+        for (name, pos) in [("carlsbad", &carlsbad), ("maroczy", &maroczy), ("iqp", &iqp), ("hedgehog", &hedgehog)] {
+            assert!(matches(preset(name).unwrap(), pos), "{}", name);
+            assert!(!matches(preset(name).unwrap(), &start), "{}", name);
+        }
+        assert!(matches(preset("Carlsbad").unwrap(), &caro_exchange));
+        assert!(!matches(preset("iqp").unwrap(), &carlsbad));
+        assert!(!matches(preset("hedgehog").unwrap(), &maroczy));
+        assert!(preset("stonewall").is_none());
+    }
+
+    #[test]
+    fn test_game_structures() {
+        let dir = TempDir::new("pawns");
+        let path = dir.join("data.db");
+        let db = Db::new(&path);
+        db.init_schema();
+
+        let start = parse_structure("8/pppppppp/8/8/8/8/PPPPPPPP/8").unwrap();
+        let mut counts = HashMap::new();
+        let mut games = Vec::new();
+        for pgn in [
+            "[White \"alice\"] [Black \"bob\"] [Result \"1-0\"] 1. Nf3 Nf6 2. d4 d5 1-0",
+            "[White \"carol\"] [Black \"dave\"] [Result \"1/2-1/2\"] 1. d4 d5 2. Nf3 Nf6 1/2-1/2",
+        ] {
+            let gv = game_for_pgn(pgn).unwrap();
+            let id = Game::insert(&db, &gv.game).unwrap();
+            games.push((id, game_structures(&gv.start_position(), &gv.fens, &mut counts)));
+        }
+        // start, 1.d4 and 1...d5 for the second game; the knight moves change nothing
+        assert_eq!(games[1].1.iter().map(|(ply, _)| *ply).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(games[0].1.iter().map(|(ply, _)| *ply).collect::<Vec<_>>(), vec![0, 3, 4]);
+        assert_eq!(GamePawns::unindexed_games(&db).unwrap(), 2);
+        GamePawns::bulk_insert(&db, &games).unwrap();
+        assert_eq!(GamePawns::unindexed_games(&db).unwrap(), 0);
+        PawnStructure::bulk_add(&db, &counts.into_values().collect::<Vec<_>>()).unwrap();
+
+        let d4_d5 = parse_structure("8/ppp1pppp/8/3p4/3P4/8/PPP1PPPP/8").unwrap().pawn_key();
+        let found = GamePawns::games_for_key(&db, d4_d5).unwrap();
+        assert_eq!(found.iter().map(|g| (g.ply, g.result.as_deref())).collect::<Vec<_>>(),
+            vec![(4, Some("1-0")), (2, Some("1/2-1/2"))]);
+        assert_eq!(found[1].white.as_deref(), Some("carol"));
+
+        let mut structures = Vec::new();
+        PawnStructure::for_each(&db, |ps| structures.push(ps)).unwrap();
+        // start, d4 and d4/d5, each in both games; the start for 3 and 1 plies
+        assert_eq!(structures.len(), 3);
+        let tally = |key: u64| structures.iter().find(|ps| ps.pawn_key == key).map(|ps| (ps.games, ps.positions));
+        assert_eq!(tally(start.pawn_key()), Some((2, 4)));
+        assert_eq!(tally(d4_d5), Some((2, 4)));
+        assert_eq!(tally(parse_structure("8/pppppppp/8/8/3P4/8/PPP1PPPP/8").unwrap().pawn_key()), Some((2, 2)));
+
+        PawnStructure::clear(&db).unwrap();
+        assert!(GamePawns::games_for_key(&db, d4_d5).unwrap().is_empty());
+    }
+}
@@ -1,7 +1,13 @@
-use std::fs::OpenOptions;
-use std::io::Write;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use std::cmp::{Ordering, Eq};
 
+use crate::manifest::Manifest;
+
 /*
 persistence layer
 
@@ -45,13 +51,68 @@ pub struct PositionTrieAddress {
                           // is the position itself
 }
 
+#[derive(Debug, Clone, Copy)]
 pub struct Position {
+    pub zobrist: u64,
     pub r12: u64,
     pub r34: u64,
     pub r56: u64,
     pub r78: u64,
 }
 
+// on-disk record: 8 byte zobrist key followed by the 256 bit position, all big endian
+pub const SEGMENT_RECORD_SIZE: usize = 40;
+// block header: magic, format version and record count, all big endian
+pub const SEGMENT_HEADER_SIZE: usize = 12;
+pub const SEGMENT_MAGIC: [u8; 4] = [0xcc, 0xdd, 0x69, 0x43];
+pub const SEGMENT_FORMAT_VERSION: u32 = 2;
+// format 1 had the same records under an 8 byte header (magic and record count),
+// and still reads as is
+const SEGMENT_MAGIC_V1: [u8; 4] = [0xcc, 0xdd, 0x69, 0x42];
+// the first segment1.db files: 32 byte records with neither zobrist key nor readable quads
+const SEGMENT_MAGIC_V0: [u8; 4] = [0x01, 0x02, 0x04, 0x08];
+
+pub struct BlockHeader {
+    pub bytes: Vec<u8>, // as found in the file
+    pub records: u64,
+}
+
+// the header of the block at the reader's position, None at the end of the file
+pub fn read_block_header(reader: &mut impl Read) -> Result<Option<BlockHeader>, std::io::Error> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    let mut bytes = vec![0u8; 4];
+    match reader.read_exact(&mut bytes) {
+        Ok(_) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let magic: [u8; 4] = bytes[..].try_into().unwrap();
+    let header_size = match magic {
+        SEGMENT_MAGIC => SEGMENT_HEADER_SIZE,
+        SEGMENT_MAGIC_V1 => 8,
+        SEGMENT_MAGIC_V0 => {
+            return Err(invalid(
+                "segment written before zobrist keys, which cannot be converted; move it out of the \
+                 data directory and import the games again into an empty data.db to rebuild it"
+                    .to_string(),
+            ))
+        }
+        _ => return Err(invalid("bad segment header".to_string())),
+    };
+    bytes.resize(header_size, 0);
+    reader.read_exact(&mut bytes[4..])?;
+    if header_size == SEGMENT_HEADER_SIZE {
+        let version = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
+        if version > SEGMENT_FORMAT_VERSION {
+            return Err(invalid(format!(
+                "segment format {} is newer than this version of crusty reads ({}), upgrade crusty",
+                version, SEGMENT_FORMAT_VERSION)));
+        }
+    }
+    let records = u32::from_be_bytes(bytes[header_size - 4..].try_into().unwrap()) as u64;
+    Ok(Some(BlockHeader { bytes, records }))
+}
+
 impl Position {
     pub fn position_quad_to_bytes(&self) -> [u8; 32] {
         let mut result: [u8; 32] = [
@@ -60,18 +121,41 @@ impl Position {
         ];
         let mut result_ptr = 0;
         for quad in [self.r12, self.r34, self.r56, self.r78] {
-            for i in 0..7 {
-                result[result_ptr + i] = (quad >> (56 - (i * 8))) as u8;
-            }
-            result_ptr += 4;
+            result[result_ptr..result_ptr + 8].copy_from_slice(&quad.to_be_bytes());
+            result_ptr += 8;
         }
         result
     }
+
+    pub fn to_record(self) -> [u8; SEGMENT_RECORD_SIZE] {
+        let mut record = [0u8; SEGMENT_RECORD_SIZE];
+        record[0..8].copy_from_slice(&self.zobrist.to_be_bytes());
+        record[8..].copy_from_slice(&self.position_quad_to_bytes());
+        record
+    }
+
+    pub fn from_record(record: &[u8]) -> Position {
+        let quad = |i: usize| u64::from_be_bytes(record[i..i + 8].try_into().unwrap());
+        Position {
+            zobrist: quad(0),
+            r12: quad(8),
+            r34: quad(16),
+            r56: quad(24),
+            r78: quad(32),
+        }
+    }
+
+    pub fn same_board(&self, r12: u64, r34: u64, r56: u64, r78: u64) -> bool {
+        self.r12 == r12 && self.r34 == r34 && self.r56 == r56 && self.r78 == r78
+    }
 }
 
+// segments are sorted by zobrist key so lookups can binary search; ties are
+// broken on the full position
 impl Ord for Position {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.r12.cmp(&other.r12)
+        self.zobrist.cmp(&other.zobrist)
+        .then(self.r12.cmp(&other.r12))
         .then(self.r34.cmp(&other.r34))
         .then(self.r56.cmp(&other.r56))
         .then(self.r78.cmp(&other.r78))
@@ -91,7 +175,7 @@ impl PartialOrd<Self> for Position {
 
 impl PartialEq for Position {
     fn eq(&self, other: &Self) -> bool {
-        self.r12 == other.r12 && self.r34 == other.r34 && self.r56 == other.r56 && self.r78 == other.r78
+        self.zobrist == other.zobrist && self.same_board(other.r12, other.r34, other.r56, other.r78)
     }
 }
 
@@ -219,15 +303,15 @@ impl PositionTrie {
 }
 
 pub struct PositionSegment {
-    path: &'static str,
+    path: PathBuf,
     sorted: bool,
     roots: Vec<Position>,
 }
 
 impl PositionSegment {
-    pub fn new(path: &'static str) -> Self {
+    pub fn new(path: &Path) -> Self {
         PositionSegment {
-            path: path,
+            path: path.to_path_buf(),
             sorted: false,
             roots: Vec::<Position>::new(),
         }
@@ -240,32 +324,30 @@ impl PositionSegment {
     pub fn sort(&mut self) {
         if ! self.sorted {
             self.roots.sort_unstable();
+            self.roots.dedup();
         }
         self.sorted = true;
     }
 
-    pub fn insert(&mut self, r12: u64, r34: u64, r56: u64, r78: u64) {
-        self.roots.push(Position { r12, r34, r56, r78 });
+    pub fn insert(&mut self, zobrist: u64, r12: u64, r34: u64, r56: u64, r78: u64) {
+        self.roots.push(Position { zobrist, r12, r34, r56, r78 });
         self.sorted = false;
     }
 
-    pub fn get_header(&self) -> [u8; 8] {
-        let _fixed_header: [u8; 4] = [0xcc, 0xdd, 0x69, 0x42];
-
-        let mut return_header: [u8; 8] = [0x01, 0x02, 0x04, 0x08, 0x16, 0x32, 0x64, 0xff];
-        let len = self.roots.len() as u32;
-        return_header[4] = (len >> 24) as u8;
-        return_header[5] = (len >> 16) as u8;
-        return_header[6] = (len >> 8) as u8;
-        return_header[7] = len as u8;
+    pub fn get_header(&self) -> [u8; SEGMENT_HEADER_SIZE] {
+        let mut return_header = [0u8; SEGMENT_HEADER_SIZE];
+        return_header[..4].copy_from_slice(&SEGMENT_MAGIC);
+        return_header[4..8].copy_from_slice(&SEGMENT_FORMAT_VERSION.to_be_bytes());
+        return_header[8..].copy_from_slice(&(self.roots.len() as u32).to_be_bytes());
         return_header
     }
 
-    pub fn write(&self) -> Result<usize, std::io::Error> {
+    pub fn write(&mut self) -> Result<usize, std::io::Error> {
+        self.sort();
         let mut fh = OpenOptions::new()
             .append(true)
             .create(true)
-            .open(self.path)
+            .open(&self.path)
             .expect("Unable to create or open for append position segment file");
 
         match fh.write_all(&self.get_header()) {
@@ -274,7 +356,7 @@ impl PositionSegment {
         };
 
         for pos in self.roots.iter() {
-            match fh.write_all(&pos.position_quad_to_bytes()) {
+            match fh.write_all(&pos.to_record()) {
                 Ok(_) => (),
                 Err(err) => return Err(err),
             }
@@ -335,3 +417,349 @@ impl PositionSegment {
     }
     */
 }
+
+// A segment file may hold several sorted blocks, one per write() (header + records each).
+pub struct SegmentFile {
+    fh: File,
+    blocks: Vec<(u64, u64)>, // (byte offset of first record, record count)
+}
+
+impl SegmentFile {
+    pub fn open(path: &Path) -> Result<SegmentFile, std::io::Error> {
+        let mut fh = File::open(path)?;
+        let file_len = fh.metadata()?.len();
+        let mut blocks = Vec::new();
+        let mut offset: u64 = 0;
+        while offset < file_len {
+            fh.seek(SeekFrom::Start(offset))?;
+            let header = match read_block_header(&mut fh) {
+                Ok(Some(header)) => header,
+                Ok(None) => break,
+                Err(e) => return Err(std::io::Error::new(e.kind(), format!("{} at offset {}", e, offset))),
+            };
+            let start = offset + header.bytes.len() as u64;
+            blocks.push((start, header.records));
+            offset = start + header.records * SEGMENT_RECORD_SIZE as u64;
+        }
+        Ok(SegmentFile { fh, blocks })
+    }
+
+    fn read_at(&mut self, offset: u64) -> Result<Position, std::io::Error> {
+        let mut record = [0u8; SEGMENT_RECORD_SIZE];
+        self.fh.seek(SeekFrom::Start(offset))?;
+        self.fh.read_exact(&mut record)?;
+        Ok(Position::from_record(&record))
+    }
+
+    // all records carrying this zobrist key, as (byte offset, position)
+    pub fn lookup(&mut self, zobrist: u64) -> Result<Vec<(u64, Position)>, std::io::Error> {
+        let mut found = Vec::new();
+        for (start, count) in self.blocks.clone() {
+            let record_offset = |i: u64| start + i * SEGMENT_RECORD_SIZE as u64;
+            // lower bound
+            let (mut lo, mut hi) = (0, count);
+            while lo < hi {
+                let mid = (lo + hi) / 2;
+                if self.read_at(record_offset(mid))?.zobrist < zobrist {
+                    lo = mid + 1;
+                } else {
+                    hi = mid;
+                }
+            }
+            while lo < count {
+                let pos = self.read_at(record_offset(lo))?;
+                if pos.zobrist != zobrist {
+                    break;
+                }
+                found.push((record_offset(lo), pos));
+                lo += 1;
+            }
+        }
+        Ok(found)
+    }
+
+    // every record, block by block
+    pub fn for_each(&mut self, f: &mut dyn FnMut(&Position)) -> Result<(), std::io::Error> {
+        for (start, count) in self.blocks.clone() {
+            for i in 0..count {
+                f(&self.read_at(start + i * SEGMENT_RECORD_SIZE as u64)?);
+            }
+        }
+        Ok(())
+    }
+
+    // every record with from <= zobrist < to, block by block
+    pub fn for_each_in_range(&mut self, from: u64, to: u64, f: &mut dyn FnMut(&Position)) -> Result<(), std::io::Error> {
+        for (start, count) in self.blocks.clone() {
+            let record_offset = |i: u64| start + i * SEGMENT_RECORD_SIZE as u64;
+            let (mut lo, mut hi) = (0, count);
+            while lo < hi {
+                let mid = (lo + hi) / 2;
+                if self.read_at(record_offset(mid))?.zobrist < from {
+                    lo = mid + 1;
+                } else {
+                    hi = mid;
+                }
+            }
+            while lo < count {
+                let pos = self.read_at(record_offset(lo))?;
+                if pos.zobrist >= to {
+                    break;
+                }
+                f(&pos);
+                lo += 1;
+            }
+        }
+        Ok(())
+    }
+
+}
+
+/*
+Position stores
+
+Everything that keeps positions around (flat segment files, SQLite, plain memory)
+implements PositionStore, so ingestion and lookups do not care which one is in use.
+Positions are ordered by zobrist key, ties broken on the full 256 bits.
+ */
+pub trait PositionStore {
+    fn insert_batch(&mut self, positions: &[Position]) -> Result<(), Box<dyn Error>>;
+
+    // all positions carrying this key; callers resolve collisions with Position::same_board
+    fn lookup(&mut self, zobrist: u64) -> Result<Vec<Position>, Box<dyn Error>>;
+
+    // every position with from <= zobrist < to, in key order
+    fn for_each_in_range(&mut self, from: u64, to: u64, f: &mut dyn FnMut(&Position)) -> Result<(), Box<dyn Error>>;
+
+    // catch up with what other writers stored since the store was opened, for readers
+    // that keep a store open (crusty serve)
+    fn refresh(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    // make everything inserted so far durable; returns how many of the positions inserted
+    // since the last flush were new to the store, repeats and already stored positions not counted
+    fn flush(&mut self) -> Result<usize, Box<dyn Error>>;
+
+    fn name(&self) -> String;
+}
+
+// the segment files listed in the dataset manifest; every flush adds one segment
+pub struct SegmentStore {
+    manifest: Manifest,
+    pending: Vec<Position>,
+    loaded: Option<SystemTime>, // manifest.json's mtime when it was read, see refresh
+}
+
+impl SegmentStore {
+    pub fn open(data_dir: &Path) -> Result<SegmentStore, Box<dyn Error>> {
+        let loaded = manifest_modified(data_dir);
+        Ok(SegmentStore {
+            manifest: Manifest::load(data_dir)?,
+            pending: Vec::new(),
+            loaded,
+        })
+    }
+
+    // never writes: not manifest.json, not a segment; inserting is an error
+    pub fn open_read_only(data_dir: &Path) -> Result<SegmentStore, Box<dyn Error>> {
+        let loaded = manifest_modified(data_dir);
+        Ok(SegmentStore {
+            manifest: Manifest::load_read_only(data_dir)?,
+            pending: Vec::new(),
+            loaded,
+        })
+    }
+}
+
+fn manifest_modified(data_dir: &Path) -> Option<SystemTime> {
+    std::fs::metadata(Manifest::path(data_dir)).and_then(|m| m.modified()).ok()
+}
+
+impl PositionStore for SegmentStore {
+    fn insert_batch(&mut self, positions: &[Position]) -> Result<(), Box<dyn Error>> {
+        if self.manifest.read_only() {
+            return Err(format!("{} is open read-only", self.name()).into());
+        }
+        self.pending.extend_from_slice(positions);
+        Ok(())
+    }
+
+    fn lookup(&mut self, zobrist: u64) -> Result<Vec<Position>, Box<dyn Error>> {
+        let mut found = Vec::new();
+        for segment in self.manifest.segments.iter() {
+            if segment.min_key <= zobrist && zobrist <= segment.max_key {
+                let mut file = SegmentFile::open(&self.manifest.segment_path(segment))?;
+                found.extend(file.lookup(zobrist)?.into_iter().map(|(_, p)| p));
+            }
+        }
+        found.extend(self.pending.iter().filter(|p| p.zobrist == zobrist));
+        // segments written before flushes checked for repeats can hold the same position
+        found.sort_unstable();
+        found.dedup();
+        Ok(found)
+    }
+
+    fn for_each_in_range(&mut self, from: u64, to: u64, f: &mut dyn FnMut(&Position)) -> Result<(), Box<dyn Error>> {
+        // segments are only sorted within themselves, so merge them through a set
+        let mut merged = BTreeSet::new();
+        for segment in self.manifest.segments.iter().filter(|s| s.overlaps(from, to)) {
+            let mut file = SegmentFile::open(&self.manifest.segment_path(segment))?;
+            file.for_each_in_range(from, to, &mut |p| {
+                merged.insert(*p);
+            })?;
+        }
+        merged.extend(self.pending.iter().filter(|p| p.zobrist >= from && p.zobrist < to));
+        merged.iter().for_each(f);
+        Ok(())
+    }
+
+    // a manifest that changed on disk is read again; what was inserted and not flushed stays
+    fn refresh(&mut self) -> Result<(), Box<dyn Error>> {
+        let modified = manifest_modified(self.manifest.data_dir());
+        if modified != self.loaded {
+            let data_dir = self.manifest.data_dir().to_path_buf();
+            self.manifest = match self.manifest.read_only() {
+                true => Manifest::load_read_only(&data_dir)?,
+                false => Manifest::load(&data_dir)?,
+            };
+            self.loaded = modified;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<usize, Box<dyn Error>> {
+        self.pending.sort_unstable();
+        self.pending.dedup();
+        // drop what earlier segments hold already, so every position is stored once
+        let mut stored = vec![false; self.pending.len()];
+        for segment in self.manifest.segments.iter() {
+            let mut file = SegmentFile::open(&self.manifest.segment_path(segment))?;
+            file.for_each(&mut |p| {
+                if let Ok(idx) = self.pending.binary_search(p) {
+                    stored[idx] = true;
+                }
+            })?;
+        }
+        let mut stored = stored.into_iter();
+        self.pending.retain(|_| !stored.next().unwrap());
+        if self.pending.is_empty() {
+            return Ok(0);
+        }
+        let segment = self.manifest.add_segment(&self.pending)?;
+        self.pending.clear();
+        Ok(segment.records as usize)
+    }
+
+    fn name(&self) -> String {
+        format!("segments in {}", self.manifest.data_dir().display())
+    }
+}
+
+// nothing is persisted; for tests and for comparing against the other stores
+#[derive(Default)]
+pub struct MemoryStore {
+    positions: BTreeSet<Position>,
+    added: usize,
+}
+
+impl PositionStore for MemoryStore {
+    fn insert_batch(&mut self, positions: &[Position]) -> Result<(), Box<dyn Error>> {
+        for p in positions {
+            self.added += self.positions.insert(*p) as usize;
+        }
+        Ok(())
+    }
+
+    fn lookup(&mut self, zobrist: u64) -> Result<Vec<Position>, Box<dyn Error>> {
+        let lower = Position { zobrist, r12: 0, r34: 0, r56: 0, r78: 0 };
+        Ok(self
+            .positions
+            .range(lower..)
+            .take_while(|p| p.zobrist == zobrist)
+            .copied()
+            .collect())
+    }
+
+    fn for_each_in_range(&mut self, from: u64, to: u64, f: &mut dyn FnMut(&Position)) -> Result<(), Box<dyn Error>> {
+        let lower = Position { zobrist: from, r12: 0, r34: 0, r56: 0, r78: 0 };
+        self.positions
+            .range(lower..)
+            .take_while(|p| p.zobrist < to)
+            .for_each(f);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<usize, Box<dyn Error>> {
+        Ok(std::mem::take(&mut self.added))
+    }
+
+    fn name(&self) -> String {
+        "memory".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn test_segment_lookup_resolves_collisions() {
+        let dir = TempDir::new("segment");
+        let path = dir.join("segment.db");
+        let mut segment = PositionSegment::new(&path);
+        segment.insert(42, 1, 2, 3, 4);
+        segment.insert(7, 5, 6, 7, 8);
+        segment.insert(42, 9, 9, 9, 9); // same key, different position
+        segment.write().unwrap();
+
+        let mut file = SegmentFile::open(&path).unwrap();
+        let found = file.lookup(42).unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().any(|(_, p)| p.same_board(9, 9, 9, 9)));
+        assert!(!found.iter().any(|(_, p)| p.same_board(5, 6, 7, 8)));
+        assert!(file.lookup(8).unwrap().is_empty());
+        assert!(file.lookup(7).unwrap()[0].1.same_board(5, 6, 7, 8));
+    }
+
+    #[test]
+    fn test_stores_agree() {
+        let dir = TempDir::new("store");
+        let position = |zobrist, r| Position { zobrist, r12: r, r34: r, r56: r, r78: r };
+        let high = (1u64 << 63) + 5; // stored as a negative key in sqlite
+        let first = [position(42, 1), position(7, 2), position(u64::MAX - 1, 3), position(high, 6)];
+        // repeats within the batch and of the first one
+        let second = [position(42, 4), position(9, 5), position(9, 5), position(42, 1)];
+
+        let db_path = dir.join("data.db");
+        let db = crate::db::Db::new(&db_path);
+        db.init_schema();
+        let mut sqlite = crate::db::SqlitePositionStore::new(&db);
+        let mut segment = SegmentStore::open(dir.path()).unwrap();
+        let mut memory = MemoryStore::default();
+        for store in [&mut segment as &mut dyn PositionStore, &mut memory, &mut sqlite] {
+            store.insert_batch(&first).unwrap();
+            assert_eq!(store.flush().unwrap(), 4, "{}", store.name());
+            // the second batch ends up in its own segment
+            store.insert_batch(&second).unwrap();
+            assert_eq!(store.flush().unwrap(), 2, "{}", store.name());
+            assert_eq!(store.flush().unwrap(), 0, "{}", store.name());
+        }
+
+        for store in [&mut segment as &mut dyn PositionStore, &mut memory, &mut sqlite] {
+            assert_eq!(store.lookup(42).unwrap().len(), 2, "{}", store.name());
+            assert!(store.lookup(8).unwrap().is_empty());
+            assert_eq!(store.lookup(high).unwrap().len(), 1, "{}", store.name());
+            let mut keys = Vec::new();
+            store.for_each_in_range(8, u64::MAX, &mut |p| keys.push(p.zobrist)).unwrap();
+            assert_eq!(keys, vec![9, 42, 42, high, u64::MAX - 1], "{}", store.name());
+            keys.clear();
+            store.for_each_in_range(1 << 63, u64::MAX - 1, &mut |p| keys.push(p.zobrist)).unwrap();
+            assert_eq!(keys, vec![high], "{}", store.name());
+            keys.clear();
+            store.for_each_in_range(0, 10, &mut |p| keys.push(p.zobrist)).unwrap();
+            assert_eq!(keys, vec![7, 9], "{}", store.name());
+        }
+    }
+}
@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use serde::Serialize;
+
+use crate::db::{site_source, Db, Game, GameFilter, Player, PlayerAlias};
+
+/*
+Player reports
+
+Everything is worked out from the games table: a game is the player's when
+White or Black is one of their aliases and the game's site is that alias's
+source, so merged identities aggregate across sites (see db.rs). A name nobody
+has registered yet is matched on White/Black alone (case-insensitively).
+Results are scored from the player's side and ratings come from the player's
+own *_elo column. Games are taken in date order (utc_date, falling back to the
+pgn Date header) so rating history and streaks follow the order the games were
+played in.
+
+Players are named on the command line as "#12" (player id), "lichess.org:alice"
+(one alias) or just "alice" (whoever has an alias of that name, which has to be
+one player).
+ */
+
+// "lichess.org:alice" -> ("lichess.org", "alice"); None unless the part before the colon is a site
+pub fn split_alias_key(spec: &str) -> Option<(String, &str)> {
+    let (source, name) = spec.split_once(':')?;
+    let source = source.to_lowercase();
+    (!source.is_empty() && site_source(&source) == source).then_some((source, name))
+}
+
+pub struct PlayerIdentity {
+    pub player: Option<Player>,
+    pub name: String,
+    pub aliases: Vec<PlayerAlias>, // empty: match on the name alone
+}
+
+impl PlayerIdentity {
+    pub fn named(name: &str) -> PlayerIdentity {
+        PlayerIdentity { player: None, name: name.to_string(), aliases: Vec::new() }
+    }
+
+    fn for_player(db: &Db, player: Player) -> Result<PlayerIdentity, Box<dyn Error>> {
+        let aliases = player.aliases(db)?;
+        Ok(PlayerIdentity { name: player.name.clone(), player: Some(player), aliases })
+    }
+
+    pub fn resolve(db: &Db, spec: &str) -> Result<PlayerIdentity, Box<dyn Error>> {
+        if let Some(id) = spec.strip_prefix('#').and_then(|id| id.parse::<i64>().ok()) {
+            let player = Player::get(db, id)?.ok_or(format!("no player #{}", id))?;
+            return PlayerIdentity::for_player(db, player);
+        }
+        if let Some((source, name)) = split_alias_key(spec) {
+            let alias = PlayerAlias::get(db, &source, name)?.ok_or(format!("no alias {}", spec))?;
+            let player = Player::get(db, alias.player_id)?.ok_or(format!("no player #{}", alias.player_id))?;
+            return PlayerIdentity::for_player(db, player);
+        }
+        let mut players = Player::for_alias_name(db, spec)?;
+        match players.len() {
+            0 => Ok(PlayerIdentity::named(spec)),
+            1 => PlayerIdentity::for_player(db, players.remove(0)),
+            _ => {
+                let mut candidates = Vec::new();
+                for player in players {
+                    let aliases: Vec<_> = player.aliases(db)?.iter().map(PlayerAlias::key).collect();
+                    candidates.push(format!("#{} ({})", player.id, aliases.join(", ")));
+                }
+                Err(format!("'{}' is more than one player: {}", spec, candidates.join("; ")).into())
+            }
+        }
+    }
+
+    // the White/Black names the player's games go by
+    pub fn names(&self) -> Vec<String> {
+        match self.aliases.is_empty() {
+            true => vec![self.name.clone()],
+            false => self.aliases.iter().map(|alias| alias.name.clone()).collect(),
+        }
+    }
+
+    // a filter for this identity's games, to fill in the rest of: through the aliases
+    // (and so per site) when there is a player, on the bare name otherwise
+    pub fn game_filter(&self) -> GameFilter {
+        match &self.player {
+            Some(player) => GameFilter { player_id: Some(player.id), ..Default::default() },
+            None => GameFilter { players: vec![self.name.clone()], ..Default::default() },
+        }
+    }
+
+    pub fn plays(&self, name: Option<&str>, site: &str) -> bool {
+        let name = match name {
+            Some(name) => name,
+            None => return false,
+        };
+        if self.aliases.is_empty() {
+            return name.eq_ignore_ascii_case(&self.name);
+        }
+        let source = site_source(site);
+        self.aliases.iter().any(|a| a.source == source && a.name.eq_ignore_ascii_case(name))
+    }
+
+    // the player's games in date order, through the aliases as export picks them
+    pub fn games(&self, db: &Db) -> Result<Vec<Game>, Box<dyn Error>> {
+        let mut games = Vec::new();
+        self.game_filter().for_each(db, |game, _| -> Result<(), rusqlite::Error> {
+            games.push(game);
+            Ok(())
+        })?;
+        games.sort_by(|a, b| {
+            let date = |g: &Game| g.utc_date.clone().or(g.date.clone());
+            (date(a), &a.utc_time, a.id).cmp(&(date(b), &b.utc_time, b.id))
+        });
+        Ok(games)
+    }
+
+}
+
+#[derive(Serialize, Default, Debug, Clone, Copy, PartialEq)]
+pub struct Score {
+    pub games: u64,
+    pub wins: u64,
+    pub draws: u64,
+    pub losses: u64,
+}
+
+impl Score {
+    pub fn add(&mut self, outcome: Outcome) {
+        self.games += 1;
+        match outcome {
+            Outcome::Win => self.wins += 1,
+            Outcome::Draw => self.draws += 1,
+            Outcome::Loss => self.losses += 1,
+            Outcome::Unknown => (),
+        }
+    }
+
+    // points per game with a result, draws counting half, 0.0 - 1.0
+    pub fn percentage(&self) -> Option<f64> {
+        let scored = self.wins + self.draws + self.losses;
+        if scored == 0 {
+            return None;
+        }
+        Some((self.wins as f64 + self.draws as f64 / 2.0) / scored as f64)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Outcome {
+    Win,
+    Draw,
+    Loss,
+    Unknown,
+}
+
+impl Outcome {
+    // the pgn Result from one side's point of view
+    pub fn for_side(result: Option<&str>, as_white: bool) -> Outcome {
+        match (result, as_white) {
+            (Some("1-0"), true) | (Some("0-1"), false) => Outcome::Win,
+            (Some("0-1"), true) | (Some("1-0"), false) => Outcome::Loss,
+            (Some("1/2-1/2"), _) => Outcome::Draw,
+            _ => Outcome::Unknown,
+        }
+    }
+
+    pub fn points(&self) -> Option<f64> {
+        match self {
+            Outcome::Win => Some(1.0),
+            Outcome::Draw => Some(0.5),
+            Outcome::Loss => Some(0.0),
+            Outcome::Unknown => None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct RatingPoint {
+    pub date: String,
+    pub rating: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct OpeningStats {
+    pub eco: String,
+    pub opening: Option<String>,
+    pub score: Score,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TimeControlStats {
+    pub time_control: String,
+    pub games: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PlayerReport {
+    pub name: String,
+    pub player_id: Option<i64>,
+    pub aliases: Vec<String>,
+    pub total: Score,
+    pub white: Score,
+    pub black: Score,
+    pub rating_history: Vec<RatingPoint>, // last rating seen on each date
+    pub openings: Vec<OpeningStats>,      // most played first
+    pub time_controls: Vec<TimeControlStats>,
+    pub longest_win_streak: u64,
+    pub longest_loss_streak: u64,
+}
+
+impl PlayerReport {
+    // spec as in PlayerIdentity::resolve
+    pub fn for_player(db: &Db, spec: &str) -> Result<PlayerReport, Box<dyn Error>> {
+        let identity = PlayerIdentity::resolve(db, spec)?;
+        let games = identity.games(db)?;
+        Ok(PlayerReport::from_games(&identity, &games))
+    }
+
+    // games must be in the order they were played; games the player is not in are skipped
+    pub fn from_games(identity: &PlayerIdentity, games: &[Game]) -> PlayerReport {
+        let mut report = PlayerReport {
+            name: identity.name.clone(),
+            player_id: identity.player.as_ref().map(|p| p.id),
+            aliases: identity.aliases.iter().map(PlayerAlias::key).collect(),
+            total: Score::default(),
+            white: Score::default(),
+            black: Score::default(),
+            rating_history: Vec::new(),
+            openings: Vec::new(),
+            time_controls: Vec::new(),
+            longest_win_streak: 0,
+            longest_loss_streak: 0,
+        };
+        let mut openings = HashMap::<String, (Score, HashMap<String, u64>)>::new();
+        let mut time_controls = HashMap::<String, u64>::new();
+        let (mut win_streak, mut loss_streak) = (0, 0);
+
+        for game in games {
+            let as_white = identity.plays(game.white.as_deref(), &game.site);
+            if !as_white && !identity.plays(game.black.as_deref(), &game.site) {
+                continue;
+            }
+            let outcome = Outcome::for_side(game.result.as_deref(), as_white);
+            report.total.add(outcome);
+            if as_white {
+                report.white.add(outcome);
+            } else {
+                report.black.add(outcome);
+            }
+
+            match outcome {
+                Outcome::Win => {
+                    win_streak += 1;
+                    loss_streak = 0;
+                }
+                Outcome::Loss => {
+                    loss_streak += 1;
+                    win_streak = 0;
+                }
+                Outcome::Draw => (win_streak, loss_streak) = (0, 0),
+                Outcome::Unknown => (),
+            }
+            report.longest_win_streak = report.longest_win_streak.max(win_streak);
+            report.longest_loss_streak = report.longest_loss_streak.max(loss_streak);
+
+            let elo = if as_white { &game.white_elo } else { &game.black_elo };
+            let date = game.utc_date.as_ref().or(game.date.as_ref()).filter(|d| !d.starts_with('?'));
+            if let (Some(rating), Some(date)) = (elo.as_deref().and_then(|e| e.parse::<u32>().ok()), date) {
+                match report.rating_history.last_mut() {
+                    Some(last) if last.date == *date => last.rating = rating,
+                    _ => report.rating_history.push(RatingPoint { date: date.clone(), rating }),
+                }
+            }
+
+            if let Some(eco) = game.eco.as_ref().filter(|e| !e.is_empty() && *e != "?") {
+                let (score, names) = openings.entry(eco.clone()).or_default();
+                score.add(outcome);
+                if let Some(opening) = &game.opening {
+                    *names.entry(opening.clone()).or_default() += 1;
+                }
+            }
+            if let Some(tc) = game.time_control.as_ref().filter(|tc| !tc.is_empty() && *tc != "-") {
+                *time_controls.entry(tc.clone()).or_default() += 1;
+            }
+        }
+
+        report.openings = openings
+            .into_iter()
+            .map(|(eco, (score, names))| OpeningStats {
+                eco,
+                // the name most games gave for the code
+                opening: names.into_iter().max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0))).map(|(n, _)| n),
+                score,
+            })
+            .collect();
+        report.openings.sort_by(|a, b| b.score.games.cmp(&a.score.games).then(a.eco.cmp(&b.eco)));
+        report.time_controls = time_controls
+            .into_iter()
+            .map(|(time_control, games)| TimeControlStats { time_control, games })
+            .collect();
+        report.time_controls.sort_by(|a, b| b.games.cmp(&a.games).then(a.time_control.cmp(&b.time_control)));
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn game(white: &str, black: &str, result: &str, date: &str, eco: &str) -> Game {
+        let mut game = Game::new();
+        game.white = Some(white.to_string());
+        game.black = Some(black.to_string());
+        game.result = Some(result.to_string());
+        game.utc_date = Some(date.to_string());
+        game.eco = Some(eco.to_string());
+        game.opening = Some(format!("Opening {}", eco));
+        game.time_control = Some("180+0".to_string());
+        game.white_elo = Some("1500".to_string());
+        game.black_elo = Some("1600".to_string());
+        game
+    }
+
+    #[test]
+    fn test_report() {
+        let mut games = vec![
+            game("alice", "bob", "1-0", "2023.01.01", "C50"),
+            game("bob", "Alice", "0-1", "2023.01.01", "B01"),
+            game("alice", "carol", "1-0", "2023.01.02", "C50"),
+            game("alice", "bob", "1/2-1/2", "2023.01.03", "C50"),
+            game("dave", "alice", "1-0", "2023.01.04", "B01"),
+            game("dave", "bob", "1-0", "2023.01.05", "A00"), // not alice's
+        ];
+        games[4].time_control = Some("600+5".to_string());
+
+        let report = PlayerReport::from_games(&PlayerIdentity::named("alice"), &games);
+        assert_eq!(report.total, Score { games: 5, wins: 3, draws: 1, losses: 1 });
+        assert_eq!(report.white.games, 3);
+        assert_eq!(report.black, Score { games: 2, wins: 1, draws: 0, losses: 1 });
+        assert_eq!(report.total.percentage(), Some(0.7));
+        assert_eq!((report.longest_win_streak, report.longest_loss_streak), (3, 1));
+
+        // one point per date, the last game of the day wins
+        let history: Vec<_> = report.rating_history.iter().map(|p| (p.date.as_str(), p.rating)).collect();
+        assert_eq!(history, vec![("2023.01.01", 1600), ("2023.01.02", 1500), ("2023.01.03", 1500), ("2023.01.04", 1600)]);
+
+        assert_eq!(report.openings[0].eco, "C50");
+        assert_eq!(report.openings[0].opening.as_deref(), Some("Opening C50"));
+        assert_eq!(report.openings[0].score, Score { games: 3, wins: 2, draws: 1, losses: 0 });
+        assert_eq!(report.time_controls[0].time_control, "180+0");
+        assert_eq!(report.time_controls[0].games, 4);
+    }
+
+    #[test]
+    fn test_merged_identity_spans_sites() {
+        let dir = TempDir::new("player");
+        let path = dir.join("data.db");
+        let db = Db::new(&path);
+        db.init_schema();
+
+        let mut games = [
+            game("alice", "bob", "1-0", "2023.01.01", "C50"),
+            game("bob", "alice", "1-0", "2023.01.02", "C50"),
+            game("Smith, Alice", "Jones, Bob", "1/2-1/2", "2023.01.03", "B01"),
+        ];
+        games[0].site = "https://lichess.org/a".to_string();
+        games[1].site = "Chess.com".to_string(); // a different alice
+        games[2].site = "Hastings ENG".to_string();
+        for (i, game) in games.iter_mut().enumerate() {
+            game.hash = i as i64;
+            game.id = Game::insert(&db, game).unwrap();
+        }
+        Player::sync(&db).unwrap();
+
+        // two alices: has to be spelled out
+        assert!(PlayerReport::for_player(&db, "alice").is_err());
+        let lichess = PlayerReport::for_player(&db, "lichess.org:alice").unwrap();
+        assert_eq!(lichess.total, Score { games: 1, wins: 1, draws: 0, losses: 0 });
+
+        let alice = PlayerIdentity::resolve(&db, "lichess.org:alice").unwrap().player.unwrap();
+        let smith = PlayerIdentity::resolve(&db, "Smith, Alice").unwrap().player.unwrap();
+        Player::merge(&db, alice.id, smith.id).unwrap();
+        let merged = PlayerReport::for_player(&db, &format!("#{}", alice.id)).unwrap();
+        assert_eq!(merged.total, Score { games: 2, wins: 1, draws: 1, losses: 0 });
+        assert_eq!(merged.aliases, vec!["Smith, Alice", "lichess.org:alice"]);
+        // the chess.com alice's game shares a name with an alias but not the site
+        let identity = PlayerIdentity::resolve(&db, &format!("#{}", alice.id)).unwrap();
+        let ids: Vec<i64> = identity.games(&db).unwrap().iter().map(|g| g.id).collect();
+        assert_eq!(ids, [games[0].id, games[2].id]);
+
+        // nobody registered under this name: matched on the name alone
+        let unknown = PlayerReport::for_player(&db, "Jones, Bob").unwrap();
+        assert_eq!(unknown.total.games, 1);
+    }
+}
@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use shakmaty::variant::VariantPosition;
+use shakmaty::{Chess, Move, Position, Role};
+
+use crate::parsing::{BitPosition, GameVisitor};
+
+/*
+Polyglot opening books
+
+A .bin book is a flat list of 16 byte big endian entries sorted by key:
+
+    key     u64   zobrist key of the position (see BitPosition::zobrist)
+    move    u16   to file/row in bits 0-5, from file/row in bits 6-11,
+                  promotion piece in bits 12-14 (0 none, 1 n, 2 b, 3 r, 4 q)
+    weight  u16   relative preference among the moves for one key
+    learn   u32   unused here, written as 0
+
+Castling is encoded as the king capturing its own rook (e1h1, e1a1, e8h8, e8a8).
+Books are standard chess: games of other variants or from a FEN are left out.
+ */
+
+pub const BOOK_ENTRY_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BookEntry {
+    pub key: u64,
+    pub mv: u16,
+    pub weight: u16,
+    pub learn: u32,
+}
+
+impl BookEntry {
+    pub fn to_bytes(self) -> [u8; BOOK_ENTRY_SIZE] {
+        let mut bytes = [0u8; BOOK_ENTRY_SIZE];
+        bytes[0..8].copy_from_slice(&self.key.to_be_bytes());
+        bytes[8..10].copy_from_slice(&self.mv.to_be_bytes());
+        bytes[10..12].copy_from_slice(&self.weight.to_be_bytes());
+        bytes[12..16].copy_from_slice(&self.learn.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> BookEntry {
+        BookEntry {
+            key: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            mv: u16::from_be_bytes(bytes[8..10].try_into().unwrap()),
+            weight: u16::from_be_bytes(bytes[10..12].try_into().unwrap()),
+            learn: u32::from_be_bytes(bytes[12..16].try_into().unwrap()),
+        }
+    }
+
+    // move in polyglot's own uci flavour, castling shows up as e1h1 etc.
+    pub fn move_uci(&self) -> String {
+        let square = |sq: u16| {
+            let file = (b'a' + (sq & 7) as u8) as char;
+            let rank = (b'1' + ((sq >> 3) & 7) as u8) as char;
+            format!("{}{}", file, rank)
+        };
+        let mut uci = format!("{}{}", square((self.mv >> 6) & 0x3f), square(self.mv & 0x3f));
+        match (self.mv >> 12) & 0x7 {
+            1 => uci.push('n'),
+            2 => uci.push('b'),
+            3 => uci.push('r'),
+            4 => uci.push('q'),
+            _ => (),
+        }
+        uci
+    }
+}
+
+pub fn encode_move(m: &Move) -> Option<u16> {
+    let (from, to, promotion) = match *m {
+        Move::Normal { from, to, promotion, .. } => (from, to, promotion),
+        Move::EnPassant { from, to } => (from, to, None),
+        Move::Castle { king, rook } => (king, rook, None),
+        Move::Put { .. } => return None,
+    };
+    let promotion: u16 = match promotion {
+        None => 0,
+        Some(Role::Knight) => 1,
+        Some(Role::Bishop) => 2,
+        Some(Role::Rook) => 3,
+        Some(Role::Queen) => 4,
+        Some(_) => return None,
+    };
+    Some(u32::from(to) as u16 | (u32::from(from) as u16) << 6 | promotion << 12)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum BookWeight {
+    /// number of games the move was played in, unfinished or unknown results (*) included
+    Frequency,
+    /// 2 per win plus 1 per draw for the side playing the move
+    Score,
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct MoveStats {
+    pub games: u64, // every game, wins + draws + losses only count those with a result
+    pub wins: u64,
+    pub draws: u64,
+    pub losses: u64,
+}
+
+pub struct BookFilter {
+    pub min_games: u64,
+    pub min_elo: u32,
+    pub max_ply: usize,
+}
+
+// position -> move statistics aggregated over a set of games
+pub struct BookBuilder {
+    filter: BookFilter,
+    games: u64,
+    stats: HashMap<(u64, u16), MoveStats>,
+}
+
+impl BookBuilder {
+    pub fn new(filter: BookFilter) -> Self {
+        BookBuilder {
+            filter,
+            games: 0,
+            stats: HashMap::new(),
+        }
+    }
+
+    pub fn games(&self) -> u64 {
+        self.games
+    }
+
+    pub fn add_game(&mut self, gv: &GameVisitor) {
+        let elo = |elo: &Option<String>| elo.as_deref().and_then(|e| e.parse::<u32>().ok()).unwrap_or(0);
+        let white_elo = elo(&gv.game.white_elo);
+        let black_elo = elo(&gv.game.black_elo);
+        // score from white's point of view, in half points; None for * and missing results
+        let white_score = match gv.game.result.as_deref() {
+            Some("1-0") => Some(2),
+            Some("0-1") => Some(0),
+            Some("1/2-1/2") => Some(1),
+            _ => None,
+        };
+
+        // the keys are standard chess from the usual start: other variants and set-up
+        // positions would put their moves under keys they do not belong to
+        let mut pos = match &gv.start {
+            VariantPosition::Chess(start) if *start == Chess::default() => start.clone(),
+            _ => return,
+        };
+        self.games += 1;
+        for san_plus in gv.sans.iter().take(self.filter.max_ply) {
+            let m = match san_plus.san.to_move(&pos) {
+                Ok(m) => m,
+                Err(_) => break,
+            };
+            let white_to_move = pos.turn().is_white();
+            let mover_elo = if white_to_move { white_elo } else { black_elo };
+            if mover_elo >= self.filter.min_elo {
+                if let Some(mv) = encode_move(&m) {
+                    let key = BitPosition::from_chess(&pos).zobrist();
+                    let stats = self.stats.entry((key, mv)).or_default();
+                    stats.games += 1;
+                    match white_score.map(|score| if white_to_move { score } else { 2 - score }) {
+                        Some(2) => stats.wins += 1,
+                        Some(1) => stats.draws += 1,
+                        Some(_) => stats.losses += 1,
+                        None => (),
+                    }
+                }
+            }
+            pos.play_unchecked(&m);
+        }
+    }
+
+    pub fn entries(&self, weight: BookWeight) -> Vec<BookEntry> {
+        let raw = |s: &MoveStats| match weight {
+            BookWeight::Frequency => s.games,
+            BookWeight::Score => 2 * s.wins + s.draws,
+        };
+        let kept: Vec<_> = self
+            .stats
+            .iter()
+            .filter(|(_, s)| s.games >= self.filter.min_games && raw(s) > 0)
+            .collect();
+
+        // weights are u16 and only compared between moves of the same position, so
+        // scale a position's moves down when its busiest move does not fit
+        let mut max_per_key = HashMap::<u64, u64>::new();
+        for (&(key, _), s) in kept.iter() {
+            let max = max_per_key.entry(key).or_default();
+            *max = (*max).max(raw(s));
+        }
+
+        let mut entries: Vec<BookEntry> = kept
+            .into_iter()
+            .map(|(&(key, mv), s)| {
+                let max = max_per_key[&key];
+                let weight = if max > u16::MAX as u64 {
+                    (raw(s) as f64 * u16::MAX as f64 / max as f64) as u16
+                } else {
+                    raw(s) as u16
+                };
+                BookEntry { key, mv, weight: weight.max(1), learn: 0 }
+            })
+            .collect();
+        entries.sort_by(|a, b| a.key.cmp(&b.key).then(b.weight.cmp(&a.weight)).then(a.mv.cmp(&b.mv)));
+        entries
+    }
+}
+
+pub fn write_book(path: &Path, entries: &[BookEntry]) -> Result<usize, std::io::Error> {
+    let mut out = BufWriter::new(File::create(path)?);
+    for entry in entries {
+        out.write_all(&entry.to_bytes())?;
+    }
+    out.flush()?;
+    Ok(entries.len())
+}
+
+pub struct PolyglotBook {
+    fh: File,
+    len: u64,
+}
+
+impl PolyglotBook {
+    pub fn open(path: &Path) -> Result<PolyglotBook, std::io::Error> {
+        let fh = File::open(path)?;
+        let size = fh.metadata()?.len();
+        if size % BOOK_ENTRY_SIZE as u64 != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("book size {} is not a multiple of {}", size, BOOK_ENTRY_SIZE),
+            ));
+        }
+        Ok(PolyglotBook { fh, len: size / BOOK_ENTRY_SIZE as u64 })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn entry(&mut self, index: u64) -> Result<BookEntry, std::io::Error> {
+        let mut bytes = [0u8; BOOK_ENTRY_SIZE];
+        self.fh.seek(SeekFrom::Start(index * BOOK_ENTRY_SIZE as u64))?;
+        self.fh.read_exact(&mut bytes)?;
+        Ok(BookEntry::from_bytes(&bytes))
+    }
+
+    pub fn entries(&mut self) -> Result<Vec<BookEntry>, std::io::Error> {
+        let mut bytes = Vec::with_capacity(self.len as usize * BOOK_ENTRY_SIZE);
+        self.fh.seek(SeekFrom::Start(0))?;
+        self.fh.read_to_end(&mut bytes)?;
+        Ok(bytes.chunks_exact(BOOK_ENTRY_SIZE).map(BookEntry::from_bytes).collect())
+    }
+
+    // all entries for a key, in file order (which is by descending weight for books we write)
+    pub fn probe(&mut self, key: u64) -> Result<Vec<BookEntry>, std::io::Error> {
+        let (mut lo, mut hi) = (0, self.len);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.entry(mid)?.key < key {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        let mut found = Vec::new();
+        while lo < self.len {
+            let entry = self.entry(lo)?;
+            if entry.key != key {
+                break;
+            }
+            found.push(entry);
+            lo += 1;
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::execution::game_for_pgn;
+
+    #[test]
+    fn test_encode_move() {
+        let pos = Chess::default();
+        let e4 = "e4".parse::<shakmaty::san::San>().unwrap().to_move(&pos).unwrap();
+        let entry = BookEntry { key: 0, mv: encode_move(&e4).unwrap(), weight: 1, learn: 0 };
+        assert_eq!(entry.move_uci(), "e2e4");
+
+        let gv = game_for_pgn("1. e4 e5 2. Nf3 Nc6 3. Bc4 Bc5 4. O-O *").unwrap();
+        let mut pos = Chess::default();
+        for san in gv.sans.iter().take(6) {
+            pos.play_unchecked(&san.san.to_move(&pos).unwrap());
+        }
+        let castle = gv.sans[6].san.to_move(&pos).unwrap();
+        let entry = BookEntry { key: 0, mv: encode_move(&castle).unwrap(), weight: 1, learn: 0 };
+        assert_eq!(entry.move_uci(), "e1h1");
+    }
+
+    #[test]
+    fn test_build_write_and_probe() {
+        let mut builder = BookBuilder::new(BookFilter { min_games: 2, min_elo: 0, max_ply: 2 });
+        for pgn in [
+            "[Result \"1-0\"] 1. e4 e5 2. Nf3 1-0",
+            "[Result \"1/2-1/2\"] 1. e4 c5 1/2-1/2",
+            "[Result \"0-1\"] 1. e4 e5 0-1",
+            "[Result \"1-0\"] 1. d4 d5 1-0",
+            "[Result \"*\"] 1. e4 *",
+        ] {
+            builder.add_game(&game_for_pgn(pgn).unwrap());
+        }
+        assert_eq!(builder.games(), 5);
+
+        let entries = builder.entries(BookWeight::Frequency);
+        // e4 (4 games, the unfinished one too) and e5 (2 games) pass min_games, d4/c5/d5 do not
+        assert_eq!(entries.len(), 2);
+
+        let dir = TempDir::new("book");
+        let path = dir.join("book.bin");
+        assert_eq!(write_book(&path, &entries).unwrap(), 2);
+
+        let mut book = PolyglotBook::open(&path).unwrap();
+        assert_eq!(book.len(), 2);
+        let start = BitPosition::from_chess(&Chess::default()).zobrist();
+        let moves = book.probe(start).unwrap();
+        assert_eq!(moves.len(), 1);
+        assert_eq!(moves[0].move_uci(), "e2e4");
+        assert_eq!(moves[0].weight, 4);
+
+        let scored = builder.entries(BookWeight::Score);
+        let e4 = scored.iter().find(|e| e.key == start).unwrap();
+        assert_eq!(e4.weight, 2 + 1); // one white win, one draw, one loss, one without a result
+    }
+
+    #[test]
+    fn test_variants_left_out() {
+        let mut builder = BookBuilder::new(BookFilter { min_games: 1, min_elo: 0, max_ply: 4 });
+        for pgn in [
+            "[Variant \"Crazyhouse\"] [Result \"1-0\"] 1. e4 d5 2. exd5 Qxd5 1-0",
+            "[SetUp \"1\"] [FEN \"rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1\"] [Result \"0-1\"] 1... e5 2. Nf3 0-1",
+        ] {
+            builder.add_game(&game_for_pgn(pgn).unwrap());
+        }
+        assert_eq!(builder.games(), 0);
+        assert!(builder.entries(BookWeight::Frequency).is_empty());
+
+        builder.add_game(&game_for_pgn("[Result \"1-0\"] 1. e4 e5 1-0").unwrap());
+        assert_eq!((builder.games(), builder.entries(BookWeight::Frequency).len()), (1, 2));
+    }
+}
@@ -0,0 +1,525 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use shakmaty::CastlingMode;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::db::{Db, Game, GameFilter, GamePositionKey, GameText};
+use crate::execution::{open_store_read_only, StoreKind};
+use crate::explorer;
+use crate::parsing::BitPosition;
+use crate::persistance::PositionStore;
+use crate::player::{PlayerIdentity, PlayerReport};
+use crate::variants;
+
+/*
+Query server
+
+`crusty serve` answers JSON over HTTP so several people can query one dataset:
+
+    GET  /api/games/<id>                 a game
+    GET  /api/games?q=<terms>            full-text search, as `crusty search`
+    GET  /api/games?player=..            filtered games, as `crusty export`; also eco, result,
+                                         variant, event, site, since and until
+    GET  /api/position?fen=..            the position in the store and the games reaching it
+    GET  /api/explorer?fen=..            moves played from the position, as `crusty position --json`
+    GET  /api/player?name=..             a player report, as `crusty player --json`
+    POST /api/games                      a pgn body to import, only with --allow-import
+
+position and explorer take variant=.. like `crusty position`, the lists take limit=..
+(default 50, at most 1000). Failures are {"error": ".."} with status 400 for bad
+parameters and unknown players, 403 for imports on a read-only server, 404 for unknown
+games and paths, 413 for a posted body over MAX_BODY and 500 when the data fails.
+
+Every worker thread has its own read-only connection to data.db (WAL lets them read
+while an import writes) and its own read-only position store, opened by its first
+position request and refreshed by the next ones, so a request sees the segments the
+manifest lists when it comes in and the server never writes manifest.json. Posted pgn is written to uploads/
+in the data directory and imported one file at a time, as the stores take a single writer.
+ */
+
+const UPLOADS_DIR: &str = "uploads";
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 1000;
+// the largest pgn body an import takes
+const MAX_BODY: u64 = 64 << 20;
+// how often idle workers look whether the server was stopped
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub type Importer<'a> = dyn Fn(&[PathBuf]) + Send + Sync + 'a;
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl ApiError {
+    fn new(status: u16, message: impl ToString) -> ApiError {
+        ApiError { status, message: message.to_string() }
+    }
+}
+
+impl From<rusqlite::Error> for ApiError {
+    fn from(e: rusqlite::Error) -> ApiError {
+        ApiError::new(500, e)
+    }
+}
+
+impl From<Box<dyn Error>> for ApiError {
+    fn from(e: Box<dyn Error>) -> ApiError {
+        ApiError::new(500, e)
+    }
+}
+
+impl From<std::io::Error> for ApiError {
+    fn from(e: std::io::Error) -> ApiError {
+        ApiError::new(500, e)
+    }
+}
+
+#[derive(Serialize)]
+struct PositionGame {
+    game_id: i64,
+    ply: u16,
+    white: Option<String>,
+    black: Option<String>,
+    result: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PositionReport {
+    fen: String,
+    board: String,
+    zobrist: u64,
+    store: String,
+    in_store: bool,
+    games: usize,
+    variation_games: usize,
+    game_list: Vec<PositionGame>,
+}
+
+pub struct QueryServer<'a> {
+    server: Server,
+    data_dir: PathBuf,
+    db_path: PathBuf,
+    store: StoreKind,
+    pub importer: Option<Box<Importer<'a>>>, // None: read-only, posted games are refused
+    import_lock: Mutex<()>,
+    max_body: u64,
+    stopped: AtomicBool,
+}
+
+impl<'a> QueryServer<'a> {
+    pub fn bind(addr: &str, data_dir: &Path, db_path: &Path, store: StoreKind) -> Result<QueryServer<'a>, Box<dyn Error>> {
+        let server = Server::http(addr).map_err(|e| format!("listening on {} failed: {}", addr, e))?;
+        Ok(QueryServer {
+            server,
+            data_dir: data_dir.to_path_buf(),
+            db_path: db_path.to_path_buf(),
+            store,
+            importer: None,
+            import_lock: Mutex::new(()),
+            max_body: MAX_BODY,
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    // the address actually bound, e.g. when asked for port 0
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    // answers requests on `threads` workers, for good unless `stopped` is set (the tests do)
+    pub fn run(&self, threads: usize) {
+        std::thread::scope(|scope| {
+            for _ in 0..threads.max(1) {
+                scope.spawn(|| self.work());
+            }
+        });
+    }
+
+    fn work(&self) {
+        let db = Db::read_only(&self.db_path);
+        let mut store = None;
+        while !self.stopped.load(Ordering::SeqCst) {
+            let mut request = match self.server.recv_timeout(POLL_INTERVAL) {
+                Ok(Some(request)) => request,
+                Ok(None) => continue,
+                Err(e) => {
+                    println!("receiving requests failed: {}", e);
+                    return;
+                }
+            };
+            let (status, body) = match self.handle(&db, &mut store, &mut request) {
+                Ok(body) => (200, body),
+                Err(e) => (e.status, json!({ "error": e.message })),
+            };
+            let response = Response::from_string(body.to_string())
+                .with_status_code(status)
+                .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+            let url = request.url().to_string();
+            if let Err(e) = request.respond(response) {
+                println!("answering {} failed: {}", url, e);
+            }
+        }
+    }
+
+    fn handle(&self, db: &Db, store: &mut Option<Box<dyn PositionStore>>, request: &mut Request) -> Result<Value, ApiError> {
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        let params: HashMap<String, String> = form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+        let route: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        match (request.method(), route.as_slice()) {
+            (Method::Get, ["api", "games", id]) => game(db, id),
+            (Method::Get, ["api", "games"]) => games(db, &params),
+            (Method::Get, ["api", "position"]) => self.position(db, store, &params),
+            (Method::Get, ["api", "explorer"]) => {
+                let (fen, variant) = (required(&params, "fen")?, variant(&params)?);
+                Ok(json!(explorer::explore(db, fen, variant).map_err(|e| ApiError::new(400, e))?))
+            }
+            (Method::Get, ["api", "player"]) => {
+                let report = PlayerReport::for_player(db, required(&params, "name")?).map_err(player_error)?;
+                Ok(json!(report))
+            }
+            (Method::Post, ["api", "games"]) => self.import(request),
+            (method, _) => Err(ApiError::new(404, format!("nothing at {} {}", method, path))),
+        }
+    }
+
+    fn position(&self, db: &Db, store: &mut Option<Box<dyn PositionStore>>, params: &HashMap<String, String>) -> Result<Value, ApiError> {
+        let (fen, variant, limit) = (required(params, "fen")?, variant(params)?, limit(params)?);
+        let pos = variants::position_from_fen(fen, variant, CastlingMode::Standard).map_err(|e| ApiError::new(400, e))?;
+        let pos = BitPosition::from_variant(&pos);
+        let zobrist = pos.zobrist();
+        let (r12, r34, r56, r78) = pos.to_bits();
+
+        let store = match store {
+            Some(store) => {
+                store.refresh()?;
+                store
+            }
+            empty => empty.insert(open_store_read_only(self.store, &self.data_dir, db)?),
+        };
+        let candidates = store.lookup(zobrist)?;
+        let in_store = candidates.iter().any(|p| p.same_board(r12, r34, r56, r78));
+        let keys = if candidates.len() > in_store as usize {
+            GamePositionKey::games_for_board(db, zobrist, (r12, r34, r56, r78))?
+        } else {
+            GamePositionKey::games_for_zobrist(db, zobrist)?
+        };
+        let mut game_list = Vec::new();
+        for key in keys.iter().take(limit) {
+            let game = Game::query_by_id(db, key.game_id)?;
+            game_list.push(PositionGame {
+                game_id: key.game_id,
+                ply: key.ply,
+                white: game.as_ref().and_then(|g| g.white.clone()),
+                black: game.as_ref().and_then(|g| g.black.clone()),
+                result: game.as_ref().and_then(|g| g.result.clone()),
+            });
+        }
+        Ok(json!(PositionReport {
+            fen: fen.to_string(),
+            board: pos.to_board_fen(),
+            zobrist,
+            store: store.name(),
+            in_store,
+            games: keys.len(),
+            variation_games: GamePositionKey::variation_games_for_zobrist(db, zobrist)?.len(),
+            game_list,
+        }))
+    }
+
+    fn import(&self, request: &mut Request) -> Result<Value, ApiError> {
+        let importer = self
+            .importer
+            .as_ref()
+            .ok_or(ApiError::new(403, "this server is read-only, start it with --allow-import to post games"))?;
+        let mut pgn = Vec::new();
+        request.as_reader().take(self.max_body + 1).read_to_end(&mut pgn)?;
+        if pgn.len() as u64 > self.max_body {
+            return Err(ApiError::new(413, format!("the body is over {} bytes", self.max_body)));
+        }
+        if pgn.iter().all(u8::is_ascii_whitespace) {
+            return Err(ApiError::new(400, "expected pgn in the request body"));
+        }
+
+        let _importing = self.import_lock.lock().unwrap();
+        let dir = self.data_dir.join(UPLOADS_DIR);
+        std::fs::create_dir_all(&dir)?;
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+        let path = dir.join(format!("{}.pgn", nanos));
+        std::fs::write(&path, &pgn)?;
+        importer(std::slice::from_ref(&path));
+        Ok(json!({ "imported": path.strip_prefix(&self.data_dir).unwrap_or(&path), "bytes": pgn.len() }))
+    }
+}
+
+fn game(db: &Db, id: &str) -> Result<Value, ApiError> {
+    let id: i64 = id.parse().map_err(|_| ApiError::new(400, format!("'{}' is not a game id", id)))?;
+    match Game::query_by_id(db, id)? {
+        Some(game) => Ok(json!(game)),
+        None => Err(ApiError::new(404, format!("no game {}", id))),
+    }
+}
+
+fn games(db: &Db, params: &HashMap<String, String>) -> Result<Value, ApiError> {
+    let limit = limit(params)?;
+    if let Some(terms) = params.get("q") {
+        let hits = GameText::search(db, terms, limit).map_err(|e| ApiError::new(400, e))?;
+        let hits: Vec<Value> = hits
+            .into_iter()
+            .map(|hit| json!({ "game": hit.game, "rank": hit.rank, "snippet": hit.snippet }))
+            .collect();
+        return Ok(json!({ "games": hits }));
+    }
+
+    let player = match params.get("player") {
+        Some(spec) => PlayerIdentity::resolve(db, spec).map_err(player_error)?.game_filter(),
+        None => GameFilter::default(),
+    };
+    let param = |name: &str| params.get(name).cloned();
+    let filter = GameFilter {
+        eco: param("eco"),
+        result: param("result"),
+        variant: param("variant"),
+        event: param("event"),
+        site: param("site"),
+        since: param("since"),
+        until: param("until"),
+        limit: Some(limit),
+        ..player
+    };
+    let mut found = Vec::new();
+    filter.for_each(db, |game, facts| -> Result<(), ApiError> {
+        found.push(json!({ "game": game, "facts": facts }));
+        Ok(())
+    })?;
+    Ok(json!({ "games": found }))
+}
+
+// no such player, or more than one, is the caller's to fix; a failing database is not
+fn player_error(e: Box<dyn Error>) -> ApiError {
+    let status = if e.is::<rusqlite::Error>() { 500 } else { 400 };
+    ApiError::new(status, e)
+}
+
+fn required<'p>(params: &'p HashMap<String, String>, name: &str) -> Result<&'p str, ApiError> {
+    params.get(name).map(String::as_str).ok_or(ApiError::new(400, format!("missing parameter '{}'", name)))
+}
+
+fn limit(params: &HashMap<String, String>) -> Result<usize, ApiError> {
+    match params.get("limit") {
+        Some(limit) => match limit.parse::<usize>() {
+            Ok(limit) => Ok(limit.min(MAX_LIMIT)),
+            Err(_) => Err(ApiError::new(400, format!("limit '{}' is not a number", limit))),
+        },
+        None => Ok(DEFAULT_LIMIT),
+    }
+}
+
+fn variant(params: &HashMap<String, String>) -> Result<shakmaty::variant::Variant, ApiError> {
+    let name = params.get("variant").map_or("standard", String::as_str);
+    match variants::parse_variant(name) {
+        Some((variant, _)) => Ok(variant),
+        None => Err(ApiError::new(400, format!("unknown variant '{}'", name))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{GamePositionKey, SqlitePositionStore};
+    use crate::execution::game_for_pgn;
+    use crate::manifest::Manifest;
+    use crate::persistance::{Position, PositionStore, SegmentStore};
+    use crate::test_support::TempDir;
+
+    const AFTER_E4: &str = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1";
+
+    fn data_dir(name: &str) -> TempDir {
+        let dir = TempDir::new(&format!("serve-{}", name));
+        let db_path = dir.join("data.db");
+        let db = Db::new(&db_path);
+        db.init_schema();
+        let mut store = SqlitePositionStore::new(&db);
+        for pgn in [
+            "[Event \"Club\"] [Site \"https://lichess.org/abc\"] [White \"alice\"] [Black \"bob\"] [Result \"1-0\"] 1. e4 e5 2. Nf3 {a quiet developing move} 1-0",
+            "[Event \"Open\"] [Site \"Hastings\"] [White \"carol\"] [Black \"alice\"] [Result \"0-1\"] 1. e4 c5 0-1",
+        ] {
+            let gv = game_for_pgn(pgn).unwrap();
+            let id = Game::insert(&db, &gv.game).unwrap();
+            let positions: Vec<BitPosition> = std::iter::once(gv.start_position()).chain(gv.fens.iter().cloned()).collect();
+            GamePositionKey::bulk_insert(&db, &[(id, positions.iter().map(|p| p.zobrist()).collect())]).unwrap();
+            GameText::bulk_insert(&db, &[gv.text(id)]).unwrap();
+            let stored: Vec<Position> = positions
+                .iter()
+                .map(|p| {
+                    let (r12, r34, r56, r78) = p.to_bits();
+                    Position { zobrist: p.zobrist(), r12, r34, r56, r78 }
+                })
+                .collect();
+            store.insert_batch(&stored).unwrap();
+        }
+        dir
+    }
+
+    // stops the server when dropped, so a failed assertion ends the test instead of hanging it
+    struct Stop<'s, 'a>(&'s QueryServer<'a>);
+
+    impl Drop for Stop<'_, '_> {
+        fn drop(&mut self) {
+            self.0.stopped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn call(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, Value) {
+        let request = ureq::request(method, &format!("http://{}{}", addr, path));
+        let response = match request.send_string(body) {
+            Ok(response) => response,
+            Err(ureq::Error::Status(_, response)) => response,
+            Err(e) => panic!("{} {}: {}", method, path, e),
+        };
+        (response.status(), serde_json::from_str(&response.into_string().unwrap()).unwrap())
+    }
+
+    #[test]
+    fn test_queries() {
+        let dir = data_dir("queries");
+        let db_path = dir.join("data.db");
+        let server = QueryServer::bind("127.0.0.1:0", dir.path(), &db_path, StoreKind::Sqlite).unwrap();
+        let addr = server.local_addr().unwrap();
+        let get = |path: &str| call(addr, "GET", path, "");
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| server.run(2));
+            let _stop = Stop(&server);
+
+            let (status, game) = get("/api/games/1");
+            assert_eq!(status, 200);
+            assert_eq!((game["white"].as_str(), game["result"].as_str()), (Some("alice"), Some("1-0")));
+            assert_eq!(get("/api/games/9").0, 404);
+            assert_eq!(get("/api/games/x").0, 400);
+
+            let (_, found) = get("/api/games?q=quiet");
+            assert_eq!(found["games"].as_array().unwrap().len(), 1);
+            assert!(found["games"][0]["snippet"].as_str().unwrap().contains("quiet"));
+            let (_, found) = get("/api/games?player=alice&result=0-1");
+            assert_eq!(found["games"][0]["game"]["white"].as_str(), Some("carol"));
+            assert_eq!(found["games"][0]["facts"]["plies"].as_u64(), Some(2));
+            assert_eq!(get("/api/games?limit=many").0, 400);
+
+            let (status, position) = get(&format!("/api/position?fen={}", AFTER_E4.replace(' ', "+")));
+            assert_eq!(status, 200);
+            assert_eq!((position["games"].as_u64(), position["in_store"].as_bool()), (Some(2), Some(true)));
+            assert_eq!(position["game_list"][1]["white"].as_str(), Some("carol"));
+            assert_eq!(get("/api/position?fen=8/8/8/8/8/8/8/8+w+-+-+0+1").0, 400);
+            assert_eq!(get("/api/position").0, 400);
+
+            let (_, explored) = get(&format!("/api/explorer?fen={}", AFTER_E4.replace(' ', "+")));
+            let moves: Vec<&str> = explored["moves"].as_array().unwrap().iter().map(|m| m["san"].as_str().unwrap()).collect();
+            assert_eq!(moves.len(), 2);
+            assert!(moves.contains(&"e5") && moves.contains(&"c5"));
+
+            let (_, report) = get("/api/player?name=alice");
+            assert_eq!(report["total"]["games"].as_u64(), Some(2));
+            assert_eq!(report["total"]["wins"].as_u64(), Some(2));
+            assert_eq!(get("/api/player?name=%239").0, 400);
+            assert_eq!(get("/api/games?player=%239").0, 400);
+
+            assert_eq!(get("/api/nothing").0, 404);
+            let (status, refused) = call(addr, "POST", "/api/games", "1. d4 d5 *");
+            assert_eq!(status, 403);
+            assert!(refused["error"].as_str().unwrap().contains("read-only"));
+        });
+
+        // the server's connections could not have written anything anyway
+        assert!(Game::insert(&Db::read_only(&db_path), &Game::new()).is_err());
+    }
+
+    // the default store, kept open by the worker: read-only, so the server never writes
+    // manifest.json, and refreshed, so a segment another process adds is found
+    #[test]
+    fn test_segment_store() {
+        let dir = data_dir("segments");
+        let db_path = dir.join("data.db");
+        let server = QueryServer::bind("127.0.0.1:0", dir.path(), &db_path, StoreKind::Segment).unwrap();
+        let addr = server.local_addr().unwrap();
+        let position = || call(addr, "GET", &format!("/api/position?fen={}", AFTER_E4.replace(' ', "+")), "");
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| server.run(1));
+            let _stop = Stop(&server);
+            let (status, report) = position();
+            assert_eq!(status, 200);
+            assert_eq!((report["games"].as_u64(), report["in_store"].as_bool()), (Some(2), Some(false)));
+            assert!(report["store"].as_str().unwrap().starts_with("segments in"));
+            assert!(!Manifest::path(dir.path()).exists());
+
+            let after_e4 = game_for_pgn("1. e4 *").unwrap().fens[0].clone();
+            let (r12, r34, r56, r78) = after_e4.to_bits();
+            let mut writer = SegmentStore::open(dir.path()).unwrap();
+            writer.insert_batch(&[Position { zobrist: after_e4.zobrist(), r12, r34, r56, r78 }]).unwrap();
+            assert_eq!(writer.flush().unwrap(), 1);
+            let saved = std::fs::read(Manifest::path(dir.path())).unwrap();
+
+            let (_, report) = position();
+            assert_eq!(report["in_store"].as_bool(), Some(true));
+            assert_eq!(std::fs::read(Manifest::path(dir.path())).unwrap(), saved);
+        });
+    }
+
+    #[test]
+    fn test_import() {
+        let dir = data_dir("import");
+        let db_path = dir.join("data.db");
+        let imported = Mutex::new(Vec::<PathBuf>::new());
+        let mut server = QueryServer::bind("127.0.0.1:0", dir.path(), &db_path, StoreKind::Sqlite).unwrap();
+        server.importer = Some(Box::new(|paths: &[PathBuf]| imported.lock().unwrap().extend_from_slice(paths)));
+        server.max_body = 16;
+        let addr = server.local_addr().unwrap();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| server.run(1));
+            let _stop = Stop(&server);
+            let (status, body) = call(addr, "POST", "/api/games", "1. d4 d5 *");
+            assert_eq!(status, 200);
+            assert_eq!(body["bytes"].as_u64(), Some(10));
+            assert_eq!(call(addr, "POST", "/api/games", " \n").0, 400);
+            let (status, refused) = call(addr, "POST", "/api/games", "1. e4 e5 2. Nf3 Nc6 *");
+            assert_eq!(status, 413);
+            assert!(refused["error"].as_str().unwrap().contains("over 16 bytes"));
+        });
+        drop(server);
+        let imported = imported.into_inner().unwrap();
+        assert_eq!(imported.len(), 1);
+        assert_eq!(std::fs::read_to_string(&imported[0]).unwrap(), "1. d4 d5 *");
+        assert!(imported[0].starts_with(dir.join(UPLOADS_DIR)));
+    }
+
+    // a database without the tables: the player lookups fail on the database, not the name
+    #[test]
+    fn test_database_errors() {
+        let dir = TempDir::new("serve-broken");
+        let db_path = dir.join("data.db");
+        drop(Db::new(&db_path));
+        let server = QueryServer::bind("127.0.0.1:0", dir.path(), &db_path, StoreKind::Sqlite).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| server.run(1));
+            let _stop = Stop(&server);
+            assert_eq!(call(addr, "GET", "/api/player?name=alice", "").0, 500);
+            assert_eq!(call(addr, "GET", "/api/games?player=alice", "").0, 500);
+        });
+    }
+}
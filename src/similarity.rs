@@ -0,0 +1,544 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use bilge::prelude::*;
+
+use crate::parsing::{BLACK_PAWN, WHITE_PAWN};
+use crate::persistance::{Position, SEGMENT_RECORD_SIZE};
+
+/*
+Position similarity
+
+"Positions like this one", nearest first, by a distance over the 4-bit square
+codes of BitPosition::to_bits:
+
+    placement   the number of squares whose contents differ
+    pawns       the number of squares where one position has a pawn the other
+                has not (per colour), everything but the pawns ignored
+
+Both are Hamming distances, which is what makes an index possible without
+scanning every position. Each position is cut into four blocks, and two
+positions within distance 3 are identical in at least one of them (four blocks
+cannot all differ with fewer than four differences). So for each block the
+index keeps every position sorted by that block's value: a query looks its own
+four block values up, scores the positions sharing one, and keeps the nearest.
+That is exact while the k-th result is within distance 3; beyond that it is the
+nearest among positions sharing a block, and Nearest::exact says which.
+
+    placement blocks   the words r12, r34, r56, r78 (ranks 1-2, 3-4, 5-6, 7-8)
+    pawns blocks       the pawns on files a-b, c-d, e-f, g-h
+
+The index is a file next to the segments, built from the position store by
+`crusty similar --build` and rebuilt the same way after imports:
+
+    magic, u64 position count
+    the positions, 40-byte segment records in zobrist order, no duplicates
+    per metric, per block: (u64 block value, u32 position number) sorted
+
+all big endian, so up to 2^32 positions. The store can be bigger than memory,
+so the build sorts on disk: sorted runs of at most RUN_BYTES are spilled next
+to the index and k-way merged, first the positions (dropping duplicates as
+they meet), then each table's entries. Very common blocks (the back rank
+before castling, an empty middle) would make a block's range most of the index,
+so a query reads at most `candidates` entries from each range.
+ */
+
+pub const SIMILARITY_INDEX_MAGIC: [u8; 4] = [0xcc, 0xdd, 0x69, 0x53];
+const HEADER_SIZE: u64 = 12;
+const ENTRY_SIZE: usize = 12;
+const BLOCKS: usize = 4;
+const READ_ENTRIES: u64 = 4096;
+// memory for sorting while building, the positions first and then split between the tables
+const RUN_BYTES: usize = 256 << 20;
+const MERGE_WAYS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Metric {
+    /// squares whose contents differ
+    Placement,
+    /// squares with a pawn in one position and not the other, other pieces ignored
+    Pawns,
+}
+
+const METRICS: [Metric; 2] = [Metric::Placement, Metric::Pawns];
+
+// the lowest bit of each 4-bit square code
+const NIBBLE_LOW: u64 = 0x1111_1111_1111_1111;
+
+fn words(p: &Position) -> [u64; 4] {
+    [p.r12, p.r34, p.r56, p.r78]
+}
+
+// (white, black) pawn bitboards, a1 = bit 0
+fn pawns(p: &Position) -> (u64, u64) {
+    let (white_code, black_code) = (
+        u4::from(WHITE_PAWN.clone()).value() as u64,
+        u4::from(BLACK_PAWN.clone()).value() as u64,
+    );
+    let (mut white, mut black) = (0u64, 0u64);
+    for (w, word) in words(p).iter().enumerate() {
+        for i in 0..16 {
+            let code = (word >> (4 * i)) & 0xf;
+            let bit = 1u64 << (16 * w + i);
+            if code == white_code {
+                white |= bit;
+            } else if code == black_code {
+                black |= bit;
+            }
+        }
+    }
+    (white, black)
+}
+
+impl Metric {
+    pub fn distance(self, a: &Position, b: &Position) -> u32 {
+        match self {
+            Metric::Placement => words(a)
+                .iter()
+                .zip(words(b))
+                .map(|(x, y)| {
+                    let diff = x ^ y;
+                    ((diff | diff >> 1 | diff >> 2 | diff >> 3) & NIBBLE_LOW).count_ones()
+                })
+                .sum(),
+            Metric::Pawns => {
+                let ((aw, ab), (bw, bb)) = (pawns(a), pawns(b));
+                (aw ^ bw).count_ones() + (ab ^ bb).count_ones()
+            }
+        }
+    }
+
+    fn blocks(self, p: &Position) -> [u64; BLOCKS] {
+        match self {
+            Metric::Placement => words(p),
+            Metric::Pawns => {
+                let (white, black) = pawns(p);
+                let mut blocks = [0; BLOCKS];
+                for (j, block) in blocks.iter_mut().enumerate() {
+                    let files = 0x0303_0303_0303_0303u64 << (2 * j);
+                    // black's pawns two files over, off the block's files; on g-h they wrap to
+                    // a-b a rank up, and only the 8th rank (where there are no pawns) is lost
+                    *block = (white & files) | ((black & files) << 2);
+                }
+                blocks
+            }
+        }
+    }
+
+    fn index(self) -> usize {
+        METRICS.iter().position(|m| *m == self).unwrap_or(0)
+    }
+}
+
+// Fixed size records sorted by their bytes, with at most `run_bytes` of them in memory: a
+// full buffer is sorted and spilled to a run file, and `merge` hands every record back in
+// order through a k-way merge of the runs, `ways` at a time
+struct ExternalSort {
+    size: usize,
+    run_bytes: usize,
+    ways: usize,
+    prefix: PathBuf, // run files are <prefix>.1, <prefix>.2, ...
+    buffer: Vec<u8>,
+    runs: Vec<PathBuf>,
+    written: usize,
+}
+
+impl ExternalSort {
+    fn new(prefix: PathBuf, size: usize, run_bytes: usize, ways: usize) -> ExternalSort {
+        ExternalSort { size, run_bytes: run_bytes.max(size), ways: ways.max(2), prefix, buffer: Vec::new(), runs: Vec::new(), written: 0 }
+    }
+
+    fn push(&mut self, record: &[u8]) -> io::Result<()> {
+        self.buffer.extend_from_slice(record);
+        if self.buffer.len() + self.size > self.run_bytes {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn next_run(&mut self) -> PathBuf {
+        self.written += 1;
+        PathBuf::from(format!("{}.{}", self.prefix.display(), self.written))
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let path = self.next_run();
+        let mut records: Vec<&[u8]> = self.buffer.chunks_exact(self.size).collect();
+        records.sort_unstable();
+        let mut out = BufWriter::new(File::create(&path)?);
+        for record in records {
+            out.write_all(record)?;
+        }
+        out.flush()?;
+        self.buffer.clear();
+        self.runs.push(path);
+        Ok(())
+    }
+
+    // each run file is removed once it is merged
+    fn merge(mut self, f: &mut dyn FnMut(&[u8]) -> io::Result<()>) -> io::Result<()> {
+        self.spill()?;
+        self.buffer = Vec::new();
+        while self.runs.len() > self.ways {
+            let group: Vec<PathBuf> = self.runs.drain(..self.ways).collect();
+            let path = self.next_run();
+            let mut out = BufWriter::new(File::create(&path)?);
+            merge_runs(&group, self.size, &mut |record| out.write_all(record))?;
+            out.flush()?;
+            self.runs.push(path);
+            group.iter().try_for_each(std::fs::remove_file)?;
+        }
+        merge_runs(&self.runs, self.size, f)?;
+        self.runs.iter().try_for_each(std::fs::remove_file)
+    }
+}
+
+fn merge_runs(runs: &[PathBuf], size: usize, f: &mut dyn FnMut(&[u8]) -> io::Result<()>) -> io::Result<()> {
+    let mut readers = runs.iter().map(|run| File::open(run).map(BufReader::new)).collect::<io::Result<Vec<_>>>()?;
+    let mut heap = BinaryHeap::new();
+    for (i, reader) in readers.iter_mut().enumerate() {
+        if let Some(record) = next_record(reader, size)? {
+            heap.push(Reverse((record, i)));
+        }
+    }
+    while let Some(Reverse((record, i))) = heap.pop() {
+        f(&record)?;
+        if let Some(record) = next_record(&mut readers[i], size)? {
+            heap.push(Reverse((record, i)));
+        }
+    }
+    Ok(())
+}
+
+// the next record of a run, None at its end
+fn next_record(reader: &mut impl Read, size: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut record = vec![0u8; size];
+    match reader.read_exact(&mut record) {
+        Ok(()) => Ok(Some(record)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Writes the index from positions pushed in any order, duplicates and all. Positions
+// and then each table's entries go through an ExternalSort, so memory stays within
+// RUN_BYTES however big the store; the runs live in <index>.runs until finish.
+pub struct IndexBuilder {
+    path: PathBuf,
+    runs: PathBuf,
+    run_bytes: usize,
+    ways: usize,
+    positions: Option<ExternalSort>,
+}
+
+impl IndexBuilder {
+    pub fn new(path: &Path) -> io::Result<IndexBuilder> {
+        IndexBuilder::with_limits(path, RUN_BYTES, MERGE_WAYS)
+    }
+
+    fn with_limits(path: &Path, run_bytes: usize, ways: usize) -> io::Result<IndexBuilder> {
+        let runs = path.with_extension("runs");
+        // left over by a build that was killed
+        if runs.exists() {
+            std::fs::remove_dir_all(&runs)?;
+        }
+        std::fs::create_dir_all(&runs)?;
+        let positions = ExternalSort::new(runs.join("positions"), SEGMENT_RECORD_SIZE, run_bytes, ways);
+        Ok(IndexBuilder { path: path.to_path_buf(), runs, run_bytes, ways, positions: Some(positions) })
+    }
+
+    pub fn push(&mut self, position: &Position) -> io::Result<()> {
+        match &mut self.positions {
+            Some(positions) => positions.push(&position.to_record()),
+            None => Ok(()),
+        }
+    }
+
+    // write the index; returns how many positions it holds
+    pub fn finish(mut self) -> Result<usize, Box<dyn Error>> {
+        let positions = self.positions.take().ok_or("index already written")?;
+        let tables = METRICS.len() * BLOCKS;
+        let mut entries: Vec<ExternalSort> = (0..tables)
+            .map(|t| ExternalSort::new(self.runs.join(format!("entries{}", t)), ENTRY_SIZE, self.run_bytes / tables, self.ways))
+            .collect();
+
+        // written aside and renamed, so a half written index is never opened
+        let partial = self.path.with_extension("part");
+        let mut out = BufWriter::new(File::create(&partial)?);
+        out.write_all(&SIMILARITY_INDEX_MAGIC)?;
+        out.write_all(&0u64.to_be_bytes())?; // the count, once it is known
+        let mut count = 0u64;
+        let mut last = Vec::new();
+        positions.merge(&mut |record| {
+            // in order, so duplicates are next to each other
+            if last == record {
+                return Ok(());
+            }
+            if count > u32::MAX as u64 {
+                return Err(io::Error::other("more positions than the index can number"));
+            }
+            out.write_all(record)?;
+            let position = Position::from_record(record);
+            for metric in METRICS {
+                for (block, value) in metric.blocks(&position).into_iter().enumerate() {
+                    let mut entry = [0u8; ENTRY_SIZE];
+                    entry[..8].copy_from_slice(&value.to_be_bytes());
+                    entry[8..].copy_from_slice(&(count as u32).to_be_bytes());
+                    entries[metric.index() * BLOCKS + block].push(&entry)?;
+                }
+            }
+            count += 1;
+            last.clear();
+            last.extend_from_slice(record);
+            Ok(())
+        })?;
+        // (block value, position number) big endian sorts by its bytes
+        for table in entries {
+            table.merge(&mut |entry| out.write_all(entry))?;
+        }
+        out.seek(SeekFrom::Start(SIMILARITY_INDEX_MAGIC.len() as u64))?;
+        out.write_all(&count.to_be_bytes())?;
+        out.into_inner()?.sync_all()?;
+        std::fs::rename(&partial, &self.path)?;
+        Ok(count as usize)
+    }
+}
+
+impl Drop for IndexBuilder {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.runs);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Similar {
+    pub distance: u32,
+    pub position: Position,
+}
+
+#[derive(Debug)]
+pub struct Nearest {
+    pub found: Vec<Similar>,
+    pub candidates: usize,
+    pub exact: bool, // nothing closer than the last result was left out
+}
+
+pub struct SimilarityIndex {
+    fh: File,
+    count: u64,
+}
+
+impl SimilarityIndex {
+    pub fn open(path: &Path) -> Result<SimilarityIndex, Box<dyn Error>> {
+        let mut fh = File::open(path)?;
+        let mut header = [0u8; HEADER_SIZE as usize];
+        fh.read_exact(&mut header)?;
+        if header[..4] != SIMILARITY_INDEX_MAGIC {
+            return Err(format!("{} is not a similarity index", path.display()).into());
+        }
+        let count = u64::from_be_bytes(header[4..].try_into().unwrap());
+        let expected = HEADER_SIZE + count * (SEGMENT_RECORD_SIZE + METRICS.len() * BLOCKS * ENTRY_SIZE) as u64;
+        if fh.metadata()?.len() != expected {
+            return Err(format!("{} is truncated, rebuild it", path.display()).into());
+        }
+        Ok(SimilarityIndex { fh, count })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.count
+    }
+
+    fn position(&mut self, ordinal: u32) -> std::io::Result<Position> {
+        let mut record = [0u8; SEGMENT_RECORD_SIZE];
+        self.fh.seek(SeekFrom::Start(HEADER_SIZE + ordinal as u64 * SEGMENT_RECORD_SIZE as u64))?;
+        self.fh.read_exact(&mut record)?;
+        Ok(Position::from_record(&record))
+    }
+
+    fn table_start(&self, metric: Metric, block: usize) -> u64 {
+        let table = (metric.index() * BLOCKS + block) as u64;
+        HEADER_SIZE + self.count * SEGMENT_RECORD_SIZE as u64 + table * self.count * ENTRY_SIZE as u64
+    }
+
+    // up to `n` entries from entry `from` of a table
+    fn entries(&mut self, start: u64, from: u64, n: u64) -> std::io::Result<Vec<(u64, u32)>> {
+        let n = n.min(self.count - from);
+        let mut bytes = vec![0u8; n as usize * ENTRY_SIZE];
+        self.fh.seek(SeekFrom::Start(start + from * ENTRY_SIZE as u64))?;
+        self.fh.read_exact(&mut bytes)?;
+        Ok(bytes
+            .chunks_exact(ENTRY_SIZE)
+            .map(|e| (u64::from_be_bytes(e[..8].try_into().unwrap()), u32::from_be_bytes(e[8..].try_into().unwrap())))
+            .collect())
+    }
+
+    // the k positions nearest the query, reading at most `candidates` entries per block
+    pub fn nearest(&mut self, query: &Position, metric: Metric, k: usize, candidates: usize) -> std::io::Result<Nearest> {
+        let mut seen = HashSet::new();
+        let mut found = Vec::new();
+        let mut cut_short = false;
+        for (block, value) in metric.blocks(query).into_iter().enumerate() {
+            let start = self.table_start(metric, block);
+            // lower bound of the block value
+            let (mut lo, mut hi) = (0, self.count);
+            while lo < hi {
+                let mid = (lo + hi) / 2;
+                if self.entries(start, mid, 1)?[0].0 < value {
+                    lo = mid + 1;
+                } else {
+                    hi = mid;
+                }
+            }
+            let mut read = 0;
+            'range: while lo < self.count {
+                for (entry, ordinal) in self.entries(start, lo, READ_ENTRIES)? {
+                    if entry != value {
+                        break 'range;
+                    }
+                    if read == candidates {
+                        cut_short = true;
+                        break 'range;
+                    }
+                    read += 1;
+                    lo += 1;
+                    if seen.insert(ordinal) {
+                        let position = self.position(ordinal)?;
+                        found.push(Similar { distance: metric.distance(query, &position), position });
+                    }
+                }
+            }
+        }
+        found.sort_by_key(|s| (s.distance, s.position.zobrist));
+        found.truncate(k);
+        // fewer than k found is exact too, when nothing within reach was skipped
+        let exact = !cut_short && found.last().is_none_or(|s| (s.distance as usize) < BLOCKS);
+        Ok(Nearest { found, candidates: seen.len(), exact })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::game_for_pgn;
+    use crate::parsing::BitPosition;
+    use crate::test_support::TempDir;
+
+    fn position(pos: &BitPosition) -> Position {
+        let (r12, r34, r56, r78) = pos.to_bits();
+        Position { zobrist: pos.zobrist(), r12, r34, r56, r78 }
+    }
+
+    fn positions(pgns: &[&str]) -> Vec<Position> {
+        pgns.iter()
+            .flat_map(|pgn| {
+                let gv = game_for_pgn(pgn).unwrap();
+                std::iter::once(gv.start_position()).chain(gv.fens).map(|p| position(&p)).collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_distances() {
+        let p = positions(&["1. e4 e5 2. Nf3 *"]);
+        let (start, e4, e5, nf3) = (&p[0], &p[1], &p[2], &p[3]);
+        assert_eq!(Metric::Placement.distance(start, start), 0);
+        assert_eq!(Metric::Placement.distance(start, e4), 2);
+        assert_eq!(Metric::Placement.distance(start, nf3), 6);
+        assert_eq!(Metric::Pawns.distance(start, e5), 4);
+        assert_eq!(Metric::Pawns.distance(e5, nf3), 0);
+        // equal blocks for equal pawns, whatever else moved
+        assert_eq!(Metric::Pawns.blocks(e5), Metric::Pawns.blocks(nf3));
+        assert_ne!(Metric::Pawns.blocks(start)[2], Metric::Pawns.blocks(e4)[2]);
+    }
+
+    fn build(path: &Path, positions: &[Position], run_bytes: usize, ways: usize) -> usize {
+        let mut builder = IndexBuilder::with_limits(path, run_bytes, ways).unwrap();
+        for p in positions {
+            builder.push(p).unwrap();
+        }
+        builder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_build_on_disk() {
+        let all = positions(&[
+            "1. e4 e5 2. Nf3 Nc6 3. Bb5 a6 4. Ba4 Nf6 5. O-O Be7 *",
+            "1. d4 d5 2. c4 e6 3. Nc3 Nf6 4. cxd5 exd5 5. Bg5 c6 *",
+            "1. e4 e5 2. Nf3 Nc6 3. Bb5 a6 4. Ba4 Nf6 5. d3 *",
+        ]);
+        let mut unique = all.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        let dir = TempDir::new("similar-build");
+        let (in_memory, on_disk) = (dir.join("memory.idx"), dir.join("disk.idx"));
+        assert_eq!(build(&in_memory, &all, RUN_BYTES, MERGE_WAYS), unique.len());
+        // three positions or a couple of entries a run, merged two at a time, in reverse
+        // and twice over
+        let twice: Vec<Position> = all.iter().rev().chain(all.iter()).cloned().collect();
+        assert_eq!(build(&on_disk, &twice, 3 * SEGMENT_RECORD_SIZE, 2), unique.len());
+        assert_eq!(std::fs::read(&in_memory).unwrap(), std::fs::read(&on_disk).unwrap());
+        assert!(!on_disk.with_extension("runs").exists());
+
+        let mut index = SimilarityIndex::open(&on_disk).unwrap();
+        let found: Vec<Position> = unique.iter().map(|p| index.nearest(p, Metric::Placement, 1, 1000).unwrap().found[0].position).collect();
+        assert_eq!(found, unique);
+    }
+
+    #[test]
+    fn test_nearest() {
+        let all = positions(&[
+            "1. e4 e5 2. Nf3 Nc6 3. Bb5 a6 4. Ba4 Nf6 5. O-O Be7 *",
+            "1. e4 e5 2. Nf3 Nc6 3. Bc4 Bc5 4. c3 Nf6 5. d3 d6 *",
+            "1. d4 d5 2. c4 e6 3. Nc3 Nf6 4. cxd5 exd5 5. Bg5 c6 *",
+            "1. d4 Nf6 2. c4 e6 3. Nc3 Bb4 4. e3 O-O 5. Bd3 d5 *",
+        ]);
+        let dir = TempDir::new("similar");
+        let path = dir.join("similar.idx");
+        let count = build(&path, &all, RUN_BYTES, MERGE_WAYS);
+        let mut index = SimilarityIndex::open(&path).unwrap();
+        assert_eq!(index.len(), count as u64);
+
+        // the Ruy Lopez with 5.d3 (not itself in the index) against a brute force ranking
+        let query = positions(&["1. e4 e5 2. Nf3 Nc6 3. Bb5 a6 4. Ba4 Nf6 5. d3 *"])[9];
+        for metric in METRICS {
+            let mut brute: Vec<u32> = all.iter().map(|p| metric.distance(&query, p)).collect();
+            brute.sort();
+            let nearest = index.nearest(&query, metric, 1, 1000).unwrap();
+            assert_eq!(nearest.found[0].distance, brute[0]);
+            assert!(nearest.exact);
+            // further out it is the nearest of what shares a block, never nearer than the real ranking
+            let nearest = index.nearest(&query, metric, 5, 1000).unwrap();
+            assert!(!nearest.found.is_empty() && nearest.found.len() <= 5);
+            assert!(nearest.found.windows(2).all(|w| w[0].distance <= w[1].distance));
+            assert!(nearest.found.iter().zip(brute.iter()).all(|(s, b)| s.distance >= *b));
+        }
+        // 5.d3 against 4...Nf6: only d2 and d3 differ
+        assert_eq!(index.nearest(&query, Metric::Placement, 1, 1000).unwrap().found[0].distance, 2);
+
+        // reading one entry per block is not enough to be sure
+        assert!(!index.nearest(&query, Metric::Pawns, 3, 1).unwrap().exact);
+    }
+
+    #[test]
+    fn test_fewer_than_k() {
+        let all = positions(&["1. e4 *"]);
+        let dir = TempDir::new("similar-few");
+        let path = dir.join("similar.idx");
+        build(&path, &all, RUN_BYTES, MERGE_WAYS);
+        let mut index = SimilarityIndex::open(&path).unwrap();
+
+        // both positions found, and nothing else could have been
+        let nearest = index.nearest(&all[0], Metric::Placement, 5, 1000).unwrap();
+        assert_eq!(nearest.found.iter().map(|s| s.distance).collect::<Vec<_>>(), vec![0, 2]);
+        assert!(nearest.exact);
+        // the black half is the same in both, and only one of them is read
+        assert!(!index.nearest(&all[0], Metric::Placement, 5, 1).unwrap().exact);
+    }
+}
@@ -0,0 +1,312 @@
+use std::error::Error;
+
+use serde::Serialize;
+use shakmaty::{Color, Position};
+
+use crate::annotations::{PlyAnnotation, TimeControl};
+use crate::db::{Db, Game, GameAnnotations};
+use crate::parsing::setup_fen;
+use crate::player::{Outcome, PlayerIdentity, Score};
+use crate::variants;
+
+/*
+Time usage
+
+Worked out from the per-ply clocks stored at import (see annotations.rs), one
+game side at a time: every side of every annotated game, or only the player's
+side for a player profile.
+
+Plies alternate from the side to move in the game's start position, White
+unless a FEN header says otherwise, and are numbered from its move number.
+Phases go by move number: the opening is moves 1-10, the middlegame 11-30 and
+everything after that the endgame. To compare blitz with classical, time is
+also given as a share of the game's time budget, base + 40 * increment (the
+same estimate lichess uses to sort games into speeds).
+
+A side is in time trouble once its clock drops under the threshold; the
+correlation is Pearson's r between the lowest clock (as a share of the budget)
+and the points the side scored (a draw is half a point), over every game with a
+result.
+ */
+
+const OPENING_MOVES: u32 = 10;
+const MIDDLEGAME_MOVES: u32 = 30;
+const BUDGET_MOVES: u32 = 40;
+
+#[derive(Serialize, Debug, Default)]
+pub struct PhaseStats {
+    pub phase: &'static str,
+    pub moves: u64,
+    pub avg_seconds: Option<f64>,
+    pub avg_budget_percent: Option<f64>,
+    #[serde(skip)]
+    centis: u64,
+    #[serde(skip)]
+    budget_share: f64,
+    #[serde(skip)]
+    budget_moves: u64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct MoveCount {
+    pub move_number: u32,
+    pub sides: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TimeUsageReport {
+    pub under: u32, // seconds
+    pub sides: u64, // game sides with clock data
+    pub phases: Vec<PhaseStats>,
+    pub time_trouble: u64, // sides that went under
+    pub avg_first_move_under: Option<f64>,
+    pub first_moves_under: Vec<MoveCount>, // when sides first went under, by move number
+    pub score_in_time_trouble: Score,
+    pub score_otherwise: Score,
+    pub clock_result_correlation: Option<f64>,
+    #[serde(skip)]
+    lowest_and_points: Vec<(f64, f64)>,
+}
+
+impl TimeUsageReport {
+    pub fn new(under: u32) -> TimeUsageReport {
+        TimeUsageReport {
+            under,
+            sides: 0,
+            phases: ["opening", "middlegame", "endgame"]
+                .iter()
+                .map(|phase| PhaseStats { phase, ..Default::default() })
+                .collect(),
+            time_trouble: 0,
+            avg_first_move_under: None,
+            first_moves_under: Vec::new(),
+            score_in_time_trouble: Score::default(),
+            score_otherwise: Score::default(),
+            clock_result_correlation: None,
+            lowest_and_points: Vec::new(),
+        }
+    }
+
+    // every side of every annotated game
+    pub fn for_all(db: &Db, under: u32) -> Result<TimeUsageReport, Box<dyn Error>> {
+        let mut report = TimeUsageReport::new(under);
+        GameAnnotations::for_each_game(db, |game, plies| {
+            report.add_side(&game, &plies, true);
+            report.add_side(&game, &plies, false);
+        })?;
+        Ok(report.finish())
+    }
+
+    // spec as in PlayerIdentity::resolve
+    pub fn for_player(db: &Db, spec: &str, under: u32) -> Result<TimeUsageReport, Box<dyn Error>> {
+        let identity = PlayerIdentity::resolve(db, spec)?;
+        let mut report = TimeUsageReport::new(under);
+        GameAnnotations::for_each_game_of(db, &identity.names(), |game, plies| {
+            let as_white = identity.plays(game.white.as_deref(), &game.site);
+            if as_white || identity.plays(game.black.as_deref(), &game.site) {
+                report.add_side(&game, &plies, as_white);
+            }
+        })?;
+        Ok(report.finish())
+    }
+
+    pub fn add_side(&mut self, game: &Game, plies: &[PlyAnnotation], as_white: bool) {
+        let (first, fullmoves) = first_move(game);
+        // counted in half moves from White's move of the start's move number
+        let offset = (first == Color::Black) as usize;
+        let side: Vec<(u32, &PlyAnnotation)> = plies
+            .iter()
+            .enumerate()
+            .filter(|(ply, p)| (ply + offset).is_multiple_of(2) == as_white && p.clock.is_some())
+            .map(|(ply, p)| (fullmoves + (ply + offset) as u32 / 2, p))
+            .collect();
+        if side.is_empty() {
+            return;
+        }
+        self.sides += 1;
+        let budget = game
+            .time_control
+            .as_deref()
+            .and_then(TimeControl::parse)
+            .map(|tc| (tc.base + BUDGET_MOVES * tc.increment) * 100)
+            .filter(|budget| *budget > 0);
+
+        for (move_number, ply) in side.iter() {
+            let spent = match ply.spent {
+                Some(spent) => spent,
+                None => continue,
+            };
+            let phase = match move_number {
+                n if *n <= OPENING_MOVES => &mut self.phases[0],
+                n if *n <= MIDDLEGAME_MOVES => &mut self.phases[1],
+                _ => &mut self.phases[2],
+            };
+            phase.moves += 1;
+            phase.centis += spent as u64;
+            if let Some(budget) = budget {
+                phase.budget_share += spent as f64 / budget as f64;
+                phase.budget_moves += 1;
+            }
+        }
+
+        let outcome = Outcome::for_side(game.result.as_deref(), as_white);
+        match side.iter().find(|(_, p)| p.clock.is_some_and(|c| c < self.under * 100)) {
+            Some((move_number, _)) => {
+                self.time_trouble += 1;
+                match self.first_moves_under.iter_mut().find(|m| m.move_number == *move_number) {
+                    Some(count) => count.sides += 1,
+                    None => self.first_moves_under.push(MoveCount { move_number: *move_number, sides: 1 }),
+                }
+                self.score_in_time_trouble.add(outcome);
+            }
+            None => self.score_otherwise.add(outcome),
+        }
+        let lowest = side.iter().filter_map(|(_, p)| p.clock).min();
+        if let (Some(lowest), Some(budget), Some(points)) = (lowest, budget, outcome.points()) {
+            self.lowest_and_points.push((lowest as f64 / budget as f64, points));
+        }
+    }
+
+    pub fn finish(mut self) -> TimeUsageReport {
+        for phase in self.phases.iter_mut() {
+            if phase.moves > 0 {
+                phase.avg_seconds = Some(phase.centis as f64 / phase.moves as f64 / 100.0);
+            }
+            if phase.budget_moves > 0 {
+                phase.avg_budget_percent = Some(phase.budget_share / phase.budget_moves as f64 * 100.0);
+            }
+        }
+        self.first_moves_under.sort_by_key(|m| m.move_number);
+        if self.time_trouble > 0 {
+            let total: u64 = self.first_moves_under.iter().map(|m| m.move_number as u64 * m.sides).sum();
+            self.avg_first_move_under = Some(total as f64 / self.time_trouble as f64);
+        }
+        self.clock_result_correlation = pearson(&self.lowest_and_points);
+        self
+    }
+}
+
+// who makes the game's first move and its move number
+fn first_move(game: &Game) -> (Color, u32) {
+    let fen = match game.pgn.as_deref().and_then(setup_fen) {
+        Some(fen) => fen,
+        None => return (Color::White, 1),
+    };
+    match variants::start_position(game.variant.as_deref(), Some(&fen)) {
+        Ok(start) => (start.turn(), start.fullmoves().get()),
+        Err(_) => (Color::White, 1),
+    }
+}
+
+fn pearson(pairs: &[(f64, f64)]) -> Option<f64> {
+    if pairs.len() < 2 {
+        return None;
+    }
+    let n = pairs.len() as f64;
+    let (mean_x, mean_y) = pairs.iter().fold((0.0, 0.0), |(x, y), (px, py)| (x + px / n, y + py / n));
+    let (mut cov, mut var_x, mut var_y) = (0.0, 0.0, 0.0);
+    for (x, y) in pairs {
+        cov += (x - mean_x) * (y - mean_y);
+        var_x += (x - mean_x).powi(2);
+        var_y += (y - mean_y).powi(2);
+    }
+    if var_x == 0.0 || var_y == 0.0 {
+        return None;
+    }
+    Some(cov / (var_x * var_y).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::annotations::fill_spent;
+
+    // a 3+0 game where each side spends `spent` centiseconds on every move
+    fn game(result: &str, moves: usize, spent: [u32; 2]) -> (Game, Vec<PlyAnnotation>) {
+        let mut game = Game::new();
+        game.result = Some(result.to_string());
+        game.time_control = Some("180+0".to_string());
+        let mut clocks = [18000u32, 18000u32];
+        let mut plies: Vec<PlyAnnotation> = (0..moves * 2)
+            .map(|ply| {
+                clocks[ply % 2] = clocks[ply % 2].saturating_sub(spent[ply % 2]);
+                PlyAnnotation { clock: Some(clocks[ply % 2]), ..Default::default() }
+            })
+            .collect();
+        fill_spent(&mut plies, TimeControl::parse("180+0"));
+        (game, plies)
+    }
+
+    #[test]
+    fn test_time_usage() {
+        let mut report = TimeUsageReport::new(30);
+        // white burns 5s a move and runs low from move 31 on, black plays at 1s a move
+        for (result, moves) in [("0-1", 40), ("1-0", 20), ("0-1", 35)] {
+            let (game, plies) = game(result, moves, [500, 100]);
+            report.add_side(&game, &plies, true);
+            report.add_side(&game, &plies, false);
+        }
+        let report = report.finish();
+
+        assert_eq!(report.sides, 6);
+        assert_eq!(report.phases[0].avg_seconds, Some(3.0)); // white 5s, black 1s
+        assert!((report.phases[0].avg_budget_percent.unwrap() - 3.0 / 180.0 * 100.0).abs() < 1e-9);
+        assert_eq!(report.time_trouble, 2);
+        assert_eq!(report.first_moves_under, vec![MoveCount { move_number: 31, sides: 2 }]);
+        assert_eq!(report.score_in_time_trouble, Score { games: 2, wins: 0, draws: 0, losses: 2 });
+        assert_eq!(report.score_otherwise.wins, 3);
+        assert!(report.clock_result_correlation.unwrap() > 0.5);
+    }
+
+    // a SetUp game with Black to move: the first clock is Black's, on the FEN's move number
+    #[test]
+    fn test_black_to_move() {
+        let (mut game, plies) = game("0-1", 15, [500, 100]);
+        game.pgn = Some("[SetUp \"1\"]\n[FEN \"4k3/8/8/8/8/8/4P3/4K3 b - - 0 20\"]\n\n20... Kd7 *\n".to_string());
+        let side = |as_white| {
+            let mut report = TimeUsageReport::new(30);
+            report.add_side(&game, &plies, as_white);
+            report.finish()
+        };
+        let (white, black) = (side(true), side(false));
+        assert_eq!(black.phases[0].moves, 0);
+        // moves 20-30 and 31-34 for Black, 21-30 and 31-35 for White
+        assert_eq!((black.phases[1].moves, black.phases[2].moves), (11, 4));
+        assert_eq!((white.phases[1].moves, white.phases[2].moves), (10, 5));
+        assert_eq!(black.phases[1].avg_seconds, Some(5.0));
+        assert_eq!(white.phases[1].avg_seconds, Some(1.0));
+    }
+
+    #[test]
+    fn test_for_player() {
+        let dir = TempDir::new("time-usage");
+        let path = dir.join("data.db");
+        let db = Db::new(&path);
+        db.init_schema();
+
+        // alice on lichess twice, a different alice on chess.com, and a game without clocks
+        let players = [("alice", "bob", "https://lichess.org/a"), ("carol", "Alice", "https://lichess.org/b"),
+            ("alice", "bob", "Chess.com"), ("alice", "dave", "https://lichess.org/c")];
+        let mut annotated = Vec::new();
+        for (i, (white, black, site)) in players.iter().enumerate() {
+            let (mut game, plies) = game("1-0", 20, [500, 100]);
+            game.white = Some(white.to_string());
+            game.black = Some(black.to_string());
+            game.site = site.to_string();
+            game.hash = i as i64;
+            let game_id = Game::insert(&db, &game).unwrap();
+            if i < 3 {
+                annotated.push(GameAnnotations { game_id, plies });
+            }
+        }
+        GameAnnotations::bulk_insert(&db, &annotated).unwrap();
+        crate::db::Player::sync(&db).unwrap();
+
+        let report = TimeUsageReport::for_player(&db, "lichess.org:alice", 30).unwrap();
+        assert_eq!(report.sides, 2);
+        // 5s a move as White, 1s as Black
+        assert_eq!(report.phases[0].avg_seconds, Some(3.0));
+        assert_eq!(report.score_otherwise, Score { games: 2, wins: 1, draws: 0, losses: 1 });
+    }
+}
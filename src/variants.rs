@@ -0,0 +1,118 @@
+use std::error::Error;
+
+use shakmaty::{
+    fen::Fen,
+    variant::{Variant, VariantPosition},
+    CastlingMode,
+};
+
+/*
+Variants
+
+Games are replayed in the variant their Variant header names, from the FEN
+header when there is one (chess960, "From Position" and set-up games all come
+with a FEN). Chess960 is ordinary chess with castling worked out from the rook
+files, so it is Variant::Chess with chess960 castling.
+
+Variant positions never share keys with standard ones: BitPosition keeps the
+state the board does not show (pockets, promoted pieces, checks left, castling
+rooks off the corners) and the variant itself, and folds them into the zobrist
+key, see VariantState. Every position index (game keys, material, variation
+keys, the position stores) is keyed by zobrist, so the variants stay separate
+datasets in one database, and `crusty position --variant` picks which one a
+FEN is looked up in. Standard games keep the plain polyglot keys.
+ */
+
+// the Variant header as lichess, chess.com and most GUIs write it; None for variants
+// that cannot be replayed
+pub fn parse_variant(name: &str) -> Option<(Variant, CastlingMode)> {
+    let folded: String = name.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_lowercase();
+    if folded.contains("960") || folded.starts_with("fischer") {
+        return Some((Variant::Chess, CastlingMode::Chess960));
+    }
+    let variant = match folded.as_str() {
+        "" | "chess" | "standard" | "normal" | "fromposition" => Variant::Chess,
+        "atomic" => Variant::Atomic,
+        "antichess" => Variant::Antichess,
+        "kingofthehill" | "koth" => Variant::KingOfTheHill,
+        "3check" | "threecheck" => Variant::ThreeCheck,
+        "crazyhouse" => Variant::Crazyhouse,
+        "racingkings" => Variant::RacingKings,
+        "horde" => Variant::Horde,
+        _ => return None,
+    };
+    Some((variant, CastlingMode::Standard))
+}
+
+// where a game starts, from its Variant and FEN headers
+pub fn start_position(variant: Option<&str>, fen: Option<&str>) -> Result<VariantPosition, Box<dyn Error>> {
+    let (variant, mode) = match variant {
+        Some(name) => parse_variant(name).ok_or(format!("unknown variant '{}'", name))?,
+        None => (Variant::Chess, CastlingMode::Standard),
+    };
+    match fen {
+        Some(fen) => position_from_fen(fen, variant, mode),
+        None => Ok(VariantPosition::new(variant)),
+    }
+}
+
+// shredder and x-fen castling rights that only make sense in chess960 switch to
+// chess960 castling by themselves
+pub fn position_from_fen(fen: &str, variant: Variant, mode: CastlingMode) -> Result<VariantPosition, Box<dyn Error>> {
+    let setup = fen.trim().parse::<Fen>()?.into_setup();
+    let mode = match mode {
+        CastlingMode::Standard => CastlingMode::detect(&setup),
+        CastlingMode::Chess960 => CastlingMode::Chess960,
+    };
+    Ok(VariantPosition::from_setup(variant, setup, mode)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::game_for_pgn;
+    use crate::parsing::BitPosition;
+
+    #[test]
+    fn test_parse_variant() {
+        assert_eq!(parse_variant("Standard"), Some((Variant::Chess, CastlingMode::Standard)));
+        assert_eq!(parse_variant("Chess960"), Some((Variant::Chess, CastlingMode::Chess960)));
+        assert_eq!(parse_variant("King of the Hill"), Some((Variant::KingOfTheHill, CastlingMode::Standard)));
+        assert_eq!(parse_variant("Three-check"), Some((Variant::ThreeCheck, CastlingMode::Standard)));
+        assert_eq!(parse_variant("bughouse"), None);
+    }
+
+    #[test]
+    fn test_chess960_game() {
+        // king on c1 between rooks on b1 and g1: castling long leaves the king where it is
+        let gv = game_for_pgn(
+            "[Variant \"Chess960\"] [FEN \"nrkbbqrn/pppppppp/8/8/8/8/PPPPPPPP/NRKBBQRN w GBgb - 0 1\"] \
+             1. c3 c6 2. Bc2 Bc7 3. O-O-O O-O-O *",
+        )
+        .unwrap();
+        assert_eq!(gv.fens.len(), 6);
+        assert_eq!(gv.fens[5].to_board_fen(), "n1krbqrn/ppbppppp/2p5/8/8/2P5/PPBPPPPP/N1KRBQRN");
+        assert_eq!(gv.game.classified_eco, None);
+        // castling rooks off the corners are part of the key
+        let start = gv.start_position();
+        assert_eq!(start.variant.castling_rooks.count_ones(), 4);
+        assert_ne!(start.zobrist(), BitPosition::parse_from_str("nrkbbqrn/pppppppp/8/8/8/8/PPPPPPPP/NRKBBQRN w - - 0 1").unwrap().zobrist());
+    }
+
+    #[test]
+    fn test_crazyhouse_game() {
+        let gv = game_for_pgn("[Variant \"Crazyhouse\"] 1. e4 d5 2. exd5 Qxd5 3. Nc3 Qd8 4. P@e6 *").unwrap();
+        assert_eq!(gv.fens.len(), 7);
+        // after exd5 white holds a pawn, after Qxd5 both sides do
+        assert_eq!(gv.fens[2].variant.pockets[0][0], 1);
+        assert_eq!(gv.fens[3].variant.pockets, [[1, 0, 0, 0, 0], [1, 0, 0, 0, 0]]);
+        assert_eq!(gv.fens[6].variant.pockets[0][0], 0);
+
+        // the same moves in standard chess: same boards, different keys
+        let standard = game_for_pgn("1. e4 d5 2. exd5 Qxd5 3. Nc3 Qd8 *").unwrap();
+        for (zh, std) in gv.fens.iter().zip(standard.fens.iter()) {
+            assert_eq!(zh.to_bits(), std.to_bits());
+            assert_ne!(zh.zobrist(), std.zobrist());
+        }
+    }
+}
@@ -0,0 +1,400 @@
+use serde::Serialize;
+use shakmaty::{san::SanPlus, uci::Uci, variant::{Variant, VariantPosition}, Position, Role, Square};
+
+use crate::annotations;
+use crate::parsing::BitPosition;
+
+/*
+Move trees
+
+By default only the mainline of a game is read. With `crusty --variations`
+GameVisitor also builds the whole tree: the mainline with every (nested)
+variation hanging off the move it replaces, NAGs and comments kept on the
+move they follow (or precede, for a comment opening a line). Comments are
+stored without their [%...] commands, those are in game_annotations already.
+
+A tree is stored per game as one blob, moves as 16 bits, so it stays around
+2-3 bytes a move:
+
+    line  := varint move count, node*
+    node  := u16 move, big endian: from | to << 6 | promotion role << 12
+             u8 flags: NAGS | COMMENT_BEFORE | COMMENT | VARIATIONS
+             [u8 nag count, u8 nag*]                       if NAGS
+             [varint length, utf8]                         if COMMENT_BEFORE
+             [varint length, utf8]                         if COMMENT
+             [varint line count, line*]                    if VARIATIONS
+
+A drop (crazyhouse) is stored as a move from its square to the same square
+with the dropped role in the promotion bits. Decoding replays the moves from
+the game's start position, so SAN never has to be stored. Positions reached only inside variations are indexed separately from
+the mainline (see GamePositionKey::bulk_insert_variations), so a position
+search can tell "played" from "analysed".
+ */
+
+const NAGS: u8 = 1;
+const COMMENT_BEFORE: u8 = 2;
+const COMMENT: u8 = 4;
+const VARIATIONS: u8 = 8;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MoveNode {
+    pub san: String,
+    pub uci: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub nags: Vec<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment_before: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    // alternatives to this move, each starting from the position before it
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub variations: Vec<Vec<MoveNode>>,
+}
+
+impl MoveNode {
+    fn new(san: &SanPlus, uci: &Uci) -> MoveNode {
+        MoveNode {
+            san: san.to_string(),
+            uci: uci.to_string(),
+            nags: Vec::new(),
+            comment_before: None,
+            comment: None,
+            variations: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct MoveTree {
+    pub mainline: Vec<MoveNode>,
+}
+
+impl MoveTree {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        encode_line(&mut bytes, &self.mainline);
+        bytes
+    }
+
+    // None when the blob is cut short or a move is illegal in its position
+    pub fn decode(bytes: &[u8], start: &VariantPosition) -> Option<MoveTree> {
+        let mut reader = Reader { bytes, at: 0 };
+        let mainline = reader.line(start)?;
+        Some(MoveTree { mainline })
+    }
+
+    // pgn movetext, without the result
+    pub fn to_pgn(&self) -> String {
+        let mut pgn = String::new();
+        write_line(&mut pgn, &self.mainline, 0);
+        pgn.trim_end().to_string()
+    }
+}
+
+fn write_line(pgn: &mut String, line: &[MoveNode], start_ply: usize) {
+    let mut numbered = false;
+    for (i, node) in line.iter().enumerate() {
+        let ply = start_ply + i;
+        if let Some(comment) = &node.comment_before {
+            pgn.push_str(&format!("{{ {} }} ", comment));
+            numbered = false;
+        }
+        if ply.is_multiple_of(2) {
+            pgn.push_str(&format!("{}. ", ply / 2 + 1));
+        } else if !numbered {
+            pgn.push_str(&format!("{}... ", ply / 2 + 1));
+        }
+        numbered = true;
+        pgn.push_str(&node.san);
+        for nag in node.nags.iter() {
+            pgn.push_str(&format!(" ${}", nag));
+        }
+        pgn.push(' ');
+        if let Some(comment) = &node.comment {
+            pgn.push_str(&format!("{{ {} }} ", comment));
+            numbered = false;
+        }
+        for variation in node.variations.iter() {
+            pgn.push('(');
+            write_line(pgn, variation, ply);
+            let end = pgn.trim_end().len();
+            pgn.truncate(end);
+            pgn.push_str(") ");
+            numbered = false;
+        }
+    }
+}
+
+fn encode_line(bytes: &mut Vec<u8>, line: &[MoveNode]) {
+    write_varint(bytes, line.len());
+    for node in line {
+        bytes.extend_from_slice(&pack_move(&node.uci).to_be_bytes());
+        let flags = [
+            (!node.nags.is_empty(), NAGS),
+            (node.comment_before.is_some(), COMMENT_BEFORE),
+            (node.comment.is_some(), COMMENT),
+            (!node.variations.is_empty(), VARIATIONS),
+        ];
+        bytes.push(flags.iter().filter(|(set, _)| *set).fold(0, |acc, (_, flag)| acc | flag));
+        if !node.nags.is_empty() {
+            bytes.push(node.nags.len() as u8);
+            bytes.extend_from_slice(&node.nags);
+        }
+        for comment in [&node.comment_before, &node.comment].into_iter().flatten() {
+            write_varint(bytes, comment.len());
+            bytes.extend_from_slice(comment.as_bytes());
+        }
+        if !node.variations.is_empty() {
+            write_varint(bytes, node.variations.len());
+            for variation in node.variations.iter() {
+                encode_line(bytes, variation);
+            }
+        }
+    }
+}
+
+fn pack_move(uci: &str) -> u16 {
+    match Uci::from_ascii(uci.as_bytes()) {
+        Ok(Uci::Normal { from, to, promotion }) => {
+            u32::from(from) as u16 | (u32::from(to) as u16) << 6 | (promotion.map_or(0, |r| r as u16)) << 12
+        }
+        Ok(Uci::Put { role, to }) => (u32::from(to) as u16 * 65) | (role as u16) << 12,
+        _ => 0,
+    }
+}
+
+fn unpack_move(packed: u16) -> Option<Uci> {
+    let role = match packed >> 12 {
+        0 => None,
+        role => Some(Role::try_from(role as u32).ok()?),
+    };
+    let (from, to) = (Square::new(u32::from(packed & 63)), Square::new(u32::from((packed >> 6) & 63)));
+    match role {
+        Some(role) if from == to => Some(Uci::Put { role, to }),
+        promotion => Some(Uci::Normal { from, to, promotion }),
+    }
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        bytes.push((n as u8 & 0x7f) | 0x80);
+        n >>= 7;
+    }
+    bytes.push(n as u8);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        let taken = self.bytes.get(self.at..self.at + n)?;
+        self.at += n;
+        Some(taken)
+    }
+
+    fn varint(&mut self) -> Option<usize> {
+        let mut n = 0usize;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            n |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Some(n);
+            }
+        }
+        None
+    }
+
+    fn text(&mut self) -> Option<String> {
+        let len = self.varint()?;
+        Some(String::from_utf8_lossy(self.take(len)?).to_string())
+    }
+
+    fn line(&mut self, start: &VariantPosition) -> Option<Vec<MoveNode>> {
+        let count = self.varint()?;
+        let mut pos = start.clone();
+        let mut line = Vec::new();
+        for _ in 0..count {
+            let packed = self.take(2)?;
+            let uci = unpack_move(u16::from_be_bytes([packed[0], packed[1]]))?;
+            let flags = self.take(1)?[0];
+            let m = uci.to_move(&pos).ok()?;
+            let before = pos.clone();
+            let san = SanPlus::from_move_and_play_unchecked(&mut pos, &m);
+            let mut node = MoveNode::new(&san, &m.to_uci(before.castles().mode()));
+            if flags & NAGS != 0 {
+                let count = self.take(1)?[0] as usize;
+                node.nags = self.take(count)?.to_vec();
+            }
+            if flags & COMMENT_BEFORE != 0 {
+                node.comment_before = Some(self.text()?);
+            }
+            if flags & COMMENT != 0 {
+                node.comment = Some(self.text()?);
+            }
+            if flags & VARIATIONS != 0 {
+                for _ in 0..self.varint()? {
+                    node.variations.push(self.line(&before)?);
+                }
+            }
+            line.push(node);
+        }
+        Some(line)
+    }
+}
+
+// one line being read, the mainline or a variation
+struct Frame {
+    line: Vec<MoveNode>,
+    pos: VariantPosition,
+    before_last: VariantPosition, // where a variation of the last move starts
+    ply: u16,
+    comment: Option<String>, // read before the line's next move
+}
+
+impl Frame {
+    fn new(pos: VariantPosition, ply: u16) -> Frame {
+        Frame { line: Vec::new(), before_last: pos.clone(), pos, ply, comment: None }
+    }
+}
+
+// fed by GameVisitor as the reader walks the movetext
+pub struct TreeBuilder {
+    frames: Vec<Frame>,
+    pub variation_keys: Vec<(u16, u64)>, // (ply, zobrist) of positions reached inside variations
+}
+
+impl TreeBuilder {
+    pub fn new() -> TreeBuilder {
+        TreeBuilder { frames: vec![Frame::new(VariantPosition::new(Variant::Chess), 0)], variation_keys: Vec::new() }
+    }
+
+    // set once the headers are read
+    pub fn start_from(&mut self, start: &VariantPosition) {
+        self.frames = vec![Frame::new(start.clone(), 0)];
+    }
+
+    pub fn in_variation(&self) -> bool {
+        self.frames.len() > 1
+    }
+
+    pub fn tree(&self) -> MoveTree {
+        MoveTree { mainline: self.frames[0].line.clone() }
+    }
+
+    pub fn begin_variation(&mut self) {
+        let parent = self.frames.last().unwrap();
+        let ply = if parent.line.is_empty() { parent.ply } else { parent.ply - 1 };
+        let frame = Frame::new(parent.before_last.clone(), ply);
+        self.frames.push(frame);
+    }
+
+    pub fn end_variation(&mut self) {
+        if !self.in_variation() {
+            return;
+        }
+        let variation = self.frames.pop().unwrap().line;
+        if let Some(node) = self.frames.last_mut().unwrap().line.last_mut() {
+            if !variation.is_empty() {
+                node.variations.push(variation);
+            }
+        }
+    }
+
+    pub fn san(&mut self, san_plus: &SanPlus) {
+        let in_variation = self.in_variation();
+        let frame = self.frames.last_mut().unwrap();
+        // an illegal move ends what can be followed of the line
+        let m = match san_plus.san.to_move(&frame.pos) {
+            Ok(m) => m,
+            Err(_) => return,
+        };
+        let uci = m.to_uci(frame.pos.castles().mode());
+        frame.before_last = frame.pos.clone();
+        frame.pos.play_unchecked(&m);
+        frame.ply += 1;
+        if in_variation {
+            self.variation_keys.push((frame.ply, BitPosition::from_variant(&frame.pos).zobrist()));
+        }
+        let mut node = MoveNode::new(san_plus, &uci);
+        node.comment_before = frame.comment.take();
+        frame.line.push(node);
+    }
+
+    pub fn nag(&mut self, nag: u8) {
+        if let Some(node) = self.frames.last_mut().unwrap().line.last_mut() {
+            node.nags.push(nag);
+        }
+    }
+
+    pub fn comment(&mut self, raw: &str) {
+        let text = annotations::strip_commands(raw);
+        if text.is_empty() {
+            return;
+        }
+        let frame = self.frames.last_mut().unwrap();
+        let comment = match frame.line.last_mut() {
+            Some(node) => &mut node.comment,
+            None => &mut frame.comment,
+        };
+        match comment {
+            Some(existing) => {
+                existing.push(' ');
+                existing.push_str(&text);
+            }
+            None => *comment = Some(text),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::GameVisitor;
+    use pgn_reader::BufferedReader;
+
+    const PGN: &str = "{ start } 1. e4 $1 { best by test } ( 1. d4 d5 ( 1... Nf6 2. c4 $5 ) 2. c4 ) 1... c5 \
+                       2. Nf3 ( 2. c3 { Alapin } ) 2... d6 1-0";
+
+    #[test]
+    fn test_tree_from_pgn() {
+        let mut reader = BufferedReader::new_cursor(PGN.as_bytes());
+        let gv = reader.read_game(&mut GameVisitor::with_variations()).unwrap().unwrap();
+        // the mainline is read as before
+        assert_eq!(gv.sans.len(), 4);
+        assert_eq!(gv.comments, vec!["start", "best by test"]);
+
+        let builder = gv.tree.as_ref().unwrap();
+        let tree = builder.tree();
+        assert_eq!(tree.mainline.len(), 4);
+        assert_eq!(tree.mainline[0].nags, vec![1]);
+        assert_eq!(tree.mainline[0].comment_before.as_deref(), Some("start"));
+        assert_eq!(tree.mainline[0].variations[0][1].variations[0][1].san, "c4");
+        assert_eq!(
+            tree.to_pgn(),
+            "{ start } 1. e4 $1 { best by test } (1. d4 d5 (1... Nf6 2. c4 $5) 2. c4) 1... c5 2. Nf3 (2. c3 { Alapin }) 2... d6"
+        );
+
+        // d4, d5, Nf6, c4 (after Nf6), c4 (after d5), c3
+        let plies: Vec<u16> = builder.variation_keys.iter().map(|(ply, _)| *ply).collect();
+        assert_eq!(plies, vec![1, 2, 2, 3, 3, 3]);
+
+        let bytes = tree.encode();
+        assert!(bytes.len() < tree.to_pgn().len());
+        assert_eq!(MoveTree::decode(&bytes, &gv.start), Some(tree));
+        assert_eq!(MoveTree::decode(&bytes[..bytes.len() - 1], &gv.start), None);
+    }
+
+    #[test]
+    fn test_drops_round_trip() {
+        let pgn = "[Variant \"Crazyhouse\"] 1. e4 d5 2. exd5 Qxd5 3. Nc3 ( 3. P@e4 Qa5 ) 3... Qd8 4. P@e6 *";
+        let mut reader = BufferedReader::new_cursor(pgn.as_bytes());
+        let gv = reader.read_game(&mut GameVisitor::with_variations()).unwrap().unwrap();
+        let tree = gv.tree.as_ref().unwrap().tree();
+        assert_eq!(tree.mainline[4].variations[0][0].uci, "P@e4");
+        assert_eq!(tree.to_pgn(), "1. e4 d5 2. exd5 Qxd5 3. Nc3 (3. @e4 Qa5) 3... Qd8 4. @e6");
+        assert_eq!(MoveTree::decode(&tree.encode(), &gv.start), Some(tree));
+    }
+}